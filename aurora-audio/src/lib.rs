@@ -1,13 +1,28 @@
 use anyhow::Result;
-use rodio::{Decoder, OutputStream, Sink};
+use rodio::{Decoder, OutputStream, Sink, Source};
 use std::fs::File;
 use std::io::BufReader;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
 
 pub struct AudioEngine {
     _stream: OutputStream,
-    stream_handle: rodio::OutputStreamHandle,
     sink: Arc<Mutex<Sink>>,
+    /// Bumped whenever playback is replaced, so ranges still being sought
+    /// for the old queue are dropped rather than appended.
+    generation: Arc<AtomicU64>,
+    /// Ranges being sought in the background, not yet in the sink.
+    loading: Arc<AtomicUsize>,
+    loader: mpsc::Sender<Load>,
+}
+
+/// A range waiting for the loader thread to reach its start.
+struct Load {
+    source: Decoder<BufReader<File>>,
+    start: Duration,
+    end: Option<Duration>,
+    generation: u64,
 }
 
 // SAFETY: _stream is only kept alive and never accessed. sink is Arc<Mutex> which is Send+Sync.
@@ -17,43 +32,64 @@ unsafe impl Sync for AudioEngine {}
 impl AudioEngine {
     pub fn new() -> Result<Self> {
         let (_stream, stream_handle) = OutputStream::try_default()?;
-        let sink = Sink::try_new(&stream_handle)?;
-        
+        let sink = Arc::new(Mutex::new(Sink::try_new(&stream_handle)?));
+        let generation = Arc::new(AtomicU64::new(0));
+        let loading = Arc::new(AtomicUsize::new(0));
+
+        let (loader, loads) = mpsc::channel();
+        let (sink_load, generation_load, loading_load) = (sink.clone(), generation.clone(), loading.clone());
+        std::thread::Builder::new()
+            .name("audio-loader".into())
+            .spawn(move || load_ranges(loads, &sink_load, &generation_load, &loading_load))?;
+
         Ok(Self {
             _stream,
-            stream_handle,
-            sink: Arc::new(Mutex::new(sink)),
+            sink,
+            generation,
+            loading,
+            loader,
         })
     }
 
     pub fn play_file(&self, uri: &str) -> Result<()> {
-        let path = if uri.starts_with("file://") {
-            uri.trim_start_matches("file://")
-        } else {
-            uri
-        };
-
-        let file = File::open(path)?;
-        let source = Decoder::new(BufReader::new(file))?;
-        
+        let source = open(uri)?;
+        self.generation.fetch_add(1, Ordering::SeqCst);
+
         let sink = self.sink.lock().unwrap();
         if !sink.empty() {
             sink.stop();
-             // Since sink.stop() might not clear the queue immediately or might require a new sink for clean state,
-             // in Rodio it's often better to just append to a new sink or clear if possible.
-             // For this simple implementation, we'll just append. To "stop and play new", 
-             // we ideally create a new sink, but for now let's just create a new one to be safe.
         }
-        
-        // Re-create sink to ensure clean state for new track
-        // Note: In a real app we'd manage this better to avoid popping audio
-        // For now, let's just append to the existing sink
         sink.append(source);
         sink.play();
-        
         Ok(())
     }
 
+    /// Plays `uri` from `start` to `end`, or to the end of the file, in place
+    /// of whatever is playing. Used for tracks cut from a CUE sheet image.
+    pub fn play_range(&self, uri: &str, start: Duration, end: Option<Duration>) -> Result<()> {
+        let source = open(uri)?;
+        let generation = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        self.sink.lock().unwrap().stop();
+        self.load_range(source, start, end, generation);
+        Ok(())
+    }
+
+    /// Queues a range of `uri` behind what is playing, so it follows on
+    /// without a gap.
+    pub fn queue_range(&self, uri: &str, start: Duration, end: Option<Duration>) -> Result<()> {
+        let source = open(uri)?;
+        self.load_range(source, start, end, self.generation.load(Ordering::SeqCst));
+        Ok(())
+    }
+
+    fn load_range(&self, source: Decoder<BufReader<File>>, start: Duration, end: Option<Duration>, generation: u64) {
+        self.loading.fetch_add(1, Ordering::SeqCst);
+        let load = Load { source, start, end, generation };
+        if self.loader.send(load).is_err() {
+            self.loading.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
     pub fn pause(&self) -> Result<()> {
         self.sink.lock().unwrap().pause();
         Ok(())
@@ -65,6 +101,7 @@ impl AudioEngine {
     }
 
     pub fn stop(&self) -> Result<()> {
+        self.generation.fetch_add(1, Ordering::SeqCst);
         self.sink.lock().unwrap().stop();
         Ok(())
     }
@@ -74,7 +111,112 @@ impl AudioEngine {
     }
 
     pub fn is_busy(&self) -> bool {
-        !self.sink.lock().unwrap().empty()
+        !self.sink.lock().unwrap().empty() || self.loading.load(Ordering::SeqCst) > 0
+    }
+
+    /// How far into the sound playing now, from the start of its range for
+    /// a range.
+    pub fn position(&self) -> Duration {
+        self.sink.lock().unwrap().get_pos()
+    }
+
+    /// Sounds playing or waiting to play, counting ranges still loading.
+    pub fn queue_len(&self) -> usize {
+        self.sink.lock().unwrap().len() + self.loading.load(Ordering::SeqCst)
+    }
+}
+
+/// Appends ranges to the sink in the order they were asked for. Getting to
+/// the start of a range may mean decoding everything before it, as not every
+/// decoder can seek, so that happens here rather than on the caller's thread
+/// or the output's. Ranges for a queue that has since been replaced are
+/// given up on, even part way.
+fn load_ranges(loads: mpsc::Receiver<Load>, sink: &Mutex<Sink>, generation: &AtomicU64, loading: &AtomicUsize) {
+    const CHUNK: usize = 1 << 16;
+    let current = |load: &Load| generation.load(Ordering::SeqCst) == load.generation;
+
+    for mut load in loads {
+        if load.source.try_seek(load.start).is_err() {
+            let mut skip = samples_in(&load.source, load.start) as usize;
+            while skip > 0 && current(&load) {
+                let chunk = skip.min(CHUNK);
+                load.source.by_ref().take(chunk).for_each(drop);
+                skip -= chunk;
+            }
+        }
+
+        let sink = sink.lock().unwrap();
+        if current(&load) {
+            let remaining = load.end.map(|end| samples_in(&load.source, end.saturating_sub(load.start)));
+            sink.append(Range { inner: load.source, remaining });
+            sink.play();
+        }
+        loading.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn open(uri: &str) -> Result<Decoder<BufReader<File>>> {
+    let path = uri.strip_prefix("file://").unwrap_or(uri);
+    let file = File::open(path)?;
+    Ok(Decoder::new(BufReader::new(file))?)
+}
+
+/// Interleaved samples in `duration` of `source`, whole frames only.
+fn samples_in<S: Source>(source: &S, duration: Duration) -> u64
+where
+    S::Item: rodio::Sample,
+{
+    let frames = duration.as_micros() as u64 * source.sample_rate() as u64 / 1_000_000;
+    frames * source.channels() as u64
+}
+
+/// Plays on from where `inner` is for `remaining` samples, or to its end.
+/// Counts samples rather than time, as `TakeDuration` rounds each sample's
+/// length and drifts over a long image.
+struct Range<S> {
+    inner: S,
+    remaining: Option<u64>,
+}
+
+impl<S: Source> Iterator for Range<S>
+where
+    S::Item: rodio::Sample,
+{
+    type Item = S::Item;
+
+    fn next(&mut self) -> Option<S::Item> {
+        if let Some(remaining) = self.remaining.as_mut() {
+            if *remaining == 0 {
+                return None;
+            }
+            *remaining -= 1;
+        }
+        self.inner.next()
+    }
+}
+
+impl<S: Source> Source for Range<S>
+where
+    S::Item: rodio::Sample,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        match (self.inner.current_frame_len(), self.remaining) {
+            (Some(len), Some(remaining)) => Some(len.min(remaining as usize)),
+            (None, Some(remaining)) => Some(remaining as usize),
+            (len, None) => len,
+        }
+    }
+
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
     }
 }
 
@@ -86,6 +228,18 @@ impl mlua::UserData for ScriptableAudioEngine {
             this.0.play_file(&uri).map_err(mlua::Error::external)
         });
 
+        methods.add_method("play_range", |_lua, this, (uri, start_ms, end_ms): (String, u64, Option<u64>)| {
+            this.0
+                .play_range(&uri, Duration::from_millis(start_ms), end_ms.map(Duration::from_millis))
+                .map_err(mlua::Error::external)
+        });
+
+        methods.add_method("queue_range", |_lua, this, (uri, start_ms, end_ms): (String, u64, Option<u64>)| {
+            this.0
+                .queue_range(&uri, Duration::from_millis(start_ms), end_ms.map(Duration::from_millis))
+                .map_err(mlua::Error::external)
+        });
+
         methods.add_method("pause", |_lua, this, ()| {
             this.0.pause().map_err(mlua::Error::external)
         });
@@ -106,5 +260,13 @@ impl mlua::UserData for ScriptableAudioEngine {
         methods.add_method("is_busy", |_lua, this, ()| {
             Ok(this.0.is_busy())
         });
+
+        methods.add_method("position", |_lua, this, ()| {
+            Ok(this.0.position().as_millis() as u64)
+        });
+
+        methods.add_method("queue_len", |_lua, this, ()| {
+            Ok(this.0.queue_len())
+        });
     }
 }
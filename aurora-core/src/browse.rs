@@ -0,0 +1,460 @@
+use crate::rules::order_by;
+use crate::{track_from_row, Artist, ArtistRole, Field, Genre, LibraryManager, SortKey, Track, TRACK_SELECT};
+use anyhow::Result;
+use rusqlite::{params_from_iter, OptionalExtension};
+use rusqlite::types::Value as SqlValue;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::path::MAIN_SEPARATOR;
+
+/// Name of the collation registered on every connection by `natural_cmp`.
+pub(crate) const NATURAL_ORDER: &str = "natural_order";
+
+/// The joins behind `TRACK_SELECT`, plus play counts, for queries that
+/// group tracks rather than list them.
+const TRACK_TABLES: &str = "FROM tracks t
+     JOIN artists ar ON t.artist_id = ar.id
+     JOIN albums al ON t.album_id = al.id
+     JOIN artists aa ON al.artist_id = aa.id
+     LEFT JOIN track_play_stats s ON s.track_id = t.id";
+
+/// Order of a `TrackQuery` that sets none: albums by artist in release
+/// order, each album disc by disc.
+const LIBRARY_ORDER: [Field; 6] =
+    [Field::AlbumArtist, Field::Year, Field::Album, Field::DiscNumber, Field::TrackNumber, Field::Title];
+
+/// Which tracks to fetch, in what order and which page of them. Filters
+/// left unset match everything; those that are set must all match.
+///
+/// ```text
+/// let query = TrackQuery::new().genre(jazz).years(1955, 1965).page(0, 100);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TrackQuery {
+    /// Tracks crediting the artist in any role.
+    pub artist_id: Option<i64>,
+    pub album_id: Option<i64>,
+    pub genre_id: Option<i64>,
+    /// Inclusive. Tracks without a year match no range.
+    pub year_from: Option<u32>,
+    pub year_to: Option<u32>,
+    /// Tracks in this folder or below it.
+    pub path_prefix: Option<String>,
+    /// Library order when empty.
+    pub sort: Vec<SortKey>,
+    /// Rows skipped before the page starts.
+    pub offset: u32,
+    pub limit: Option<u32>,
+}
+
+/// An album with at least one track, and totals over its tracks.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Album {
+    pub id: i64,
+    pub title: String,
+    pub artist_id: i64,
+    /// The album artist.
+    pub artist: String,
+    pub compilation: bool,
+    /// The earliest year among its tracks.
+    pub year: Option<u32>,
+    pub track_count: u32,
+    /// Seconds.
+    pub duration: u32,
+}
+
+impl TrackQuery {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn artist(mut self, artist_id: i64) -> Self {
+        self.artist_id = Some(artist_id);
+        self
+    }
+
+    pub fn album(mut self, album_id: i64) -> Self {
+        self.album_id = Some(album_id);
+        self
+    }
+
+    pub fn genre(mut self, genre_id: i64) -> Self {
+        self.genre_id = Some(genre_id);
+        self
+    }
+
+    pub fn years(mut self, from: u32, to: u32) -> Self {
+        self.year_from = Some(from.min(to));
+        self.year_to = Some(from.max(to));
+        self
+    }
+
+    pub fn path_prefix(mut self, prefix: &str) -> Self {
+        self.path_prefix = Some(prefix.to_string());
+        self
+    }
+
+    /// Adds a sort key after those already given.
+    pub fn sort_by(mut self, field: Field, descending: bool) -> Self {
+        self.sort.push(SortKey { field, descending });
+        self
+    }
+
+    pub fn page(mut self, offset: u32, limit: u32) -> Self {
+        self.offset = offset;
+        self.limit = Some(limit);
+        self
+    }
+
+    /// The `WHERE` clause of the filters, empty if there are none. Values
+    /// are appended to `params` and numbered after those already there.
+    fn where_sql(&self, params: &mut Vec<SqlValue>) -> String {
+        let mut param = |value: SqlValue| {
+            params.push(value);
+            format!("?{}", params.len())
+        };
+        let mut conditions = Vec::new();
+        if let Some(id) = self.artist_id {
+            conditions.push(format!("t.id IN (SELECT track_id FROM track_artists WHERE artist_id = {})", param(id.into())));
+        }
+        if let Some(id) = self.album_id {
+            conditions.push(format!("t.album_id = {}", param(id.into())));
+        }
+        if let Some(id) = self.genre_id {
+            conditions.push(format!("t.id IN (SELECT track_id FROM track_genres WHERE genre_id = {})", param(id.into())));
+        }
+        if let Some(year) = self.year_from {
+            conditions.push(format!("t.year >= {}", param(year.into())));
+        }
+        if let Some(year) = self.year_to {
+            conditions.push(format!("t.year <= {}", param(year.into())));
+        }
+        if let Some(prefix) = &self.path_prefix {
+            // On a path boundary, so "/music/a" does not take in "/music/abba".
+            let prefix = if prefix.ends_with(['/', '\\']) {
+                prefix.clone()
+            } else {
+                format!("{}{}", prefix, MAIN_SEPARATOR)
+            };
+            conditions.push(format!("substr(t.path, 1, length({0})) = {0}", param(prefix.into())));
+        }
+        if conditions.is_empty() {
+            String::new()
+        } else {
+            format!(" WHERE {}", conditions.join(" AND "))
+        }
+    }
+
+    fn order_sql(&self) -> String {
+        if self.sort.is_empty() {
+            let keys: Vec<SortKey> = LIBRARY_ORDER.iter().map(|&field| SortKey { field, descending: false }).collect();
+            order_by(&keys)
+        } else {
+            order_by(&self.sort)
+        }
+    }
+
+    fn page_sql(&self, params: &mut Vec<SqlValue>) -> String {
+        params.push(self.limit.map_or(-1, i64::from).into());
+        params.push(i64::from(self.offset).into());
+        format!(" LIMIT ?{} OFFSET ?{}", params.len() - 1, params.len())
+    }
+}
+
+impl LibraryManager {
+    pub fn find_tracks(&self, query: &TrackQuery) -> Result<Vec<Track>> {
+        let mut params = Vec::new();
+        let filter = query.where_sql(&mut params);
+        let sql = format!(
+            "{} LEFT JOIN track_play_stats s ON s.track_id = t.id{} ORDER BY {}{}",
+            TRACK_SELECT,
+            filter,
+            query.order_sql(),
+            query.page_sql(&mut params)
+        );
+        let mut stmt = self.conn.prepare(&sql)?;
+        let tracks = stmt
+            .query_map(params_from_iter(params), track_from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(tracks)
+    }
+
+    /// How many tracks match the filters of `query`, whatever its page.
+    pub fn count_tracks(&self, query: &TrackQuery) -> Result<u32> {
+        let mut params = Vec::new();
+        let sql = format!("SELECT COUNT(*) {}{}", TRACK_TABLES, query.where_sql(&mut params));
+        let count = self.conn.query_row(&sql, params_from_iter(params), |row| row.get(0))?;
+        Ok(count)
+    }
+
+    /// Where a track falls among those matching the filters of `query`, in
+    /// its order and whatever its page: the offset of a page starting with
+    /// it. None when the track does not match.
+    pub fn track_position(&self, query: &TrackQuery, track_id: i64) -> Result<Option<u32>> {
+        let mut params = Vec::new();
+        let filter = query.where_sql(&mut params);
+        params.push(track_id.into());
+        let sql = format!(
+            "SELECT position FROM (SELECT t.id, ROW_NUMBER() OVER (ORDER BY {}) - 1 AS position {}{}) WHERE id = ?{}",
+            query.order_sql(),
+            TRACK_TABLES,
+            filter,
+            params.len()
+        );
+        let position = self.conn.query_row(&sql, params_from_iter(params), |row| row.get(0)).optional()?;
+        Ok(position)
+    }
+
+    /// Albums holding tracks that match the filters of `query`, with totals
+    /// over those tracks, by album artist, year and title. `query` picks
+    /// the page; its sort keys do not apply.
+    pub fn list_albums(&self, query: &TrackQuery) -> Result<Vec<Album>> {
+        let mut params = Vec::new();
+        let sql = format!(
+            "SELECT al.id, al.title, aa.id, aa.name, al.compilation, MIN(t.year), COUNT(*), SUM(t.duration)
+             {0}{1}
+             GROUP BY al.id
+             ORDER BY aa.name COLLATE {2}, MIN(t.year), al.title COLLATE {2}, al.id{3}",
+            TRACK_TABLES,
+            query.where_sql(&mut params),
+            NATURAL_ORDER,
+            query.page_sql(&mut params)
+        );
+        let mut stmt = self.conn.prepare(&sql)?;
+        let albums = stmt
+            .query_map(params_from_iter(params), |row| {
+                Ok(Album {
+                    id: row.get(0)?,
+                    title: row.get(1)?,
+                    artist_id: row.get(2)?,
+                    artist: row.get(3)?,
+                    compilation: row.get(4)?,
+                    year: row.get(5)?,
+                    track_count: row.get(6)?,
+                    duration: row.get(7)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(albums)
+    }
+
+    /// Artists credited, in `role` or in any role, on tracks that match the
+    /// filters of `query`, with totals over those tracks, by name.
+    pub fn list_artists(&self, query: &TrackQuery, role: Option<ArtistRole>) -> Result<Vec<Artist>> {
+        let mut params = vec![role.map(|role| role.name().to_string()).into()];
+        let sql = format!(
+            "SELECT c.id, c.name, COUNT(*), SUM(t.duration)
+             {}
+             JOIN (SELECT DISTINCT track_id, artist_id FROM track_artists WHERE ?1 IS NULL OR role = ?1) ta
+               ON ta.track_id = t.id
+             JOIN artists c ON c.id = ta.artist_id{}
+             GROUP BY c.id
+             ORDER BY c.name COLLATE {}, c.id{}",
+            TRACK_TABLES,
+            query.where_sql(&mut params),
+            NATURAL_ORDER,
+            query.page_sql(&mut params)
+        );
+        let mut stmt = self.conn.prepare(&sql)?;
+        let artists = stmt
+            .query_map(params_from_iter(params), |row| {
+                Ok(Artist { id: row.get(0)?, name: row.get(1)?, track_count: row.get(2)?, duration: row.get(3)? })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(artists)
+    }
+
+    /// Genres of tracks that match the filters of `query`, with totals over
+    /// those tracks, by name.
+    pub fn list_genres(&self, query: &TrackQuery) -> Result<Vec<Genre>> {
+        let mut params = Vec::new();
+        let sql = format!(
+            "SELECT g.id, g.name, COUNT(*), SUM(t.duration)
+             {}
+             JOIN track_genres tg ON tg.track_id = t.id
+             JOIN genres g ON g.id = tg.genre_id{}
+             GROUP BY g.id
+             ORDER BY g.name COLLATE {}, g.id{}",
+            TRACK_TABLES,
+            query.where_sql(&mut params),
+            NATURAL_ORDER,
+            query.page_sql(&mut params)
+        );
+        let mut stmt = self.conn.prepare(&sql)?;
+        let genres = stmt
+            .query_map(params_from_iter(params), |row| {
+                Ok(Genre { id: row.get(0)?, name: row.get(1)?, track_count: row.get(2)?, duration: row.get(3)? })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(genres)
+    }
+}
+
+/// Compares runs of digits by value and everything else ignoring case, so
+/// "Track 2" sorts before "Track 10". Falls back to a plain comparison for
+/// strings that only differ in case or leading zeros, to stay a total order.
+pub(crate) fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut x, mut y) = (a, b);
+    loop {
+        let (Some(c), Some(d)) = (x.chars().next(), y.chars().next()) else {
+            return x.len().cmp(&y.len()).then_with(|| a.cmp(b));
+        };
+        if c.is_ascii_digit() && d.is_ascii_digit() {
+            let (m, rest_x) = split_digits(x);
+            let (n, rest_y) = split_digits(y);
+            let (m, n) = (m.trim_start_matches('0'), n.trim_start_matches('0'));
+            match m.len().cmp(&n.len()).then_with(|| m.cmp(n)) {
+                Ordering::Equal => (x, y) = (rest_x, rest_y),
+                ordering => return ordering,
+            }
+        } else {
+            match c.to_lowercase().cmp(d.to_lowercase()) {
+                Ordering::Equal => (x, y) = (&x[c.len_utf8()..], &y[d.len_utf8()..]),
+                ordering => return ordering,
+            }
+        }
+    }
+}
+
+fn split_digits(text: &str) -> (&str, &str) {
+    let end = text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+    text.split_at(end)
+}
+
+impl mlua::UserData for Album {
+    fn add_fields<'lua, F: mlua::UserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_method_get("id", |_lua, this| Ok(this.id));
+        fields.add_field_method_get("title", |_lua, this| Ok(this.title.clone()));
+        fields.add_field_method_get("artist_id", |_lua, this| Ok(this.artist_id));
+        fields.add_field_method_get("artist", |_lua, this| Ok(this.artist.clone()));
+        fields.add_field_method_get("compilation", |_lua, this| Ok(this.compilation));
+        fields.add_field_method_get("year", |_lua, this| Ok(this.year));
+        fields.add_field_method_get("track_count", |_lua, this| Ok(this.track_count));
+        fields.add_field_method_get("duration", |_lua, this| Ok(this.duration));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use crate::LibraryRoot;
+
+    fn sorted(names: &[&str]) -> Vec<String> {
+        let mut names: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+        names.sort_by(|a, b| natural_cmp(a, b));
+        names
+    }
+
+    #[test]
+    fn compares_digit_runs_by_value() {
+        assert_eq!(natural_cmp("Track 2", "Track 10"), Ordering::Less);
+        assert_eq!(natural_cmp("Disc 2 Track 10", "Disc 10 Track 2"), Ordering::Less);
+        assert_eq!(natural_cmp("99 Luftballons", "100 Years"), Ordering::Less);
+        assert_eq!(natural_cmp("Track", "Track 1"), Ordering::Less);
+        // Longer than any integer type.
+        assert_eq!(natural_cmp("123456789012345678901234567890", "123456789012345678901234567891"), Ordering::Less);
+    }
+
+    #[test]
+    fn leading_zeros_only_break_ties() {
+        assert_eq!(natural_cmp("Track 010", "Track 9"), Ordering::Greater);
+        assert_eq!(natural_cmp("Track 02b", "Track 2a"), Ordering::Greater);
+        assert_eq!(natural_cmp("Track 02", "Track 2"), Ordering::Less);
+        assert_eq!(natural_cmp("Track 2", "Track 02"), Ordering::Greater);
+        assert_eq!(natural_cmp("Track 2", "Track 2"), Ordering::Equal);
+    }
+
+    #[test]
+    fn ignores_case_unless_that_is_all_that_differs() {
+        assert_eq!(natural_cmp("apple", "Banana"), Ordering::Less);
+        assert_eq!(natural_cmp("ABBA", "abba"), Ordering::Less);
+        assert_eq!(natural_cmp("abba", "ABBA"), Ordering::Greater);
+        assert_eq!(
+            sorted(&["track 9", "Track 10", "Track 1b", "Track 1", "Track 01"]),
+            ["Track 01", "Track 1", "Track 1b", "track 9", "Track 10"]
+        );
+    }
+
+    #[test]
+    fn compares_non_ascii_text_by_lowercase_character() {
+        assert_eq!(natural_cmp("Émile", "émile"), Ordering::Less);
+        assert_eq!(natural_cmp("ÉMILE 10", "émile 9"), Ordering::Greater);
+        assert_eq!(natural_cmp("Straße 2", "straße 10"), Ordering::Less);
+        assert_eq!(natural_cmp("Zappa", "Øystein"), Ordering::Less);
+        // Only ASCII digits count as numbers.
+        assert_eq!(natural_cmp("٣", "2"), Ordering::Greater);
+    }
+
+    fn library(dir: &TempDir, names: &[&str]) -> LibraryManager {
+        for name in names {
+            dir.write_wav(name);
+        }
+        let manager = LibraryManager::new(dir.path().join("aurora.db")).unwrap();
+        manager.add_library_root(&LibraryRoot::new(dir.path().join("music"))).unwrap();
+        manager.scan_library().unwrap();
+        manager
+    }
+
+    fn titles(tracks: &[Track]) -> Vec<&str> {
+        tracks.iter().map(|track| track.title.as_str()).collect()
+    }
+
+    #[test]
+    fn pages_add_up_to_the_whole_query() {
+        let dir = TempDir::new("browse-paging");
+        let names: Vec<String> = (1..=12).map(|n| format!("music/Track {}.wav", n)).collect();
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+        let manager = library(&dir, &names);
+
+        let query = TrackQuery::new().sort_by(Field::Title, false);
+        let all = manager.find_tracks(&query).unwrap();
+        let expected: Vec<String> = (1..=12).map(|n| format!("Track {}", n)).collect();
+        assert_eq!(titles(&all), expected);
+
+        let mut paged = Vec::new();
+        for offset in (0..15).step_by(5) {
+            let page = manager.find_tracks(&query.clone().page(offset, 5)).unwrap();
+            assert_eq!(page.len(), 5.min(12 - offset as usize));
+            paged.extend(page);
+        }
+        assert_eq!(titles(&paged), expected);
+        assert!(manager.find_tracks(&query.clone().page(12, 5)).unwrap().is_empty());
+        assert_eq!(manager.count_tracks(&query.clone().page(5, 5)).unwrap(), 12);
+
+        let descending = manager.find_tracks(&TrackQuery::new().sort_by(Field::Title, true).page(0, 2)).unwrap();
+        assert_eq!(titles(&descending), ["Track 12", "Track 11"]);
+    }
+
+    #[test]
+    fn finds_where_a_track_falls_in_a_query() {
+        let dir = TempDir::new("browse-position");
+        let manager = library(&dir, &["music/a/Track 1.wav", "music/a/Track 2.wav", "music/a/Track 10.wav", "music/b/Track 3.wav"]);
+        let query = TrackQuery::new().sort_by(Field::Title, false);
+        let all = manager.find_tracks(&query).unwrap();
+        for (i, track) in all.iter().enumerate() {
+            assert_eq!(manager.track_position(&query, track.id).unwrap(), Some(i as u32));
+            // The page does not matter.
+            assert_eq!(manager.track_position(&query.clone().page(2, 1), track.id).unwrap(), Some(i as u32));
+        }
+
+        let folder = dir.path().join("music").join("a");
+        let in_a = TrackQuery::new().path_prefix(&folder.to_string_lossy()).sort_by(Field::Title, true);
+        let positions: Vec<Option<u32>> = all.iter().map(|track| manager.track_position(&in_a, track.id).unwrap()).collect();
+        assert_eq!(positions, [Some(2), Some(1), None, Some(0)]);
+        assert_eq!(manager.track_position(&query, -1).unwrap(), None);
+    }
+
+    #[test]
+    fn path_prefix_stops_at_folder_boundaries() {
+        let dir = TempDir::new("browse-prefix");
+        let manager = library(&dir, &["music/a/one.wav", "music/a/deep/two.wav", "music/abba/three.wav"]);
+        let folder = dir.path().join("music").join("a");
+
+        for prefix in [folder.to_string_lossy().into_owned(), format!("{}{}", folder.to_string_lossy(), MAIN_SEPARATOR)] {
+            let query = TrackQuery::new().path_prefix(&prefix).sort_by(Field::Title, false);
+            assert_eq!(titles(&manager.find_tracks(&query).unwrap()), ["one", "two"]);
+            assert_eq!(manager.count_tracks(&query).unwrap(), 2);
+        }
+    }
+}
@@ -0,0 +1,430 @@
+use crate::{fnv1a, LibraryManager};
+use anyhow::{bail, Result};
+use lofty::config::ParseOptions;
+use lofty::file::TaggedFileExt;
+use lofty::picture::{MimeType, Picture, PictureType};
+use lofty::probe::Probe;
+use rusqlite::{params, OptionalExtension};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf, MAIN_SEPARATOR};
+
+const IMAGE_EXTENSIONS: [&str; 3] = ["jpg", "jpeg", "png"];
+
+/// Embedded pictures are looked for in this many of an album's tracks.
+const EMBEDDED_TRACKS: usize = 3;
+
+/// Words in an image's name that mark it as something other than the front
+/// cover, such as a booklet scan.
+const NOT_FRONT: [&str; 10] = ["back", "booklet", "inlay", "inside", "tray", "cd", "disc", "disk", "media", "leaflet"];
+
+/// Where an album's cover came from, as stored in `albums.cover_source`.
+/// NULL means nobody has looked yet.
+const EMBEDDED: &str = "embedded";
+const SIDECAR: &str = "sidecar";
+const NONE: &str = "none";
+
+/// A possible cover for an album. Lower ranks win: an embedded front cover,
+/// then sidecars named cover, folder and front, then embedded pictures of
+/// no particular type, then any other image in the album's folder.
+pub(crate) enum Candidate {
+    Embedded { rank: u8, data: Vec<u8>, extension: &'static str },
+    Sidecar { rank: u8, size: u64, path: PathBuf },
+}
+
+impl Candidate {
+    fn rank(&self) -> u8 {
+        match self {
+            Candidate::Embedded { rank, .. } | Candidate::Sidecar { rank, .. } => *rank,
+        }
+    }
+
+    /// Larger files of the same rank are usually the higher resolution scan.
+    fn beats(&self, other: &Candidate) -> bool {
+        match (self, other) {
+            (Candidate::Sidecar { rank: a, size: x, .. }, Candidate::Sidecar { rank: b, size: y, .. }) => (a, y) < (b, x),
+            _ => self.rank() < other.rank(),
+        }
+    }
+}
+
+/// An album's cover as the library has it, without looking for one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StoredCover {
+    Found(PathBuf),
+    /// Looked for, and there is none.
+    Missing,
+    /// Not looked for since the album's tracks or images changed, or the
+    /// cached copy has gone.
+    Unknown,
+}
+
+impl LibraryManager {
+    /// Cover image of the album a track is on.
+    pub fn get_track_cover(&self, track_id: i64) -> Result<Option<PathBuf>> {
+        match self.track_album(track_id)? {
+            Some(album_id) => self.get_album_cover(album_id),
+            None => Ok(None),
+        }
+    }
+
+    /// Cover image of an album, looking for one first if that has not been
+    /// done yet or the cached copy has gone.
+    pub fn get_album_cover(&self, album_id: i64) -> Result<Option<PathBuf>> {
+        match self.stored_album_cover(album_id)? {
+            StoredCover::Found(path) => Ok(Some(path)),
+            StoredCover::Missing => Ok(None),
+            StoredCover::Unknown => self.refresh_album_cover(album_id),
+        }
+    }
+
+    /// What `get_track_cover` would find without writing anything, so it
+    /// can run on a read-only connection. Tracks without an album have no
+    /// cover.
+    pub fn stored_track_cover(&self, track_id: i64) -> Result<StoredCover> {
+        match self.track_album(track_id)? {
+            Some(album_id) => self.stored_album_cover(album_id),
+            None => Ok(StoredCover::Missing),
+        }
+    }
+
+    pub fn stored_album_cover(&self, album_id: i64) -> Result<StoredCover> {
+        let cover: Option<(Option<String>, Option<String>)> = self.conn.query_row(
+            "SELECT cover_path, cover_source FROM albums WHERE id = ?1",
+            params![album_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        ).optional()?;
+        match cover {
+            Some((Some(path), Some(_))) if Path::new(&path).exists() => Ok(StoredCover::Found(PathBuf::from(path))),
+            Some((None, Some(_))) => Ok(StoredCover::Missing),
+            Some(_) => Ok(StoredCover::Unknown),
+            None => bail!("Album {} does not exist", album_id),
+        }
+    }
+
+    fn track_album(&self, track_id: i64) -> Result<Option<i64>> {
+        Ok(self.conn.query_row(
+            "SELECT album_id FROM tracks WHERE id = ?1",
+            params![track_id],
+            |row| row.get(0),
+        ).optional()?.flatten())
+    }
+
+    /// Looks for covers of every album that has not been looked at since
+    /// its tracks or images changed. Returns how many albums got one.
+    pub fn update_covers(&self) -> Result<u32> {
+        let found = self.find_covers()?;
+        self.store_covers(found)
+    }
+
+    /// The best cover of every album that has not been looked at since its
+    /// tracks or images changed, without writing anything, so the files can
+    /// be probed on a reader. `store_covers` records them.
+    pub(crate) fn find_covers(&self) -> Result<Vec<(i64, Option<Candidate>)>> {
+        let mut stmt = self.conn.prepare("SELECT id FROM albums WHERE cover_source IS NULL")?;
+        let albums: Vec<i64> = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+
+        let mut found = Vec::with_capacity(albums.len());
+        for album_id in albums {
+            match self.find_album_cover(album_id) {
+                Ok(cover) => found.push((album_id, cover)),
+                Err(e) => log::error!("Failed to find a cover for album {}: {}", album_id, e),
+            }
+        }
+        Ok(found)
+    }
+
+    /// Records covers from `find_covers` for albums still waiting for one.
+    /// Returns how many albums got one.
+    pub(crate) fn store_covers(&self, found: Vec<(i64, Option<Candidate>)>) -> Result<u32> {
+        if found.is_empty() {
+            return Ok(0);
+        }
+        let mut stored = 0;
+        for (album_id, cover) in found {
+            let unknown = matches!(self.stored_album_cover(album_id), Ok(StoredCover::Unknown));
+            if !unknown {
+                // Gone since, or looked at by someone else.
+                continue;
+            }
+            match self.store_album_cover(album_id, cover) {
+                Ok(Some(_)) => stored += 1,
+                Ok(None) => {}
+                Err(e) => log::error!("Failed to store the cover of album {}: {}", album_id, e),
+            }
+        }
+        self.prune_cover_cache()?;
+        Ok(stored)
+    }
+
+    /// Picks the best cover for an album from its tracks' embedded pictures
+    /// and the images next to them, and records it.
+    pub fn refresh_album_cover(&self, album_id: i64) -> Result<Option<PathBuf>> {
+        let cover = self.find_album_cover(album_id)?;
+        self.store_album_cover(album_id, cover)
+    }
+
+    fn find_album_cover(&self, album_id: i64) -> Result<Option<Candidate>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT path FROM tracks WHERE album_id = ?1 ORDER BY disc_number, track_number, path"
+        )?;
+        let paths: Vec<PathBuf> = stmt
+            .query_map(params![album_id], |row| row.get::<_, String>(0))?
+            .map(|path| path.map(PathBuf::from))
+            .collect::<rusqlite::Result<_>>()?;
+
+        let mut best: Option<Candidate> = None;
+        let mut consider = |candidate: Candidate| {
+            if best.as_ref().is_none_or(|best| candidate.beats(best)) {
+                best = Some(candidate);
+            }
+        };
+        for path in paths.iter().take(EMBEDDED_TRACKS) {
+            if let Some(candidate) = embedded_cover(path) {
+                let front = candidate.rank() == 0;
+                consider(candidate);
+                if front {
+                    break;
+                }
+            }
+        }
+        for dir in cover_dirs(&paths) {
+            for candidate in sidecar_covers(&dir) {
+                consider(candidate);
+            }
+        }
+        Ok(best)
+    }
+
+    fn store_album_cover(&self, album_id: i64, cover: Option<Candidate>) -> Result<Option<PathBuf>> {
+        let (path, source) = match cover {
+            Some(Candidate::Embedded { data, extension, .. }) => (Some(self.cache_cover(&data, extension)?), EMBEDDED),
+            Some(Candidate::Sidecar { path, .. }) => (Some(path), SIDECAR),
+            None => (None, NONE),
+        };
+        self.conn.execute(
+            "UPDATE albums SET cover_path = ?1, cover_source = ?2 WHERE id = ?3",
+            params![path.as_ref().map(|path| path.to_string_lossy()), source, album_id],
+        )?;
+        Ok(path)
+    }
+
+    pub(crate) fn forget_album_cover(&self, album_id: i64) -> Result<()> {
+        self.conn.execute(
+            "UPDATE albums SET cover_path = NULL, cover_source = NULL WHERE id = ?1",
+            params![album_id],
+        )?;
+        Ok(())
+    }
+
+    /// Marks the covers of albums with tracks below `dir` as unknown, after
+    /// an image there changed.
+    pub(crate) fn forget_covers_in(&self, dir: &Path) -> Result<()> {
+        let prefix = format!("{}{}", dir.to_string_lossy(), MAIN_SEPARATOR);
+        self.conn.execute(
+            "UPDATE albums SET cover_path = NULL, cover_source = NULL
+             WHERE id IN (SELECT album_id FROM tracks WHERE substr(path, 1, length(?1)) = ?1)",
+            params![prefix],
+        )?;
+        Ok(())
+    }
+
+    /// Writes an extracted picture into the cache, named after its content
+    /// so albums sharing a picture share the file.
+    fn cache_cover(&self, data: &[u8], extension: &str) -> Result<PathBuf> {
+        std::fs::create_dir_all(&self.cover_dir)?;
+        let path = self.cover_dir.join(format!("{:016x}-{}.{}", fnv1a(data), data.len(), extension));
+        if !path.exists() {
+            std::fs::write(&path, data)?;
+        }
+        Ok(path)
+    }
+
+    /// Deletes cached pictures no album uses any more.
+    fn prune_cover_cache(&self) -> Result<()> {
+        let Ok(entries) = std::fs::read_dir(&self.cover_dir) else {
+            return Ok(());
+        };
+        let mut stmt = self.conn.prepare("SELECT cover_path FROM albums WHERE cover_source = ?1")?;
+        let used: HashSet<PathBuf> = stmt
+            .query_map(params![EMBEDDED], |row| row.get::<_, String>(0))?
+            .map(|path| path.map(PathBuf::from))
+            .collect::<rusqlite::Result<_>>()?;
+        for entry in entries.filter_map(|e| e.ok()) {
+            let path = entry.path();
+            if path.is_file() && !used.contains(&path) {
+                if let Err(e) = std::fs::remove_file(&path) {
+                    log::error!("Failed to remove cached cover {:?}: {}", path, e);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn is_cover_image(path: &Path) -> bool {
+    path.extension()
+        .and_then(|s| s.to_str())
+        .is_some_and(|ext| IMAGE_EXTENSIONS.iter().any(|image| ext.eq_ignore_ascii_case(image)))
+}
+
+/// The best picture embedded in a file: its front cover, or failing that a
+/// picture of no particular type, which is all MP4 `covr` atoms have.
+/// Back covers, booklets and the like are never album covers.
+fn embedded_cover(path: &Path) -> Option<Candidate> {
+    let tagged_file = Probe::open(path)
+        .and_then(|probe| probe.options(ParseOptions::new().read_properties(false)).guess_file_type()?.read())
+        .map_err(|e| log::error!("Failed to read pictures from {:?}: {}", path, e))
+        .ok()?;
+    let rank = |picture: &Picture| match picture.pic_type() {
+        PictureType::CoverFront => Some(0),
+        PictureType::Other | PictureType::Undefined(_) => Some(4),
+        _ => None,
+    };
+    tagged_file
+        .tags()
+        .iter()
+        .flat_map(|tag| tag.pictures())
+        .filter_map(|picture| Some((rank(picture)?, picture)))
+        .filter_map(|(rank, picture)| Some((rank, picture, image_extension(picture)?)))
+        .min_by_key(|(rank, _, _)| *rank)
+        .map(|(rank, picture, extension)| Candidate::Embedded { rank, data: picture.data().to_vec(), extension })
+}
+
+fn image_extension(picture: &Picture) -> Option<&'static str> {
+    match picture.mime_type() {
+        Some(MimeType::Jpeg) => Some("jpg"),
+        Some(MimeType::Png) => Some("png"),
+        _ => match picture.data() {
+            [0xFF, 0xD8, ..] => Some("jpg"),
+            [0x89, b'P', b'N', b'G', ..] => Some("png"),
+            _ => None,
+        },
+    }
+}
+
+/// Folders holding an album's tracks. A folder named like "CD1" or
+/// "Disc 2" brings in its parent too, where multi-disc sets keep the cover.
+fn cover_dirs(paths: &[PathBuf]) -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    for dir in paths.iter().filter_map(|path| path.parent()) {
+        let name = dir.file_name().map(|name| name.to_string_lossy().to_lowercase()).unwrap_or_default();
+        let is_disc = ["cd", "disc", "disk"].iter().any(|prefix| {
+            name.strip_prefix(prefix)
+                .is_some_and(|rest| rest.trim_start().starts_with(|c: char| c.is_ascii_digit()))
+        });
+        let parent = dir.parent().filter(|_| is_disc);
+        for dir in std::iter::once(dir).chain(parent) {
+            if !dirs.iter().any(|known: &PathBuf| known == dir) {
+                dirs.push(dir.to_path_buf());
+            }
+        }
+    }
+    dirs
+}
+
+fn sidecar_covers(dir: &Path) -> Vec<Candidate> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(|e| e.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file() && is_cover_image(path))
+        .filter_map(|path| {
+            let stem = path.file_stem()?.to_string_lossy().to_lowercase();
+            let words: Vec<&str> = stem.split(|c: char| !c.is_alphanumeric()).collect();
+            if words.iter().any(|word| NOT_FRONT.contains(word)) {
+                return None;
+            }
+            let rank = if words.contains(&"cover") {
+                1
+            } else if words.contains(&"folder") {
+                2
+            } else if words.contains(&"front") {
+                3
+            } else {
+                5
+            };
+            let size = path.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+            Some(Candidate::Sidecar { rank, size, path })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use crate::{read_metadata, LibraryRoot};
+
+    const JPEG: [u8; 12] = [0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x10, b'J', b'F', b'I', b'F', 0x00, 0x01];
+
+    fn album_of(manager: &LibraryManager, path: &Path) -> i64 {
+        manager.conn.query_row(
+            "SELECT album_id FROM tracks WHERE path = ?1",
+            params![path.to_string_lossy()],
+            |row| row.get(0),
+        ).unwrap()
+    }
+
+    #[test]
+    fn rescans_keep_the_cover_unless_pictures_or_albums_change() {
+        let dir = TempDir::new("cover-invalidation");
+        let one = dir.write_wav("music/one.wav");
+        dir.write_wav("music/two.wav");
+        let cover = dir.write("music/cover.jpg", JPEG);
+        let manager = LibraryManager::new(dir.path().join("aurora.db")).unwrap();
+        manager.add_library_root(&LibraryRoot::new(dir.path().join("music"))).unwrap();
+        manager.scan_library().unwrap();
+        let album = album_of(&manager, &one);
+        assert_eq!(manager.stored_album_cover(album).unwrap(), StoredCover::Found(cover.clone()));
+
+        // Read again with the same pictures.
+        let mut metadata = read_metadata(&one).unwrap();
+        manager.store_metadata(&metadata, 1).unwrap();
+        assert_eq!(manager.stored_album_cover(album).unwrap(), StoredCover::Found(cover.clone()));
+
+        metadata.picture_stamp = Some(1);
+        manager.store_metadata(&metadata, 1).unwrap();
+        assert_eq!(manager.stored_album_cover(album).unwrap(), StoredCover::Unknown);
+
+        // Moving to another album looks again for both.
+        manager.update_covers().unwrap();
+        metadata.album = "Another Album".into();
+        manager.store_metadata(&metadata, 1).unwrap();
+        let other = album_of(&manager, &one);
+        assert_ne!(other, album);
+        assert_eq!(manager.stored_album_cover(album).unwrap(), StoredCover::Unknown);
+        assert_eq!(manager.stored_album_cover(other).unwrap(), StoredCover::Unknown);
+    }
+
+    #[test]
+    fn finds_covers_without_writing_and_stores_them_later() {
+        let dir = TempDir::new("cover-find-store");
+        let one = dir.write_wav("music/one.wav");
+        let cover = dir.write("music/folder.jpg", JPEG);
+        let manager = LibraryManager::new(dir.path().join("aurora.db")).unwrap();
+        manager.add_library_root(&LibraryRoot::new(dir.path().join("music"))).unwrap();
+        manager.scan_library().unwrap();
+        let album = album_of(&manager, &one);
+
+        manager.forget_album_cover(album).unwrap();
+        let found = manager.find_covers().unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(manager.stored_album_cover(album).unwrap(), StoredCover::Unknown);
+        assert_eq!(manager.store_covers(found).unwrap(), 1);
+        assert_eq!(manager.stored_album_cover(album).unwrap(), StoredCover::Found(cover.clone()));
+
+        // An album looked at in the meantime keeps what it has.
+        manager.forget_album_cover(album).unwrap();
+        let found = manager.find_covers().unwrap();
+        manager.conn.execute(
+            "UPDATE albums SET cover_path = NULL, cover_source = ?1 WHERE id = ?2",
+            params![NONE, album],
+        ).unwrap();
+        assert_eq!(manager.store_covers(found).unwrap(), 0);
+        assert_eq!(manager.stored_album_cover(album).unwrap(), StoredCover::Missing);
+    }
+}
@@ -0,0 +1,397 @@
+use crate::{track_from_row, LibraryManager, Track, TrackQuery, TRACK_SELECT};
+use anyhow::Result;
+use rusqlite::params;
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+
+const SPLIT_RULES: &str = "split_rules";
+
+/// ID3v2.4 separates multiple values in one frame with NUL, whatever the
+/// configured separators are.
+const NUL: &str = "\0";
+
+/// What an artist did on a track.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArtistRole {
+    Main,
+    Featured,
+    Composer,
+    Remixer,
+}
+
+impl ArtistRole {
+    const ALL: [ArtistRole; 4] = [ArtistRole::Main, ArtistRole::Featured, ArtistRole::Composer, ArtistRole::Remixer];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ArtistRole::Main => "main",
+            ArtistRole::Featured => "featured",
+            ArtistRole::Composer => "composer",
+            ArtistRole::Remixer => "remixer",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|role| role.name().eq_ignore_ascii_case(name))
+    }
+}
+
+/// One artist credited on a track.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Credit {
+    pub artist_id: i64,
+    pub name: String,
+    pub role: ArtistRole,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Artist {
+    pub id: i64,
+    pub name: String,
+    pub track_count: u32,
+    /// Seconds.
+    pub duration: u32,
+}
+
+/// How tag values holding several names are taken apart. Matching ignores
+/// ASCII case.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SplitRules {
+    /// Text between two names, e.g. `";"`.
+    pub separators: Vec<String>,
+    /// Words that introduce featured artists, e.g. `"feat."`. They only
+    /// count as a word of their own, after a space or an opening bracket.
+    pub featuring: Vec<String>,
+    /// Names that contain a separator but are one artist, e.g. `"AC/DC"`.
+    pub keep: Vec<String>,
+}
+
+impl Default for SplitRules {
+    fn default() -> Self {
+        let strings = |values: &[&str]| values.iter().map(|value| value.to_string()).collect();
+        SplitRules {
+            separators: strings(&[";", "/"]),
+            featuring: strings(&["feat.", "feat", "ft.", "ft", "featuring"]),
+            keep: strings(&["AC/DC"]),
+        }
+    }
+}
+
+impl SplitRules {
+    /// The names in `value`, trimmed, without empty or repeated ones.
+    pub fn split(&self, value: &str) -> Vec<String> {
+        let lower = value.to_ascii_lowercase();
+        let kept = self.kept_ranges(&lower);
+        let separators: Vec<String> = self
+            .separators
+            .iter()
+            .map(|separator| separator.to_ascii_lowercase())
+            .chain(std::iter::once(NUL.to_string()))
+            .filter(|separator| !separator.is_empty())
+            .collect();
+
+        let mut parts = Vec::new();
+        let (mut start, mut i) = (0, 0);
+        while i < value.len() {
+            let separator = if kept.iter().any(|range| range.contains(&i)) {
+                None
+            } else {
+                separators.iter().find(|separator| lower[i..].starts_with(separator.as_str()))
+            };
+            match separator {
+                Some(separator) => {
+                    parts.push(&value[start..i]);
+                    i += separator.len();
+                    start = i;
+                }
+                None => i += value[i..].chars().next().map_or(1, char::len_utf8),
+            }
+        }
+        parts.push(&value[start..]);
+
+        let mut names: Vec<String> = Vec::new();
+        for part in parts.into_iter().map(str::trim).filter(|part| !part.is_empty()) {
+            if !names.iter().any(|name| name.eq_ignore_ascii_case(part)) {
+                names.push(part.to_string());
+            }
+        }
+        names
+    }
+
+    /// Splits "Artist feat. Guest" into the artist and the guests.
+    /// "Title (feat. Guest)" works too, in which case the guests end at the
+    /// closing bracket.
+    pub fn split_featuring<'a>(&self, value: &'a str) -> (&'a str, Option<&'a str>) {
+        let lower = value.to_ascii_lowercase();
+        let kept = self.kept_ranges(&lower);
+        let found = self
+            .featuring
+            .iter()
+            .map(|word| word.to_ascii_lowercase())
+            .filter(|word| !word.is_empty())
+            .flat_map(|word| {
+                lower
+                    .match_indices(word.as_str())
+                    .map(|(i, _)| (i, i + word.len()))
+                    .collect::<Vec<_>>()
+            })
+            .filter(|(start, end)| {
+                let before = lower[..*start].chars().next_back();
+                let after = lower[*end..].chars().next();
+                matches!(before, Some(' ' | '(' | '['))
+                    && after.is_some_and(char::is_whitespace)
+                    && !kept.iter().any(|range| range.contains(start))
+            })
+            .min();
+        let Some((start, end)) = found else {
+            return (value.trim(), None);
+        };
+
+        let main = value[..start].trim_end_matches([' ', '(', '[']).trim();
+        let mut guests = &value[end..];
+        if let Some(close) = match value[..start].chars().next_back() {
+            Some('(') => guests.find(')'),
+            Some('[') => guests.find(']'),
+            _ => None,
+        } {
+            guests = &guests[..close];
+        }
+        let guests = Some(guests.trim()).filter(|guests| !guests.is_empty());
+        if main.is_empty() {
+            return (value.trim(), None);
+        }
+        (main, guests)
+    }
+
+    /// Every artist credited by a track's tags. Guests are taken from the
+    /// artist and the title.
+    pub(crate) fn credits(
+        &self,
+        title: &str,
+        artists: &[String],
+        composers: &[String],
+        remixers: &[String],
+    ) -> Vec<(ArtistRole, String)> {
+        let mut credits: Vec<(ArtistRole, String)> = Vec::new();
+        let mut add = |role: ArtistRole, value: &str| {
+            for name in self.split(value) {
+                if !credits.iter().any(|(r, n)| *r == role && n.eq_ignore_ascii_case(&name)) {
+                    credits.push((role, name));
+                }
+            }
+        };
+        for artist in artists {
+            let (main, guests) = self.split_featuring(artist);
+            add(ArtistRole::Main, main);
+            if let Some(guests) = guests {
+                add(ArtistRole::Featured, guests);
+            }
+        }
+        if let (_, Some(guests)) = self.split_featuring(title) {
+            add(ArtistRole::Featured, guests);
+        }
+        for composer in composers {
+            add(ArtistRole::Composer, composer);
+        }
+        for remixer in remixers {
+            add(ArtistRole::Remixer, remixer);
+        }
+        credits
+    }
+
+    /// Byte ranges of `lower` taken up by names that must not be split.
+    fn kept_ranges(&self, lower: &str) -> Vec<Range<usize>> {
+        self.keep
+            .iter()
+            .map(|name| name.to_ascii_lowercase())
+            .filter(|name| !name.is_empty())
+            .flat_map(|name| {
+                lower
+                    .match_indices(name.as_str())
+                    .map(|(i, _)| i..i + name.len())
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+}
+
+impl LibraryManager {
+    pub fn split_rules(&self) -> Result<SplitRules> {
+        Ok(self.setting(SPLIT_RULES)?.unwrap_or_default())
+    }
+
+    /// Saves new rules. The next library scan reads every file again and
+    /// splits the whole library with them.
+    pub fn set_split_rules(&self, rules: &SplitRules) -> Result<()> {
+        self.set_setting(SPLIT_RULES, rules)?;
+        self.mark_metadata_stale()
+    }
+
+    /// Replaces the credits of a track.
+    pub(crate) fn store_credits(&self, track_id: i64, credits: &[(ArtistRole, String)]) -> Result<()> {
+        self.conn.execute("DELETE FROM track_artists WHERE track_id = ?1", params![track_id])?;
+        for (position, (role, name)) in credits.iter().enumerate() {
+            let artist_id = self.get_or_create_artist(name)?;
+            self.conn.execute(
+                "INSERT OR IGNORE INTO track_artists (track_id, artist_id, role, position) VALUES (?1, ?2, ?3, ?4)",
+                params![track_id, artist_id, role.name(), position as i64],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Everyone credited on a track, in tag order.
+    pub fn get_track_credits(&self, track_id: i64) -> Result<Vec<Credit>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT ar.id, ar.name, ta.role
+             FROM track_artists ta
+             JOIN artists ar ON ar.id = ta.artist_id
+             WHERE ta.track_id = ?1
+             ORDER BY ta.position",
+        )?;
+        let rows = stmt
+            .query_map(params![track_id], |row| Ok((row.get(0)?, row.get(1)?, row.get::<_, String>(2)?)))?
+            .collect::<rusqlite::Result<Vec<(i64, String, String)>>>()?;
+        let credits = rows
+            .into_iter()
+            .filter_map(|(artist_id, name, role)| {
+                ArtistRole::from_name(&role).map(|role| Credit { artist_id, name, role })
+            })
+            .collect();
+        Ok(credits)
+    }
+
+    /// Artists credited on at least one track, in `role` or in any role.
+    pub fn get_artists(&self, role: Option<ArtistRole>) -> Result<Vec<Artist>> {
+        self.list_artists(&TrackQuery::new(), role)
+    }
+
+    /// Tracks crediting an artist, in `role` or in any role.
+    pub fn get_tracks_by_artist(&self, artist_id: i64, role: Option<ArtistRole>) -> Result<Vec<Track>> {
+        let sql = format!(
+            "{} WHERE t.id IN (
+                SELECT track_id FROM track_artists WHERE artist_id = ?1 AND (?2 IS NULL OR role = ?2)
+             )",
+            TRACK_SELECT
+        );
+        let mut stmt = self.conn.prepare(&sql)?;
+        let tracks = stmt
+            .query_map(params![artist_id, role.map(ArtistRole::name)], track_from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(tracks)
+    }
+}
+
+impl mlua::UserData for Credit {
+    fn add_fields<'lua, F: mlua::UserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_method_get("artist_id", |_lua, this| Ok(this.artist_id));
+        fields.add_field_method_get("name", |_lua, this| Ok(this.name.clone()));
+        fields.add_field_method_get("role", |_lua, this| Ok(this.role.name()));
+    }
+}
+
+impl mlua::UserData for Artist {
+    fn add_fields<'lua, F: mlua::UserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_method_get("id", |_lua, this| Ok(this.id));
+        fields.add_field_method_get("name", |_lua, this| Ok(this.name.clone()));
+        fields.add_field_method_get("track_count", |_lua, this| Ok(this.track_count));
+        fields.add_field_method_get("duration", |_lua, this| Ok(this.duration));
+    }
+}
+
+impl mlua::UserData for SplitRules {
+    fn add_fields<'lua, F: mlua::UserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_method_get("separators", |_lua, this| Ok(this.separators.clone()));
+        fields.add_field_method_get("featuring", |_lua, this| Ok(this.featuring.clone()));
+        fields.add_field_method_get("keep", |_lua, this| Ok(this.keep.clone()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn splits_on_the_default_separators_and_nul() {
+        let rules = SplitRules::default();
+        assert_eq!(rules.split("Simon; Garfunkel"), strings(&["Simon", "Garfunkel"]));
+        assert_eq!(rules.split("Rock/Pop\0Jazz"), strings(&["Rock", "Pop", "Jazz"]));
+        // Empty and repeated names go, whatever their case.
+        assert_eq!(rules.split(" ; Björk;;björk / "), strings(&["Björk"]));
+        assert!(rules.split("").is_empty());
+    }
+
+    #[test]
+    fn keeps_names_that_contain_a_separator() {
+        let rules = SplitRules::default();
+        assert_eq!(rules.split("AC/DC"), strings(&["AC/DC"]));
+        assert_eq!(rules.split("ac/dc / Motörhead"), strings(&["ac/dc", "Motörhead"]));
+    }
+
+    #[test]
+    fn splits_on_custom_separators() {
+        let rules = SplitRules {
+            separators: strings(&[" & ", ",", " AND "]),
+            featuring: Vec::new(),
+            keep: strings(&["Simon & Garfunkel"]),
+        };
+        assert_eq!(
+            rules.split("Simon & Garfunkel & Cher, Sonny and Cher"),
+            strings(&["Simon & Garfunkel", "Cher", "Sonny"])
+        );
+        // "/" is no longer one.
+        assert_eq!(rules.split("AC/DC,Blur"), strings(&["AC/DC", "Blur"]));
+    }
+
+    #[test]
+    fn splits_featured_artists() {
+        let rules = SplitRules::default();
+        assert_eq!(rules.split_featuring("A feat. B"), ("A", Some("B")));
+        assert_eq!(rules.split_featuring("A FT B; C"), ("A", Some("B; C")));
+        assert_eq!(rules.split_featuring("A featuring B"), ("A", Some("B")));
+        assert_eq!(rules.split_featuring("Solo Artist"), ("Solo Artist", None));
+    }
+
+    #[test]
+    fn takes_guests_from_brackets_in_titles() {
+        let rules = SplitRules::default();
+        assert_eq!(rules.split_featuring("Song (ft. X)"), ("Song", Some("X")));
+        assert_eq!(rules.split_featuring("Song [feat. X & Y] (Live)"), ("Song", Some("X & Y")));
+    }
+
+    #[test]
+    fn needs_the_featuring_word_on_its_own() {
+        let rules = SplitRules::default();
+        assert_eq!(rules.split_featuring("Daft Punk"), ("Daft Punk", None));
+        assert_eq!(rules.split_featuring("Craft Work"), ("Craft Work", None));
+        assert_eq!(rules.split_featuring("Left Behind feat."), ("Left Behind feat.", None));
+        // Nothing before it to be the main artist.
+        assert_eq!(rules.split_featuring("Ft. Lauderdale"), ("Ft. Lauderdale", None));
+    }
+
+    #[test]
+    fn credits_main_featured_composers_and_remixers() {
+        let rules = SplitRules::default();
+        let credits = rules.credits(
+            "Song (feat. C)",
+            &strings(&["A feat. B", "a"]),
+            &strings(&["D/E"]),
+            &strings(&["F"]),
+        );
+        let expected = [
+            (ArtistRole::Main, "A"),
+            (ArtistRole::Featured, "B"),
+            (ArtistRole::Featured, "C"),
+            (ArtistRole::Composer, "D"),
+            (ArtistRole::Composer, "E"),
+            (ArtistRole::Remixer, "F"),
+        ];
+        let credits: Vec<(ArtistRole, &str)> = credits.iter().map(|(role, name)| (*role, name.as_str())).collect();
+        assert_eq!(credits, expected);
+    }
+}
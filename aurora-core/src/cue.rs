@@ -0,0 +1,502 @@
+use crate::lyrics::lyrics_sidecar;
+use crate::playlist_files::decode_text;
+use crate::scan::{file_stamp, is_audio_file};
+use crate::{read_metadata, TrackMetadata};
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// A CUE sheet: the tracks of one or more audio files, each file usually
+/// holding a whole disc.
+#[derive(Debug, Default, Clone)]
+pub struct CueSheet {
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    pub songwriter: Option<String>,
+    pub genre: Option<String>,
+    pub date: Option<String>,
+    pub disc_number: Option<u32>,
+    pub disc_total: Option<u32>,
+    pub files: Vec<CueFile>,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct CueFile {
+    /// As written in the sheet, relative to the sheet's folder.
+    pub name: String,
+    pub tracks: Vec<CueTrack>,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct CueTrack {
+    pub number: u32,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    pub songwriter: Option<String>,
+    /// Where `INDEX 01` puts the track within its file. The pregap before
+    /// it belongs to the previous track, as on the disc.
+    pub start_ms: u32,
+}
+
+impl CueSheet {
+    /// Reads the commands this library has a use for and skips the rest.
+    /// Tracks without an `INDEX 01`, such as data tracks, are left out.
+    pub fn parse(text: &str) -> CueSheet {
+        let mut sheet = CueSheet::default();
+        let mut track: Option<(CueTrack, bool)> = None;
+        let finish = |sheet: &mut CueSheet, track: &mut Option<(CueTrack, bool)>| {
+            if let (Some((track, true)), Some(file)) = (track.take(), sheet.files.last_mut()) {
+                file.tracks.push(track);
+            }
+        };
+
+        for line in text.lines() {
+            let (command, rest) = split_word(line.trim());
+            let (command, rest) = match command.to_ascii_uppercase().as_str() {
+                "REM" => {
+                    let (key, rest) = split_word(rest);
+                    (format!("REM {}", key.to_ascii_uppercase()), rest)
+                }
+                command => (command.to_string(), rest),
+            };
+            let value = || Some(unquote(rest)).filter(|value| !value.is_empty());
+            match (command.as_str(), track.as_mut()) {
+                ("FILE", _) => {
+                    // A track whose pregap ends a file starts in the next one.
+                    if track.as_ref().is_some_and(|(_, indexed)| *indexed) {
+                        finish(&mut sheet, &mut track);
+                    }
+                    // The file type comes last and may be missing.
+                    let name = match rest.strip_prefix('"') {
+                        Some(quoted) => quoted.split('"').next().unwrap_or_default(),
+                        None => split_word(rest).0,
+                    };
+                    sheet.files.push(CueFile { name: name.to_string(), tracks: Vec::new() });
+                }
+                ("TRACK", _) => {
+                    finish(&mut sheet, &mut track);
+                    let number = split_word(rest).0.parse().unwrap_or_default();
+                    track = Some((CueTrack { number, ..Default::default() }, false));
+                }
+                ("INDEX", Some((track, indexed))) => {
+                    let (index, time) = split_word(rest);
+                    if index.parse() == Ok(1) {
+                        if let Some(start_ms) = parse_time(time) {
+                            track.start_ms = start_ms;
+                            *indexed = true;
+                        }
+                    }
+                }
+                ("TITLE", Some((track, _))) => track.title = value(),
+                ("PERFORMER", Some((track, _))) => track.performer = value(),
+                ("SONGWRITER", Some((track, _))) => track.songwriter = value(),
+                ("TITLE", None) => sheet.title = value(),
+                ("PERFORMER", None) => sheet.performer = value(),
+                ("SONGWRITER", None) => sheet.songwriter = value(),
+                ("REM GENRE", None) => sheet.genre = value(),
+                ("REM DATE", None) => sheet.date = value(),
+                ("REM DISCNUMBER", None) => sheet.disc_number = value().and_then(|n| n.parse().ok()),
+                ("REM TOTALDISCS", None) => sheet.disc_total = value().and_then(|n| n.parse().ok()),
+                _ => {}
+            }
+        }
+        finish(&mut sheet, &mut track);
+        sheet
+    }
+
+    pub fn read(path: &Path) -> Result<CueSheet> {
+        let bytes = std::fs::read(path).with_context(|| format!("Failed to read {:?}", path))?;
+        Ok(CueSheet::parse(&decode_text(&bytes)))
+    }
+}
+
+fn split_word(text: &str) -> (&str, &str) {
+    let text = text.trim_start();
+    match text.split_once(char::is_whitespace) {
+        Some((word, rest)) => (word, rest.trim()),
+        None => (text, ""),
+    }
+}
+
+fn unquote(text: &str) -> String {
+    let text = text.trim();
+    text.strip_prefix('"')
+        .map(|quoted| quoted.strip_suffix('"').unwrap_or(quoted))
+        .unwrap_or(text)
+        .to_string()
+}
+
+/// `mm:ss:ff`, in frames of 1/75 second.
+fn parse_time(time: &str) -> Option<u32> {
+    let mut parts = time.split(':').map(|part| part.parse::<u32>().ok());
+    let (minutes, seconds, frames) = (parts.next()??, parts.next()??, parts.next()??);
+    // A time past what fits is no time at all, rather than a wrapped one.
+    let seconds = minutes.checked_mul(60)?.checked_add(seconds)?;
+    seconds.checked_mul(1000)?.checked_add(frames.checked_mul(1000)? / 75)
+}
+
+pub(crate) fn is_cue_sheet(path: &Path) -> bool {
+    path.extension()
+        .and_then(|s| s.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("cue"))
+}
+
+/// The audio files `sheets` cut into tracks, each with the sheet that does
+/// it. A file the sheet gives a single track is played whole, as are files
+/// two sheets claim after the first, in name order.
+pub(crate) fn match_sheets(sheets: &[PathBuf]) -> HashMap<PathBuf, PathBuf> {
+    let mut sheets = sheets.to_vec();
+    sheets.sort();
+    let mut matched = HashMap::new();
+    for sheet_path in sheets {
+        let sheet = match CueSheet::read(&sheet_path) {
+            Ok(sheet) => sheet,
+            Err(e) => {
+                log::error!("{:#}", e);
+                continue;
+            }
+        };
+        for file in sheet.files.iter().filter(|file| file.tracks.len() > 1) {
+            if let Some(audio) = resolve_file(&sheet_path, &file.name) {
+                matched.entry(audio).or_insert_with(|| sheet_path.clone());
+            }
+        }
+    }
+    matched
+}
+
+/// The sheet beside `audio` that cuts it into tracks, if there is one,
+/// among those `admits` lets in.
+pub(crate) fn sheet_for(audio: &Path, admits: impl Fn(&Path) -> bool) -> Option<PathBuf> {
+    let dir = audio.parent()?;
+    let sheets: Vec<PathBuf> = std::fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| is_cue_sheet(path) && path.is_file() && admits(path))
+        .collect();
+    match_sheets(&sheets).remove(audio)
+}
+
+/// The audio file a sheet's `FILE` names. Rips are often encoded after the
+/// sheet was written, so "Album.wav" also finds "Album.flac".
+pub(crate) fn resolve_file(sheet: &Path, name: &str) -> Option<PathBuf> {
+    let dir = sheet.parent()?;
+    let named = dir.join(name.replace('\\', "/"));
+    if named.is_file() {
+        return Some(named);
+    }
+    let stem = named.file_stem()?.to_os_string();
+    std::fs::read_dir(named.parent()?)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .find(|path| {
+            path.file_stem().is_some_and(|s| s.eq_ignore_ascii_case(&stem)) && is_audio_file(path)
+        })
+}
+
+/// Modification time and size for the tracks of `path`. Tracks cut by a
+/// sheet beside the file are as new as the newer of the two, and an LRC
+/// file beside it counts the same way.
+pub(crate) fn tracks_stamp(path: &Path, sheet: Option<&Path>) -> Result<(i64, i64)> {
+    let (mut mtime, size) = file_stamp(path)?;
+    if let Some(sheet) = sheet {
+        mtime = mtime.max(file_stamp(sheet)?.0);
+    }
+    if let Ok((lyrics, _)) = file_stamp(&lyrics_sidecar(path)) {
+        mtime = mtime.max(lyrics);
+    }
+    Ok((mtime, size))
+}
+
+/// The tracks of the file at `path`: the file itself, or one per track of
+/// `sheet`, or of a sheet embedded in the file's tags when there is none.
+pub(crate) fn read_tracks(path: &Path, sheet: Option<&Path>) -> Result<Vec<TrackMetadata>> {
+    let metadata = read_metadata(path)?;
+    let cue = match sheet {
+        Some(sheet) => Some(CueSheet::read(sheet)?),
+        None => metadata.embedded_cue.as_deref().map(CueSheet::parse),
+    };
+    let Some(cue) = cue else {
+        return Ok(vec![metadata]);
+    };
+
+    // An embedded sheet names the file it was written for, which need not
+    // be this file's name now.
+    let file = match sheet {
+        Some(sheet) => cue.files.iter().find(|file| resolve_file(sheet, &file.name).as_deref() == Some(path)),
+        None => cue.files.first(),
+    };
+    let tracks = match file {
+        Some(file) if file.tracks.len() > 1 => &file.tracks,
+        _ => return Ok(vec![metadata]),
+    };
+    let mtime = tracks_stamp(path, sheet)?.0;
+    Ok(split_tracks(&metadata, &cue, tracks, sheet, mtime))
+}
+
+/// One track per sheet entry, each running up to the start of the next.
+/// What the sheet does not say comes from the file's own tags, except what
+/// only makes sense for the file as a whole, such as its rating.
+fn split_tracks(
+    metadata: &TrackMetadata,
+    cue: &CueSheet,
+    tracks: &[CueTrack],
+    sheet: Option<&Path>,
+    mtime: i64,
+) -> Vec<TrackMetadata> {
+    let mut split = Vec::with_capacity(tracks.len());
+    for (i, track) in tracks.iter().enumerate() {
+        let end_ms = tracks.get(i + 1).map(|next| next.start_ms);
+        let length = end_ms.unwrap_or(metadata.length_ms).saturating_sub(track.start_ms);
+        if length == 0 {
+            // Past the end of the file: the sheet was written for another one.
+            continue;
+        }
+
+        let artist = track.performer.clone()
+            .or_else(|| cue.performer.clone())
+            .unwrap_or_else(|| metadata.artist.clone());
+        let composer = track.songwriter.clone().or_else(|| cue.songwriter.clone());
+        let genres = match &cue.genre {
+            Some(genre) => vec![genre.clone()],
+            None => metadata.genres.clone(),
+        };
+        split.push(TrackMetadata {
+            title: track.title.clone().unwrap_or_else(|| format!("Track {:02}", track.number)),
+            artists: vec![artist.clone()],
+            artist,
+            album: cue.title.clone().unwrap_or_else(|| metadata.album.clone()),
+            // Keeps the tracks together when their performers differ.
+            album_artist: cue.performer.clone()
+                .or_else(|| metadata.album_artist.clone())
+                .or_else(|| Some(metadata.artist.clone())),
+            composers: composer.iter().cloned().collect(),
+            composer,
+            remixers: Vec::new(),
+            genre: genres.first().cloned(),
+            genres,
+            duration: length / 1000,
+            track_number: Some(track.number),
+            track_total: Some(tracks.len() as u32),
+            year: cue.date.as_deref()
+                .and_then(|date| date.get(..4))
+                .and_then(|year| year.parse().ok())
+                .or(metadata.year),
+            release_date: cue.date.clone().or_else(|| metadata.release_date.clone()),
+            disc_number: cue.disc_number.or(metadata.disc_number),
+            disc_total: cue.disc_total.or(metadata.disc_total),
+            rating: None,
+            loved: None,
+            musicbrainz_recording_id: None,
+            start_ms: Some(track.start_ms),
+            end_ms,
+            cue_path: sheet.map(Path::to_path_buf),
+            embedded_cue: None,
+            lyrics: metadata.lyrics.as_ref().and_then(|lyrics| lyrics.cut(track.start_ms, end_ms)),
+            mtime,
+            ..metadata.clone()
+        });
+    }
+    split
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn starts(file: &CueFile) -> Vec<(u32, u32)> {
+        file.tracks.iter().map(|track| (track.number, track.start_ms)).collect()
+    }
+
+    #[test]
+    fn parses_times_in_frames() {
+        assert_eq!(parse_time("00:00:00"), Some(0));
+        assert_eq!(parse_time("03:15:37"), Some(195_493));
+        assert_eq!(parse_time("00:01:74"), Some(1_986));
+        assert_eq!(parse_time("120:00:00"), Some(7_200_000));
+        assert_eq!(parse_time("3:15"), None);
+        assert_eq!(parse_time("aa:bb:cc"), None);
+        assert_eq!(parse_time("-1:00:00"), None);
+        assert_eq!(parse_time(""), None);
+    }
+
+    #[test]
+    fn rejects_times_that_overflow() {
+        assert_eq!(parse_time("71582:00:00"), Some(4_294_920_000));
+        assert_eq!(parse_time("71583:00:00"), None);
+        assert_eq!(parse_time("71582:47:00"), Some(4_294_967_000));
+        assert_eq!(parse_time("71582:48:00"), None);
+        assert_eq!(parse_time("99999999:00:00"), None);
+        assert_eq!(parse_time("00:4294968:00"), None);
+        assert_eq!(parse_time("00:00:4294968"), None);
+        assert_eq!(parse_time("00:00:4294967295"), None);
+
+        let sheet = CueSheet::parse(
+            "FILE \"a.flac\" WAVE\n  TRACK 01 AUDIO\n    INDEX 01 99999999:00:00\n  TRACK 02 AUDIO\n    INDEX 01 03:00:00\n",
+        );
+        assert_eq!(starts(&sheet.files[0]), vec![(2, 180_000)]);
+    }
+
+    #[test]
+    fn parses_a_sheet_and_its_rem_fields() {
+        let sheet = CueSheet::parse(
+            "REM GENRE \"Progressive Rock\"\r\n\
+             rem date 1973\r\n\
+             REM DISCID 8F0A4C0B\r\n\
+             REM COMMENT \"ExactAudioCopy v1.6\"\r\n\
+             REM DISCNUMBER 2\r\n\
+             REM TOTALDISCS 3\r\n\
+             PERFORMER \"Pink Floyd\"\r\n\
+             SONGWRITER Waters\r\n\
+             TITLE \"The Dark Side of the Moon\"\r\n\
+             FILE \"Pink Floyd - Dark Side.flac\" WAVE\r\n\
+             \x20 TRACK 01 AUDIO\r\n\
+             \x20   TITLE \"Speak to Me\"\r\n\
+             \x20   PERFORMER \"Pink Floyd\"\r\n\
+             \x20   REM GENRE Ambient\r\n\
+             \x20   INDEX 01 00:00:00\r\n\
+             \x20 TRACK 02 AUDIO\r\n\
+             \x20   TITLE Breathe\r\n\
+             \x20   SONGWRITER \"Gilmour, Waters, Wright\"\r\n\
+             \x20   INDEX 00 01:05:30\r\n\
+             \x20   INDEX 01 01:07:00\r\n",
+        );
+        assert_eq!(sheet.genre.as_deref(), Some("Progressive Rock"));
+        assert_eq!(sheet.date.as_deref(), Some("1973"));
+        assert_eq!((sheet.disc_number, sheet.disc_total), (Some(2), Some(3)));
+        assert_eq!(sheet.performer.as_deref(), Some("Pink Floyd"));
+        assert_eq!(sheet.songwriter.as_deref(), Some("Waters"));
+        assert_eq!(sheet.title.as_deref(), Some("The Dark Side of the Moon"));
+
+        assert_eq!(sheet.files.len(), 1);
+        let file = &sheet.files[0];
+        assert_eq!(file.name, "Pink Floyd - Dark Side.flac");
+        assert_eq!(starts(file), vec![(1, 0), (2, 67_000)]);
+        assert_eq!(file.tracks[0].title.as_deref(), Some("Speak to Me"));
+        assert_eq!(file.tracks[1].title.as_deref(), Some("Breathe"));
+        assert_eq!(file.tracks[1].songwriter.as_deref(), Some("Gilmour, Waters, Wright"));
+        assert_eq!(file.tracks[1].performer, None);
+    }
+
+    #[test]
+    fn ignores_malformed_rem_values() {
+        let sheet = CueSheet::parse("REM DISCNUMBER one\nREM TOTALDISCS\nREM GENRE \"\"\nREM\n");
+        assert_eq!((sheet.disc_number, sheet.disc_total, sheet.genre), (None, None, None));
+    }
+
+    #[test]
+    fn keeps_the_tracks_of_each_file_apart() {
+        let sheet = CueSheet::parse(
+            "FILE \"CD1\\Disc 1.wav\" WAVE\n\
+             \x20 TRACK 01 AUDIO\n    INDEX 01 00:00:00\n\
+             \x20 TRACK 02 AUDIO\n    INDEX 01 04:00:00\n\
+             FILE disc2.flac\n\
+             \x20 TRACK 03 AUDIO\n    INDEX 01 00:00:00\n\
+             \x20 TRACK 04 AUDIO\n    INDEX 01 02:30:15\n\
+             FILE \"bonus.mp3\" MP3\n\
+             \x20 TRACK 05 AUDIO\n    INDEX 01 00:00:00\n",
+        );
+        let names: Vec<&str> = sheet.files.iter().map(|file| file.name.as_str()).collect();
+        assert_eq!(names, vec!["CD1\\Disc 1.wav", "disc2.flac", "bonus.mp3"]);
+        assert_eq!(starts(&sheet.files[0]), vec![(1, 0), (2, 240_000)]);
+        assert_eq!(starts(&sheet.files[1]), vec![(3, 0), (4, 150_200)]);
+        assert_eq!(starts(&sheet.files[2]), vec![(5, 0)]);
+    }
+
+    #[test]
+    fn moves_a_track_whose_pregap_ends_a_file_to_the_next() {
+        let sheet = CueSheet::parse(
+            "FILE \"01.wav\" WAVE\n\
+             \x20 TRACK 01 AUDIO\n    TITLE One\n    INDEX 01 00:00:00\n\
+             \x20 TRACK 02 AUDIO\n    TITLE Two\n    INDEX 00 04:58:00\n\
+             FILE \"02.wav\" WAVE\n\
+             \x20   INDEX 01 00:00:00\n\
+             \x20 TRACK 03 AUDIO\n    TITLE Three\n    INDEX 00 03:00:00\n    INDEX 01 03:02:00\n",
+        );
+        assert_eq!(starts(&sheet.files[0]), vec![(1, 0)]);
+        assert_eq!(starts(&sheet.files[1]), vec![(2, 0), (3, 182_000)]);
+        assert_eq!(sheet.files[1].tracks[0].title.as_deref(), Some("Two"));
+    }
+
+    #[test]
+    fn leaves_out_tracks_without_index_01() {
+        let sheet = CueSheet::parse(
+            "FILE \"image.bin\" BINARY\n\
+             \x20 TRACK 01 MODE1/2352\n    INDEX 00 00:00:00\n\
+             \x20 TRACK 02 AUDIO\n    INDEX 01 00:30:00\n\
+             \x20 TRACK 03 AUDIO\n    INDEX 01 garbage\n\
+             \x20 TRACK 04 AUDIO\n    INDEX 02 05:00:00\n\
+             \x20 TRACK 05 AUDIO\n    INDEX 01 06:00:00\n\
+             \x20 TRACK 06 AUDIO\n",
+        );
+        assert_eq!(starts(&sheet.files[0]), vec![(2, 30_000), (5, 360_000)]);
+    }
+
+    #[test]
+    fn ignores_tracks_before_any_file() {
+        let sheet = CueSheet::parse("TRACK 01 AUDIO\n  INDEX 01 00:00:00\nFILE a.wav WAVE\n");
+        assert_eq!(sheet.files.len(), 1);
+        assert!(sheet.files[0].tracks.is_empty());
+    }
+
+    #[test]
+    fn resolves_the_named_file() {
+        let dir = TempDir::new("cue-named");
+        let sheet = dir.write("Album.cue", "");
+        let audio = dir.write_wav("Album.wav");
+        dir.write_wav("Album.flac");
+        assert_eq!(resolve_file(&sheet, "Album.wav"), Some(audio));
+
+        let nested = dir.write_wav("CD1/Disc 1.wav");
+        assert_eq!(resolve_file(&sheet, "CD1\\Disc 1.wav"), Some(nested));
+    }
+
+    #[test]
+    fn resolves_a_file_encoded_after_the_sheet() {
+        let dir = TempDir::new("cue-fallback");
+        let sheet = dir.write("Album.cue", "");
+        dir.write("Album.log", "not audio");
+        dir.write("Album.txt", "not audio");
+        let flac = dir.write_wav("ALBUM.flac");
+        assert_eq!(resolve_file(&sheet, "Album.wav"), Some(flac));
+        assert_eq!(resolve_file(&sheet, "album.WAV"), resolve_file(&sheet, "Album.wav"));
+        assert_eq!(resolve_file(&sheet, "Other.wav"), None);
+        assert_eq!(resolve_file(&sheet, "Missing/Album.wav"), None);
+    }
+
+    #[test]
+    fn does_not_resolve_to_files_that_are_not_audio() {
+        let dir = TempDir::new("cue-not-audio");
+        let sheet = dir.write("Album.cue", "");
+        dir.write("Album.jpg", [0xFF, 0xD8, 0xFF, 0xE0]);
+        assert_eq!(resolve_file(&sheet, "Album.wav"), None);
+    }
+
+    #[test]
+    fn matches_files_split_into_several_tracks() {
+        let dir = TempDir::new("cue-match");
+        let image = dir.write_wav("image.wav");
+        let single = dir.write_wav("single.wav");
+        let sheet = dir.write(
+            "a.cue",
+            "FILE image.wav WAVE\n TRACK 01 AUDIO\n  INDEX 01 00:00:00\n TRACK 02 AUDIO\n  INDEX 01 00:00:50\n\
+             FILE single.wav WAVE\n TRACK 03 AUDIO\n  INDEX 01 00:00:00\n",
+        );
+        // A second sheet for the same image loses to the first in name order.
+        let other = dir.write(
+            "b.cue",
+            "FILE image.wav WAVE\n TRACK 01 AUDIO\n  INDEX 01 00:00:00\n TRACK 02 AUDIO\n  INDEX 01 00:00:30\n",
+        );
+
+        let matched = match_sheets(&[other, sheet.clone()]);
+        assert_eq!(matched.len(), 1);
+        assert_eq!(matched.get(&image), Some(&sheet));
+        assert_eq!(matched.get(&single), None);
+        assert_eq!(sheet_for(&image, |_| true), Some(sheet));
+        assert_eq!(sheet_for(&image, |path| path.ends_with("b.cue")), Some(dir.path().join("b.cue")));
+        assert_eq!(sheet_for(&single, |_| true), None);
+    }
+}
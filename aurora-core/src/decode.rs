@@ -0,0 +1,223 @@
+use crate::scan::audio_file_type;
+use anyhow::{anyhow, bail, Context, Result};
+use audiopus::coder::Decoder as OpusDecoder;
+use audiopus::packet::Packet as OpusPacket;
+use audiopus::{Channels, MutSignals, SampleRate};
+use claxon::FlacReader;
+use hound::{SampleFormat, WavReader};
+use lewton::inside_ogg::OggStreamReader;
+use lofty::file::FileType;
+use ogg::PacketReader;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{DecoderOptions, CODEC_TYPE_NULL};
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+
+/// Interleaved 16-bit samples, as the analyses want them.
+type Samples = Box<dyn Iterator<Item = i16> + Send>;
+
+/// Opus always decodes at 48 kHz.
+const OPUS_RATE: u32 = 48000;
+/// The longest Opus packet, 120 ms, in samples per channel.
+const OPUS_MAX_FRAMES: usize = 5760;
+
+/// Whether `decode_range` can read `path`.
+pub(crate) fn is_decodable(path: &Path) -> bool {
+    matches!(
+        audio_file_type(path),
+        Some(
+            FileType::Flac
+                | FileType::Wav
+                | FileType::Vorbis
+                | FileType::Mpeg
+                | FileType::Opus
+                | FileType::Aac
+                | FileType::Mp4
+        )
+    )
+}
+
+/// Decodes `path`, or with offsets only the part of it a CUE track covers.
+/// Returns the channel count, the sample rate and the interleaved samples.
+pub(crate) fn decode_range(
+    path: &Path,
+    start_ms: Option<u32>,
+    end_ms: Option<u32>,
+) -> Result<(u16, u32, impl Iterator<Item = i16>)> {
+    let (channels, rate, samples) = decode(path).with_context(|| format!("Cannot decode {:?}", path))?;
+    if channels == 0 || rate == 0 {
+        bail!("{:?} has no audio", path);
+    }
+    // Whole frames, so the first sample kept is always the first channel.
+    let samples_at = |ms: u32| ms as usize * rate as usize / 1000 * channels as usize;
+    let skip = start_ms.map_or(0, samples_at);
+    let take = end_ms.map_or(usize::MAX, |end| samples_at(end).saturating_sub(skip));
+    Ok((channels, rate, samples.skip(skip).take(take)))
+}
+
+fn decode(path: &Path) -> Result<(u16, u32, Samples)> {
+    let file = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    let file = BufReader::new(file);
+    match audio_file_type(path) {
+        Some(FileType::Wav) => decode_wav(file),
+        Some(FileType::Flac) => decode_flac(file),
+        Some(FileType::Vorbis) => decode_vorbis(file),
+        Some(FileType::Opus) => decode_opus(file),
+        Some(FileType::Mpeg) => decode_symphonia(file, "mp3"),
+        Some(FileType::Aac) => decode_symphonia(file, "aac"),
+        // AAC or ALAC in an MP4 container.
+        Some(FileType::Mp4) => decode_symphonia(file, "m4a"),
+        Some(file_type) => bail!("No decoder for {:?}", file_type),
+        None => bail!("Not an audio file"),
+    }
+}
+
+fn decode_wav(file: BufReader<File>) -> Result<(u16, u32, Samples)> {
+    let reader = WavReader::new(file)?;
+    let spec = reader.spec();
+    let samples: Samples = match (spec.sample_format, spec.bits_per_sample) {
+        (SampleFormat::Float, 32) => Box::new(
+            reader
+                .into_samples::<f32>()
+                .map_while(Result::ok)
+                .map(|sample| (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16),
+        ),
+        (SampleFormat::Int, bits @ 1..=32) => Box::new(
+            reader
+                .into_samples::<i32>()
+                .map_while(Result::ok)
+                .map(move |sample| to_i16(sample, bits as u32)),
+        ),
+        (format, bits) => bail!("Unsupported WAV encoding: {:?}, {} bits", format, bits),
+    };
+    Ok((spec.channels, spec.sample_rate, samples))
+}
+
+/// FLAC blocks hold each channel in turn; they are interleaved here.
+fn decode_flac(file: BufReader<File>) -> Result<(u16, u32, Samples)> {
+    let mut reader = FlacReader::new(file)?;
+    let info = reader.streaminfo();
+    let bits = info.bits_per_sample;
+    let mut buffer = Vec::new();
+    let blocks = std::iter::from_fn(move || {
+        let block = reader.blocks().read_next_or_eof(std::mem::take(&mut buffer)).ok()??;
+        let interleaved: Vec<i16> = (0..block.duration())
+            .flat_map(|i| (0..block.channels()).map(move |channel| (channel, i)))
+            .map(|(channel, i)| to_i16(block.sample(channel, i), bits))
+            .collect();
+        buffer = block.into_buffer();
+        Some(interleaved)
+    });
+    Ok((info.channels as u16, info.sample_rate, Box::new(blocks.flatten())))
+}
+
+fn decode_vorbis(file: BufReader<File>) -> Result<(u16, u32, Samples)> {
+    let mut reader = OggStreamReader::new(file)?;
+    let (channels, rate) = (reader.ident_hdr.audio_channels as u16, reader.ident_hdr.audio_sample_rate);
+    let packets = std::iter::from_fn(move || reader.read_dec_packet_itl().ok().flatten());
+    Ok((channels, rate, Box::new(packets.flatten())))
+}
+
+/// An Ogg Opus stream (RFC 7845) starts with an `OpusHead` packet giving the
+/// channel count, the samples to drop at the start and a gain to apply, then
+/// an `OpusTags` packet. The last page's granule position marks the end, so
+/// the padding of the final packet is dropped as well.
+fn decode_opus(file: BufReader<File>) -> Result<(u16, u32, Samples)> {
+    let mut reader = PacketReader::new(file);
+    let head = reader.read_packet()?.ok_or_else(|| anyhow!("No audio stream"))?.data;
+    if head.len() < 19 || !head.starts_with(b"OpusHead") {
+        bail!("Not an Opus stream");
+    }
+    // Mapping family 0 is mono or stereo in a single stream; the surround
+    // families need the multistream decoder.
+    let (channels, count) = match (head[9], head[18]) {
+        (1, 0) => (Channels::Mono, 1),
+        (2, 0) => (Channels::Stereo, 2),
+        (channels, family) => bail!("Unsupported Opus layout: {} channels, mapping family {}", channels, family),
+    };
+    let pre_skip = u16::from_le_bytes([head[10], head[11]]) as u64;
+    let gain = 10f32.powf(i16::from_le_bytes([head[16], head[17]]) as f32 / 256.0 / 20.0);
+    reader.read_packet()?.ok_or_else(|| anyhow!("No audio stream"))?;
+
+    let mut decoder = OpusDecoder::new(SampleRate::Hz48000, channels)?;
+    let mut buffer = vec![0i16; OPUS_MAX_FRAMES * count];
+    // Samples per channel decoded so far, counted as granule positions are.
+    let mut position = 0u64;
+    let packets = std::iter::from_fn(move || loop {
+        let packet = reader.read_packet().ok()??;
+        let Ok(data) = OpusPacket::try_from(packet.data.as_slice()) else {
+            continue;
+        };
+        let signals = MutSignals::try_from(&mut buffer[..]).ok()?;
+        // A damaged packet is skipped, as players do.
+        let Ok(mut frames) = decoder.decode(Some(data), signals, false) else {
+            continue;
+        };
+        if packet.last_in_stream() {
+            frames = frames.min(packet.absgp_page().saturating_sub(position) as usize);
+        }
+        let start = pre_skip.saturating_sub(position).min(frames as u64) as usize;
+        position += frames as u64;
+        return Some(buffer[start * count..frames * count].to_vec());
+    });
+    let samples = packets.flatten();
+    let samples: Samples = if gain == 1.0 {
+        Box::new(samples)
+    } else {
+        Box::new(samples.map(move |sample| (sample as f32 * gain).clamp(i16::MIN as f32, i16::MAX as f32) as i16))
+    };
+    Ok((count as u16, OPUS_RATE, samples))
+}
+
+/// MP3 and AAC, raw or in an MP4 container along with ALAC. `extension` tells
+/// the probe which container to expect.
+fn decode_symphonia(file: BufReader<File>, extension: &str) -> Result<(u16, u32, Samples)> {
+    let source = MediaSourceStream::new(Box::new(file.into_inner()), Default::default());
+    let mut hint = Hint::new();
+    hint.with_extension(extension);
+    let mut format = symphonia::default::get_probe()
+        .format(&hint, source, &FormatOptions::default(), &MetadataOptions::default())?
+        .format;
+    let track = format
+        .tracks()
+        .iter()
+        .find(|track| track.codec_params.codec != CODEC_TYPE_NULL)
+        .ok_or_else(|| anyhow!("No audio stream"))?;
+    let track_id = track.id;
+    let rate = track.codec_params.sample_rate.unwrap_or(0);
+    let channels = track.codec_params.channels.map_or(0, |channels| channels.count() as u16);
+    let mut decoder = symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
+
+    let packets = std::iter::from_fn(move || loop {
+        let packet = format.next_packet().ok()?;
+        if packet.track_id() != track_id {
+            continue;
+        }
+        match decoder.decode(&packet) {
+            Ok(decoded) => {
+                let mut buffer = SampleBuffer::<i16>::new(decoded.capacity() as u64, *decoded.spec());
+                buffer.copy_interleaved_ref(decoded);
+                return Some(buffer.samples().to_vec());
+            }
+            // A damaged frame is skipped, as players do.
+            Err(SymphoniaError::DecodeError(_)) => continue,
+            Err(_) => return None,
+        }
+    });
+    Ok((channels, rate, Box::new(packets.flatten())))
+}
+
+/// Scales an integer sample of `bits` bits to 16 bits.
+fn to_i16(sample: i32, bits: u32) -> i16 {
+    match bits {
+        0..=15 => (sample << (16 - bits)) as i16,
+        16 => sample as i16,
+        _ => (sample >> (bits - 16)) as i16,
+    }
+}
@@ -0,0 +1,324 @@
+use crate::decode::decode_range;
+use crate::fingerprint::NewAnalyses;
+use crate::{fnv1a_extend, Fingerprint, LibraryManager, LibraryService, SplitRules, Track, FINGERPRINT_MATCH, FNV1A_OFFSET};
+use anyhow::{bail, Result};
+use rusqlite::params;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Samples hashed at a time while decoding.
+const HASH_CHUNK: usize = 4096;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct DuplicateOptions {
+    /// Seconds two tracks' durations may differ by and still count as the
+    /// same recording.
+    pub duration_tolerance: u32,
+    /// Also decode tracks of equal length and compare their audio, which
+    /// finds copies whatever their tags say. Slow the first time; the
+    /// hashes are kept until the file changes.
+    pub compare_audio: bool,
+    /// Also compare the acoustic fingerprints of tracks of similar length,
+    /// which finds other encodings of a recording whatever their tags say.
+    /// Slower still the first time, as every track is decoded.
+    pub compare_fingerprints: bool,
+}
+
+impl Default for DuplicateOptions {
+    fn default() -> Self {
+        DuplicateOptions { duration_tolerance: 3, compare_audio: true, compare_fingerprints: false }
+    }
+}
+
+/// Tracks that look like copies of one recording.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DuplicateGroup {
+    pub tracks: Vec<Track>,
+    /// The copy worth keeping: lossless over lossy, then the highest
+    /// bitrate, sample rate and bit depth.
+    pub keeper: i64,
+    /// Every track in the group decodes to the same samples.
+    pub identical_audio: bool,
+}
+
+impl LibraryService {
+    /// `LibraryManager::find_duplicates`, decoding on this thread and only
+    /// storing the hashes and fingerprints on the writer.
+    pub fn find_duplicates(&self, options: &DuplicateOptions) -> Result<Vec<DuplicateGroup>> {
+        self.analyze(|library, new| library.group_duplicates(options, new))
+    }
+}
+
+impl LibraryManager {
+    /// Groups tracks with the same artist and title, ignoring case,
+    /// punctuation, guests and remaster notes, and durations within the
+    /// tolerance. With `compare_audio`, tracks whose decoded audio is
+    /// identical are grouped too, whatever their tags, and with
+    /// `compare_fingerprints` so are tracks that sound alike.
+    pub fn find_duplicates(&self, options: &DuplicateOptions) -> Result<Vec<DuplicateGroup>> {
+        let mut new = NewAnalyses::default();
+        let duplicates = self.group_duplicates(options, &mut new);
+        self.store_analyses(&new)?;
+        duplicates
+    }
+
+    fn group_duplicates(&self, options: &DuplicateOptions, new: &mut NewAnalyses) -> Result<Vec<DuplicateGroup>> {
+        let tracks = self.get_all_tracks()?;
+        let rules = self.split_rules()?;
+        let mut groups = UnionFind::new(tracks.len());
+
+        let mut by_name: HashMap<(String, String), Vec<usize>> = HashMap::new();
+        for (i, track) in tracks.iter().enumerate() {
+            by_name.entry(name_key(&rules, track)).or_default().push(i);
+        }
+        for mut members in by_name.into_values() {
+            members.sort_by_key(|&i| tracks[i].duration);
+            for pair in members.windows(2) {
+                if tracks[pair[1]].duration - tracks[pair[0]].duration <= options.duration_tolerance {
+                    groups.union(pair[0], pair[1]);
+                }
+            }
+        }
+
+        let mut hashes: HashMap<usize, String> = HashMap::new();
+        if options.compare_audio {
+            // Identical audio has the same length, so only tracks that share
+            // theirs with another track need decoding.
+            let mut by_duration: HashMap<u32, Vec<usize>> = HashMap::new();
+            for (i, track) in tracks.iter().enumerate() {
+                by_duration.entry(track.duration).or_default().push(i);
+            }
+            let mut by_hash: HashMap<String, usize> = HashMap::new();
+            for (i, track) in tracks.iter().enumerate() {
+                let duration = track.duration;
+                let shared = (duration.saturating_sub(1)..=duration + 1)
+                    .filter_map(|d| by_duration.get(&d))
+                    .map(Vec::len)
+                    .sum::<usize>()
+                    > 1;
+                if !shared {
+                    continue;
+                }
+                match self.audio_hash(track, new) {
+                    Ok(hash) => {
+                        match by_hash.get(&hash) {
+                            Some(&j) => groups.union(i, j),
+                            None => {
+                                by_hash.insert(hash.clone(), i);
+                            }
+                        }
+                        hashes.insert(i, hash);
+                    }
+                    Err(e) => log::error!("Failed to hash audio of {:?}: {:#}", track.path, e),
+                }
+            }
+        }
+
+        if options.compare_fingerprints {
+            let mut order: Vec<usize> = (0..tracks.len()).collect();
+            order.sort_by_key(|&i| tracks[i].duration);
+            let mut fingerprints: HashMap<usize, Option<Fingerprint>> = HashMap::new();
+            let mut fingerprint = |i: usize| {
+                fingerprints
+                    .entry(i)
+                    .or_insert_with(|| {
+                        self.fingerprint_of(tracks[i].id, new)
+                            .map_err(|e| log::error!("Failed to fingerprint {:?}: {:#}", tracks[i].path, e))
+                            .ok()
+                    })
+                    .clone()
+            };
+            for (n, &i) in order.iter().enumerate() {
+                for &j in &order[n + 1..] {
+                    if tracks[j].duration - tracks[i].duration > options.duration_tolerance {
+                        break;
+                    }
+                    if groups.find(i) == groups.find(j) {
+                        continue;
+                    }
+                    if let (Some(a), Some(b)) = (fingerprint(i), fingerprint(j)) {
+                        if a.similarity(&b) >= FINGERPRINT_MATCH {
+                            groups.union(i, j);
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut members: HashMap<usize, Vec<usize>> = HashMap::new();
+        for i in 0..tracks.len() {
+            members.entry(groups.find(i)).or_default().push(i);
+        }
+        let mut duplicates: Vec<DuplicateGroup> = members
+            .into_values()
+            .filter(|members| members.len() > 1)
+            .map(|members| {
+                let first_hash = hashes.get(&members[0]);
+                let identical_audio = first_hash.is_some() && members.iter().all(|i| hashes.get(i) == first_hash);
+                let tracks: Vec<Track> = members.into_iter().map(|i| tracks[i].clone()).collect();
+                let keeper = tracks
+                    .iter()
+                    .max_by_key(|track| (quality(track), std::cmp::Reverse(track.id)))
+                    .map(|track| track.id)
+                    .unwrap_or_default();
+                DuplicateGroup { tracks, keeper, identical_audio }
+            })
+            .collect();
+        duplicates.sort_by_key(|group| group.tracks.iter().map(|track| track.id).min());
+        Ok(duplicates)
+    }
+
+    /// Moves the play history and playlist entries of `duplicates` onto
+    /// `keeper`, and carries over a rating or loved flag the keeper lacks.
+    /// The duplicates and their files are left for the caller to remove.
+    pub fn merge_duplicates(&self, keeper: i64, duplicates: &[i64]) -> Result<()> {
+        if duplicates.contains(&keeper) {
+            bail!("Track {} cannot be merged into itself", keeper);
+        }
+        let tx = self.conn.unchecked_transaction()?;
+        for &id in std::iter::once(&keeper).chain(duplicates) {
+            let exists: bool = tx.query_row("SELECT EXISTS(SELECT 1 FROM tracks WHERE id = ?1)", params![id], |row| row.get(0))?;
+            if !exists {
+                bail!("Track {} does not exist", id);
+            }
+        }
+        for &id in duplicates {
+            tx.execute("UPDATE plays SET track_id = ?1 WHERE track_id = ?2", params![keeper, id])?;
+            tx.execute("UPDATE playlist_entries SET track_id = ?1 WHERE track_id = ?2", params![keeper, id])?;
+            tx.execute(
+                "UPDATE tracks SET
+                    rating = COALESCE(rating, (SELECT rating FROM tracks WHERE id = ?2)),
+                    loved = loved OR (SELECT loved FROM tracks WHERE id = ?2)
+                 WHERE id = ?1",
+                params![keeper, id],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Hash of a track's decoded samples, worked out once per version of
+    /// the file; a new one is added to `new`.
+    fn audio_hash(&self, track: &Track, new: &mut NewAnalyses) -> Result<String> {
+        let cached: Option<String> = self.conn.query_row(
+            "SELECT audio_hash FROM tracks WHERE id = ?1",
+            params![track.id],
+            |row| row.get(0),
+        )?;
+        if let Some(hash) = cached {
+            return Ok(hash);
+        }
+        let path = Path::new(&track.path);
+        let hash = format!("{:016x}", decode_hash(path, track.start_ms, track.end_ms)?);
+        new.hashes.push((track.id, hash.clone()));
+        Ok(hash)
+    }
+}
+
+fn decode_hash(path: &Path, start_ms: Option<u32>, end_ms: Option<u32>) -> Result<u64> {
+    let (_, _, samples) = decode_range(path, start_ms, end_ms)?;
+    let mut hash = FNV1A_OFFSET;
+    let mut chunk = Vec::with_capacity(HASH_CHUNK * 2);
+    for sample in samples {
+        chunk.extend_from_slice(&sample.to_le_bytes());
+        if chunk.len() >= HASH_CHUNK * 2 {
+            hash = fnv1a_extend(hash, &chunk);
+            chunk.clear();
+        }
+    }
+    Ok(fnv1a_extend(hash, &chunk))
+}
+
+fn name_key(rules: &SplitRules, track: &Track) -> (String, String) {
+    let (artist, _) = rules.split_featuring(&track.artist);
+    let (title, _) = rules.split_featuring(&track.title);
+    (normalize(artist), normalize(title))
+}
+
+/// Lowercase words without punctuation, a leading "the" or bracketed
+/// remaster notes, so "The Band - Song (2011 Remaster)" matches "band - song".
+fn normalize(text: &str) -> String {
+    let lower = text.to_lowercase();
+    let mut kept = String::with_capacity(lower.len());
+    let mut rest = lower.as_str();
+    while let Some(open) = rest.find(['(', '[']) {
+        let close = if rest[open..].starts_with('(') { ')' } else { ']' };
+        let Some(len) = rest[open..].find(close) else {
+            break;
+        };
+        kept.push_str(&rest[..open]);
+        let inner = &rest[open + 1..open + len];
+        if !inner.contains("remaster") {
+            kept.push(' ');
+            kept.push_str(inner);
+            kept.push(' ');
+        }
+        rest = &rest[open + len + 1..];
+    }
+    kept.push_str(rest);
+
+    let words: Vec<&str> = kept.split(|c: char| !c.is_alphanumeric()).filter(|word| !word.is_empty()).collect();
+    match words.as_slice() {
+        ["the", rest @ ..] if !rest.is_empty() => rest.join(" "),
+        words => words.join(" "),
+    }
+}
+
+/// Sort key for the better copy of a recording.
+fn quality(track: &Track) -> (u8, u32, u32, u8) {
+    let codec = match track.codec.as_deref() {
+        Some("FLAC") => 5,
+        Some("ALAC") => 4,
+        Some("WavPack") | Some("Monkey's Audio") => 3,
+        // Lossless, but with the weakest tag support.
+        Some("PCM") => 2,
+        Some(_) => 1,
+        None => 0,
+    };
+    (
+        codec,
+        track.bitrate.unwrap_or(0),
+        track.sample_rate.unwrap_or(0),
+        track.bit_depth.unwrap_or(0),
+    )
+}
+
+struct UnionFind {
+    parent: Vec<usize>,
+}
+
+impl UnionFind {
+    fn new(len: usize) -> Self {
+        UnionFind { parent: (0..len).collect() }
+    }
+
+    fn find(&mut self, i: usize) -> usize {
+        let mut root = i;
+        while self.parent[root] != root {
+            root = self.parent[root];
+        }
+        let mut i = i;
+        while self.parent[i] != root {
+            let next = self.parent[i];
+            self.parent[i] = root;
+            i = next;
+        }
+        root
+    }
+
+    fn union(&mut self, a: usize, b: usize) {
+        let (a, b) = (self.find(a), self.find(b));
+        if a != b {
+            self.parent[b] = a;
+        }
+    }
+}
+
+impl mlua::UserData for DuplicateGroup {
+    fn add_fields<'lua, F: mlua::UserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_method_get("tracks", |_lua, this| Ok(this.tracks.clone()));
+        fields.add_field_method_get("keeper", |_lua, this| Ok(this.keeper));
+        fields.add_field_method_get("identical_audio", |_lua, this| Ok(this.identical_audio));
+    }
+}
@@ -0,0 +1,545 @@
+use crate::playlists::unix_time;
+use crate::ratings::half_stars;
+use crate::{ArtistRole, LibraryManager, SmartQuery, Track};
+use anyhow::{bail, Context, Result};
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+
+/// Marks a file as a library export, whatever it is named.
+const EXPORT_FORMAT: &str = "aurora-library";
+
+/// Version of the export format written by this build. Imports accept this
+/// version and older ones.
+pub const EXPORT_VERSION: u32 = 1;
+
+/// Everything in a library that is not cheaper to work out again from the
+/// files: their metadata, and the playlists, ratings, play history and
+/// settings that exist nowhere else. Paths are absolute, as stored.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LibraryExport {
+    pub format: String,
+    pub version: u32,
+    /// Unix time in seconds.
+    pub exported_at: i64,
+    pub roots: Vec<String>,
+    /// Albums and artists are rebuilt from the tracks on import; these
+    /// lists are for other tools reading the file.
+    pub artists: Vec<ExportedArtist>,
+    pub albums: Vec<ExportedAlbum>,
+    pub tracks: Vec<ExportedTrack>,
+    pub playlists: Vec<ExportedPlaylist>,
+    pub smart_playlists: Vec<ExportedSmartPlaylist>,
+    pub plays: Vec<ExportedPlay>,
+    /// Settings by key, such as the artist split rules.
+    pub settings: BTreeMap<String, serde_json::Value>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ExportedArtist {
+    pub id: i64,
+    pub name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ExportedAlbum {
+    pub id: i64,
+    pub title: String,
+    pub artist: String,
+    pub compilation: bool,
+}
+
+/// A track with its rating and loved flag, plus the credits and genres
+/// that `Track` does not carry. Other sections refer to it by `id`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ExportedTrack {
+    #[serde(flatten)]
+    pub track: Track,
+    pub credits: Vec<ExportedCredit>,
+    pub genres: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ExportedCredit {
+    pub name: String,
+    pub role: ArtistRole,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ExportedPlaylist {
+    pub name: String,
+    pub created_at: i64,
+    pub updated_at: i64,
+    /// Track ids in playlist order.
+    pub tracks: Vec<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ExportedSmartPlaylist {
+    pub name: String,
+    pub query: SmartQuery,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ExportedPlay {
+    pub track_id: i64,
+    pub started_at: i64,
+    pub listened_ms: u64,
+    pub completed: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LibraryImport {
+    /// Tracks that were not in the library yet.
+    pub tracks_added: u32,
+    /// Tracks already in the library under the same path. Their metadata is
+    /// left alone; a rating or loved flag they lack is taken from the export.
+    pub tracks_merged: u32,
+    pub playlists: u32,
+    pub smart_playlists: u32,
+    pub plays: u32,
+    /// Playlists and smart playlists left out because one of that name
+    /// already exists.
+    pub skipped_playlists: Vec<String>,
+    /// Imported paths with no file behind them, which usually means a
+    /// prefix substitution is missing.
+    pub missing_files: Vec<String>,
+}
+
+impl LibraryManager {
+    pub fn export_library(&self, path: &Path) -> Result<()> {
+        let export = self.library_export()?;
+        let file = File::create(path).with_context(|| format!("Failed to create {:?}", path))?;
+        let mut writer = BufWriter::new(file);
+        serde_json::to_writer(&mut writer, &export)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn library_export(&self) -> Result<LibraryExport> {
+        let roots = self.library_roots()?.iter().map(|root| root.to_string_lossy().into_owned()).collect();
+
+        let mut stmt = self.conn.prepare("SELECT id, name FROM artists ORDER BY id")?;
+        let artists = stmt
+            .query_map([], |row| Ok(ExportedArtist { id: row.get(0)?, name: row.get(1)? }))?
+            .collect::<rusqlite::Result<_>>()?;
+
+        let mut stmt = self.conn.prepare(
+            "SELECT al.id, al.title, COALESCE(ar.name, ''), al.compilation
+             FROM albums al
+             LEFT JOIN artists ar ON ar.id = al.artist_id
+             ORDER BY al.id",
+        )?;
+        let albums = stmt
+            .query_map([], |row| {
+                Ok(ExportedAlbum { id: row.get(0)?, title: row.get(1)?, artist: row.get(2)?, compilation: row.get(3)? })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+
+        let mut credits: HashMap<i64, Vec<ExportedCredit>> = HashMap::new();
+        let mut stmt = self.conn.prepare(
+            "SELECT ta.track_id, ar.name, ta.role
+             FROM track_artists ta
+             JOIN artists ar ON ar.id = ta.artist_id
+             ORDER BY ta.track_id, ta.position",
+        )?;
+        let rows = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get::<_, String>(2)?)))?
+            .collect::<rusqlite::Result<Vec<(i64, String, String)>>>()?;
+        for (track_id, name, role) in rows {
+            if let Some(role) = ArtistRole::from_name(&role) {
+                credits.entry(track_id).or_default().push(ExportedCredit { name, role });
+            }
+        }
+
+        let mut genres: HashMap<i64, Vec<String>> = HashMap::new();
+        let mut stmt = self.conn.prepare(
+            "SELECT tg.track_id, g.name
+             FROM track_genres tg
+             JOIN genres g ON g.id = tg.genre_id
+             ORDER BY tg.track_id, tg.position",
+        )?;
+        let rows = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<Vec<(i64, String)>>>()?;
+        for (track_id, name) in rows {
+            genres.entry(track_id).or_default().push(name);
+        }
+
+        let tracks = self
+            .get_all_tracks()?
+            .into_iter()
+            .map(|track| ExportedTrack {
+                credits: credits.remove(&track.id).unwrap_or_default(),
+                genres: genres.remove(&track.id).unwrap_or_default(),
+                track,
+            })
+            .collect();
+
+        let mut entries: HashMap<i64, Vec<i64>> = HashMap::new();
+        let mut stmt = self.conn.prepare("SELECT playlist_id, track_id FROM playlist_entries ORDER BY playlist_id, position")?;
+        let rows = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<Vec<(i64, i64)>>>()?;
+        for (playlist_id, track_id) in rows {
+            entries.entry(playlist_id).or_default().push(track_id);
+        }
+        let mut stmt = self.conn.prepare("SELECT id, name, created_at, updated_at FROM playlists ORDER BY id")?;
+        let playlists = stmt
+            .query_map([], |row| {
+                Ok(ExportedPlaylist {
+                    tracks: entries.remove(&row.get(0)?).unwrap_or_default(),
+                    name: row.get(1)?,
+                    created_at: row.get(2)?,
+                    updated_at: row.get(3)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+
+        let mut stmt = self.conn.prepare("SELECT id, name, query, created_at, updated_at FROM smart_playlists ORDER BY id")?;
+        let rows = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get::<_, String>(2)?, row.get(3)?, row.get(4)?)))?
+            .collect::<rusqlite::Result<Vec<(i64, String, String, i64, i64)>>>()?;
+        let smart_playlists = rows
+            .into_iter()
+            .map(|(id, name, query, created_at, updated_at)| {
+                let query = serde_json::from_str(&query)
+                    .with_context(|| format!("Smart playlist {} has an invalid query", id))?;
+                Ok(ExportedSmartPlaylist { name, query, created_at, updated_at })
+            })
+            .collect::<Result<_>>()?;
+
+        let mut stmt = self.conn.prepare("SELECT track_id, started_at, listened_ms, completed FROM plays ORDER BY started_at, id")?;
+        let plays = stmt
+            .query_map([], |row| {
+                Ok(ExportedPlay {
+                    track_id: row.get(0)?,
+                    started_at: row.get(1)?,
+                    listened_ms: row.get::<_, i64>(2)? as u64,
+                    completed: row.get(3)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+
+        let mut stmt = self.conn.prepare("SELECT key, value FROM settings ORDER BY key")?;
+        let rows = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<Vec<(String, String)>>>()?;
+        let settings = rows
+            .into_iter()
+            .map(|(key, value)| {
+                let value = serde_json::from_str(&value).with_context(|| format!("Setting {} is not valid JSON", key))?;
+                Ok((key, value))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(LibraryExport {
+            format: EXPORT_FORMAT.to_string(),
+            version: EXPORT_VERSION,
+            exported_at: unix_time(),
+            roots,
+            artists,
+            albums,
+            tracks,
+            playlists,
+            smart_playlists,
+            plays,
+            settings,
+        })
+    }
+
+    /// Reads an export written by `export_library`, see `import_library_data`.
+    pub fn import_library(&self, path: &Path, remaps: &[(String, String)]) -> Result<LibraryImport> {
+        let file = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+        let export: LibraryExport = serde_json::from_reader(BufReader::new(file))
+            .with_context(|| format!("{:?} is not a library export", path))?;
+        self.import_library_data(&export, remaps)
+    }
+
+    /// Merges an export into this library. Each path is rewritten by the
+    /// longest matching `(from, to)` prefix in `remaps`, so a library can
+    /// move between machines and mounts. Imported tracks are read again
+    /// from their files on the next scan. Local data always wins: existing
+    /// tracks, playlists and settings are never overwritten, and plays
+    /// already recorded are not added twice.
+    pub fn import_library_data(&self, export: &LibraryExport, remaps: &[(String, String)]) -> Result<LibraryImport> {
+        if export.format != EXPORT_FORMAT {
+            bail!("Not a library export: format is {:?}", export.format);
+        }
+        if export.version > EXPORT_VERSION {
+            bail!(
+                "Library export version {} is newer than this build supports (version {})",
+                export.version,
+                EXPORT_VERSION
+            );
+        }
+
+        let mut summary = LibraryImport::default();
+        let generation = self.current_generation()?;
+        let tx = self.conn.unchecked_transaction()?;
+
+        for root in &export.roots {
+            self.conn.execute(
+                "INSERT OR IGNORE INTO library_roots (path) VALUES (?1)",
+                params![remap_path(root, remaps)],
+            )?;
+        }
+
+        let mut track_ids: HashMap<i64, i64> = HashMap::new();
+        for exported in &export.tracks {
+            let track = &exported.track;
+            let path = remap_path(&track.path, remaps);
+            let rating = track.rating.map(half_stars).transpose()?;
+            let existing: Option<i64> = self.conn.query_row(
+                "SELECT id FROM tracks WHERE path = ?1 AND start_ms IS ?2",
+                params![path, track.start_ms],
+                |row| row.get(0),
+            ).optional()?;
+
+            let track_id = match existing {
+                Some(id) => {
+                    self.conn.execute(
+                        "UPDATE tracks SET rating = COALESCE(rating, ?1), loved = loved OR ?2 WHERE id = ?3",
+                        params![rating, track.loved, id],
+                    )?;
+                    summary.tracks_merged += 1;
+                    id
+                }
+                None => {
+                    let artist_id = self.get_or_create_artist(&track.artist)?;
+                    let album_artist_id = self.get_or_create_artist(&track.album_artist)?;
+                    let album_id = self.get_or_create_album(&track.album, album_artist_id, track.compilation)?;
+                    // No mtime, so the next scan reads the file and fills in
+                    // whatever the export could not carry.
+                    let id: i64 = self.conn.query_row(
+                        "INSERT INTO tracks (path, title, artist_id, album_id, duration, track_number, year, genre, mtime, size, scan_generation, rating, loved,
+                            disc_number, disc_total, track_total, composer, release_date, original_date, label, catalog_number,
+                            musicbrainz_recording_id, musicbrainz_release_id, musicbrainz_artist_id,
+                            bitrate, sample_rate, bit_depth, channels, codec, start_ms, end_ms)
+                         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, NULL, NULL, ?9, ?10, ?11,
+                            ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22, ?23, ?24, ?25, ?26, ?27, ?28, ?29)
+                         RETURNING id",
+                        params![
+                            path, track.title, artist_id, album_id, track.duration,
+                            track.track_number, track.year, track.genre, generation, rating, track.loved,
+                            track.disc_number, track.disc_total, track.track_total, track.composer,
+                            track.release_date, track.original_date, track.label, track.catalog_number,
+                            track.musicbrainz_recording_id, track.musicbrainz_release_id, track.musicbrainz_artist_id,
+                            track.bitrate, track.sample_rate, track.bit_depth, track.channels, track.codec,
+                            track.start_ms, track.end_ms
+                        ],
+                        |row| row.get(0),
+                    )?;
+                    let credits: Vec<(ArtistRole, String)> =
+                        exported.credits.iter().map(|credit| (credit.role, credit.name.clone())).collect();
+                    self.store_credits(id, &credits)?;
+                    self.store_genres(id, &exported.genres)?;
+                    summary.tracks_added += 1;
+                    id
+                }
+            };
+            if !Path::new(&path).exists() {
+                summary.missing_files.push(path);
+            }
+            track_ids.insert(track.id, track_id);
+        }
+
+        for playlist in &export.playlists {
+            if self.name_taken("playlists", &playlist.name)? {
+                summary.skipped_playlists.push(playlist.name.clone());
+                continue;
+            }
+            self.conn.execute(
+                "INSERT INTO playlists (name, created_at, updated_at) VALUES (?1, ?2, ?3)",
+                params![playlist.name, playlist.created_at, playlist.updated_at],
+            )?;
+            let playlist_id = self.conn.last_insert_rowid();
+            let tracks = playlist.tracks.iter().filter_map(|id| track_ids.get(id));
+            for (position, track_id) in tracks.enumerate() {
+                self.conn.execute(
+                    "INSERT INTO playlist_entries (playlist_id, track_id, position) VALUES (?1, ?2, ?3)",
+                    params![playlist_id, track_id, position as i64],
+                )?;
+            }
+            summary.playlists += 1;
+        }
+
+        for playlist in &export.smart_playlists {
+            if self.name_taken("smart_playlists", &playlist.name)? {
+                summary.skipped_playlists.push(playlist.name.clone());
+                continue;
+            }
+            self.conn.execute(
+                "INSERT INTO smart_playlists (name, query, created_at, updated_at) VALUES (?1, ?2, ?3, ?4)",
+                params![playlist.name, serde_json::to_string(&playlist.query)?, playlist.created_at, playlist.updated_at],
+            )?;
+            summary.smart_playlists += 1;
+        }
+
+        for play in &export.plays {
+            let Some(track_id) = track_ids.get(&play.track_id) else {
+                continue;
+            };
+            summary.plays += self.conn.execute(
+                "INSERT INTO plays (track_id, started_at, listened_ms, completed)
+                 SELECT ?1, ?2, ?3, ?4
+                 WHERE NOT EXISTS (SELECT 1 FROM plays WHERE track_id = ?1 AND started_at = ?2)",
+                params![track_id, play.started_at, play.listened_ms as i64, play.completed],
+            )? as u32;
+        }
+
+        for (key, value) in &export.settings {
+            self.conn.execute(
+                "INSERT OR IGNORE INTO settings (key, value) VALUES (?1, ?2)",
+                params![key, serde_json::to_string(value)?],
+            )?;
+        }
+
+        tx.commit()?;
+        Ok(summary)
+    }
+
+    fn name_taken(&self, table: &str, name: &str) -> Result<bool> {
+        let taken = self.conn.query_row(
+            &format!("SELECT EXISTS(SELECT 1 FROM {} WHERE name = ?1)", table),
+            params![name],
+            |row| row.get(0),
+        )?;
+        Ok(taken)
+    }
+}
+
+/// Rewrites `path` by the longest `from` prefix that ends on a path
+/// boundary. Separators after the prefix follow the style of `to`, so
+/// `D:\Music` can become `/mnt/music`.
+fn remap_path(path: &str, remaps: &[(String, String)]) -> String {
+    let is_separator = |c: char| c == '/' || c == '\\';
+    let best = remaps
+        .iter()
+        .filter(|(from, _)| !from.is_empty())
+        .filter_map(|(from, to)| {
+            let rest = path.strip_prefix(from.as_str())?;
+            let boundary = rest.is_empty() || from.ends_with(is_separator) || rest.starts_with(is_separator);
+            boundary.then_some((from, to, rest))
+        })
+        .max_by_key(|(from, _, _)| from.len());
+    let Some((from, to, rest)) = best else {
+        return path.to_string();
+    };
+    let separator = |text: &str| text.chars().find(|&c| is_separator(c));
+    let rest = match (separator(from), separator(to)) {
+        (Some(old), Some(new)) if old != new => rest.replace(old, &new.to_string()),
+        _ => rest.to_string(),
+    };
+    match (to.ends_with(is_separator), rest.starts_with(is_separator)) {
+        (true, true) => format!("{}{}", to, &rest[1..]),
+        _ => format!("{}{}", to, rest),
+    }
+}
+
+impl mlua::UserData for LibraryImport {
+    fn add_fields<'lua, F: mlua::UserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_method_get("tracks_added", |_lua, this| Ok(this.tracks_added));
+        fields.add_field_method_get("tracks_merged", |_lua, this| Ok(this.tracks_merged));
+        fields.add_field_method_get("playlists", |_lua, this| Ok(this.playlists));
+        fields.add_field_method_get("smart_playlists", |_lua, this| Ok(this.smart_playlists));
+        fields.add_field_method_get("plays", |_lua, this| Ok(this.plays));
+        fields.add_field_method_get("skipped_playlists", |_lua, this| Ok(this.skipped_playlists.clone()));
+        fields.add_field_method_get("missing_files", |_lua, this| Ok(this.missing_files.clone()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use crate::LibraryRoot;
+
+    fn track_id(manager: &LibraryManager, path: &Path) -> i64 {
+        manager.get_track_by_path(path).unwrap().unwrap().id
+    }
+
+    #[test]
+    fn remaps_by_the_longest_prefix_on_a_path_boundary() {
+        let remaps = [
+            ("/music".to_string(), "/mnt/music".to_string()),
+            ("/music/live".to_string(), "/mnt/live/".to_string()),
+            (r"D:\Music".to_string(), "/srv/music".to_string()),
+        ];
+        assert_eq!(remap_path("/music/a/one.flac", &remaps), "/mnt/music/a/one.flac");
+        assert_eq!(remap_path("/music/live/one.flac", &remaps), "/mnt/live/one.flac");
+        assert_eq!(remap_path("/musicals/one.flac", &remaps), "/musicals/one.flac");
+        assert_eq!(remap_path(r"D:\Music\a\one.flac", &remaps), "/srv/music/a/one.flac");
+        assert_eq!(remap_path("/music", &remaps), "/mnt/music");
+    }
+
+    #[test]
+    fn round_trips_a_moved_library() {
+        let dir = TempDir::new("export-round-trip");
+        let old_music = dir.path().join("old/music");
+        let one = dir.write_wav("old/music/one.wav");
+        let two = dir.write_wav("old/music/two.wav");
+        let three = dir.write_wav("old/music/three.wav");
+
+        let old = LibraryManager::new(dir.path().join("old.db")).unwrap();
+        old.add_library_root(&LibraryRoot::new(&old_music)).unwrap();
+        old.scan_library().unwrap();
+        let (one, two, three) = (track_id(&old, &one), track_id(&old, &two), track_id(&old, &three));
+        old.set_rating(one, Some(4.5), false).unwrap();
+        old.set_loved(two, true, false).unwrap();
+        old.record_play(one, 1_000, 180_000, true).unwrap();
+        old.record_play(one, 2_000, 180_000, true).unwrap();
+        old.record_play(two, 3_000, 5_000, false).unwrap();
+        let mix = old.create_playlist("Mix").unwrap();
+        old.add_to_playlist(mix, &[three, one], None).unwrap();
+        let recent = SmartQuery { limit: Some(10), ..SmartQuery::default() };
+        old.create_smart_playlist("Recent", &recent).unwrap();
+        let export = dir.path().join("library.json");
+        old.export_library(&export).unwrap();
+
+        let new_music = dir.path().join("new/music");
+        std::fs::create_dir_all(dir.path().join("new")).unwrap();
+        std::fs::rename(&old_music, &new_music).unwrap();
+        let remaps = [(old_music.to_string_lossy().into_owned(), new_music.to_string_lossy().into_owned())];
+
+        let new = LibraryManager::new(dir.path().join("new.db")).unwrap();
+        let imported = new.import_library(&export, &remaps).unwrap();
+        assert_eq!(imported.tracks_added, 3);
+        assert_eq!(imported.playlists, 1);
+        assert_eq!(imported.smart_playlists, 1);
+        assert_eq!(imported.plays, 3);
+        assert!(imported.missing_files.is_empty(), "{:?}", imported.missing_files);
+        assert_eq!(new.library_roots().unwrap(), std::slice::from_ref(&new_music));
+
+        // A rescan reads the files again but keeps what only the library knew.
+        let scanned = new.scan_library().unwrap();
+        assert_eq!((scanned.added, scanned.updated, scanned.removed), (0, 3, 0));
+
+        let one = new.get_track_by_path(&new_music.join("one.wav")).unwrap().unwrap();
+        let two = new.get_track_by_path(&new_music.join("two.wav")).unwrap().unwrap();
+        assert_eq!((one.rating, one.loved), (Some(4.5), false));
+        assert_eq!((two.rating, two.loved), (None, true));
+        let stats = new.get_play_stats(one.id).unwrap().unwrap();
+        assert_eq!((stats.play_count, stats.skip_count, stats.last_played), (2, 0, Some(2_000)));
+        let stats = new.get_play_stats(two.id).unwrap().unwrap();
+        assert_eq!((stats.play_count, stats.skip_count), (0, 1));
+
+        let playlists = new.get_playlists().unwrap();
+        assert_eq!(playlists.len(), 1);
+        let entries = new.get_playlist_entries(playlists[0].id).unwrap();
+        let titles: Vec<&str> = entries.iter().map(|entry| entry.track.title.as_str()).collect();
+        assert_eq!(titles, ["three", "one"]);
+        let smart = new.get_smart_playlists().unwrap();
+        assert_eq!((smart[0].name.as_str(), &smart[0].query), ("Recent", &recent));
+
+        // Importing again adds nothing twice.
+        let again = new.import_library(&export, &remaps).unwrap();
+        assert_eq!((again.tracks_added, again.tracks_merged, again.plays), (0, 3, 0));
+        assert_eq!(again.skipped_playlists, ["Mix", "Recent"]);
+    }
+}
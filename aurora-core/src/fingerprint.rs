@@ -0,0 +1,449 @@
+use crate::decode::decode_range;
+use crate::{track_from_row, LibraryManager, LibraryService, Track, TRACK_SELECT};
+use anyhow::{bail, Context, Result};
+use lofty::file::AudioFile;
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::f32::consts::PI;
+use std::path::Path;
+
+/// First byte of a stored fingerprint. Fingerprints from another version
+/// of the algorithm cannot be compared and are computed again.
+const VERSION: u8 = 1;
+
+/// Audio is analysed at this rate, which keeps everything up to the top of
+/// the chroma range.
+const SAMPLE_RATE: u32 = 11025;
+const FRAME_SIZE: usize = 4096;
+const HOP_SIZE: usize = FRAME_SIZE / 3;
+/// Only the start of a track is analysed, which is plenty to tell
+/// recordings apart.
+const MAX_SECONDS: u32 = 120;
+
+/// Frequencies mapped onto the twelve pitch classes.
+const MIN_FREQ: f32 = 28.0;
+const MAX_FREQ: f32 = 3520.0;
+
+/// Smoothing over neighbouring frames, so small timing and encoding
+/// differences do not flip bits.
+const SMOOTHING: [f32; 5] = [0.25, 0.75, 1.0, 0.75, 0.25];
+
+/// How far, in frames, two fingerprints are slid against each other to
+/// make up for silence or encoder padding at the start. About two seconds.
+const MAX_OFFSET: usize = 16;
+
+/// Similarity above which two fingerprints are taken to be one recording.
+/// Unrelated audio agrees on about half of the bits.
+pub const FINGERPRINT_MATCH: f32 = 0.85;
+
+/// Tracks whose length differs by more than this are never compared.
+const DURATION_WINDOW: u32 = 5;
+
+/// A compact summary of how a recording sounds, in the spirit of
+/// Chromaprint: one 32-bit word per frame, each bit comparing pitch-class
+/// energies across time or across pitch. Tags and containers play no part,
+/// and lossy encodings of a recording stay close to the original.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Fingerprint {
+    pub words: Vec<u32>,
+}
+
+/// A library track and how close its fingerprint is to the one looked up.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SimilarTrack {
+    pub track: Track,
+    /// From 0 to 1; see `FINGERPRINT_MATCH`.
+    pub similarity: f32,
+}
+
+/// Audio hashes and fingerprints worked out over a read, for the writer to
+/// store afterwards, so that decoding never holds up the writer.
+#[derive(Debug, Default)]
+pub(crate) struct NewAnalyses {
+    pub(crate) hashes: Vec<(i64, String)>,
+    fingerprints: Vec<(i64, Vec<u8>)>,
+}
+
+impl NewAnalyses {
+    fn is_empty(&self) -> bool {
+        self.hashes.is_empty() && self.fingerprints.is_empty()
+    }
+}
+
+impl Fingerprint {
+    /// Decodes the file and fingerprints its audio.
+    pub fn compute(path: &Path) -> Result<Self> {
+        Self::compute_range(path, None, None)
+    }
+
+    /// Fingerprints the part of the file between `start_ms` and `end_ms`,
+    /// as for a track of a CUE sheet.
+    pub(crate) fn compute_range(path: &Path, start_ms: Option<u32>, end_ms: Option<u32>) -> Result<Self> {
+        let (channels, rate, decoded) = decode_range(path, start_ms, end_ms)?;
+        let samples = downsample(decoded, channels.max(1) as usize, rate);
+
+        let window: Vec<f32> = (0..FRAME_SIZE)
+            .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / (FRAME_SIZE - 1) as f32).cos())
+            .collect();
+        let chroma_bins: Vec<Option<usize>> = (0..FRAME_SIZE / 2)
+            .map(|bin| {
+                let freq = bin as f32 * SAMPLE_RATE as f32 / FRAME_SIZE as f32;
+                (MIN_FREQ..=MAX_FREQ)
+                    .contains(&freq)
+                    .then(|| (12.0 * (freq / 27.5).log2()).round() as usize % 12)
+            })
+            .collect();
+
+        let mut chroma = Vec::new();
+        let mut start = 0;
+        while start + FRAME_SIZE <= samples.len() {
+            let mut re: Vec<f32> = samples[start..start + FRAME_SIZE]
+                .iter()
+                .zip(&window)
+                .map(|(sample, weight)| sample * weight)
+                .collect();
+            let mut im = vec![0.0; FRAME_SIZE];
+            fft(&mut re, &mut im);
+
+            let mut frame = [0.0f32; 12];
+            for (bin, class) in chroma_bins.iter().enumerate() {
+                if let Some(class) = class {
+                    frame[*class] += re[bin] * re[bin] + im[bin] * im[bin];
+                }
+            }
+            let norm = frame.iter().map(|energy| energy * energy).sum::<f32>().sqrt();
+            if norm > 1e-6 {
+                frame.iter_mut().for_each(|energy| *energy /= norm);
+            }
+            chroma.push(frame);
+            start += HOP_SIZE;
+        }
+
+        let smoothed: Vec<[f32; 12]> = (0..chroma.len())
+            .map(|t| {
+                let mut frame = [0.0f32; 12];
+                for (k, weight) in SMOOTHING.iter().enumerate() {
+                    let source = (t + k).checked_sub(SMOOTHING.len() / 2).and_then(|i| chroma.get(i));
+                    for (out, energy) in frame.iter_mut().zip(source.into_iter().flatten()) {
+                        *out += weight * energy;
+                    }
+                }
+                frame
+            })
+            .collect();
+
+        let words = smoothed
+            .iter()
+            .enumerate()
+            .map(|(t, frame)| {
+                let previous = t.checked_sub(1).map(|p| &smoothed[p]).unwrap_or(frame);
+                let mut word = 0u32;
+                for class in 0..12 {
+                    word |= ((frame[class] > previous[class]) as u32) << class;
+                    word |= ((frame[class] > frame[(class + 1) % 12]) as u32) << (12 + class);
+                }
+                for class in 0..8 {
+                    word |= ((frame[class] > frame[(class + 4) % 12]) as u32) << (24 + class);
+                }
+                word
+            })
+            .collect();
+        Ok(Fingerprint { words })
+    }
+
+    /// Share of matching bits where the two line up best, from 0 to 1.
+    pub fn similarity(&self, other: &Fingerprint) -> f32 {
+        let (a, b) = (&self.words, &other.words);
+        let shortest = a.len().min(b.len());
+        if shortest == 0 {
+            return if a.len() == b.len() { 1.0 } else { 0.0 };
+        }
+        let mut best = 0.0f32;
+        for offset in 0..=MAX_OFFSET.min(shortest / 2) {
+            for (x, y) in [(&a[offset..], &b[..]), (&a[..], &b[offset..])] {
+                let overlap = x.len().min(y.len());
+                if overlap < shortest / 2 {
+                    continue;
+                }
+                let differing: u32 = x.iter().zip(y.iter()).map(|(x, y)| (x ^ y).count_ones()).sum();
+                best = best.max(1.0 - differing as f32 / (overlap as f32 * 32.0));
+            }
+        }
+        best
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(1 + self.words.len() * 4);
+        bytes.push(VERSION);
+        for word in &self.words {
+            bytes.extend_from_slice(&word.to_le_bytes());
+        }
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let (&version, words) = bytes.split_first()?;
+        if version != VERSION || words.len() % 4 != 0 {
+            return None;
+        }
+        let words = words
+            .chunks_exact(4)
+            .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+            .collect();
+        Some(Fingerprint { words })
+    }
+}
+
+impl LibraryService {
+    /// Runs `f` on a read-only connection on this thread, then stores the
+    /// hashes and fingerprints it worked out on the writer, even if it failed
+    /// part way through.
+    pub(crate) fn analyze<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&LibraryManager, &mut NewAnalyses) -> Result<T>,
+    {
+        let mut new = NewAnalyses::default();
+        let result = self.read(|library| f(library, &mut new));
+        if !new.is_empty() {
+            self.write(move |library| library.store_analyses(&new))?;
+        }
+        result
+    }
+
+    /// `LibraryManager::update_fingerprints`, decoding on this thread and
+    /// storing each fingerprint as it is computed.
+    pub fn update_fingerprints(&self) -> Result<u32> {
+        let missing = self.read(|library| library.missing_fingerprints())?;
+        let mut computed = 0;
+        for (id, path) in missing {
+            match self.analyze(|library, new| library.fingerprint_of(id, new)) {
+                Ok(_) => computed += 1,
+                Err(e) => log::error!("Failed to fingerprint {:?}: {:#}", path, e),
+            }
+        }
+        Ok(computed)
+    }
+
+    /// `LibraryManager::find_similar`, decoding on this thread.
+    pub fn find_similar(&self, track_id: i64, threshold: f32) -> Result<Vec<SimilarTrack>> {
+        self.analyze(|library, new| library.similar_to_track(track_id, threshold, new))
+    }
+
+    /// `LibraryManager::identify_file`, decoding on this thread.
+    pub fn identify_file(&self, path: &Path, threshold: f32) -> Result<Vec<SimilarTrack>> {
+        let (fingerprint, duration) = fingerprint_file(path)?;
+        self.analyze(|library, new| library.similar_to(&fingerprint, duration, threshold, new))
+    }
+}
+
+impl LibraryManager {
+    /// The fingerprint of a track, computed and stored the first time it is
+    /// asked for and again whenever the file changes.
+    pub fn track_fingerprint(&self, track_id: i64) -> Result<Fingerprint> {
+        let mut new = NewAnalyses::default();
+        let fingerprint = self.fingerprint_of(track_id, &mut new)?;
+        self.store_analyses(&new)?;
+        Ok(fingerprint)
+    }
+
+    /// The stored fingerprint of a track, or one computed and added to `new`.
+    pub(crate) fn fingerprint_of(&self, track_id: i64, new: &mut NewAnalyses) -> Result<Fingerprint> {
+        type Stored = (String, Option<Vec<u8>>, Option<u32>, Option<u32>);
+        let stored: Option<Stored> = self.conn.query_row(
+            "SELECT path, fingerprint, start_ms, end_ms FROM tracks WHERE id = ?1",
+            params![track_id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+        ).optional()?;
+        let Some((path, stored, start_ms, end_ms)) = stored else {
+            bail!("Track {} does not exist", track_id);
+        };
+        if let Some(fingerprint) = stored.as_deref().and_then(Fingerprint::from_bytes) {
+            return Ok(fingerprint);
+        }
+        let fingerprint = Fingerprint::compute_range(Path::new(&path), start_ms, end_ms)?;
+        new.fingerprints.push((track_id, fingerprint.to_bytes()));
+        Ok(fingerprint)
+    }
+
+    /// Saves what a read worked out into the cache columns.
+    pub(crate) fn store_analyses(&self, new: &NewAnalyses) -> Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        for (id, hash) in &new.hashes {
+            tx.execute("UPDATE tracks SET audio_hash = ?1 WHERE id = ?2", params![hash, id])?;
+        }
+        for (id, fingerprint) in &new.fingerprints {
+            tx.execute("UPDATE tracks SET fingerprint = ?1 WHERE id = ?2", params![fingerprint, id])?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn missing_fingerprints(&self) -> Result<Vec<(i64, String)>> {
+        let mut stmt = self.conn.prepare("SELECT id, path FROM tracks WHERE fingerprint IS NULL")?;
+        let missing = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(missing)
+    }
+
+    /// Fingerprints every track that has none yet. Returns how many were
+    /// computed; files that cannot be decoded are logged and skipped.
+    pub fn update_fingerprints(&self) -> Result<u32> {
+        let mut computed = 0;
+        for (id, path) in self.missing_fingerprints()? {
+            match self.track_fingerprint(id) {
+                Ok(_) => computed += 1,
+                Err(e) => log::error!("Failed to fingerprint {:?}: {:#}", path, e),
+            }
+        }
+        Ok(computed)
+    }
+
+    /// Other tracks that sound like `track_id`, most similar first.
+    pub fn find_similar(&self, track_id: i64, threshold: f32) -> Result<Vec<SimilarTrack>> {
+        let mut new = NewAnalyses::default();
+        let similar = self.similar_to_track(track_id, threshold, &mut new);
+        self.store_analyses(&new)?;
+        similar
+    }
+
+    /// Library tracks that sound like the file at `path`, which need not be
+    /// in the library or carry any tags. Most similar first.
+    pub fn identify_file(&self, path: &Path, threshold: f32) -> Result<Vec<SimilarTrack>> {
+        let (fingerprint, duration) = fingerprint_file(path)?;
+        let mut new = NewAnalyses::default();
+        let similar = self.similar_to(&fingerprint, duration, threshold, &mut new);
+        self.store_analyses(&new)?;
+        similar
+    }
+
+    fn similar_to_track(&self, track_id: i64, threshold: f32, new: &mut NewAnalyses) -> Result<Vec<SimilarTrack>> {
+        let fingerprint = self.fingerprint_of(track_id, new)?;
+        let duration: u32 = self.conn.query_row(
+            "SELECT duration FROM tracks WHERE id = ?1",
+            params![track_id],
+            |row| row.get(0),
+        )?;
+        let mut similar = self.similar_to(&fingerprint, duration, threshold, new)?;
+        similar.retain(|candidate| candidate.track.id != track_id);
+        Ok(similar)
+    }
+
+    fn similar_to(
+        &self,
+        fingerprint: &Fingerprint,
+        duration: u32,
+        threshold: f32,
+        new: &mut NewAnalyses,
+    ) -> Result<Vec<SimilarTrack>> {
+        let sql = format!("{} WHERE t.duration BETWEEN ?1 AND ?2", TRACK_SELECT);
+        let mut stmt = self.conn.prepare(&sql)?;
+        let candidates: Vec<Track> = stmt
+            .query_map(
+                params![duration.saturating_sub(DURATION_WINDOW), duration + DURATION_WINDOW],
+                track_from_row,
+            )?
+            .collect::<rusqlite::Result<_>>()?;
+
+        let mut similar = Vec::new();
+        for track in candidates {
+            let other = match self.fingerprint_of(track.id, new) {
+                Ok(other) => other,
+                Err(e) => {
+                    log::error!("Failed to fingerprint {:?}: {:#}", track.path, e);
+                    continue;
+                }
+            };
+            let similarity = fingerprint.similarity(&other);
+            if similarity >= threshold {
+                similar.push(SimilarTrack { track, similarity });
+            }
+        }
+        similar.sort_by(|a, b| b.similarity.total_cmp(&a.similarity));
+        Ok(similar)
+    }
+}
+
+/// The fingerprint of a file and its length in seconds.
+fn fingerprint_file(path: &Path) -> Result<(Fingerprint, u32)> {
+    let duration = lofty::read_from_path(path)
+        .with_context(|| format!("Failed to read {:?}", path))?
+        .properties()
+        .duration()
+        .as_secs() as u32;
+    Ok((Fingerprint::compute(path)?, duration))
+}
+
+/// Mono samples at `SAMPLE_RATE`, each the average of the input samples it
+/// covers, up to `MAX_SECONDS` long.
+fn downsample(samples: impl Iterator<Item = i16>, channels: usize, rate: u32) -> Vec<f32> {
+    let limit = (SAMPLE_RATE * MAX_SECONDS) as usize;
+    let mut out = Vec::with_capacity(limit.min(1 << 20));
+    let (mut sum, mut count, mut position) = (0.0f32, 0u32, 0u32);
+    let (mut frame_sum, mut frame_channels) = (0.0f32, 0usize);
+    for sample in samples {
+        frame_sum += sample as f32 / i16::MAX as f32;
+        frame_channels += 1;
+        if frame_channels < channels {
+            continue;
+        }
+        sum += frame_sum / channels as f32;
+        count += 1;
+        frame_sum = 0.0;
+        frame_channels = 0;
+
+        position += SAMPLE_RATE;
+        if position >= rate {
+            position -= rate;
+            out.push(sum / count as f32);
+            sum = 0.0;
+            count = 0;
+            if out.len() >= limit {
+                break;
+            }
+        }
+    }
+    out
+}
+
+/// In-place radix-2 FFT. `re` and `im` must have the same power-of-two length.
+fn fft(re: &mut [f32], im: &mut [f32]) {
+    let n = re.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+    let mut len = 2;
+    while len <= n {
+        let angle = -2.0 * PI / len as f32;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (sin, cos) = (angle * k as f32).sin_cos();
+                let (a, b) = (start + k, start + k + len / 2);
+                let t_re = re[b] * cos - im[b] * sin;
+                let t_im = re[b] * sin + im[b] * cos;
+                re[b] = re[a] - t_re;
+                im[b] = im[a] - t_im;
+                re[a] += t_re;
+                im[a] += t_im;
+            }
+        }
+        len <<= 1;
+    }
+}
+
+impl mlua::UserData for SimilarTrack {
+    fn add_fields<'lua, F: mlua::UserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_method_get("track", |_lua, this| Ok(this.track.clone()));
+        fields.add_field_method_get("similarity", |_lua, this| Ok(this.similarity));
+    }
+}
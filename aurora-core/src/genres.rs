@@ -0,0 +1,69 @@
+use crate::{track_from_row, LibraryManager, Track, TrackQuery, TRACK_SELECT};
+use anyhow::Result;
+use rusqlite::params;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Genre {
+    pub id: i64,
+    pub name: String,
+    pub track_count: u32,
+    /// Seconds.
+    pub duration: u32,
+}
+
+impl LibraryManager {
+    /// Replaces the genres of a track. Names differing only in case are
+    /// one genre.
+    pub(crate) fn store_genres(&self, track_id: i64, genres: &[String]) -> Result<()> {
+        self.conn.execute("DELETE FROM track_genres WHERE track_id = ?1", params![track_id])?;
+        for (position, name) in genres.iter().enumerate() {
+            self.conn.execute("INSERT OR IGNORE INTO genres (name) VALUES (?1)", params![name])?;
+            self.conn.execute(
+                "INSERT OR IGNORE INTO track_genres (track_id, genre_id, position)
+                 SELECT ?1, id, ?2 FROM genres WHERE name = ?3",
+                params![track_id, position as i64, name],
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn get_track_genres(&self, track_id: i64) -> Result<Vec<String>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT g.name FROM track_genres tg
+             JOIN genres g ON g.id = tg.genre_id
+             WHERE tg.track_id = ?1
+             ORDER BY tg.position",
+        )?;
+        let genres = stmt
+            .query_map(params![track_id], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(genres)
+    }
+
+    /// Genres with at least one track.
+    pub fn get_genres(&self) -> Result<Vec<Genre>> {
+        self.list_genres(&TrackQuery::new())
+    }
+
+    pub fn get_tracks_by_genre(&self, genre_id: i64) -> Result<Vec<Track>> {
+        let sql = format!(
+            "{} WHERE t.id IN (SELECT track_id FROM track_genres WHERE genre_id = ?1)",
+            TRACK_SELECT
+        );
+        let mut stmt = self.conn.prepare(&sql)?;
+        let tracks = stmt
+            .query_map(params![genre_id], track_from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(tracks)
+    }
+}
+
+impl mlua::UserData for Genre {
+    fn add_fields<'lua, F: mlua::UserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_method_get("id", |_lua, this| Ok(this.id));
+        fields.add_field_method_get("name", |_lua, this| Ok(this.name.clone()));
+        fields.add_field_method_get("track_count", |_lua, this| Ok(this.track_count));
+        fields.add_field_method_get("duration", |_lua, this| Ok(this.duration));
+    }
+}
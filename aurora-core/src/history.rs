@@ -0,0 +1,148 @@
+use crate::{track_from_row, track_from_row_at, LibraryManager, Track, TRACK_SELECT};
+use anyhow::Result;
+use rusqlite::params;
+use serde::{Deserialize, Serialize};
+
+/// One playback of a track. A playback that ran to the end of the track is
+/// `completed`; one the listener moved away from is a skip.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Play {
+    pub id: i64,
+    pub track: Track,
+    /// Unix time in seconds.
+    pub started_at: i64,
+    pub listened_ms: u64,
+    pub completed: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PlayStats {
+    pub track: Track,
+    pub play_count: u32,
+    pub skip_count: u32,
+    /// When a playback of the track last started, as unix time in seconds.
+    pub last_played: Option<i64>,
+}
+
+impl LibraryManager {
+    pub fn record_play(&self, track_id: i64, started_at: i64, listened_ms: u64, completed: bool) -> Result<i64> {
+        self.conn.execute(
+            "INSERT INTO plays (track_id, started_at, listened_ms, completed) VALUES (?1, ?2, ?3, ?4)",
+            params![track_id, started_at, listened_ms as i64, completed],
+        )?;
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    /// Counts for one track, all zero if it has never been played. `None`
+    /// if there is no such track.
+    pub fn get_play_stats(&self, track_id: i64) -> Result<Option<PlayStats>> {
+        let sql = format!(
+            "SELECT COALESCE(s.play_count, 0), COALESCE(s.skip_count, 0), s.last_played, t.*
+             FROM ({}) t
+             LEFT JOIN track_play_stats s ON s.track_id = t.id
+             WHERE t.id = ?1",
+            TRACK_SELECT
+        );
+        self.play_stats(&sql, params![track_id]).map(|stats| stats.into_iter().next())
+    }
+
+    /// Tracks by number of completed plays, most first.
+    pub fn most_played(&self, limit: usize) -> Result<Vec<PlayStats>> {
+        let sql = format!(
+            "SELECT s.play_count, s.skip_count, s.last_played, t.*
+             FROM track_play_stats s
+             JOIN ({}) t ON t.id = s.track_id
+             WHERE s.play_count > 0
+             ORDER BY s.play_count DESC, s.last_played DESC
+             LIMIT ?1",
+            TRACK_SELECT
+        );
+        self.play_stats(&sql, params![limit as i64])
+    }
+
+    /// Distinct tracks by their latest playback, skips included.
+    pub fn recently_played(&self, limit: usize) -> Result<Vec<PlayStats>> {
+        let sql = format!(
+            "SELECT s.play_count, s.skip_count, s.last_played, t.*
+             FROM track_play_stats s
+             JOIN ({}) t ON t.id = s.track_id
+             ORDER BY s.last_played DESC
+             LIMIT ?1",
+            TRACK_SELECT
+        );
+        self.play_stats(&sql, params![limit as i64])
+    }
+
+    /// Tracks that have never been played through, though they may have
+    /// been skipped.
+    pub fn never_played(&self) -> Result<Vec<Track>> {
+        let sql = format!(
+            "{} WHERE NOT EXISTS (SELECT 1 FROM plays p WHERE p.track_id = t.id AND p.completed)",
+            TRACK_SELECT
+        );
+        let mut stmt = self.conn.prepare(&sql)?;
+        let tracks = stmt
+            .query_map([], track_from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(tracks)
+    }
+
+    /// Individual playbacks, newest first.
+    pub fn play_history(&self, limit: usize) -> Result<Vec<Play>> {
+        let sql = format!(
+            "SELECT p.id, p.started_at, p.listened_ms, p.completed, t.*
+             FROM plays p
+             JOIN ({}) t ON t.id = p.track_id
+             ORDER BY p.started_at DESC, p.id DESC
+             LIMIT ?1",
+            TRACK_SELECT
+        );
+        let mut stmt = self.conn.prepare(&sql)?;
+        let plays = stmt
+            .query_map(params![limit as i64], |row| {
+                Ok(Play {
+                    id: row.get(0)?,
+                    started_at: row.get(1)?,
+                    listened_ms: row.get::<_, i64>(2)? as u64,
+                    completed: row.get(3)?,
+                    track: track_from_row_at(row, 4)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(plays)
+    }
+
+    fn play_stats(&self, sql: &str, params: impl rusqlite::Params) -> Result<Vec<PlayStats>> {
+        let mut stmt = self.conn.prepare(sql)?;
+        let stats = stmt
+            .query_map(params, |row| {
+                Ok(PlayStats {
+                    play_count: row.get(0)?,
+                    skip_count: row.get(1)?,
+                    last_played: row.get(2)?,
+                    track: track_from_row_at(row, 3)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(stats)
+    }
+}
+
+impl mlua::UserData for Play {
+    fn add_fields<'lua, F: mlua::UserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_method_get("id", |_lua, this| Ok(this.id));
+        fields.add_field_method_get("track", |_lua, this| Ok(this.track.clone()));
+        fields.add_field_method_get("started_at", |_lua, this| Ok(this.started_at));
+        fields.add_field_method_get("listened_ms", |_lua, this| Ok(this.listened_ms));
+        fields.add_field_method_get("completed", |_lua, this| Ok(this.completed));
+    }
+}
+
+impl mlua::UserData for PlayStats {
+    fn add_fields<'lua, F: mlua::UserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_method_get("track", |_lua, this| Ok(this.track.clone()));
+        fields.add_field_method_get("play_count", |_lua, this| Ok(this.play_count));
+        fields.add_field_method_get("skip_count", |_lua, this| Ok(this.skip_count));
+        fields.add_field_method_get("last_played", |_lua, this| Ok(this.last_played));
+    }
+}
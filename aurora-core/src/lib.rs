@@ -1,11 +1,66 @@
 use anyhow::Result;
-use rusqlite::{params, Connection};
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
-use lofty::file::{AudioFile, TaggedFileExt};
-use lofty::tag::Accessor;
+use std::time::Duration;
+use lofty::file::{AudioFile, FileType, TaggedFile, TaggedFileExt};
+use lofty::tag::{Accessor, ItemKey, Tag};
+use lofty::probe::Probe;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 
+mod browse;
+mod cover_art;
+mod credits;
+mod cue;
+mod decode;
+mod duplicates;
+mod export;
+mod fingerprint;
+mod genres;
+mod history;
+mod loudness;
+mod lyrics;
+mod migrations;
+mod playlist_files;
+mod playlists;
+mod ratings;
+mod roots;
+mod rules;
+mod scan;
+mod search;
+mod service;
+mod smart_playlists;
+mod tag_editor;
+#[cfg(test)]
+mod testing;
+mod watcher;
+pub use browse::*;
+pub use cover_art::StoredCover;
+pub use credits::*;
+pub use cue::{CueFile, CueSheet, CueTrack};
+use cue::{read_tracks, sheet_for, tracks_stamp};
+pub use duplicates::*;
+pub use export::*;
+pub use fingerprint::*;
+pub use genres::*;
+pub use history::*;
+pub use loudness::*;
+pub use lyrics::{LyricLine, Lyrics, LYRICS_SIDECAR, LYRICS_TAG};
+use lyrics::read_lyrics;
+pub use migrations::{SchemaError, SCHEMA_VERSION};
+use migrations::migrate;
+pub use playlist_files::*;
+pub use playlists::*;
+use ratings::read_rating_tags;
+pub use roots::*;
+pub use rules::*;
+pub use scan::*;
+pub use service::*;
+pub use smart_playlists::*;
+pub use tag_editor::*;
+pub use watcher::*;
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Track {
     pub id: i64,
@@ -17,59 +72,276 @@ pub struct Track {
     pub track_number: Option<u32>,
     pub year: Option<u32>,
     pub genre: Option<String>,
+    /// Stars from 0 to 5 in half-star steps.
+    pub rating: Option<f32>,
+    pub loved: bool,
+    /// The artist the album is filed under, which differs from `artist` on
+    /// compilations and featured-artist tracks.
+    pub album_artist: String,
+    pub compilation: bool,
+    pub disc_number: Option<u32>,
+    pub disc_total: Option<u32>,
+    pub track_total: Option<u32>,
+    pub composer: Option<String>,
+    /// Dates as tagged, anything from "2001" to "2001-05-14".
+    pub release_date: Option<String>,
+    pub original_date: Option<String>,
+    pub label: Option<String>,
+    pub catalog_number: Option<String>,
+    pub musicbrainz_recording_id: Option<String>,
+    pub musicbrainz_release_id: Option<String>,
+    pub musicbrainz_artist_id: Option<String>,
+    /// Audio bitrate in kbps.
+    pub bitrate: Option<u32>,
+    /// Hz.
+    pub sample_rate: Option<u32>,
+    pub bit_depth: Option<u8>,
+    pub channels: Option<u8>,
+    pub codec: Option<String>,
+    /// The track's root is unavailable, such as a drive that is not mounted.
+    #[serde(default)]
+    pub offline: bool,
+    /// Where a track cut from a longer file by a CUE sheet starts within
+    /// `path`, in milliseconds. `None` for a file played whole.
+    #[serde(default)]
+    pub start_ms: Option<u32>,
+    /// Where it ends, or `None` to play to the end of the file.
+    #[serde(default)]
+    pub end_ms: Option<u32>,
+}
+
+/// Album artist for compilations that do not name one.
+pub const VARIOUS_ARTISTS: &str = "Various Artists";
+
+/// Tags and file stamp read from disk, before anything touches the database.
+/// Kept separate from `Track` so it can be produced on scan worker threads.
+#[derive(Clone)]
+struct TrackMetadata {
+    path: PathBuf,
+    title: String,
+    artist: String,
+    album: String,
+    /// Only when the file names one.
+    album_artist: Option<String>,
+    compilation: bool,
+    /// Every value of each field, unsplit.
+    artists: Vec<String>,
+    composers: Vec<String>,
+    remixers: Vec<String>,
+    genres: Vec<String>,
+    duration: u32,
+    track_number: Option<u32>,
+    year: Option<u32>,
+    genre: Option<String>,
+    /// Half stars, only when the file carries a rating.
+    rating: Option<u8>,
+    loved: Option<bool>,
+    disc_number: Option<u32>,
+    disc_total: Option<u32>,
+    track_total: Option<u32>,
+    composer: Option<String>,
+    release_date: Option<String>,
+    original_date: Option<String>,
+    label: Option<String>,
+    catalog_number: Option<String>,
+    musicbrainz_recording_id: Option<String>,
+    musicbrainz_release_id: Option<String>,
+    musicbrainz_artist_id: Option<String>,
+    bitrate: Option<u32>,
+    sample_rate: Option<u32>,
+    bit_depth: Option<u8>,
+    channels: Option<u8>,
+    codec: Option<String>,
+    /// The whole file, however much of it the track covers.
+    length_ms: u32,
+    /// Bounds of a CUE sheet track within the file.
+    start_ms: Option<u32>,
+    end_ms: Option<u32>,
+    /// The sheet beside the file the track was cut by.
+    cue_path: Option<PathBuf>,
+    /// A CUE sheet in the file's tags, as FLAC and APE rips carry them.
+    embedded_cue: Option<String>,
+    lyrics: Option<Lyrics>,
+    /// Hash of the embedded pictures, if there are any.
+    picture_stamp: Option<i64>,
+    mtime: i64,
+    size: i64,
+}
+
+fn read_metadata(path: &Path) -> Result<TrackMetadata> {
+    let (mtime, size) = tracks_stamp(path, None)?;
+    let tagged_file = Probe::open(path)?.guess_file_type()?.read()?;
+    let tag = tagged_file.primary_tag()
+        .or_else(|| tagged_file.first_tag());
+    
+    let properties = tagged_file.properties();
+    let duration = properties.duration().as_secs() as u32;
+
+    let title = tag.and_then(|t| t.title().map(|s| s.into_owned()))
+        .unwrap_or_else(|| path.file_stem().unwrap().to_string_lossy().into_owned());
+    let artist = tag.and_then(|t| t.artist().map(|s| s.into_owned()))
+        .unwrap_or_else(|| "Unknown Artist".to_string());
+    let album = tag.and_then(|t| t.album().map(|s| s.into_owned()))
+        .unwrap_or_else(|| "Unknown Album".to_string());
+    let compilation = tag
+        .and_then(|t| t.get_string(&ItemKey::FlagCompilation))
+        .is_some_and(|flag| flag == "1" || flag.eq_ignore_ascii_case("true"));
+    let album_artist = tag
+        .and_then(|t| t.get_string(&ItemKey::AlbumArtist))
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(str::to_string);
+    let values = |key: ItemKey| -> Vec<String> {
+        tag.map(|t| t.get_strings(&key).map(str::to_string).collect()).unwrap_or_default()
+    };
+    let artists = values(ItemKey::TrackArtist);
+    let composers = values(ItemKey::Composer);
+    let remixers = values(ItemKey::Remixer);
+    let genres = values(ItemKey::Genre);
+    
+    let track_number = tag.and_then(|t| t.track());
+    let year = tag.and_then(|t| t.year());
+    let genre = tag.and_then(|t| t.genre().map(|s| s.into_owned()));
+    let (rating, loved) = tag.map(read_rating_tags).unwrap_or_default();
+    let text = |key: ItemKey| {
+        tag.and_then(|t| t.get_string(&key))
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .map(str::to_string)
+    };
+
+    Ok(TrackMetadata {
+        path: path.to_path_buf(),
+        title,
+        artist,
+        album,
+        album_artist,
+        compilation,
+        artists,
+        composers,
+        remixers,
+        genres,
+        duration,
+        track_number,
+        year,
+        genre,
+        rating,
+        loved,
+        disc_number: tag.and_then(|t| t.disk()),
+        disc_total: tag.and_then(|t| t.disk_total()),
+        track_total: tag.and_then(|t| t.track_total()),
+        composer: text(ItemKey::Composer),
+        // Vorbis DATE and ID3v2 TDRC are recording dates, but that is where
+        // most taggers put the release date.
+        release_date: text(ItemKey::ReleaseDate).or_else(|| text(ItemKey::RecordingDate)),
+        original_date: text(ItemKey::OriginalReleaseDate),
+        label: text(ItemKey::Label),
+        catalog_number: text(ItemKey::CatalogNumber),
+        musicbrainz_recording_id: text(ItemKey::MusicBrainzRecordingId),
+        musicbrainz_release_id: text(ItemKey::MusicBrainzReleaseId),
+        musicbrainz_artist_id: text(ItemKey::MusicBrainzArtistId),
+        bitrate: properties.audio_bitrate().filter(|bitrate| *bitrate > 0),
+        sample_rate: properties.sample_rate().filter(|rate| *rate > 0),
+        bit_depth: properties.bit_depth(),
+        channels: properties.channels(),
+        codec: Some(codec_name(tagged_file.file_type(), properties.bit_depth()).to_string()),
+        length_ms: properties.duration().as_millis() as u32,
+        start_ms: None,
+        end_ms: None,
+        cue_path: None,
+        embedded_cue: tag.and_then(embedded_cue_sheet),
+        lyrics: read_lyrics(path, tagged_file.file_type(), tag),
+        picture_stamp: picture_stamp(&tagged_file),
+        mtime,
+        size,
+    })
+}
+
+fn picture_stamp(tagged_file: &TaggedFile) -> Option<i64> {
+    let mut pictures = tagged_file.tags().iter().flat_map(|tag| tag.pictures()).peekable();
+    pictures.peek()?;
+    let stamp = pictures.fold(FNV1A_OFFSET, |hash, picture| {
+        fnv1a_extend(fnv1a_extend(hash, &[picture.pic_type().as_u8()]), picture.data())
+    });
+    Some(stamp as i64)
+}
+
+/// The `CUESHEET` field of a Vorbis comment or APE tag. Neither maps it to
+/// a key of its own, and APE spells it "Cuesheet".
+fn embedded_cue_sheet(tag: &Tag) -> Option<String> {
+    tag.items()
+        .find(|item| matches!(item.key(), ItemKey::Unknown(key) if key.eq_ignore_ascii_case("CUESHEET")))
+        .and_then(|item| item.value().text())
+        .map(str::to_string)
+}
+
+/// Codec of a file going by its type. MP4 holds either AAC or ALAC, and
+/// only ALAC has a bit depth.
+fn codec_name(file_type: FileType, bit_depth: Option<u8>) -> &'static str {
+    match file_type {
+        FileType::Aac => "AAC",
+        FileType::Aiff | FileType::Wav => "PCM",
+        FileType::Ape => "Monkey's Audio",
+        FileType::Flac => "FLAC",
+        FileType::Mpeg => "MP3",
+        FileType::Mp4 if bit_depth.is_some() => "ALAC",
+        FileType::Mp4 => "AAC",
+        FileType::Mpc => "Musepack",
+        FileType::Opus => "Opus",
+        FileType::Vorbis => "Vorbis",
+        FileType::Speex => "Speex",
+        FileType::WavPack => "WavPack",
+        FileType::Custom(name) => name,
+        _ => "Unknown",
+    }
+}
+
+/// Starting value of a 64-bit FNV-1a hash.
+pub(crate) const FNV1A_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+
+/// 64-bit FNV-1a, continuing from `hash`. Stable across builds, unlike
+/// `DefaultHasher`, which matters for hashes that outlive the process.
+pub(crate) fn fnv1a_extend(hash: u64, data: &[u8]) -> u64 {
+    data.iter().fold(hash, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01b3))
+}
+
+pub(crate) fn fnv1a(data: &[u8]) -> u64 {
+    fnv1a_extend(FNV1A_OFFSET, data)
 }
 
 pub struct LibraryManager {
     conn: Connection,
+    /// Pictures extracted from tags, next to the database.
+    cover_dir: PathBuf,
 }
 
 impl LibraryManager {
     pub fn new(db_path: PathBuf) -> Result<Self> {
-        let conn = Connection::open(db_path)?;
-        let manager = Self { conn };
-        manager.initialize_schema()?;
-        Ok(manager)
+        let mut conn = Connection::open(&db_path)?;
+        // Other processes, and readers opened while a checkpoint runs, wait rather than fail.
+        conn.busy_timeout(Duration::from_secs(5))?;
+        // Lets readers carry on from the last commit while a write is in progress.
+        conn.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get::<_, String>(0))?;
+        conn.create_collation(NATURAL_ORDER, natural_cmp)?;
+        // Kept off while migrating, as rebuilding a table would cascade; the
+        // bundled SQLite turns them on by default.
+        conn.pragma_update(None, "foreign_keys", false)?;
+        migrate(&mut conn, &db_path)?;
+        // Playlist entries and plays rely on ON DELETE CASCADE.
+        conn.pragma_update(None, "foreign_keys", true)?;
+        Ok(Self { conn, cover_dir: db_path.with_extension("covers") })
     }
 
-    fn initialize_schema(&self) -> Result<()> {
-        self.conn.execute(
-            "CREATE TABLE IF NOT EXISTS artists (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                name TEXT NOT NULL UNIQUE
-            )",
-            [],
-        )?;
-
-        self.conn.execute(
-            "CREATE TABLE IF NOT EXISTS albums (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                title TEXT NOT NULL,
-                artist_id INTEGER,
-                cover_path TEXT,
-                UNIQUE(title, artist_id),
-                FOREIGN KEY(artist_id) REFERENCES artists(id)
-            )",
-            [],
-        )?;
-
-        self.conn.execute(
-            "CREATE TABLE IF NOT EXISTS tracks (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                path TEXT NOT NULL UNIQUE,
-                title TEXT NOT NULL,
-                artist_id INTEGER,
-                album_id INTEGER,
-                duration INTEGER,
-                track_number INTEGER,
-                year INTEGER,
-                genre TEXT,
-                FOREIGN KEY(artist_id) REFERENCES artists(id),
-                FOREIGN KEY(album_id) REFERENCES albums(id)
-            )",
-            [],
+    /// A connection for `LibraryService` readers. Expects the schema to be
+    /// current already, as it cannot migrate.
+    pub(crate) fn open_read_only(db_path: &Path) -> Result<Self> {
+        let conn = Connection::open_with_flags(
+            db_path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX | OpenFlags::SQLITE_OPEN_URI,
         )?;
-
-        Ok(())
+        conn.busy_timeout(Duration::from_secs(5))?;
+        conn.create_collation(NATURAL_ORDER, natural_cmp)?;
+        Ok(Self { conn, cover_dir: db_path.with_extension("covers") })
     }
 
     fn get_or_create_artist(&self, name: &str) -> Result<i64> {
@@ -85,10 +357,14 @@ impl LibraryManager {
         Ok(id)
     }
 
-    fn get_or_create_album(&self, title: &str, artist_id: i64) -> Result<i64> {
+    /// Albums are keyed on title and album artist. One compilation track is
+    /// enough to mark the whole album as a compilation.
+    fn get_or_create_album(&self, title: &str, artist_id: i64, compilation: bool) -> Result<i64> {
         self.conn.execute(
-            "INSERT OR IGNORE INTO albums (title, artist_id) VALUES (?1, ?2)",
-            params![title, artist_id],
+            "INSERT INTO albums (title, artist_id, compilation) VALUES (?1, ?2, ?3)
+             ON CONFLICT(title, artist_id) DO UPDATE SET
+                compilation = MAX(compilation, excluded.compilation)",
+            params![title, artist_id, compilation],
         )?;
         let id = self.conn.query_row(
             "SELECT id FROM albums WHERE title = ?1 AND artist_id = ?2",
@@ -99,85 +375,254 @@ impl LibraryManager {
     }
 
     pub fn add_track(&self, path: &Path) -> Result<()> {
-        let tagged_file = lofty::read_from_path(path)?;
-        let tag = tagged_file.primary_tag()
-            .or_else(|| tagged_file.first_tag());
-        
-        let properties = tagged_file.properties();
-        let duration = properties.duration().as_secs() as u32;
-
-        let title = tag.and_then(|t| t.title().map(|s| s.into_owned()))
-            .unwrap_or_else(|| path.file_stem().unwrap().to_string_lossy().into_owned());
-        let artist_name = tag.and_then(|t| t.artist().map(|s| s.into_owned()))
-            .unwrap_or_else(|| "Unknown Artist".to_string());
-        let album_title = tag.and_then(|t| t.album().map(|s| s.into_owned()))
-            .unwrap_or_else(|| "Unknown Album".to_string());
-        
-        let track_number = tag.and_then(|t| t.track());
-        let year = tag.and_then(|t| t.year());
-        let genre = tag.and_then(|t| t.genre().map(|s| s.into_owned()));
-
-        let artist_id = self.get_or_create_artist(&artist_name)?;
-        let album_id = self.get_or_create_album(&album_title, artist_id)?;
-
-        let path_str = path.to_string_lossy();
+        let generation = self.current_generation()?;
+        self.add_track_with_generation(path, generation)
+    }
 
-        self.conn.execute(
-            "INSERT OR REPLACE INTO tracks (path, title, artist_id, album_id, duration, track_number, year, genre)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
-            params![path_str, title, artist_id, album_id, duration, track_number, year, genre],
-        )?;
+    fn add_track_with_generation(&self, path: &Path, generation: i64) -> Result<()> {
+        let tracks = read_tracks(path, sheet_for(path, |_| true).as_deref())?;
+        self.store_tracks(path, &tracks, generation)
+    }
 
+    /// Stores the tracks read from the file at `path` and deletes any others
+    /// it held before, as when a CUE sheet was added, removed or edited.
+    fn store_tracks(&self, path: &Path, tracks: &[TrackMetadata], generation: i64) -> Result<()> {
+        let mut kept = Vec::with_capacity(tracks.len());
+        for metadata in tracks {
+            kept.push(self.store_metadata(metadata, generation)?);
+        }
+        let mut stmt = self.conn.prepare("SELECT id FROM tracks WHERE path = ?1")?;
+        let ids: Vec<i64> = stmt
+            .query_map(params![path.to_string_lossy()], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        for id in ids.into_iter().filter(|id| !kept.contains(id)) {
+            self.delete_track(id)?;
+        }
         Ok(())
     }
 
-    pub fn scan_directory(&self, path: &Path) -> Result<()> {
-        if path.is_dir() {
-            for entry in std::fs::read_dir(path)? {
-                let entry = entry?;
-                let path = entry.path();
-                if path.is_dir() {
-                    self.scan_directory(&path)?;
-                } else if is_audio_file(&path) {
-                    if let Err(e) = self.add_track(&path) {
-                        log::error!("Failed to add track {:?}: {}", path, e);
-                    }
-                }
-            }
+    fn store_metadata(&self, metadata: &TrackMetadata, generation: i64) -> Result<i64> {
+        let rules = self.split_rules()?;
+        // Without the guests of "Artist feat. Guest", so a featured track
+        // without an album artist tag lands on the same album as the rest.
+        let album_artist = match &metadata.album_artist {
+            Some(name) => name.as_str(),
+            None if metadata.compilation => VARIOUS_ARTISTS,
+            None => rules.split_featuring(&metadata.artist).0,
+        };
+        let artist_id = self.get_or_create_artist(&metadata.artist)?;
+        let album_artist_id = self.get_or_create_artist(album_artist)?;
+        let album_id = self.get_or_create_album(&metadata.album, album_artist_id, metadata.compilation)?;
+
+        let path_str = metadata.path.to_string_lossy();
+        let before: Option<(Option<i64>, Option<i64>)> = self.conn.query_row(
+            "SELECT album_id, picture_stamp FROM tracks WHERE path = ?1 AND IFNULL(start_ms, -1) = IFNULL(?2, -1)",
+            params![path_str, metadata.start_ms],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        ).optional()?;
+
+        // Upsert rather than INSERT OR REPLACE so a rescanned track keeps its id.
+        // A file without a rating keeps the one set in the library.
+        let track_id: i64 = self.conn.query_row(
+            "INSERT INTO tracks (path, title, artist_id, album_id, duration, track_number, year, genre, mtime, size, scan_generation, rating, loved,
+                disc_number, disc_total, track_total, composer, release_date, original_date, label, catalog_number,
+                musicbrainz_recording_id, musicbrainz_release_id, musicbrainz_artist_id,
+                bitrate, sample_rate, bit_depth, channels, codec, start_ms, end_ms, cue_path, picture_stamp)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, COALESCE(?13, 0),
+                ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22, ?23, ?24, ?25, ?26, ?27, ?28, ?29, ?30, ?31, ?32, ?33)
+             ON CONFLICT(path, IFNULL(start_ms, -1)) DO UPDATE SET
+                title = excluded.title,
+                artist_id = excluded.artist_id,
+                album_id = excluded.album_id,
+                duration = excluded.duration,
+                track_number = excluded.track_number,
+                year = excluded.year,
+                genre = excluded.genre,
+                mtime = excluded.mtime,
+                size = excluded.size,
+                scan_generation = excluded.scan_generation,
+                rating = COALESCE(excluded.rating, tracks.rating),
+                loved = COALESCE(?13, tracks.loved),
+                disc_number = excluded.disc_number,
+                disc_total = excluded.disc_total,
+                track_total = excluded.track_total,
+                composer = excluded.composer,
+                release_date = excluded.release_date,
+                original_date = excluded.original_date,
+                label = excluded.label,
+                catalog_number = excluded.catalog_number,
+                musicbrainz_recording_id = excluded.musicbrainz_recording_id,
+                musicbrainz_release_id = excluded.musicbrainz_release_id,
+                musicbrainz_artist_id = excluded.musicbrainz_artist_id,
+                bitrate = excluded.bitrate,
+                sample_rate = excluded.sample_rate,
+                bit_depth = excluded.bit_depth,
+                channels = excluded.channels,
+                codec = excluded.codec,
+                end_ms = excluded.end_ms,
+                cue_path = excluded.cue_path,
+                picture_stamp = excluded.picture_stamp,
+                audio_hash = NULL,
+                fingerprint = NULL,
+                loudness = NULL,
+                true_peak = NULL,
+                loudness_range = NULL,
+                loudness_failed = 0
+             RETURNING id",
+            params![
+                path_str, metadata.title, artist_id, album_id, metadata.duration,
+                metadata.track_number, metadata.year, metadata.genre,
+                metadata.mtime, metadata.size, generation,
+                metadata.rating, metadata.loved,
+                metadata.disc_number, metadata.disc_total, metadata.track_total, metadata.composer,
+                metadata.release_date, metadata.original_date, metadata.label, metadata.catalog_number,
+                metadata.musicbrainz_recording_id, metadata.musicbrainz_release_id, metadata.musicbrainz_artist_id,
+                metadata.bitrate, metadata.sample_rate, metadata.bit_depth, metadata.channels, metadata.codec,
+                metadata.start_ms, metadata.end_ms, metadata.cue_path.as_ref().map(|path| path.to_string_lossy()),
+                metadata.picture_stamp
+            ],
+            |row| row.get(0),
+        )?;
+        // A track that joined the album or whose pictures changed may give
+        // it another cover, and one that left may have taken the old
+        // album's with it.
+        let left = before.and_then(|(album, _)| album).filter(|old| *old != album_id);
+        if let Some(old_album) = left {
+            self.forget_album_cover(old_album)?;
+        }
+        let unchanged = before.is_some_and(|(album, stamp)| album == Some(album_id) && stamp == metadata.picture_stamp);
+        if !unchanged {
+            self.forget_album_cover(album_id)?;
         }
+
+        let credits = rules.credits(&metadata.title, &metadata.artists, &metadata.composers, &metadata.remixers);
+        self.store_credits(track_id, &credits)?;
+        let genres: Vec<String> = metadata.genres.iter().flat_map(|genre| rules.split(genre)).collect();
+        self.store_genres(track_id, &genres)?;
+        self.store_lyrics(track_id, metadata.lyrics.as_ref())?;
+        Ok(track_id)
+    }
+
+    fn current_generation(&self) -> Result<i64> {
+        let generation = self.conn.query_row(
+            "SELECT COALESCE(MAX(scan_generation), 0) FROM tracks",
+            [],
+            |row| row.get(0),
+        )?;
+        Ok(generation)
+    }
+
+    fn delete_track(&self, id: i64) -> Result<()> {
+        self.conn.execute("DELETE FROM tracks WHERE id = ?1", params![id])?;
         Ok(())
     }
 
-    pub fn get_all_tracks(&self) -> Result<Vec<Track>> {
-        let mut stmt = self.conn.prepare(
-            "SELECT t.id, t.path, t.title, ar.name as artist, al.title as album, t.duration, t.track_number, t.year, t.genre
-             FROM tracks t
-             JOIN artists ar ON t.artist_id = ar.id
-             JOIN albums al ON t.album_id = al.id"
+    /// Removes albums no track belongs to any more, then artists and genres
+    /// nothing refers to.
+    pub(crate) fn remove_orphans(&self) -> Result<()> {
+        self.conn.execute_batch(
+            "DELETE FROM albums WHERE id NOT IN (SELECT album_id FROM tracks WHERE album_id IS NOT NULL);
+             DELETE FROM artists
+             WHERE id NOT IN (SELECT artist_id FROM tracks WHERE artist_id IS NOT NULL)
+               AND id NOT IN (SELECT artist_id FROM albums WHERE artist_id IS NOT NULL)
+               AND id NOT IN (SELECT artist_id FROM track_artists);
+             DELETE FROM genres WHERE id NOT IN (SELECT genre_id FROM track_genres);",
         )?;
+        Ok(())
+    }
 
-        let track_iter = stmt.query_map([], |row| {
-            Ok(Track {
-                id: row.get(0)?,
-                path: row.get(1)?,
-                title: row.get(2)?,
-                artist: row.get(3)?,
-                album: row.get(4)?,
-                duration: row.get(5)?,
-                track_number: row.get(6)?,
-                year: row.get(7)?,
-                genre: row.get(8)?,
-            })
-        })?;
-
-        let mut tracks = Vec::new();
-        for track in track_iter {
-            tracks.push(track?);
-        }
+    pub(crate) fn setting<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>> {
+        let value: Option<String> = self.conn.query_row(
+            "SELECT value FROM settings WHERE key = ?1",
+            params![key],
+            |row| row.get(0),
+        ).optional()?;
+        Ok(value.map(|value| serde_json::from_str(&value)).transpose()?)
+    }
+
+    pub(crate) fn set_setting<T: Serialize>(&self, key: &str, value: &T) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO settings (key, value) VALUES (?1, ?2)
+             ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+            params![key, serde_json::to_string(value)?],
+        )?;
+        Ok(())
+    }
+
+    /// Every track, in library order. Prefer `find_tracks` with a page for
+    /// anything that may be large.
+    pub fn get_all_tracks(&self) -> Result<Vec<Track>> {
+        self.find_tracks(&TrackQuery::new())
+    }
+
+    /// The track at `path`, or the first of those a CUE sheet cut it into.
+    pub fn get_track_by_path(&self, path: &Path) -> Result<Option<Track>> {
+        Ok(self.get_tracks_by_path(path)?.into_iter().next())
+    }
+
+    /// Every track of the file at `path`, in the order they play.
+    pub fn get_tracks_by_path(&self, path: &Path) -> Result<Vec<Track>> {
+        let mut stmt = self.conn.prepare(&format!("{} WHERE t.path = ?1 ORDER BY t.start_ms", TRACK_SELECT))?;
+        let tracks = stmt
+            .query_map(params![path.to_string_lossy()], track_from_row)?
+            .collect::<rusqlite::Result<_>>()?;
         Ok(tracks)
     }
 }
 
+const TRACK_SELECT: &str =
+    "SELECT t.id, t.path, t.title, ar.name as artist, al.title as album, t.duration, t.track_number, t.year, t.genre,
+            t.rating, t.loved, aa.name as album_artist, al.compilation,
+            t.disc_number, t.disc_total, t.track_total, t.composer, t.release_date, t.original_date,
+            t.label, t.catalog_number, t.musicbrainz_recording_id, t.musicbrainz_release_id, t.musicbrainz_artist_id,
+            t.bitrate, t.sample_rate, t.bit_depth, t.channels, t.codec, t.offline, t.start_ms, t.end_ms
+     FROM tracks t
+     JOIN artists ar ON t.artist_id = ar.id
+     JOIN albums al ON t.album_id = al.id
+     JOIN artists aa ON al.artist_id = aa.id";
+
+fn track_from_row(row: &rusqlite::Row) -> rusqlite::Result<Track> {
+    track_from_row_at(row, 0)
+}
+
+/// Reads a track from a row whose `TRACK_SELECT` columns start at `offset`.
+fn track_from_row_at(row: &rusqlite::Row, offset: usize) -> rusqlite::Result<Track> {
+    Ok(Track {
+        id: row.get(offset)?,
+        path: row.get(offset + 1)?,
+        title: row.get(offset + 2)?,
+        artist: row.get(offset + 3)?,
+        album: row.get(offset + 4)?,
+        duration: row.get(offset + 5)?,
+        track_number: row.get(offset + 6)?,
+        year: row.get(offset + 7)?,
+        genre: row.get(offset + 8)?,
+        rating: row.get::<_, Option<u8>>(offset + 9)?.map(|half_stars| half_stars as f32 / 2.0),
+        loved: row.get(offset + 10)?,
+        album_artist: row.get(offset + 11)?,
+        compilation: row.get(offset + 12)?,
+        disc_number: row.get(offset + 13)?,
+        disc_total: row.get(offset + 14)?,
+        track_total: row.get(offset + 15)?,
+        composer: row.get(offset + 16)?,
+        release_date: row.get(offset + 17)?,
+        original_date: row.get(offset + 18)?,
+        label: row.get(offset + 19)?,
+        catalog_number: row.get(offset + 20)?,
+        musicbrainz_recording_id: row.get(offset + 21)?,
+        musicbrainz_release_id: row.get(offset + 22)?,
+        musicbrainz_artist_id: row.get(offset + 23)?,
+        bitrate: row.get(offset + 24)?,
+        sample_rate: row.get(offset + 25)?,
+        bit_depth: row.get(offset + 26)?,
+        channels: row.get(offset + 27)?,
+        codec: row.get(offset + 28)?,
+        offline: row.get(offset + 29)?,
+        start_ms: row.get(offset + 30)?,
+        end_ms: row.get(offset + 31)?,
+    })
+}
+
 impl mlua::UserData for Track {
     fn add_fields<'lua, F: mlua::UserDataFields<'lua, Self>>(fields: &mut F) {
         fields.add_field_method_get("id", |_lua, this| Ok(this.id));
@@ -186,26 +631,509 @@ impl mlua::UserData for Track {
         fields.add_field_method_get("artist", |_lua, this| Ok(this.artist.clone()));
         fields.add_field_method_get("album", |_lua, this| Ok(this.album.clone()));
         fields.add_field_method_get("duration", |_lua, this| Ok(this.duration));
+        fields.add_field_method_get("rating", |_lua, this| Ok(this.rating));
+        fields.add_field_method_get("loved", |_lua, this| Ok(this.loved));
+        fields.add_field_method_get("album_artist", |_lua, this| Ok(this.album_artist.clone()));
+        fields.add_field_method_get("compilation", |_lua, this| Ok(this.compilation));
+        fields.add_field_method_get("track_number", |_lua, this| Ok(this.track_number));
+        fields.add_field_method_get("track_total", |_lua, this| Ok(this.track_total));
+        fields.add_field_method_get("disc_number", |_lua, this| Ok(this.disc_number));
+        fields.add_field_method_get("disc_total", |_lua, this| Ok(this.disc_total));
+        fields.add_field_method_get("year", |_lua, this| Ok(this.year));
+        fields.add_field_method_get("genre", |_lua, this| Ok(this.genre.clone()));
+        fields.add_field_method_get("composer", |_lua, this| Ok(this.composer.clone()));
+        fields.add_field_method_get("release_date", |_lua, this| Ok(this.release_date.clone()));
+        fields.add_field_method_get("original_date", |_lua, this| Ok(this.original_date.clone()));
+        fields.add_field_method_get("label", |_lua, this| Ok(this.label.clone()));
+        fields.add_field_method_get("catalog_number", |_lua, this| Ok(this.catalog_number.clone()));
+        fields.add_field_method_get("musicbrainz_recording_id", |_lua, this| Ok(this.musicbrainz_recording_id.clone()));
+        fields.add_field_method_get("musicbrainz_release_id", |_lua, this| Ok(this.musicbrainz_release_id.clone()));
+        fields.add_field_method_get("musicbrainz_artist_id", |_lua, this| Ok(this.musicbrainz_artist_id.clone()));
+        fields.add_field_method_get("bitrate", |_lua, this| Ok(this.bitrate));
+        fields.add_field_method_get("sample_rate", |_lua, this| Ok(this.sample_rate));
+        fields.add_field_method_get("bit_depth", |_lua, this| Ok(this.bit_depth));
+        fields.add_field_method_get("channels", |_lua, this| Ok(this.channels));
+        fields.add_field_method_get("codec", |_lua, this| Ok(this.codec.clone()));
+        fields.add_field_method_get("offline", |_lua, this| Ok(this.offline));
+        fields.add_field_method_get("start_ms", |_lua, this| Ok(this.start_ms));
+        fields.add_field_method_get("end_ms", |_lua, this| Ok(this.end_ms));
     }
 }
 
-pub struct ScriptableLibraryManager(pub Arc<LibraryManager>);
+pub struct ScriptableLibraryManager(pub LibraryService);
 
 impl mlua::UserData for ScriptableLibraryManager {
     fn add_methods<'lua, M: mlua::UserDataMethods<'lua, Self>>(methods: &mut M) {
-        methods.add_method("scan_directory", |_lua, this, path: String| {
-            this.0.scan_directory(Path::new(&path)).map_err(mlua::Error::external)
+        // The optional callback receives progress updates; returning false cancels the scan.
+        methods.add_method("scan_directory", |_lua, this, (path, on_progress): (String, Option<mlua::Function>)| {
+            let job = this.0.scan(PathBuf::from(path)).map_err(mlua::Error::external)?;
+            lua_scan(job, on_progress)
+        });
+
+        methods.add_method("scan_library", |_lua, this, on_progress: Option<mlua::Function>| {
+            let job = this.0.scan_library().map_err(mlua::Error::external)?;
+            lua_scan(job, on_progress)
+        });
+
+        // Options are `include` and `exclude` lists of globs, `follow_symlinks`,
+        // `include_hidden` and `min_size` in bytes.
+        methods.add_method("add_library_root", |_lua, this, (path, options): (String, Option<mlua::Table>)| {
+            let root = lua_library_root(path, options)?;
+            this.0.write(move |library| library.add_library_root(&root)).map_err(mlua::Error::external)
+        });
+
+        methods.add_method("remove_library_root", |_lua, this, path: String| {
+            this.0
+                .write(move |library| library.remove_library_root(Path::new(&path)))
+                .map_err(mlua::Error::external)
+        });
+
+        methods.add_method("get_library_roots", |_lua, this, ()| {
+            this.0.read(|library| library.get_library_roots()).map_err(mlua::Error::external)
+        });
+
+        methods.add_method("check_library_roots", |_lua, this, ()| {
+            this.0.write(move |library| library.check_library_roots()).map_err(mlua::Error::external)
         });
 
         methods.add_method("get_all_tracks", |_lua, this, ()| {
-            this.0.get_all_tracks().map_err(mlua::Error::external)
+            this.0.read(|library| library.get_all_tracks()).map_err(mlua::Error::external)
+        });
+
+        methods.add_method("search", |_lua, this, query: String| {
+            this.0.read(|library| library.search(&query)).map_err(mlua::Error::external)
+        });
+
+        methods.add_method("get_lyrics", |_lua, this, track_id: i64| {
+            this.0.read(|library| library.get_lyrics(track_id)).map_err(mlua::Error::external)
+        });
+
+        // `text` is LRC or plain text; `source` names where it came from.
+        methods.add_method("set_lyrics", |_lua, this, (track_id, text, source): (i64, String, Option<String>)| {
+            let source = source.unwrap_or_else(|| "script".to_string());
+            match Lyrics::parse(&source, &text) {
+                Some(lyrics) => this.0.write(move |library| library.set_lyrics(track_id, &lyrics)),
+                None => this.0.write(move |library| library.remove_lyrics(track_id)),
+            }
+            .map_err(mlua::Error::external)
+        });
+
+        // Options are `reanalyze` and `write_tags`. The callback receives
+        // progress as `scan_directory`'s does; returning false cancels.
+        methods.add_method("analyze_loudness", |_lua, this, (options, on_progress): (Option<mlua::Table>, Option<mlua::Function>)| {
+            let mut loudness_options = LoudnessOptions::default();
+            if let Some(options) = options {
+                loudness_options.reanalyze = options.get::<_, Option<bool>>("reanalyze")?.unwrap_or(false);
+                loudness_options.write_tags = options.get::<_, Option<bool>>("write_tags")?.unwrap_or(false);
+            }
+            let job = this.0.analyze_loudness(loudness_options).map_err(mlua::Error::external)?;
+            lua_loudness(job, on_progress)
+        });
+
+        methods.add_method("get_loudness", |_lua, this, track_id: i64| {
+            this.0.read(|library| library.get_loudness(track_id)).map_err(mlua::Error::external)
+        });
+
+        methods.add_method("write_loudness_tags", |_lua, this, track_id: i64| {
+            this.0
+                .write(move |library| library.write_loudness_tags(track_id))
+                .map_err(mlua::Error::external)
+        });
+
+        methods.add_method("create_playlist", |_lua, this, name: String| {
+            this.0.write(move |library| library.create_playlist(&name)).map_err(mlua::Error::external)
+        });
+
+        methods.add_method("rename_playlist", |_lua, this, (id, name): (i64, String)| {
+            this.0.write(move |library| library.rename_playlist(id, &name)).map_err(mlua::Error::external)
+        });
+
+        methods.add_method("delete_playlist", |_lua, this, id: i64| {
+            this.0.write(move |library| library.delete_playlist(id)).map_err(mlua::Error::external)
+        });
+
+        methods.add_method("get_playlists", |_lua, this, ()| {
+            this.0.read(|library| library.get_playlists()).map_err(mlua::Error::external)
+        });
+
+        methods.add_method("get_playlist_entries", |_lua, this, id: i64| {
+            this.0.read(|library| library.get_playlist_entries(id)).map_err(mlua::Error::external)
+        });
+
+        // Positions are 1-based on the Lua side.
+        methods.add_method("add_to_playlist", |_lua, this, (id, track_ids, position): (i64, Vec<i64>, Option<usize>)| {
+            let position = position.map(|p| p.saturating_sub(1));
+            this.0
+                .write(move |library| library.add_to_playlist(id, &track_ids, position))
+                .map_err(mlua::Error::external)
+        });
+
+        methods.add_method("remove_from_playlist", |_lua, this, (id, entry_ids): (i64, Vec<i64>)| {
+            this.0
+                .write(move |library| library.remove_from_playlist(id, &entry_ids))
+                .map_err(mlua::Error::external)
+        });
+
+        methods.add_method("move_playlist_entry", |_lua, this, (id, entry_id, position): (i64, i64, usize)| {
+            this.0
+                .write(move |library| library.move_playlist_entry(id, entry_id, position.saturating_sub(1)))
+                .map_err(mlua::Error::external)
         });
+
+        methods.add_method("reorder_playlist", |_lua, this, (id, entry_ids): (i64, Vec<i64>)| {
+            this.0
+                .write(move |library| library.reorder_playlist(id, &entry_ids))
+                .map_err(mlua::Error::external)
+        });
+
+        methods.add_method("set_rating", |_lua, this, (track_id, rating, write_tags): (i64, Option<f32>, Option<bool>)| {
+            this.0
+                .write(move |library| library.set_rating(track_id, rating, write_tags.unwrap_or(false)))
+                .map_err(mlua::Error::external)
+        });
+
+        methods.add_method("set_loved", |_lua, this, (track_id, loved, write_tags): (i64, bool, Option<bool>)| {
+            this.0
+                .write(move |library| library.set_loved(track_id, loved, write_tags.unwrap_or(false)))
+                .map_err(mlua::Error::external)
+        });
+
+        methods.add_method("query", |_lua, this, query: String| {
+            let query: SmartQuery = query.parse().map_err(mlua::Error::external)?;
+            this.0.read(|library| library.query_tracks(&query)).map_err(mlua::Error::external)
+        });
+
+        methods.add_method("create_smart_playlist", |_lua, this, (name, query): (String, String)| {
+            let query: SmartQuery = query.parse().map_err(mlua::Error::external)?;
+            this.0
+                .write(move |library| library.create_smart_playlist(&name, &query))
+                .map_err(mlua::Error::external)
+        });
+
+        methods.add_method("update_smart_playlist", |_lua, this, (id, query): (i64, String)| {
+            let query: SmartQuery = query.parse().map_err(mlua::Error::external)?;
+            this.0
+                .write(move |library| library.update_smart_playlist(id, &query))
+                .map_err(mlua::Error::external)
+        });
+
+        methods.add_method("rename_smart_playlist", |_lua, this, (id, name): (i64, String)| {
+            this.0
+                .write(move |library| library.rename_smart_playlist(id, &name))
+                .map_err(mlua::Error::external)
+        });
+
+        methods.add_method("delete_smart_playlist", |_lua, this, id: i64| {
+            this.0.write(move |library| library.delete_smart_playlist(id)).map_err(mlua::Error::external)
+        });
+
+        methods.add_method("get_smart_playlists", |_lua, this, ()| {
+            this.0.read(|library| library.get_smart_playlists()).map_err(mlua::Error::external)
+        });
+
+        methods.add_method("get_smart_playlist_tracks", |_lua, this, id: i64| {
+            this.0.read(|library| library.get_smart_playlist_tracks(id)).map_err(mlua::Error::external)
+        });
+
+        methods.add_method("record_play", |_lua, this, (track_id, started_at, listened_ms, completed): (i64, i64, u64, bool)| {
+            this.0
+                .write(move |library| library.record_play(track_id, started_at, listened_ms, completed))
+                .map_err(mlua::Error::external)
+        });
+
+        methods.add_method("get_play_stats", |_lua, this, track_id: i64| {
+            this.0.read(|library| library.get_play_stats(track_id)).map_err(mlua::Error::external)
+        });
+
+        methods.add_method("most_played", |_lua, this, limit: Option<usize>| {
+            this.0.read(|library| library.most_played(limit.unwrap_or(50))).map_err(mlua::Error::external)
+        });
+
+        methods.add_method("recently_played", |_lua, this, limit: Option<usize>| {
+            this.0.read(|library| library.recently_played(limit.unwrap_or(50))).map_err(mlua::Error::external)
+        });
+
+        methods.add_method("never_played", |_lua, this, ()| {
+            this.0.read(|library| library.never_played()).map_err(mlua::Error::external)
+        });
+
+        methods.add_method("get_play_history", |_lua, this, limit: Option<usize>| {
+            this.0.read(|library| library.play_history(limit.unwrap_or(50))).map_err(mlua::Error::external)
+        });
+
+        methods.add_method("get_track_credits", |_lua, this, track_id: i64| {
+            this.0.read(|library| library.get_track_credits(track_id)).map_err(mlua::Error::external)
+        });
+
+        methods.add_method("get_track_genres", |_lua, this, track_id: i64| {
+            this.0.read(|library| library.get_track_genres(track_id)).map_err(mlua::Error::external)
+        });
+
+        // Roles are "main", "featured", "composer" or "remixer"; nil means any.
+        methods.add_method("get_artists", |_lua, this, role: Option<String>| {
+            let role = role.as_deref().map(lua_artist_role).transpose()?;
+            this.0.read(|library| library.get_artists(role)).map_err(mlua::Error::external)
+        });
+
+        methods.add_method("get_tracks_by_artist", |_lua, this, (artist_id, role): (i64, Option<String>)| {
+            let role = role.as_deref().map(lua_artist_role).transpose()?;
+            this.0
+                .read(|library| library.get_tracks_by_artist(artist_id, role))
+                .map_err(mlua::Error::external)
+        });
+
+        methods.add_method("get_genres", |_lua, this, ()| {
+            this.0.read(|library| library.get_genres()).map_err(mlua::Error::external)
+        });
+
+        methods.add_method("get_tracks_by_genre", |_lua, this, genre_id: i64| {
+            this.0.read(|library| library.get_tracks_by_genre(genre_id)).map_err(mlua::Error::external)
+        });
+
+        methods.add_method("find_tracks", |_lua, this, query: Option<mlua::Table>| {
+            let query = query.map(lua_track_query).transpose()?.unwrap_or_default();
+            this.0.read(|library| library.find_tracks(&query)).map_err(mlua::Error::external)
+        });
+
+        methods.add_method("count_tracks", |_lua, this, query: Option<mlua::Table>| {
+            let query = query.map(lua_track_query).transpose()?.unwrap_or_default();
+            this.0.read(|library| library.count_tracks(&query)).map_err(mlua::Error::external)
+        });
+
+        methods.add_method("list_albums", |_lua, this, query: Option<mlua::Table>| {
+            let query = query.map(lua_track_query).transpose()?.unwrap_or_default();
+            this.0.read(|library| library.list_albums(&query)).map_err(mlua::Error::external)
+        });
+
+        methods.add_method("list_artists", |_lua, this, (query, role): (Option<mlua::Table>, Option<String>)| {
+            let query = query.map(lua_track_query).transpose()?.unwrap_or_default();
+            let role = role.as_deref().map(lua_artist_role).transpose()?;
+            this.0.read(|library| library.list_artists(&query, role)).map_err(mlua::Error::external)
+        });
+
+        methods.add_method("list_genres", |_lua, this, query: Option<mlua::Table>| {
+            let query = query.map(lua_track_query).transpose()?.unwrap_or_default();
+            this.0.read(|library| library.list_genres(&query)).map_err(mlua::Error::external)
+        });
+
+        methods.add_method("get_split_rules", |_lua, this, ()| {
+            this.0.read(|library| library.split_rules()).map_err(mlua::Error::external)
+        });
+
+        // Fields left out of the table keep their current value.
+        methods.add_method("set_split_rules", |_lua, this, table: mlua::Table| {
+            let mut rules = this.0.read(|library| library.split_rules()).map_err(mlua::Error::external)?;
+            if let Some(separators) = table.get("separators")? {
+                rules.separators = separators;
+            }
+            if let Some(featuring) = table.get("featuring")? {
+                rules.featuring = featuring;
+            }
+            if let Some(keep) = table.get("keep")? {
+                rules.keep = keep;
+            }
+            this.0.write(move |library| library.set_split_rules(&rules)).map_err(mlua::Error::external)
+        });
+
+        methods.add_method("get_track_cover", |_lua, this, track_id: i64| {
+            let cover = this.0
+                .write(move |library| library.get_track_cover(track_id))
+                .map_err(mlua::Error::external)?;
+            Ok(cover.map(|path| path.to_string_lossy().into_owned()))
+        });
+
+        methods.add_method("update_covers", |_lua, this, ()| {
+            this.0.write(move |library| library.update_covers()).map_err(mlua::Error::external)
+        });
+
+        // `changes` maps field names to new values; an empty string removes
+        // the field. With `dry_run` the returned diff is all that happens.
+        methods.add_method("update_tags", |_lua, this, (track_id, changes, dry_run): (i64, mlua::Table, Option<bool>)| {
+            let changes = lua_tag_changes(changes)?;
+            this.0
+                .write(move |library| library.update_tags(track_id, &changes, dry_run.unwrap_or(false)))
+                .map_err(mlua::Error::external)
+        });
+
+        methods.add_method("update_tags_batch", |_lua, this, (track_ids, changes, dry_run): (Vec<i64>, mlua::Table, Option<bool>)| {
+            let changes = lua_tag_changes(changes)?;
+            let edits: Vec<(i64, TagChanges)> = track_ids.into_iter().map(|id| (id, changes.clone())).collect();
+            this.0
+                .write(move |library| library.update_tags_batch(&edits, dry_run.unwrap_or(false)))
+                .map_err(mlua::Error::external)
+        });
+
+        // Options are `duration_tolerance` in seconds and `compare_audio`.
+        methods.add_method("find_duplicates", |_lua, this, options: Option<mlua::Table>| {
+            let mut duplicate_options = DuplicateOptions::default();
+            if let Some(options) = options {
+                if let Some(tolerance) = options.get("duration_tolerance")? {
+                    duplicate_options.duration_tolerance = tolerance;
+                }
+                if let Some(compare_audio) = options.get("compare_audio")? {
+                    duplicate_options.compare_audio = compare_audio;
+                }
+                if let Some(compare_fingerprints) = options.get("compare_fingerprints")? {
+                    duplicate_options.compare_fingerprints = compare_fingerprints;
+                }
+            }
+            this.0.find_duplicates(&duplicate_options).map_err(mlua::Error::external)
+        });
+
+        methods.add_method("merge_duplicates", |_lua, this, (keeper, duplicates): (i64, Vec<i64>)| {
+            this.0
+                .write(move |library| library.merge_duplicates(keeper, &duplicates))
+                .map_err(mlua::Error::external)
+        });
+
+        methods.add_method("update_fingerprints", |_lua, this, ()| {
+            this.0.update_fingerprints().map_err(mlua::Error::external)
+        });
+
+        methods.add_method("find_similar", |_lua, this, (track_id, threshold): (i64, Option<f32>)| {
+            this.0
+                .find_similar(track_id, threshold.unwrap_or(FINGERPRINT_MATCH))
+                .map_err(mlua::Error::external)
+        });
+
+        methods.add_method("identify_file", |_lua, this, (path, threshold): (String, Option<f32>)| {
+            this.0
+                .identify_file(Path::new(&path), threshold.unwrap_or(FINGERPRINT_MATCH))
+                .map_err(mlua::Error::external)
+        });
+
+        methods.add_method("export_library", |_lua, this, path: String| {
+            this.0.read(|library| library.export_library(Path::new(&path))).map_err(mlua::Error::external)
+        });
+
+        methods.add_method("import_library", |_lua, this, (path, remaps): (String, Option<HashMap<String, String>>)| {
+            let remaps: Vec<(String, String)> = remaps.unwrap_or_default().into_iter().collect();
+            this.0
+                .write(move |library| library.import_library(Path::new(&path), &remaps))
+                .map_err(mlua::Error::external)
+        });
+
+        methods.add_method("import_playlist", |_lua, this, (path, name): (String, Option<String>)| {
+            this.0
+                .write(move |library| library.import_playlist(Path::new(&path), name.as_deref()))
+                .map_err(mlua::Error::external)
+        });
+
+        methods.add_method("export_playlist", |_lua, this, (id, path, relative): (i64, String, Option<bool>)| {
+            this.0
+                .read(|library| library.export_playlist(id, Path::new(&path), relative.unwrap_or(false)))
+                .map_err(mlua::Error::external)
+        });
+    }
+}
+
+/// Runs `job` to the end, passing progress to `on_progress` and cancelling
+/// when it returns false or fails.
+fn lua_scan(job: ScanJob, on_progress: Option<mlua::Function>) -> mlua::Result<ScanSummary> {
+    for event in job.events() {
+        let (ScanEvent::Progress(progress), Some(callback)) = (event, &on_progress) else {
+            continue;
+        };
+        match callback.call::<_, Option<bool>>(progress) {
+            Ok(Some(false)) => job.cancel(),
+            Ok(_) => {}
+            Err(e) => {
+                log::error!("Scan progress callback failed: {}", e);
+                job.cancel();
+            }
+        }
+    }
+    job.wait().map_err(mlua::Error::external)
+}
+
+/// `lua_scan` for a loudness analysis.
+fn lua_loudness(job: LoudnessJob, on_progress: Option<mlua::Function>) -> mlua::Result<LoudnessSummary> {
+    for event in job.events() {
+        let (LoudnessEvent::Progress(progress), Some(callback)) = (event, &on_progress) else {
+            continue;
+        };
+        match callback.call::<_, Option<bool>>(progress) {
+            Ok(Some(false)) => job.cancel(),
+            Ok(_) => {}
+            Err(e) => {
+                log::error!("Loudness progress callback failed: {}", e);
+                job.cancel();
+            }
+        }
     }
+    job.wait().map_err(mlua::Error::external)
 }
 
-fn is_audio_file(path: &Path) -> bool {
-    matches!(
-        path.extension().and_then(|s| s.to_str()),
-        Some("mp3") | Some("flac") | Some("wav") | Some("m4a") | Some("ogg")
-    )
+fn lua_library_root(path: String, options: Option<mlua::Table>) -> mlua::Result<LibraryRoot> {
+    let mut root = LibraryRoot::new(path);
+    if let Some(options) = options {
+        if let Some(include) = options.get("include")? {
+            root.include = include;
+        }
+        if let Some(exclude) = options.get("exclude")? {
+            root.exclude = exclude;
+        }
+        if let Some(follow) = options.get("follow_symlinks")? {
+            root.follow_symlinks = follow;
+        }
+        if let Some(hidden) = options.get("include_hidden")? {
+            root.include_hidden = hidden;
+        }
+        if let Some(min_size) = options.get("min_size")? {
+            root.min_size = min_size;
+        }
+    }
+    Ok(root)
+}
+
+fn lua_artist_role(name: &str) -> mlua::Result<ArtistRole> {
+    ArtistRole::from_name(name)
+        .ok_or_else(|| mlua::Error::external(anyhow::anyhow!("Unknown artist role {:?}", name)))
+}
+
+/// A `TrackQuery` from a table such as `{ genre = 3, year_from = 1990,
+/// sort = "year desc, title", offset = 100, limit = 50 }`. `sort` takes the
+/// smart playlist syntax after `sort by`.
+fn lua_track_query(table: mlua::Table) -> mlua::Result<TrackQuery> {
+    let sort: Option<String> = table.get("sort")?;
+    let sort = match sort {
+        Some(sort) => format!("sort by {}", sort)
+            .parse::<SmartQuery>()
+            .map_err(mlua::Error::external)?
+            .sort,
+        None => Vec::new(),
+    };
+    Ok(TrackQuery {
+        artist_id: table.get("artist")?,
+        album_id: table.get("album")?,
+        genre_id: table.get("genre")?,
+        year_from: table.get("year_from")?,
+        year_to: table.get("year_to")?,
+        path_prefix: table.get("path")?,
+        sort,
+        offset: table.get::<_, Option<u32>>("offset")?.unwrap_or(0),
+        limit: table.get("limit")?,
+    })
+}
+
+fn lua_tag_changes(table: mlua::Table) -> mlua::Result<TagChanges> {
+    let mut changes = TagChanges::new();
+    for pair in table.pairs::<String, mlua::Value>() {
+        let (name, value) = pair?;
+        let field = TagField::from_name(&name)
+            .ok_or_else(|| mlua::Error::external(anyhow::anyhow!("Unknown tag field {:?}", name)))?;
+        let value = match value {
+            mlua::Value::String(s) => s.to_str()?.to_string(),
+            mlua::Value::Integer(n) => n.to_string(),
+            mlua::Value::Number(n) => n.to_string(),
+            mlua::Value::Boolean(b) => if b { "1" } else { "0" }.to_string(),
+            other => {
+                return Err(mlua::Error::external(anyhow::anyhow!(
+                    "Tag field {} cannot be set to a {}", name, other.type_name()
+                )))
+            }
+        };
+        changes = changes.set(field, value);
+    }
+    Ok(changes)
 }
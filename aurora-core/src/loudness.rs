@@ -0,0 +1,787 @@
+use crate::decode::{decode_range, is_decodable};
+use crate::{file_stamp, LibraryManager, LibraryService};
+use anyhow::{anyhow, bail, Context, Result};
+use lofty::config::WriteOptions;
+use lofty::file::{FileType, TaggedFileExt};
+use lofty::probe::Probe;
+use lofty::tag::{ItemKey, ItemValue, Tag, TagExt, TagItem};
+use rusqlite::{params, OptionalExtension};
+use std::f64::consts::PI;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Loudness ReplayGain 2.0 brings every track to, in LUFS.
+pub const REPLAYGAIN_REFERENCE: f64 = -18.0;
+/// Loudness the `R128_*` gains of Opus files are relative to, in LUFS.
+pub const R128_REFERENCE: f64 = -23.0;
+
+/// Blocks quieter than this are left out of every measurement, in LUFS.
+/// Silence measures as exactly this.
+const ABSOLUTE_GATE: f64 = -70.0;
+/// Gating block step of BS.1770, in milliseconds. Momentary blocks are four
+/// steps long and short-term blocks thirty.
+const STEP_MS: usize = 100;
+const MOMENTARY_STEPS: usize = 4;
+const SHORT_TERM_STEPS: usize = 30;
+/// Short-term blocks overlap by two thirds for the loudness range.
+const SHORT_TERM_HOP: usize = 10;
+/// Taps per phase of the true-peak interpolation filter.
+const PEAK_TAPS: usize = 12;
+/// Samples decoded between checks for cancellation.
+const CANCEL_CHECK: usize = 1 << 16;
+/// Upper bound on decoding threads.
+const MAX_WORKERS: usize = 8;
+/// Minimum time between two progress events.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+const REPLAYGAIN_KEYS: [ItemKey; 4] = [
+    ItemKey::ReplayGainTrackGain,
+    ItemKey::ReplayGainTrackPeak,
+    ItemKey::ReplayGainAlbumGain,
+    ItemKey::ReplayGainAlbumPeak,
+];
+const R128_TRACK_GAIN: &str = "R128_TRACK_GAIN";
+const R128_ALBUM_GAIN: &str = "R128_ALBUM_GAIN";
+
+/// An EBU R128 measurement of a track or a whole album.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Loudness {
+    /// Integrated loudness in LUFS.
+    pub integrated: f64,
+    /// Highest true peak in dBTP, from 4x oversampling.
+    pub true_peak: f64,
+    /// Loudness range in LU, as defined by EBU Tech 3342.
+    pub range: f64,
+}
+
+impl Loudness {
+    /// Gain in dB that brings this to the ReplayGain 2.0 reference.
+    pub fn replay_gain(&self) -> f64 {
+        REPLAYGAIN_REFERENCE - self.integrated
+    }
+
+    /// The true peak as a sample value, where 1.0 is full scale.
+    pub fn peak(&self) -> f64 {
+        10f64.powf(self.true_peak / 20.0)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TrackLoudness {
+    pub track_id: i64,
+    pub track: Loudness,
+    /// The measurement of the track's album as a whole, from those of its
+    /// tracks that could be decoded.
+    pub album: Option<Loudness>,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LoudnessOptions {
+    /// Measure every track again, not only those without a measurement.
+    pub reanalyze: bool,
+    /// Write ReplayGain tags, or `R128_*` gains for Opus, into the files.
+    pub write_tags: bool,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct LoudnessProgress {
+    pub done: u32,
+    pub total: u32,
+    pub current_path: PathBuf,
+}
+
+/// Outcome of a loudness analysis run.
+#[derive(Debug, Default, Clone)]
+pub struct LoudnessSummary {
+    pub tracks: u32,
+    pub albums: u32,
+    pub failed: u32,
+    /// Tracks in a format there is no decoder for, such as AIFF or APE,
+    /// which are left unmeasured.
+    pub skipped: u32,
+    pub tags_written: u32,
+    /// The run was stopped early. The album it was on is left unmeasured.
+    pub cancelled: bool,
+}
+
+#[derive(Debug, Clone)]
+pub enum LoudnessEvent {
+    Progress(LoudnessProgress),
+    Finished(LoudnessSummary),
+}
+
+/// A loudness analysis running in the background.
+pub struct LoudnessJob {
+    cancel: Arc<AtomicBool>,
+    events: Receiver<LoudnessEvent>,
+    result: Receiver<Result<LoudnessSummary>>,
+}
+
+impl LoudnessJob {
+    /// Progress and completion events. The channel closes when the run ends,
+    /// including when it fails, in which case `wait` returns the error.
+    pub fn events(&self) -> &Receiver<LoudnessEvent> {
+        &self.events
+    }
+
+    pub fn cancel(&self) {
+        self.cancel.store(true, Ordering::Relaxed);
+    }
+
+    pub fn wait(self) -> Result<LoudnessSummary> {
+        self.result.recv().map_err(|_| anyhow!("Loudness analysis ended without a result"))?
+    }
+}
+
+/// Tracks of one album still to be measured. Tracks without an album are
+/// grouped under `None`, but never measured as an album. `skipped` are the
+/// album's tracks that cannot be decoded.
+struct PendingAlbum {
+    album_id: Option<i64>,
+    tracks: Vec<PendingTrack>,
+    skipped: Vec<i64>,
+}
+
+struct PendingTrack {
+    id: i64,
+    path: PathBuf,
+    start_ms: Option<u32>,
+    end_ms: Option<u32>,
+}
+
+impl LibraryService {
+    /// Measures the loudness of every track not measured yet, decoding on a
+    /// background thread, and the loudness of their albums. Albums are
+    /// stored one at a time, so reads see results as they come in and only
+    /// the album being measured is lost to a cancel.
+    pub fn analyze_loudness(&self, options: LoudnessOptions) -> Result<LoudnessJob> {
+        let cancel = Arc::new(AtomicBool::new(false));
+        let (events_tx, events) = mpsc::channel();
+        let (result_tx, result) = mpsc::sync_channel(1);
+
+        let service = self.clone();
+        let job_cancel = cancel.clone();
+        std::thread::Builder::new()
+            .name("loudness".into())
+            .spawn(move || {
+                let summary = service.run_loudness(options, &job_cancel, &mut |event| {
+                    let _ = events_tx.send(event);
+                });
+                let _ = result_tx.send(summary);
+            })?;
+
+        Ok(LoudnessJob { cancel, events, result })
+    }
+
+    fn run_loudness(
+        &self,
+        options: LoudnessOptions,
+        cancel: &AtomicBool,
+        on_event: &mut dyn FnMut(LoudnessEvent),
+    ) -> Result<LoudnessSummary> {
+        let albums = self.read(move |library| library.loudness_pending(options.reanalyze))?;
+        let mut summary = LoudnessSummary::default();
+        let mut progress = LoudnessProgress {
+            total: albums.iter().map(|album| album.tracks.len() as u32).sum(),
+            ..Default::default()
+        };
+        let mut last_progress = Instant::now();
+        on_event(LoudnessEvent::Progress(progress.clone()));
+
+        for album in albums {
+            if cancel.load(Ordering::Relaxed) {
+                summary.cancelled = true;
+                break;
+            }
+            let measured = measure_album(&album.tracks, cancel, &mut |track| {
+                progress.done += 1;
+                progress.current_path = track.path.clone();
+                if last_progress.elapsed() >= PROGRESS_INTERVAL {
+                    on_event(LoudnessEvent::Progress(progress.clone()));
+                    last_progress = Instant::now();
+                }
+            });
+            if cancel.load(Ordering::Relaxed) {
+                summary.cancelled = true;
+                break;
+            }
+
+            let mut tracks = Vec::new();
+            // Marked like a failure so they are not tried again, but counted apart.
+            let mut failed = album.skipped.clone();
+            summary.skipped += album.skipped.len() as u32;
+            let mut measurements = Vec::new();
+            for (track, measured) in album.tracks.iter().zip(measured) {
+                match measured {
+                    Ok(measurement) => {
+                        tracks.push((track.id, measurement.loudness()));
+                        measurements.push(measurement);
+                    }
+                    Err(e) => {
+                        log::warn!("Failed to measure loudness of {:?}: {}", track.path, e);
+                        failed.push(track.id);
+                    }
+                }
+            }
+            let album_loudness = (album.album_id.is_some() && !measurements.is_empty())
+                .then(|| Measurement::combine(&measurements));
+            summary.tracks += tracks.len() as u32;
+            summary.failed += (failed.len() - album.skipped.len()) as u32;
+            summary.albums += album_loudness.is_some() as u32;
+
+            let album_id = album.album_id;
+            summary.tags_written += self.write(move |library| {
+                library.store_loudness(album_id, &tracks, &failed, album_loudness)?;
+                let mut written = 0;
+                if options.write_tags {
+                    for (id, _) in &tracks {
+                        match library.write_loudness_tags(*id) {
+                            Ok(true) => written += 1,
+                            Ok(false) => {}
+                            Err(e) => log::warn!("Failed to write loudness tags of track {}: {}", id, e),
+                        }
+                    }
+                }
+                Ok(written)
+            })?;
+        }
+
+        on_event(LoudnessEvent::Progress(progress));
+        on_event(LoudnessEvent::Finished(summary.clone()));
+        Ok(summary)
+    }
+}
+
+/// Measures `tracks` on up to `MAX_WORKERS` threads, calling `on_done` for
+/// each as it finishes. Results are in the order of `tracks`.
+fn measure_album(
+    tracks: &[PendingTrack],
+    cancel: &AtomicBool,
+    on_done: &mut dyn FnMut(&PendingTrack),
+) -> Vec<Result<Measurement>> {
+    let workers = std::thread::available_parallelism()
+        .map_or(1, |n| n.get())
+        .min(MAX_WORKERS)
+        .min(tracks.len())
+        .max(1);
+    let next = AtomicUsize::new(0);
+    let (tx, rx) = mpsc::channel();
+
+    let mut results: Vec<Option<Result<Measurement>>> = tracks.iter().map(|_| None).collect();
+    std::thread::scope(|scope| {
+        for _ in 0..workers {
+            let tx = tx.clone();
+            let next = &next;
+            scope.spawn(move || loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(track) = tracks.get(i) else { break };
+                let measured = Measurement::measure(&track.path, track.start_ms, track.end_ms, cancel);
+                if tx.send((i, measured)).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(tx);
+        for (i, measured) in rx {
+            on_done(&tracks[i]);
+            results[i] = Some(measured);
+        }
+    });
+    results
+        .into_iter()
+        .map(|result| result.unwrap_or_else(|| Err(anyhow!("Not measured"))))
+        .collect()
+}
+
+impl LibraryManager {
+    pub fn get_loudness(&self, track_id: i64) -> Result<Option<TrackLoudness>> {
+        let row = self.conn.query_row(
+            "SELECT t.loudness, t.true_peak, t.loudness_range,
+                    al.loudness, al.true_peak, al.loudness_range
+             FROM tracks t
+             LEFT JOIN albums al ON t.album_id = al.id
+             WHERE t.id = ?1",
+            params![track_id],
+            |row| {
+                let loudness = |i: usize| -> rusqlite::Result<Option<Loudness>> {
+                    let (integrated, true_peak, range): (Option<f64>, Option<f64>, Option<f64>) =
+                        (row.get(i)?, row.get(i + 1)?, row.get(i + 2)?);
+                    Ok(integrated.map(|integrated| Loudness {
+                        integrated,
+                        true_peak: true_peak.unwrap_or(0.0),
+                        range: range.unwrap_or(0.0),
+                    }))
+                };
+                Ok((loudness(0)?, loudness(3)?))
+            },
+        ).optional()?;
+
+        let Some((track, album)) = row else {
+            bail!("Track {} does not exist", track_id);
+        };
+        Ok(track.map(|track| TrackLoudness { track_id, track, album }))
+    }
+
+    /// Saves the loudness of the file behind the track into its primary tag:
+    /// ReplayGain 2.0 gains and peaks, or for Opus the `R128_*` gains of RFC
+    /// 7845. Returns whether anything was written; tracks cut from a file by
+    /// a CUE sheet keep theirs in the library only.
+    pub fn write_loudness_tags(&self, track_id: i64) -> Result<bool> {
+        let (path, start_ms): (String, Option<u32>) = self.conn.query_row(
+            "SELECT path, start_ms FROM tracks WHERE id = ?1",
+            params![track_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        ).optional()?
+            .with_context(|| format!("Track {} does not exist", track_id))?;
+        if start_ms.is_some() {
+            return Ok(false);
+        }
+        let Some(loudness) = self.get_loudness(track_id)? else {
+            bail!("Track {} has not been analysed", track_id);
+        };
+
+        let path = Path::new(&path);
+        let mut tagged_file = Probe::open(path)?.guess_file_type()?.read()?;
+        let file_type = tagged_file.file_type();
+        let tag_type = tagged_file.primary_tag_type();
+        if tagged_file.tag(tag_type).is_none() {
+            tagged_file.insert_tag(Tag::new(tag_type));
+        }
+        let Some(tag) = tagged_file.tag_mut(tag_type) else {
+            bail!("{:?} cannot hold a {:?} tag", path, tag_type);
+        };
+        if file_type == FileType::Opus {
+            write_r128(tag, &loudness);
+        } else {
+            write_replay_gain(tag, &loudness);
+        }
+        tag.save_to_path(path, WriteOptions::default())
+            .with_context(|| format!("Failed to write tags to {:?}", path))?;
+
+        // Our own write is not a change the next scan needs to pick up.
+        let (mtime, size) = file_stamp(path)?;
+        self.conn.execute(
+            "UPDATE tracks SET mtime = ?1, size = ?2 WHERE id = ?3",
+            params![mtime, size, track_id],
+        )?;
+        Ok(true)
+    }
+
+    /// Online tracks to measure, by album, in album and file order. An album
+    /// is measured again in full when any of its tracks has no measurement,
+    /// so its album value takes the new track in. Tracks that failed to
+    /// decode are not tried again until the file changes, and tracks in
+    /// formats without a decoder are set aside as skipped.
+    fn loudness_pending(&self, reanalyze: bool) -> Result<Vec<PendingAlbum>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT t.id, t.path, t.start_ms, t.end_ms, t.album_id
+             FROM tracks t
+             WHERE t.offline = 0 AND (
+                 ?1
+                 OR (t.loudness IS NULL AND NOT t.loudness_failed)
+                 OR t.album_id IN (
+                     SELECT album_id FROM tracks
+                     WHERE loudness IS NULL AND NOT loudness_failed AND offline = 0
+                 )
+             )
+             ORDER BY t.album_id IS NULL, t.album_id, t.path, t.start_ms",
+        )?;
+        let rows = stmt.query_map(params![reanalyze], |row| {
+            let track = PendingTrack {
+                id: row.get(0)?,
+                path: PathBuf::from(row.get::<_, String>(1)?),
+                start_ms: row.get(2)?,
+                end_ms: row.get(3)?,
+            };
+            Ok((row.get::<_, Option<i64>>(4)?, track))
+        })?;
+
+        let mut albums: Vec<PendingAlbum> = Vec::new();
+        for row in rows {
+            let (album_id, track) = row?;
+            let album = match albums.last_mut() {
+                Some(album) if album_id.is_some() && album.album_id == album_id => album,
+                _ => {
+                    albums.push(PendingAlbum { album_id, tracks: Vec::new(), skipped: Vec::new() });
+                    albums.last_mut().unwrap()
+                }
+            };
+            if is_decodable(&track.path) {
+                album.tracks.push(track);
+            } else {
+                album.skipped.push(track.id);
+            }
+        }
+        Ok(albums)
+    }
+
+    fn store_loudness(
+        &self,
+        album_id: Option<i64>,
+        tracks: &[(i64, Loudness)],
+        failed: &[i64],
+        album: Option<Loudness>,
+    ) -> Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        {
+            let mut stmt = tx.prepare(
+                "UPDATE tracks SET loudness = ?1, true_peak = ?2, loudness_range = ?3, loudness_failed = 0
+                 WHERE id = ?4",
+            )?;
+            for (id, loudness) in tracks {
+                stmt.execute(params![loudness.integrated, loudness.true_peak, loudness.range, id])?;
+            }
+            let mut stmt = tx.prepare(
+                "UPDATE tracks SET loudness = NULL, true_peak = NULL, loudness_range = NULL, loudness_failed = 1
+                 WHERE id = ?1",
+            )?;
+            for id in failed {
+                stmt.execute(params![id])?;
+            }
+        }
+        if let Some(album_id) = album_id {
+            tx.execute(
+                "UPDATE albums SET loudness = ?1, true_peak = ?2, loudness_range = ?3 WHERE id = ?4",
+                params![
+                    album.map(|album| album.integrated),
+                    album.map(|album| album.true_peak),
+                    album.map(|album| album.range),
+                    album_id,
+                ],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+fn write_replay_gain(tag: &mut Tag, loudness: &TrackLoudness) {
+    let mut set = |key: ItemKey, value: Option<String>| match value {
+        Some(value) => {
+            tag.insert_text(key, value);
+        }
+        None => tag.remove_key(&key),
+    };
+    let gain = |loudness: &Loudness| format!("{:+.2} dB", loudness.replay_gain());
+    let peak = |loudness: &Loudness| format!("{:.6}", loudness.peak());
+    set(ItemKey::ReplayGainTrackGain, Some(gain(&loudness.track)));
+    set(ItemKey::ReplayGainTrackPeak, Some(peak(&loudness.track)));
+    set(ItemKey::ReplayGainAlbumGain, loudness.album.as_ref().map(gain));
+    set(ItemKey::ReplayGainAlbumPeak, loudness.album.as_ref().map(peak));
+}
+
+/// RFC 7845 gains are Q7.8 fixed point dB, relative to -23 LUFS, applied on
+/// top of the output gain in the Opus header. ReplayGain fields must not be
+/// used in Opus files, so any are removed.
+fn write_r128(tag: &mut Tag, loudness: &TrackLoudness) {
+    let q78 = |loudness: &Loudness| {
+        let gain = ((R128_REFERENCE - loudness.integrated) * 256.0).round();
+        gain.clamp(i16::MIN as f64, i16::MAX as f64).to_string()
+    };
+    for key in &REPLAYGAIN_KEYS {
+        tag.remove_key(key);
+    }
+    tag.insert_unchecked(TagItem::new(
+        ItemKey::Unknown(R128_TRACK_GAIN.to_string()),
+        ItemValue::Text(q78(&loudness.track)),
+    ));
+    let album_key = ItemKey::Unknown(R128_ALBUM_GAIN.to_string());
+    match &loudness.album {
+        Some(album) => tag.insert_unchecked(TagItem::new(album_key, ItemValue::Text(q78(album)))),
+        None => tag.remove_key(&album_key),
+    }
+}
+
+/// What is kept of a decoded track: the mean square energy of every gating
+/// block, and the highest true peak. Albums are measured by pooling the
+/// blocks of their tracks, as if played back to back.
+struct Measurement {
+    momentary: Vec<f64>,
+    short_term: Vec<f64>,
+    peak: f64,
+}
+
+impl Measurement {
+    fn measure(path: &Path, start_ms: Option<u32>, end_ms: Option<u32>, cancel: &AtomicBool) -> Result<Self> {
+        let (channels, rate, samples) = decode_range(path, start_ms, end_ms)?;
+        if channels == 0 || rate == 0 {
+            bail!("{:?} has no audio", path);
+        }
+        let mut analyzer = Analyzer::new(channels as usize, rate);
+        for (i, sample) in samples.enumerate() {
+            if i % CANCEL_CHECK == 0 && cancel.load(Ordering::Relaxed) {
+                bail!("Cancelled");
+            }
+            analyzer.push(sample as f64 / 32768.0);
+        }
+        Ok(analyzer.finish())
+    }
+
+    fn loudness(&self) -> Loudness {
+        Measurement::combine(std::slice::from_ref(self))
+    }
+
+    fn combine(measurements: &[Measurement]) -> Loudness {
+        let momentary: Vec<f64> = measurements.iter().flat_map(|m| m.momentary.iter().copied()).collect();
+        let short_term: Vec<f64> = measurements.iter().flat_map(|m| m.short_term.iter().copied()).collect();
+        let peak = measurements.iter().map(|m| m.peak).fold(0.0, f64::max);
+        Loudness {
+            integrated: integrated_loudness(&momentary),
+            // Digital silence has no peak in dB; put it far below anything real.
+            true_peak: 20.0 * peak.max(1e-10).log10(),
+            range: loudness_range(&short_term),
+        }
+    }
+}
+
+fn lufs(energy: f64) -> f64 {
+    -0.691 + 10.0 * energy.log10()
+}
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+/// BS.1770-4 gating: blocks under the absolute gate are dropped, then
+/// those more than 10 LU below the loudness of what is left.
+fn integrated_loudness(blocks: &[f64]) -> f64 {
+    let audible: Vec<f64> = blocks.iter().copied().filter(|&z| lufs(z) > ABSOLUTE_GATE).collect();
+    if audible.is_empty() {
+        return ABSOLUTE_GATE;
+    }
+    let gate = lufs(mean(&audible)) - 10.0;
+    let gated: Vec<f64> = audible.into_iter().filter(|&z| lufs(z) > gate).collect();
+    lufs(mean(&gated))
+}
+
+/// EBU Tech 3342: the spread between the 10th and 95th percentiles of the
+/// short-term loudness, gated like integrated loudness but at -20 LU.
+fn loudness_range(blocks: &[f64]) -> f64 {
+    let audible: Vec<f64> = blocks.iter().copied().filter(|&z| lufs(z) > ABSOLUTE_GATE).collect();
+    if audible.is_empty() {
+        return 0.0;
+    }
+    let gate = lufs(mean(&audible)) - 20.0;
+    let mut levels: Vec<f64> = audible.into_iter().map(lufs).filter(|&l| l > gate).collect();
+    levels.sort_by(f64::total_cmp);
+    let percentile = |p: f64| levels[((levels.len() - 1) as f64 * p).round() as usize];
+    percentile(0.95) - percentile(0.10)
+}
+
+/// Runs interleaved samples through K-weighting and the true-peak meter,
+/// summing weighted energy per 100 ms step.
+struct Analyzer {
+    channels: usize,
+    weights: Vec<f64>,
+    filters: Vec<KWeighting>,
+    peak: TruePeak,
+    step_samples: usize,
+    /// Samples of the current step so far, across channels.
+    samples: usize,
+    energy: f64,
+    steps: Vec<f64>,
+}
+
+impl Analyzer {
+    fn new(channels: usize, rate: u32) -> Self {
+        Self {
+            channels,
+            weights: channel_weights(channels),
+            filters: (0..channels).map(|_| KWeighting::new(rate as f64)).collect(),
+            peak: TruePeak::new(channels, rate),
+            step_samples: (rate as usize * STEP_MS / 1000).max(1) * channels,
+            samples: 0,
+            energy: 0.0,
+            steps: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, sample: f64) {
+        let channel = self.samples % self.channels;
+        self.peak.push(channel, sample);
+        let weighted = self.filters[channel].process(sample);
+        self.energy += self.weights[channel] * weighted * weighted;
+        self.samples += 1;
+        if self.samples == self.step_samples {
+            self.steps.push(self.energy / (self.step_samples / self.channels) as f64);
+            self.samples = 0;
+            self.energy = 0.0;
+        }
+    }
+
+    /// Blocks from whole steps only; a last partial step is dropped, as a
+    /// block that is not complete is not measured.
+    fn finish(self) -> Measurement {
+        let blocks = |len: usize, hop: usize| -> Vec<f64> {
+            (0..self.steps.len().saturating_sub(len - 1))
+                .step_by(hop)
+                .map(|i| mean(&self.steps[i..i + len]))
+                .collect()
+        };
+        Measurement {
+            momentary: blocks(MOMENTARY_STEPS, 1),
+            short_term: blocks(SHORT_TERM_STEPS, SHORT_TERM_HOP),
+            peak: self.peak.peak,
+        }
+    }
+}
+
+/// BS.1770 weights by channel position: surround channels count for 1.41
+/// and the LFE of 5.1 not at all. Other layouts weigh every channel alike.
+fn channel_weights(channels: usize) -> Vec<f64> {
+    match channels {
+        5 => vec![1.0, 1.0, 1.0, 1.41, 1.41],
+        6 => vec![1.0, 1.0, 1.0, 0.0, 1.41, 1.41],
+        n => vec![1.0; n],
+    }
+}
+
+/// Biquad in transposed direct form II.
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    z: [f64; 2],
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+        Self { b, a, z: [0.0; 2] }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.z[0];
+        self.z[0] = self.b[1] * x - self.a[0] * y + self.z[1];
+        self.z[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
+
+/// The K-weighting filter of BS.1770: a high shelf modelling the head, then
+/// a high pass. The reference coefficients are for 48 kHz only, so they are
+/// derived from the analog prototypes for the rate at hand.
+struct KWeighting {
+    shelf: Biquad,
+    high_pass: Biquad,
+}
+
+impl KWeighting {
+    fn new(rate: f64) -> Self {
+        let (f0, gain_db, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+        let k = (PI * f0 / rate).tan();
+        let vh = 10f64.powf(gain_db / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        let a0 = 1.0 + k / q + k * k;
+        let shelf = Biquad::new(
+            [(vh + vb * k / q + k * k) / a0, 2.0 * (k * k - vh) / a0, (vh - vb * k / q + k * k) / a0],
+            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        );
+
+        let (f0, q) = (38.13547087602444, 0.5003270373238773);
+        let k = (PI * f0 / rate).tan();
+        let a0 = 1.0 + k / q + k * k;
+        let high_pass = Biquad::new([1.0, -2.0, 1.0], [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0]);
+        Self { shelf, high_pass }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        self.high_pass.process(self.shelf.process(x))
+    }
+}
+
+/// True peak by oversampling to at least 192 kHz, as BS.1770 Annex 2
+/// describes, with a polyphase windowed-sinc interpolator.
+struct TruePeak {
+    factor: usize,
+    /// Filter taps by phase.
+    phases: Vec<[f64; PEAK_TAPS]>,
+    /// The last `PEAK_TAPS` samples of each channel, newest first.
+    history: Vec<[f64; PEAK_TAPS]>,
+    peak: f64,
+}
+
+impl TruePeak {
+    fn new(channels: usize, rate: u32) -> Self {
+        let factor = match rate {
+            0..=95_999 => 4,
+            96_000..=191_999 => 2,
+            _ => 1,
+        };
+        let len = factor * PEAK_TAPS;
+        let center = (len - 1) as f64 / 2.0;
+        let tap = |n: usize| {
+            let x = (n as f64 - center) / factor as f64;
+            let sinc = if x == 0.0 { 1.0 } else { (PI * x).sin() / (PI * x) };
+            let hann = 0.5 - 0.5 * (2.0 * PI * (n + 1) as f64 / (len + 1) as f64).cos();
+            sinc * hann
+        };
+        let phases = (0..factor)
+            .map(|phase| {
+                let mut taps = [0.0; PEAK_TAPS];
+                for (k, t) in taps.iter_mut().enumerate() {
+                    *t = tap(k * factor + phase);
+                }
+                // Unity gain per phase, so a constant signal keeps its level.
+                let sum: f64 = taps.iter().sum();
+                taps.iter_mut().for_each(|t| *t /= sum);
+                taps
+            })
+            .collect();
+        Self { factor, phases, history: vec![[0.0; PEAK_TAPS]; channels], peak: 0.0 }
+    }
+
+    fn push(&mut self, channel: usize, sample: f64) {
+        self.peak = self.peak.max(sample.abs());
+        if self.factor == 1 {
+            return;
+        }
+        let history = &mut self.history[channel];
+        history.copy_within(..PEAK_TAPS - 1, 1);
+        history[0] = sample;
+        for taps in &self.phases {
+            let value: f64 = taps.iter().zip(history.iter()).map(|(t, x)| t * x).sum();
+            self.peak = self.peak.max(value.abs());
+        }
+    }
+}
+
+impl mlua::UserData for Loudness {
+    fn add_fields<'lua, F: mlua::UserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_method_get("integrated", |_lua, this| Ok(this.integrated));
+        fields.add_field_method_get("true_peak", |_lua, this| Ok(this.true_peak));
+        fields.add_field_method_get("range", |_lua, this| Ok(this.range));
+        fields.add_field_method_get("replay_gain", |_lua, this| Ok(this.replay_gain()));
+        fields.add_field_method_get("peak", |_lua, this| Ok(this.peak()));
+    }
+}
+
+impl mlua::UserData for TrackLoudness {
+    fn add_fields<'lua, F: mlua::UserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_method_get("track_id", |_lua, this| Ok(this.track_id));
+        fields.add_field_method_get("track", |_lua, this| Ok(this.track));
+        fields.add_field_method_get("album", |_lua, this| Ok(this.album));
+    }
+}
+
+impl mlua::UserData for LoudnessProgress {
+    fn add_fields<'lua, F: mlua::UserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_method_get("done", |_lua, this| Ok(this.done));
+        fields.add_field_method_get("total", |_lua, this| Ok(this.total));
+        fields.add_field_method_get("current_path", |_lua, this| {
+            Ok(this.current_path.to_string_lossy().into_owned())
+        });
+    }
+}
+
+impl mlua::UserData for LoudnessSummary {
+    fn add_fields<'lua, F: mlua::UserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_method_get("tracks", |_lua, this| Ok(this.tracks));
+        fields.add_field_method_get("albums", |_lua, this| Ok(this.albums));
+        fields.add_field_method_get("failed", |_lua, this| Ok(this.failed));
+        fields.add_field_method_get("skipped", |_lua, this| Ok(this.skipped));
+        fields.add_field_method_get("tags_written", |_lua, this| Ok(this.tags_written));
+        fields.add_field_method_get("cancelled", |_lua, this| Ok(this.cancelled));
+    }
+}
@@ -0,0 +1,486 @@
+use crate::playlist_files::decode_text;
+use crate::scan::is_audio_file;
+use crate::LibraryManager;
+use anyhow::Result;
+use lofty::config::ParseOptions;
+use lofty::file::{AudioFile, FileType};
+use lofty::id3::v2::{Frame, FrameFlags, Id3v2Tag, SyncTextContentType, SynchronizedTextFrame, TimestampFormat};
+use lofty::iff::aiff::AiffFile;
+use lofty::iff::wav::WavFile;
+use lofty::mpeg::MpegFile;
+use lofty::tag::{ItemKey, Tag};
+use rusqlite::{params, OptionalExtension};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+/// Lyrics read from an `.lrc` file beside the audio file.
+pub const LYRICS_SIDECAR: &str = "lrc";
+/// Lyrics read from the file's tags, synced (`SYLT`) or not (`USLT`, `LYRICS`).
+pub const LYRICS_TAG: &str = "tag";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LyricLine {
+    /// When the line starts, from the start of the track. `None` for
+    /// lyrics that are not synced.
+    pub time_ms: Option<u32>,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lyrics {
+    /// Where the lyrics came from: `LYRICS_SIDECAR`, `LYRICS_TAG`, or the
+    /// name of the script provider that found them.
+    pub source: String,
+    /// Every line has a time, in order.
+    pub synced: bool,
+    pub lines: Vec<LyricLine>,
+}
+
+impl Lyrics {
+    /// Reads LRC, as in sidecar files and many `LYRICS` tags, or plain text
+    /// when no line has a timestamp. A line may carry several timestamps
+    /// for a chorus that repeats; word timings (`<mm:ss.xx>`) are dropped,
+    /// and `[offset:]` is applied. `None` when there is no text at all.
+    pub fn parse(source: &str, text: &str) -> Option<Self> {
+        let mut offset = 0i64;
+        let mut synced = Vec::new();
+        let mut plain = Vec::new();
+
+        for line in text.lines() {
+            let mut rest = line.trim();
+            let mut times = Vec::new();
+            while let Some((tag, after)) = rest.strip_prefix('[').and_then(|r| r.split_once(']')) {
+                match parse_timestamp(tag) {
+                    Some(ms) => times.push(ms),
+                    None => {
+                        if let Some(value) = tag.strip_prefix("offset:") {
+                            offset = value.trim().parse().unwrap_or(0);
+                        } else if !tag.contains(':') {
+                            break;
+                        }
+                    }
+                }
+                rest = after.trim_start();
+            }
+            let text = strip_word_times(rest);
+
+            if times.is_empty() {
+                // Metadata tags such as `[ar:Artist]` are not lyrics.
+                if !(line.trim_start().starts_with('[') && rest.is_empty()) {
+                    plain.push(text);
+                }
+            } else {
+                synced.extend(times.into_iter().map(|ms| (ms, text.clone())));
+            }
+        }
+
+        if !synced.is_empty() {
+            // A positive offset shows the lyrics sooner.
+            let mut lines: Vec<LyricLine> = synced
+                .into_iter()
+                .map(|(ms, text)| {
+                    let ms = (ms as i64).saturating_sub(offset).clamp(0, u32::MAX as i64);
+                    LyricLine { time_ms: Some(ms as u32), text }
+                })
+                .collect();
+            lines.sort_by_key(|line| line.time_ms);
+            return Some(Self { source: source.to_string(), synced: true, lines });
+        }
+
+        let start = plain.iter().position(|line| !line.trim().is_empty())?;
+        let end = plain.iter().rposition(|line| !line.trim().is_empty())? + 1;
+        let lines = plain[start..end]
+            .iter()
+            .map(|text| LyricLine { time_ms: None, text: text.clone() })
+            .collect();
+        Some(Self { source: source.to_string(), synced: false, lines })
+    }
+
+    /// The lines, one per line, without timestamps.
+    pub fn text(&self) -> String {
+        self.lines.iter().map(|line| line.text.as_str()).collect::<Vec<_>>().join("\n")
+    }
+
+    /// The line playing `position_ms` into the track: the last to have
+    /// started. `None` before the first line, or when not synced.
+    pub fn line_at(&self, position_ms: u32) -> Option<usize> {
+        if !self.synced {
+            return None;
+        }
+        let next = self.lines.partition_point(|line| line.time_ms.is_some_and(|ms| ms <= position_ms));
+        next.checked_sub(1)
+    }
+
+    /// The part of a whole file's lyrics that falls within a CUE sheet track,
+    /// timed from the track's start. Lyrics that are not synced cannot be
+    /// split and give `None`.
+    pub(crate) fn cut(&self, start_ms: u32, end_ms: Option<u32>) -> Option<Self> {
+        if !self.synced {
+            return None;
+        }
+        let lines: Vec<LyricLine> = self
+            .lines
+            .iter()
+            .filter_map(|line| {
+                let ms = line.time_ms?;
+                (ms >= start_ms && end_ms.is_none_or(|end| ms < end))
+                    .then(|| LyricLine { time_ms: Some(ms - start_ms), text: line.text.clone() })
+            })
+            .collect();
+        (!lines.is_empty()).then(|| Self { source: self.source.clone(), synced: true, lines })
+    }
+}
+
+/// `mm:ss`, `mm:ss.xx` or `mm:ss:xx` in milliseconds.
+fn parse_timestamp(tag: &str) -> Option<u32> {
+    let (minutes, rest) = tag.split_once(':')?;
+    let minutes: u32 = minutes.trim().parse().ok()?;
+    let (seconds, fraction) = match rest.split_once(['.', ':']) {
+        Some((seconds, fraction)) => (seconds, fraction),
+        None => (rest, ""),
+    };
+    let seconds: u32 = seconds.trim().parse().ok()?;
+    if seconds >= 60 || !fraction.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    // Hundredths as usual, but any number of digits.
+    let fraction_ms = format!("{:0<3}", fraction).get(..3)?.parse::<u32>().ok()?;
+    minutes.checked_mul(60)?.checked_add(seconds)?.checked_mul(1000)?.checked_add(fraction_ms)
+}
+
+/// Removes the word timings of enhanced LRC.
+fn strip_word_times(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(open) = rest.find('<') {
+        match rest[open + 1..].find('>') {
+            Some(close) if parse_timestamp(&rest[open + 1..open + 1 + close]).is_some() => {
+                stripped.push_str(&rest[..open]);
+                rest = &rest[open + close + 2..];
+            }
+            _ => {
+                stripped.push_str(&rest[..=open]);
+                rest = &rest[open + 1..];
+            }
+        }
+    }
+    stripped.push_str(rest);
+    stripped.trim().to_string()
+}
+
+/// The LRC file that goes with `audio`.
+pub(crate) fn lyrics_sidecar(audio: &Path) -> PathBuf {
+    audio.with_extension(LYRICS_SIDECAR)
+}
+
+pub(crate) fn is_lyrics_sidecar(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case(LYRICS_SIDECAR))
+}
+
+/// Audio files beside the LRC file at `path` that it belongs to.
+pub(crate) fn sidecar_audio(path: &Path) -> Vec<PathBuf> {
+    let (Some(dir), Some(stem)) = (path.parent(), path.file_stem()) else {
+        return Vec::new();
+    };
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|audio| audio.file_stem() == Some(stem) && is_audio_file(audio))
+        .collect()
+}
+
+/// The lyrics for the file at `path`: an LRC file beside it, a `SYLT`
+/// frame, or the lyrics tag, in that order. Synced lyrics from any of them
+/// win over plain ones.
+pub(crate) fn read_lyrics(path: &Path, file_type: FileType, tag: Option<&Tag>) -> Option<Lyrics> {
+    let sidecar = std::fs::read(lyrics_sidecar(path))
+        .ok()
+        .and_then(|bytes| Lyrics::parse(LYRICS_SIDECAR, &decode_text(&bytes)));
+    let synced_tag = read_sylt(path, file_type);
+    let tagged = tag
+        .and_then(|t| t.get_string(&ItemKey::Lyrics))
+        .and_then(|text| Lyrics::parse(LYRICS_TAG, text));
+
+    let found = [sidecar, synced_tag, tagged];
+    match found.iter().flatten().find(|lyrics| lyrics.synced) {
+        Some(synced) => Some(synced.clone()),
+        None => found.into_iter().flatten().next(),
+    }
+}
+
+/// Lyrics from an ID3v2 `SYLT` frame. The generic tag leaves these out, so
+/// the ID3v2 tag of the formats that carry one is read on its own. Only
+/// millisecond timestamps are used; MPEG frame counts are rare.
+fn read_sylt(path: &Path, file_type: FileType) -> Option<Lyrics> {
+    let mut file = File::open(path).ok()?;
+    let options = ParseOptions::new().read_properties(false);
+    let id3v2: Option<Id3v2Tag> = match file_type {
+        FileType::Mpeg => MpegFile::read_from(&mut file, options).ok()?.id3v2().cloned(),
+        FileType::Wav => WavFile::read_from(&mut file, options).ok()?.id3v2().cloned(),
+        FileType::Aiff => AiffFile::read_from(&mut file, options).ok()?.id3v2().cloned(),
+        _ => None,
+    };
+
+    id3v2?.into_iter().find_map(|frame| {
+        let Frame::Binary(binary) = frame else {
+            return None;
+        };
+        if binary.id().as_str() != "SYLT" {
+            return None;
+        }
+        let sylt = SynchronizedTextFrame::parse(&binary.data, FrameFlags::default()).ok()?;
+        if sylt.timestamp_format != TimestampFormat::MS || sylt.content_type != SyncTextContentType::Lyrics {
+            return None;
+        }
+        let mut lines: Vec<LyricLine> = sylt
+            .content
+            .into_iter()
+            .map(|(ms, text)| LyricLine { time_ms: Some(ms), text: text.trim().replace(['\r', '\n'], " ") })
+            .collect();
+        lines.sort_by_key(|line| line.time_ms);
+        (!lines.is_empty()).then(|| Lyrics { source: LYRICS_TAG.to_string(), synced: true, lines })
+    })
+}
+
+/// Timestamps of synced lyrics as stored: little-endian milliseconds, one
+/// per line.
+fn times_to_bytes(lines: &[LyricLine]) -> Vec<u8> {
+    lines.iter().flat_map(|line| line.time_ms.unwrap_or(0).to_le_bytes()).collect()
+}
+
+fn times_from_bytes(bytes: &[u8]) -> Vec<u32> {
+    bytes
+        .chunks_exact(4)
+        .map(|chunk| u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+        .collect()
+}
+
+impl LibraryManager {
+    pub fn get_lyrics(&self, track_id: i64) -> Result<Option<Lyrics>> {
+        let stored: Option<(String, String, Option<Vec<u8>>)> = self.conn.query_row(
+            "SELECT source, text, times FROM lyrics WHERE track_id = ?1",
+            params![track_id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        ).optional()?;
+        let Some((source, text, times)) = stored else {
+            return Ok(None);
+        };
+
+        let times = times.as_deref().map(times_from_bytes);
+        let lines = text
+            .split('\n')
+            .enumerate()
+            .map(|(i, text)| LyricLine {
+                time_ms: times.as_ref().and_then(|times| times.get(i).copied()),
+                text: text.to_string(),
+            })
+            .collect();
+        Ok(Some(Lyrics { source, synced: times.is_some(), lines }))
+    }
+
+    /// Stores lyrics for a track, as found by a script provider. Lyrics read
+    /// from the file on the next scan take their place.
+    pub fn set_lyrics(&self, track_id: i64, lyrics: &Lyrics) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO lyrics (track_id, source, text, times) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT(track_id) DO UPDATE SET
+                source = excluded.source,
+                text = excluded.text,
+                times = excluded.times",
+            params![
+                track_id,
+                lyrics.source,
+                lyrics.text(),
+                lyrics.synced.then(|| times_to_bytes(&lyrics.lines))
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn remove_lyrics(&self, track_id: i64) -> Result<()> {
+        self.conn.execute("DELETE FROM lyrics WHERE track_id = ?1", params![track_id])?;
+        Ok(())
+    }
+
+    /// Lyrics read from the file replace whatever the track had. Without
+    /// any, those read before go, but ones a provider found are kept.
+    pub(crate) fn store_lyrics(&self, track_id: i64, lyrics: Option<&Lyrics>) -> Result<()> {
+        match lyrics {
+            Some(lyrics) => self.set_lyrics(track_id, lyrics),
+            None => {
+                self.conn.execute(
+                    "DELETE FROM lyrics WHERE track_id = ?1 AND source IN (?2, ?3)",
+                    params![track_id, LYRICS_SIDECAR, LYRICS_TAG],
+                )?;
+                Ok(())
+            }
+        }
+    }
+}
+
+impl mlua::UserData for LyricLine {
+    fn add_fields<'lua, F: mlua::UserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_method_get("time_ms", |_lua, this| Ok(this.time_ms));
+        fields.add_field_method_get("text", |_lua, this| Ok(this.text.clone()));
+    }
+}
+
+impl mlua::UserData for Lyrics {
+    fn add_fields<'lua, F: mlua::UserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_method_get("source", |_lua, this| Ok(this.source.clone()));
+        fields.add_field_method_get("synced", |_lua, this| Ok(this.synced));
+        fields.add_field_method_get("lines", |_lua, this| Ok(this.lines.clone()));
+        fields.add_field_method_get("text", |_lua, this| Ok(this.text()));
+    }
+
+    fn add_methods<'lua, M: mlua::UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("line_at", |_lua, this, position_ms: u32| {
+            // Lua counts from 1.
+            Ok(this.line_at(position_ms).map(|i| i + 1))
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn synced(text: &str) -> Vec<(u32, String)> {
+        let lyrics = Lyrics::parse(LYRICS_SIDECAR, text).unwrap();
+        assert!(lyrics.synced);
+        lyrics.lines.into_iter().map(|line| (line.time_ms.unwrap(), line.text)).collect()
+    }
+
+    fn line(ms: u32, text: &str) -> (u32, String) {
+        (ms, text.to_string())
+    }
+
+    #[test]
+    fn parses_timestamps() {
+        assert_eq!(parse_timestamp("00:00"), Some(0));
+        assert_eq!(parse_timestamp("01:02"), Some(62_000));
+        assert_eq!(parse_timestamp("01:02.34"), Some(62_340));
+        assert_eq!(parse_timestamp("01:02:34"), Some(62_340));
+        assert_eq!(parse_timestamp("1:02.5"), Some(62_500));
+        assert_eq!(parse_timestamp("01:02.345"), Some(62_345));
+        assert_eq!(parse_timestamp("01:02.3456"), Some(62_345));
+        assert_eq!(parse_timestamp("125:00.00"), Some(7_500_000));
+        assert_eq!(parse_timestamp(" 01: 02.00"), Some(62_000));
+    }
+
+    #[test]
+    fn rejects_what_is_not_a_timestamp() {
+        for tag in ["", "01", "ar:Artist", "offset:500", "01:60.00", "01:02.x", "-1:00.00", "01:-2", "aa:bb", "01:02.3.4"] {
+            assert_eq!(parse_timestamp(tag), None, "{:?}", tag);
+        }
+        // Past what fits in milliseconds.
+        assert_eq!(parse_timestamp("71582:47.00"), Some(4_294_967_000));
+        assert_eq!(parse_timestamp("71582:48.00"), None);
+        assert_eq!(parse_timestamp("99999999:00.00"), None);
+    }
+
+    #[test]
+    fn parses_synced_lyrics_in_time_order() {
+        let text = "[ti:Song]\n[ar:Artist]\n[length: 03:00]\n\n[00:12.00]First\n[00:05.50] Intro \n[00:20.00]\n[00:30.00]Last";
+        assert_eq!(
+            synced(text),
+            vec![line(5_500, "Intro"), line(12_000, "First"), line(20_000, ""), line(30_000, "Last")]
+        );
+    }
+
+    #[test]
+    fn repeats_lines_with_several_timestamps() {
+        let text = "[00:10.00][01:10.00] [02:10.00]Chorus\n[00:40.00]Verse";
+        assert_eq!(
+            synced(text),
+            vec![line(10_000, "Chorus"), line(40_000, "Verse"), line(70_000, "Chorus"), line(130_000, "Chorus")]
+        );
+    }
+
+    #[test]
+    fn drops_word_timings() {
+        let text = "[00:10.00]<00:10.00>Hello <00:10.50>bright <00:11.00>world<00:11.80>\n\
+                    [00:12.00] <00:12.00> Again <00:12:50>and again";
+        assert_eq!(synced(text), vec![line(10_000, "Hello bright world"), line(12_000, "Again and again")]);
+
+        assert_eq!(strip_word_times("<01:02.03>word"), "word");
+        assert_eq!(strip_word_times("word<1:02>"), "word");
+        assert_eq!(strip_word_times("<00:00.00><00:00.50>a"), "a");
+    }
+
+    #[test]
+    fn keeps_angle_brackets_that_are_not_timings() {
+        assert_eq!(strip_word_times("x < y"), "x < y");
+        assert_eq!(strip_word_times("I <3 you"), "I <3 you");
+        assert_eq!(strip_word_times("<b>bold</b>"), "<b>bold</b>");
+        assert_eq!(strip_word_times("a <00:01.00 b"), "a <00:01.00 b");
+        assert_eq!(strip_word_times("<<00:01.00>a"), "<a");
+        assert_eq!(strip_word_times("a <"), "a <");
+        assert_eq!(strip_word_times("<"), "<");
+        assert_eq!(strip_word_times("<>"), "<>");
+        assert_eq!(strip_word_times("ünï <00:01.00>cödé <ß>"), "ünï cödé <ß>");
+        assert_eq!(synced("[00:01.00]1 < 2 <00:01.50>and 3 > 2"), vec![line(1_000, "1 < 2 and 3 > 2")]);
+    }
+
+    #[test]
+    fn applies_the_offset() {
+        // A positive offset shows the lyrics sooner, but never before the start.
+        let text = "[offset:+500]\n[00:00.20]Zero\n[00:10.00]Ten";
+        assert_eq!(synced(text), vec![line(0, "Zero"), line(9_500, "Ten")]);
+
+        let text = "[00:00.20]Zero\n[00:10.00]Ten\n[offset: -1500]";
+        assert_eq!(synced(text), vec![line(1_700, "Zero"), line(11_500, "Ten")]);
+
+        let text = "[offset:soon]\n[00:10.00]Ten";
+        assert_eq!(synced(text), vec![line(10_000, "Ten")]);
+
+        let text = "[offset:-9223372036854775808]\n[00:10.00]Ten";
+        assert_eq!(synced(text), vec![line(u32::MAX, "Ten")]);
+        let text = "[offset:9223372036854775807]\n[00:10.00]Ten";
+        assert_eq!(synced(text), vec![line(0, "Ten")]);
+    }
+
+    #[test]
+    fn reads_plain_text_without_timestamps() {
+        let lyrics = Lyrics::parse(LYRICS_TAG, "\n\n[ar:Artist]\nFirst line\n\nSecond [verse] line\n[Chorus] sung\n\n").unwrap();
+        assert_eq!(lyrics.source, LYRICS_TAG);
+        assert!(!lyrics.synced);
+        assert!(lyrics.lines.iter().all(|line| line.time_ms.is_none()));
+        assert_eq!(lyrics.text(), "First line\n\nSecond [verse] line\n[Chorus] sung");
+        assert_eq!(lyrics.line_at(1_000), None);
+    }
+
+    #[test]
+    fn gives_nothing_without_text() {
+        assert_eq!(Lyrics::parse(LYRICS_TAG, ""), None);
+        assert_eq!(Lyrics::parse(LYRICS_TAG, " \n\t\n"), None);
+        assert_eq!(Lyrics::parse(LYRICS_TAG, "[ar:Artist]\n[ti:Title]\n"), None);
+    }
+
+    #[test]
+    fn finds_the_line_playing() {
+        let lyrics = Lyrics::parse(LYRICS_SIDECAR, "[00:05.00]One\n[00:10.00]Two\n[00:10.00]Two again\n[00:20.00]Three").unwrap();
+        assert_eq!(lyrics.line_at(0), None);
+        assert_eq!(lyrics.line_at(4_999), None);
+        assert_eq!(lyrics.line_at(5_000), Some(0));
+        assert_eq!(lyrics.line_at(9_999), Some(0));
+        assert_eq!(lyrics.line_at(10_000), Some(2));
+        assert_eq!(lyrics.line_at(u32::MAX), Some(3));
+    }
+
+    #[test]
+    fn cuts_a_cue_track_out_of_the_lyrics() {
+        let lyrics = Lyrics::parse(LYRICS_SIDECAR, "[00:05.00]One\n[01:00.00]Two\n[01:30.00]Three\n[02:00.00]Four").unwrap();
+        let cut = lyrics.cut(60_000, Some(120_000)).unwrap();
+        let lines: Vec<_> = cut.lines.iter().map(|line| (line.time_ms.unwrap(), line.text.as_str())).collect();
+        assert_eq!(lines, vec![(0, "Two"), (30_000, "Three")]);
+        assert_eq!(lyrics.cut(120_000, None).unwrap().lines.len(), 1);
+        assert_eq!(lyrics.cut(10_000, Some(50_000)), None);
+
+        let plain = Lyrics::parse(LYRICS_TAG, "Not synced").unwrap();
+        assert_eq!(plain.cut(0, None), None);
+    }
+}
@@ -0,0 +1,800 @@
+use anyhow::{Context, Result};
+use rusqlite::{Connection, TransactionBehavior};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// One step of the schema history. Each step runs in its own transaction
+/// together with the `user_version` bump, so a failed step leaves the
+/// database at the previous version.
+struct Migration {
+    description: &'static str,
+    apply: fn(&Connection) -> Result<()>,
+}
+
+/// Entry `n` takes a database from version `n` to `n + 1`. Append only:
+/// a step that has shipped must never be edited or reordered.
+const MIGRATIONS: &[Migration] = &[
+    Migration { description: "library schema and search index", apply: baseline },
+    Migration { description: "playlists", apply: playlists },
+    Migration { description: "play history", apply: play_history },
+    Migration { description: "ratings and loved tracks", apply: ratings },
+    Migration { description: "smart playlists", apply: smart_playlists },
+    Migration { description: "album artists and compilations", apply: album_artists },
+    Migration { description: "artist credits and genres", apply: credits_and_genres },
+    Migration { description: "extended track metadata", apply: extended_metadata },
+    Migration { description: "album cover sources", apply: cover_sources },
+    Migration { description: "audio hashes", apply: audio_hashes },
+    Migration { description: "acoustic fingerprints", apply: fingerprints },
+    Migration { description: "library root rules", apply: root_rules },
+    Migration { description: "CUE sheet tracks", apply: cue_tracks },
+    Migration { description: "lyrics", apply: lyrics },
+    Migration { description: "loudness", apply: loudness },
+    Migration { description: "picture stamps", apply: picture_stamps },
+];
+
+/// Schema version written by this build.
+pub const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+#[derive(Debug, thiserror::Error)]
+pub enum SchemaError {
+    #[error(
+        "database schema version {found} is newer than this build supports (version {supported}); \
+         open it with a newer Aurora or point this one at another database"
+    )]
+    TooNew { found: u32, supported: u32 },
+}
+
+/// Brings the database at `db_path` up to `SCHEMA_VERSION`, backing it up
+/// first if it already holds data.
+pub(crate) fn migrate(conn: &mut Connection, db_path: &Path) -> Result<()> {
+    let version = user_version(conn)?;
+    if version > SCHEMA_VERSION {
+        return Err(SchemaError::TooNew { found: version, supported: SCHEMA_VERSION }.into());
+    }
+    if version == SCHEMA_VERSION {
+        return Ok(());
+    }
+
+    if has_tables(conn)? {
+        backup(conn, db_path, version)?;
+    }
+
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let to = from as u32 + 1;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        // Another connection may have migrated while we waited for the write lock.
+        if user_version(&tx)? >= to {
+            continue;
+        }
+
+        log::info!("Migrating database to schema version {}: {}", to, migration.description);
+        (migration.apply)(&tx)
+            .with_context(|| format!("Migration to schema version {} failed", to))?;
+        tx.pragma_update(None, "user_version", to)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+fn user_version(conn: &Connection) -> Result<u32> {
+    Ok(conn.pragma_query_value(None, "user_version", |row| row.get(0))?)
+}
+
+fn has_tables(conn: &Connection) -> Result<bool> {
+    Ok(conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table')",
+        [],
+        |row| row.get(0),
+    )?)
+}
+
+/// Writes a consistent copy of the database next to it, e.g.
+/// `aurora.db.v1-1718000000.bak`.
+fn backup(conn: &Connection, db_path: &Path, version: u32) -> Result<()> {
+    let stamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let mut backup_path = db_path.as_os_str().to_owned();
+    backup_path.push(format!(".v{}-{}.bak", version, stamp));
+
+    conn.execute("VACUUM INTO ?1", [backup_path.to_string_lossy()])
+        .with_context(|| format!("Failed to back up database to {:?}", backup_path))?;
+    log::info!("Backed up database to {:?}", backup_path);
+    Ok(())
+}
+
+/// Adds a column unless it is already there. Only for the baseline step,
+/// which has to cope with databases created before versioning existed.
+fn ensure_column(conn: &Connection, table: &str, column: &str, definition: &str) -> Result<()> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let exists = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .filter_map(|name| name.ok())
+        .any(|name| name == column);
+    if !exists {
+        conn.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+            [],
+        )?;
+    }
+    Ok(())
+}
+
+/// Version 1: the schema as it stood before versioning. Every statement is
+/// idempotent because unversioned databases may already contain any part of it.
+fn baseline(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS artists (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT NOT NULL UNIQUE
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS albums (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            title TEXT NOT NULL,
+            artist_id INTEGER,
+            cover_path TEXT,
+            UNIQUE(title, artist_id),
+            FOREIGN KEY(artist_id) REFERENCES artists(id)
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS tracks (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            path TEXT NOT NULL UNIQUE,
+            title TEXT NOT NULL,
+            artist_id INTEGER,
+            album_id INTEGER,
+            duration INTEGER,
+            track_number INTEGER,
+            year INTEGER,
+            genre TEXT,
+            mtime INTEGER,
+            size INTEGER,
+            scan_generation INTEGER NOT NULL DEFAULT 0,
+            FOREIGN KEY(artist_id) REFERENCES artists(id),
+            FOREIGN KEY(album_id) REFERENCES albums(id)
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS library_roots (
+            path TEXT PRIMARY KEY
+        )",
+        [],
+    )?;
+
+    ensure_column(conn, "tracks", "mtime", "INTEGER")?;
+    ensure_column(conn, "tracks", "size", "INTEGER")?;
+    ensure_column(conn, "tracks", "scan_generation", "INTEGER NOT NULL DEFAULT 0")?;
+
+    let search_index_exists: bool = conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE name = 'tracks_fts')",
+        [],
+        |row| row.get(0),
+    )?;
+
+    conn.execute_batch(
+        "CREATE VIRTUAL TABLE IF NOT EXISTS tracks_fts USING fts5(
+            title, artist, album, genre, path,
+            tokenize = 'unicode61 remove_diacritics 2'
+        );
+
+        CREATE TRIGGER IF NOT EXISTS tracks_fts_insert AFTER INSERT ON tracks BEGIN
+            INSERT INTO tracks_fts (rowid, title, artist, album, genre, path)
+            VALUES (
+                new.id, new.title,
+                (SELECT name FROM artists WHERE id = new.artist_id),
+                (SELECT title FROM albums WHERE id = new.album_id),
+                new.genre, new.path
+            );
+        END;
+
+        CREATE TRIGGER IF NOT EXISTS tracks_fts_delete AFTER DELETE ON tracks BEGIN
+            DELETE FROM tracks_fts WHERE rowid = old.id;
+        END;
+
+        -- Only searchable columns: rescans touch scan_generation on every row.
+        CREATE TRIGGER IF NOT EXISTS tracks_fts_update
+        AFTER UPDATE OF title, artist_id, album_id, genre, path ON tracks BEGIN
+            DELETE FROM tracks_fts WHERE rowid = old.id;
+            INSERT INTO tracks_fts (rowid, title, artist, album, genre, path)
+            VALUES (
+                new.id, new.title,
+                (SELECT name FROM artists WHERE id = new.artist_id),
+                (SELECT title FROM albums WHERE id = new.album_id),
+                new.genre, new.path
+            );
+        END;",
+    )?;
+
+    if !search_index_exists {
+        conn.execute(
+            "INSERT INTO tracks_fts (rowid, title, artist, album, genre, path)
+             SELECT t.id, t.title, ar.name, al.title, t.genre, t.path
+             FROM tracks t
+             LEFT JOIN artists ar ON t.artist_id = ar.id
+             LEFT JOIN albums al ON t.album_id = al.id",
+            [],
+        )?;
+    }
+    Ok(())
+}
+
+/// Version 2: user playlists. Entries have their own id so a track can
+/// appear more than once; `position` is kept dense from 0.
+fn playlists(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE playlists (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT NOT NULL,
+            created_at INTEGER NOT NULL,
+            updated_at INTEGER NOT NULL
+        );
+
+        CREATE TABLE playlist_entries (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            playlist_id INTEGER NOT NULL,
+            track_id INTEGER NOT NULL,
+            position INTEGER NOT NULL,
+            FOREIGN KEY(playlist_id) REFERENCES playlists(id) ON DELETE CASCADE,
+            FOREIGN KEY(track_id) REFERENCES tracks(id) ON DELETE CASCADE
+        );
+
+        CREATE INDEX playlist_entries_by_position ON playlist_entries (playlist_id, position);
+        CREATE INDEX playlist_entries_by_track ON playlist_entries (track_id);",
+    )?;
+    Ok(())
+}
+
+/// Version 3: one row per playback. Counts are derived rather than stored
+/// so they can never drift from the history they summarise.
+fn play_history(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE plays (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            track_id INTEGER NOT NULL,
+            started_at INTEGER NOT NULL,
+            listened_ms INTEGER NOT NULL,
+            completed INTEGER NOT NULL,
+            FOREIGN KEY(track_id) REFERENCES tracks(id) ON DELETE CASCADE
+        );
+
+        CREATE INDEX plays_by_track ON plays (track_id, started_at);
+        CREATE INDEX plays_by_time ON plays (started_at);
+
+        CREATE VIEW track_play_stats AS
+        SELECT track_id,
+               SUM(completed) AS play_count,
+               SUM(NOT completed) AS skip_count,
+               MAX(started_at) AS last_played
+        FROM plays
+        GROUP BY track_id;",
+    )?;
+    Ok(())
+}
+
+/// Version 4: `rating` counts half stars from 0 to 10, NULL when unrated.
+fn ratings(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "ALTER TABLE tracks ADD COLUMN rating INTEGER;
+        ALTER TABLE tracks ADD COLUMN loved INTEGER NOT NULL DEFAULT 0;",
+    )?;
+    Ok(())
+}
+
+/// Version 5: smart playlists. `query` is a `SmartQuery` as JSON.
+fn smart_playlists(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE smart_playlists (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT NOT NULL,
+            query TEXT NOT NULL,
+            created_at INTEGER NOT NULL,
+            updated_at INTEGER NOT NULL
+        );",
+    )?;
+    Ok(())
+}
+
+/// Version 6: `albums.artist_id` becomes the album artist rather than the
+/// artist of whichever track created the album. Only the files know the
+/// album artist, so the next scan reads them all again (see
+/// `METADATA_VERSION`) and regroups them; albums left empty are removed by
+/// that scan.
+fn album_artists(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "ALTER TABLE albums ADD COLUMN compilation INTEGER NOT NULL DEFAULT 0;",
+    )?;
+    Ok(())
+}
+
+/// Version 7: every artist a track credits, by role, and its genres as rows
+/// rather than one free-text column. `settings` holds JSON values by key.
+/// Composers and remixers only exist in the files, which the next scan
+/// reads again to fill these in.
+fn credits_and_genres(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE genres (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT NOT NULL UNIQUE COLLATE NOCASE
+        );
+
+        CREATE TABLE track_artists (
+            track_id INTEGER NOT NULL,
+            artist_id INTEGER NOT NULL,
+            role TEXT NOT NULL,
+            position INTEGER NOT NULL,
+            PRIMARY KEY (track_id, artist_id, role),
+            FOREIGN KEY(track_id) REFERENCES tracks(id) ON DELETE CASCADE,
+            FOREIGN KEY(artist_id) REFERENCES artists(id)
+        );
+
+        CREATE TABLE track_genres (
+            track_id INTEGER NOT NULL,
+            genre_id INTEGER NOT NULL,
+            position INTEGER NOT NULL,
+            PRIMARY KEY (track_id, genre_id),
+            FOREIGN KEY(track_id) REFERENCES tracks(id) ON DELETE CASCADE,
+            FOREIGN KEY(genre_id) REFERENCES genres(id)
+        );
+
+        CREATE INDEX track_artists_by_artist ON track_artists (artist_id, role);
+        CREATE INDEX track_genres_by_genre ON track_genres (genre_id);
+
+        CREATE TABLE settings (
+            key TEXT PRIMARY KEY,
+            value TEXT NOT NULL
+        );",
+    )?;
+    Ok(())
+}
+
+/// Version 8: disc numbers, dates, release details, MusicBrainz IDs and
+/// stream properties, read by the next scan.
+fn extended_metadata(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "ALTER TABLE tracks ADD COLUMN disc_number INTEGER;
+        ALTER TABLE tracks ADD COLUMN disc_total INTEGER;
+        ALTER TABLE tracks ADD COLUMN track_total INTEGER;
+        ALTER TABLE tracks ADD COLUMN composer TEXT;
+        ALTER TABLE tracks ADD COLUMN release_date TEXT;
+        ALTER TABLE tracks ADD COLUMN original_date TEXT;
+        ALTER TABLE tracks ADD COLUMN label TEXT;
+        ALTER TABLE tracks ADD COLUMN catalog_number TEXT;
+        ALTER TABLE tracks ADD COLUMN musicbrainz_recording_id TEXT;
+        ALTER TABLE tracks ADD COLUMN musicbrainz_release_id TEXT;
+        ALTER TABLE tracks ADD COLUMN musicbrainz_artist_id TEXT;
+        ALTER TABLE tracks ADD COLUMN bitrate INTEGER;
+        ALTER TABLE tracks ADD COLUMN sample_rate INTEGER;
+        ALTER TABLE tracks ADD COLUMN bit_depth INTEGER;
+        ALTER TABLE tracks ADD COLUMN channels INTEGER;
+        ALTER TABLE tracks ADD COLUMN codec TEXT;",
+    )?;
+    Ok(())
+}
+
+/// Version 9: `cover_source` says where `cover_path` came from: "embedded"
+/// for a picture extracted into the cover cache, "sidecar" for an image
+/// next to the tracks, "none" when there is nothing. NULL means nobody has
+/// looked yet, which is where every album starts.
+fn cover_sources(conn: &Connection) -> Result<()> {
+    conn.execute_batch("ALTER TABLE albums ADD COLUMN cover_source TEXT;")?;
+    Ok(())
+}
+
+/// Version 10: `audio_hash` is a hash of the decoded samples, filled in by
+/// the duplicate finder and cleared whenever the file is read again.
+fn audio_hashes(conn: &Connection) -> Result<()> {
+    conn.execute_batch("ALTER TABLE tracks ADD COLUMN audio_hash TEXT;")?;
+    Ok(())
+}
+
+/// Version 11: `fingerprint` is the track's acoustic fingerprint, a version
+/// byte followed by little-endian 32-bit words. Computed when first needed
+/// and cleared, like `audio_hash`, whenever the file is read again.
+fn fingerprints(conn: &Connection) -> Result<()> {
+    conn.execute_batch("ALTER TABLE tracks ADD COLUMN fingerprint BLOB;")?;
+    Ok(())
+}
+
+/// Version 12: per-root scan rules, with `include` and `exclude` as JSON
+/// arrays of globs, and whether each root was there at the last check.
+/// `tracks.offline` mirrors the `online` flag of the track's root.
+fn root_rules(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "ALTER TABLE library_roots ADD COLUMN include TEXT NOT NULL DEFAULT '[]';
+        ALTER TABLE library_roots ADD COLUMN exclude TEXT NOT NULL DEFAULT '[]';
+        ALTER TABLE library_roots ADD COLUMN follow_symlinks INTEGER NOT NULL DEFAULT 1;
+        ALTER TABLE library_roots ADD COLUMN include_hidden INTEGER NOT NULL DEFAULT 0;
+        ALTER TABLE library_roots ADD COLUMN min_size INTEGER NOT NULL DEFAULT 0;
+        ALTER TABLE library_roots ADD COLUMN online INTEGER NOT NULL DEFAULT 1;
+        ALTER TABLE tracks ADD COLUMN offline INTEGER NOT NULL DEFAULT 0;",
+    )?;
+    Ok(())
+}
+
+/// Version 13: tracks cut from a longer file by a CUE sheet. `start_ms` and
+/// `end_ms` bound the track within `path`; both are NULL for a file played
+/// whole, and `end_ms` is NULL for a sheet's last track, which runs to the
+/// end of the file. `cue_path` is the sheet beside the file, NULL when the
+/// sheet is in the file's tags. Several tracks now share a path, so the
+/// table is rebuilt without `UNIQUE(path)`; foreign keys are off while
+/// migrating, so dropping the old table leaves plays and playlists alone.
+/// The next scan reads every file again and finds the sheets.
+fn cue_tracks(conn: &Connection) -> Result<()> {
+    const COLUMNS: &str = "id, path, title, artist_id, album_id, duration, track_number, year, genre,
+        mtime, size, scan_generation, rating, loved,
+        disc_number, disc_total, track_total, composer, release_date, original_date, label, catalog_number,
+        musicbrainz_recording_id, musicbrainz_release_id, musicbrainz_artist_id,
+        bitrate, sample_rate, bit_depth, channels, codec, audio_hash, fingerprint, offline";
+
+    conn.execute_batch(&format!(
+        "CREATE TABLE tracks_new (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            path TEXT NOT NULL,
+            title TEXT NOT NULL,
+            artist_id INTEGER,
+            album_id INTEGER,
+            duration INTEGER,
+            track_number INTEGER,
+            year INTEGER,
+            genre TEXT,
+            mtime INTEGER,
+            size INTEGER,
+            scan_generation INTEGER NOT NULL DEFAULT 0,
+            rating INTEGER,
+            loved INTEGER NOT NULL DEFAULT 0,
+            disc_number INTEGER,
+            disc_total INTEGER,
+            track_total INTEGER,
+            composer TEXT,
+            release_date TEXT,
+            original_date TEXT,
+            label TEXT,
+            catalog_number TEXT,
+            musicbrainz_recording_id TEXT,
+            musicbrainz_release_id TEXT,
+            musicbrainz_artist_id TEXT,
+            bitrate INTEGER,
+            sample_rate INTEGER,
+            bit_depth INTEGER,
+            channels INTEGER,
+            codec TEXT,
+            audio_hash TEXT,
+            fingerprint BLOB,
+            offline INTEGER NOT NULL DEFAULT 0,
+            start_ms INTEGER,
+            end_ms INTEGER,
+            cue_path TEXT,
+            FOREIGN KEY(artist_id) REFERENCES artists(id),
+            FOREIGN KEY(album_id) REFERENCES albums(id)
+        );
+
+        INSERT INTO tracks_new ({0}) SELECT {0} FROM tracks;
+        DROP TABLE tracks;
+        ALTER TABLE tracks_new RENAME TO tracks;
+        CREATE UNIQUE INDEX tracks_by_path ON tracks (path, IFNULL(start_ms, -1));
+
+        CREATE TRIGGER tracks_fts_insert AFTER INSERT ON tracks BEGIN
+            INSERT INTO tracks_fts (rowid, title, artist, album, genre, path)
+            VALUES (
+                new.id, new.title,
+                (SELECT name FROM artists WHERE id = new.artist_id),
+                (SELECT title FROM albums WHERE id = new.album_id),
+                new.genre, new.path
+            );
+        END;
+
+        CREATE TRIGGER tracks_fts_delete AFTER DELETE ON tracks BEGIN
+            DELETE FROM tracks_fts WHERE rowid = old.id;
+        END;
+
+        CREATE TRIGGER tracks_fts_update
+        AFTER UPDATE OF title, artist_id, album_id, genre, path ON tracks BEGIN
+            DELETE FROM tracks_fts WHERE rowid = old.id;
+            INSERT INTO tracks_fts (rowid, title, artist, album, genre, path)
+            VALUES (
+                new.id, new.title,
+                (SELECT name FROM artists WHERE id = new.artist_id),
+                (SELECT title FROM albums WHERE id = new.album_id),
+                new.genre, new.path
+            );
+        END;",
+        COLUMNS
+    ))?;
+    Ok(())
+}
+
+/// Version 14: one set of lyrics per track. `text` holds the lines joined
+/// by newlines; for synced lyrics `times` holds when each starts, as
+/// little-endian 32-bit milliseconds, and is NULL otherwise. `source` is
+/// "lrc" or "tag" for lyrics read from disk, or the script provider that
+/// found them. The search index gains a `lyrics` column, so it is rebuilt,
+/// and lyrics changing refreshes the track's row in it. The next scan reads
+/// every file again for its lyrics.
+fn lyrics(conn: &Connection) -> Result<()> {
+    // Rewrites the search index row of the track with id `id`.
+    let refresh = |id: &str| {
+        format!(
+            "DELETE FROM tracks_fts WHERE rowid = {0};
+            INSERT INTO tracks_fts (rowid, title, artist, album, genre, path, lyrics)
+            SELECT t.id, t.title, ar.name, al.title, t.genre, t.path,
+                (SELECT text FROM lyrics WHERE track_id = t.id)
+            FROM tracks t
+            LEFT JOIN artists ar ON t.artist_id = ar.id
+            LEFT JOIN albums al ON t.album_id = al.id
+            WHERE t.id = {0};",
+            id
+        )
+    };
+
+    conn.execute_batch(&format!(
+        "CREATE TABLE lyrics (
+            track_id INTEGER PRIMARY KEY,
+            source TEXT NOT NULL,
+            text TEXT NOT NULL,
+            times BLOB,
+            FOREIGN KEY(track_id) REFERENCES tracks(id) ON DELETE CASCADE
+        );
+
+        DROP TRIGGER tracks_fts_insert;
+        DROP TRIGGER tracks_fts_delete;
+        DROP TRIGGER tracks_fts_update;
+        DROP TABLE tracks_fts;
+
+        CREATE VIRTUAL TABLE tracks_fts USING fts5(
+            title, artist, album, genre, path, lyrics,
+            tokenize = 'unicode61 remove_diacritics 2'
+        );
+
+        INSERT INTO tracks_fts (rowid, title, artist, album, genre, path)
+        SELECT t.id, t.title, ar.name, al.title, t.genre, t.path
+        FROM tracks t
+        LEFT JOIN artists ar ON t.artist_id = ar.id
+        LEFT JOIN albums al ON t.album_id = al.id;
+
+        CREATE TRIGGER tracks_fts_insert AFTER INSERT ON tracks BEGIN
+            {insert}
+        END;
+
+        CREATE TRIGGER tracks_fts_delete AFTER DELETE ON tracks BEGIN
+            DELETE FROM tracks_fts WHERE rowid = old.id;
+        END;
+
+        CREATE TRIGGER tracks_fts_update
+        AFTER UPDATE OF title, artist_id, album_id, genre, path ON tracks BEGIN
+            {update}
+        END;
+
+        CREATE TRIGGER lyrics_fts_insert AFTER INSERT ON lyrics BEGIN
+            {lyrics_insert}
+        END;
+
+        CREATE TRIGGER lyrics_fts_update AFTER UPDATE OF text ON lyrics BEGIN
+            {lyrics_update}
+        END;
+
+        CREATE TRIGGER lyrics_fts_delete AFTER DELETE ON lyrics BEGIN
+            {lyrics_delete}
+        END;",
+        insert = refresh("new.id"),
+        update = refresh("new.id"),
+        lyrics_insert = refresh("new.track_id"),
+        lyrics_update = refresh("new.track_id"),
+        lyrics_delete = refresh("old.track_id"),
+    ))?;
+    Ok(())
+}
+
+/// Version 15: EBU R128 loudness of tracks and albums: integrated loudness
+/// in LUFS, true peak in dBTP and loudness range in LU. Measured in the
+/// background and, like `audio_hash`, cleared whenever the file is read
+/// again. `loudness_failed` marks files that could not be decoded.
+fn loudness(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "ALTER TABLE tracks ADD COLUMN loudness REAL;
+        ALTER TABLE tracks ADD COLUMN true_peak REAL;
+        ALTER TABLE tracks ADD COLUMN loudness_range REAL;
+        ALTER TABLE tracks ADD COLUMN loudness_failed INTEGER NOT NULL DEFAULT 0;
+        ALTER TABLE albums ADD COLUMN loudness REAL;
+        ALTER TABLE albums ADD COLUMN true_peak REAL;
+        ALTER TABLE albums ADD COLUMN loudness_range REAL;",
+    )?;
+    Ok(())
+}
+
+/// Version 16: `picture_stamp` is a hash of the pictures embedded in the
+/// track's file, NULL when it has none, so a rescan only sends the album
+/// looking for a new cover when they changed.
+fn picture_stamps(conn: &Connection) -> Result<()> {
+    conn.execute_batch("ALTER TABLE tracks ADD COLUMN picture_stamp INTEGER;")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// A fresh directory for one test, removed again when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("aurora-migrations-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+
+        fn db(&self) -> PathBuf {
+            self.0.join("aurora.db")
+        }
+
+        fn backups(&self) -> Vec<String> {
+            std::fs::read_dir(&self.0)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+                .filter(|name| name.ends_with(".bak"))
+                .collect()
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Opens as `LibraryManager::new` does.
+    fn open(path: &Path) -> Connection {
+        let conn = Connection::open(path).unwrap();
+        conn.pragma_update(None, "foreign_keys", false).unwrap();
+        conn
+    }
+
+    fn columns(conn: &Connection, table: &str) -> Vec<String> {
+        let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table)).unwrap();
+        let names = stmt.query_map([], |row| row.get(1)).unwrap();
+        names.map(Result::unwrap).collect()
+    }
+
+    fn schema(conn: &Connection) -> Vec<String> {
+        let mut stmt = conn.prepare("SELECT sql FROM sqlite_master WHERE sql IS NOT NULL ORDER BY name").unwrap();
+        let sql = stmt.query_map([], |row| row.get(0)).unwrap();
+        sql.map(Result::unwrap).collect()
+    }
+
+    /// The tables as they stood before versioning, before tracks had a
+    /// stamp, holding one track.
+    fn create_unversioned(conn: &Connection) {
+        conn.execute_batch(
+            "CREATE TABLE artists (id INTEGER PRIMARY KEY AUTOINCREMENT, name TEXT NOT NULL UNIQUE);
+            CREATE TABLE albums (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                title TEXT NOT NULL,
+                artist_id INTEGER,
+                cover_path TEXT,
+                UNIQUE(title, artist_id)
+            );
+            CREATE TABLE tracks (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                path TEXT NOT NULL UNIQUE,
+                title TEXT NOT NULL,
+                artist_id INTEGER,
+                album_id INTEGER,
+                duration INTEGER,
+                track_number INTEGER,
+                year INTEGER,
+                genre TEXT
+            );
+            CREATE TABLE library_roots (path TEXT PRIMARY KEY);
+            INSERT INTO artists (name) VALUES ('Nina Simone');
+            INSERT INTO albums (title, artist_id) VALUES ('Pastel Blues', 1);
+            INSERT INTO tracks (path, title, artist_id, album_id, duration, year, genre)
+            VALUES ('/music/sinnerman.flac', 'Sinnerman', 1, 1, 622, 1965, 'Jazz');",
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn migrates_an_unversioned_database() {
+        let dir = TempDir::new("baseline");
+        let mut conn = open(&dir.db());
+        create_unversioned(&conn);
+
+        migrate(&mut conn, &dir.db()).unwrap();
+        assert_eq!(user_version(&conn).unwrap(), SCHEMA_VERSION);
+
+        let tracks = columns(&conn, "tracks");
+        for column in [
+            "mtime", "size", "scan_generation", "rating", "loved", "disc_number", "composer", "codec",
+            "audio_hash", "fingerprint", "offline", "start_ms", "end_ms", "cue_path", "loudness",
+            "loudness_failed",
+        ] {
+            assert!(tracks.iter().any(|name| name == column), "tracks has no {}: {:?}", column, tracks);
+        }
+        assert!(columns(&conn, "albums").iter().any(|name| name == "cover_source"));
+        assert!(columns(&conn, "library_roots").iter().any(|name| name == "online"));
+        for table in ["playlists", "playlist_entries", "plays", "smart_playlists", "track_artists", "genres"] {
+            assert!(!columns(&conn, table).is_empty(), "no table {}", table);
+        }
+
+        // The existing track survives, and is in the search index.
+        let (title, generation): (String, i64) = conn
+            .query_row("SELECT title, scan_generation FROM tracks WHERE path = '/music/sinnerman.flac'", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!((title.as_str(), generation), ("Sinnerman", 0));
+        let found: i64 = conn
+            .query_row("SELECT COUNT(*) FROM tracks_fts WHERE tracks_fts MATCH 'simone'", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(found, 1);
+
+        // The data was backed up first, at the version it was found at.
+        let backups = dir.backups();
+        assert_eq!(backups.len(), 1, "{:?}", backups);
+        assert!(backups[0].starts_with("aurora.db.v0-"), "{:?}", backups);
+        let backup = Connection::open(dir.0.join(&backups[0])).unwrap();
+        assert_eq!(user_version(&backup).unwrap(), 0);
+        assert!(!columns(&backup, "tracks").iter().any(|name| name == "mtime"));
+    }
+
+    #[test]
+    fn running_again_changes_nothing() {
+        let dir = TempDir::new("rerun");
+        let mut conn = open(&dir.db());
+        create_unversioned(&conn);
+        migrate(&mut conn, &dir.db()).unwrap();
+        let before = schema(&conn);
+        drop(conn);
+
+        let mut conn = open(&dir.db());
+        migrate(&mut conn, &dir.db()).unwrap();
+        assert_eq!(user_version(&conn).unwrap(), SCHEMA_VERSION);
+        assert_eq!(schema(&conn), before);
+        assert_eq!(dir.backups().len(), 1);
+    }
+
+    #[test]
+    fn creates_a_new_database_without_a_backup() {
+        let dir = TempDir::new("fresh");
+        let mut conn = open(&dir.db());
+        migrate(&mut conn, &dir.db()).unwrap();
+        assert_eq!(user_version(&conn).unwrap(), SCHEMA_VERSION);
+        assert!(columns(&conn, "tracks").iter().any(|name| name == "loudness"));
+        assert!(dir.backups().is_empty());
+    }
+
+    #[test]
+    fn refuses_a_newer_schema_and_leaves_it_alone() {
+        let dir = TempDir::new("too-new");
+        let conn = open(&dir.db());
+        conn.execute_batch("CREATE TABLE future (id INTEGER PRIMARY KEY);").unwrap();
+        conn.pragma_update(None, "user_version", SCHEMA_VERSION + 1).unwrap();
+        drop(conn);
+        let before = std::fs::read(dir.db()).unwrap();
+
+        let mut conn = open(&dir.db());
+        let error = migrate(&mut conn, &dir.db()).unwrap_err();
+        match error.downcast_ref::<SchemaError>() {
+            Some(SchemaError::TooNew { found, supported }) => {
+                assert_eq!((*found, *supported), (SCHEMA_VERSION + 1, SCHEMA_VERSION));
+            }
+            None => panic!("unexpected error: {}", error),
+        }
+        drop(conn);
+
+        assert_eq!(std::fs::read(dir.db()).unwrap(), before);
+        assert!(dir.backups().is_empty());
+    }
+}
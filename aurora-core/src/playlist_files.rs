@@ -0,0 +1,841 @@
+use crate::{LibraryManager, Track};
+use anyhow::{anyhow, Context, Result};
+use quick_xml::escape::{escape, resolve_xml_entity};
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use rusqlite::{params, OptionalExtension};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Write as _;
+use std::path::{Component, Path, PathBuf, MAIN_SEPARATOR};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaylistFormat {
+    M3u,
+    M3u8,
+    Pls,
+    Xspf,
+}
+
+impl PlaylistFormat {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "m3u" => Some(Self::M3u),
+            "m3u8" => Some(Self::M3u8),
+            "pls" => Some(Self::Pls),
+            "xspf" => Some(Self::Xspf),
+            _ => None,
+        }
+    }
+}
+
+/// A playlist entry that could not be matched to a track in the library.
+#[derive(Debug, Clone)]
+pub struct UnmatchedEntry {
+    /// Position of the entry in the playlist file, from 0.
+    pub position: u32,
+    pub location: String,
+    pub title: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PlaylistImport {
+    pub playlist_id: i64,
+    pub matched: u32,
+    pub unmatched: Vec<UnmatchedEntry>,
+}
+
+/// VLC's option for where in a file an entry starts, in seconds. Tracks
+/// cut from an image by a CUE sheet are written with it, in M3U and XSPF
+/// alike, so they read back as the same track rather than the first one.
+const START_TIME: &str = "start-time=";
+const STOP_TIME: &str = "stop-time=";
+const VLC_NAMESPACE: &str = "http://www.videolan.org/vlc/playlist/ns/0/";
+const VLC_APPLICATION: &str = "http://www.videolan.org/vlc/playlist/0";
+
+/// An entry as written in a playlist file.
+#[derive(Debug, Default)]
+struct FileEntry {
+    location: String,
+    title: Option<String>,
+    artist: Option<String>,
+    start_ms: Option<u32>,
+}
+
+impl LibraryManager {
+    /// Creates a playlist from an M3U/M3U8, PLS or XSPF file, chosen by
+    /// extension. Locations are tried as given, relative to the playlist
+    /// file and relative to each library root; entries that still match no
+    /// track are left out and reported.
+    pub fn import_playlist(&self, file: &Path, name: Option<&str>) -> Result<PlaylistImport> {
+        let format = PlaylistFormat::from_path(file)
+            .ok_or_else(|| anyhow!("Unsupported playlist format: {:?}", file))?;
+        let bytes = std::fs::read(file)
+            .with_context(|| format!("Failed to read playlist {:?}", file))?;
+        let text = decode_text(&bytes);
+
+        let entries = match format {
+            PlaylistFormat::M3u | PlaylistFormat::M3u8 => parse_m3u(&text),
+            PlaylistFormat::Pls => parse_pls(&text),
+            PlaylistFormat::Xspf => parse_xspf(&text)?,
+        };
+
+        let base = file.parent().unwrap_or(Path::new(""));
+        let roots = self.library_roots()?;
+        let mut track_ids = Vec::new();
+        let mut unmatched = Vec::new();
+        for (position, entry) in entries.into_iter().enumerate() {
+            let uri = format == PlaylistFormat::Xspf;
+            match self.resolve_entry(&entry, uri, base, &roots)? {
+                Some(track_id) => track_ids.push(track_id),
+                None => unmatched.push(UnmatchedEntry {
+                    position: position as u32,
+                    location: entry.location,
+                    title: entry.title,
+                }),
+            }
+        }
+
+        let name = match name {
+            Some(name) => name.to_string(),
+            None => file
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_else(|| "Imported Playlist".to_string()),
+        };
+        // Both or neither, so a failed import leaves no empty playlist behind.
+        let tx = self.conn.unchecked_transaction()?;
+        let playlist_id = self.create_playlist(&name)?;
+        self.insert_playlist_entries(playlist_id, &track_ids, None)?;
+        tx.commit()?;
+
+        Ok(PlaylistImport {
+            playlist_id,
+            matched: track_ids.len() as u32,
+            unmatched,
+        })
+    }
+
+    /// Writes a playlist in the format given by `file`'s extension. With
+    /// `relative`, tracks are written relative to the playlist's folder
+    /// wherever that is possible. CUE tracks carry where they start in their
+    /// image; PLS cannot say so and leaves them out.
+    pub fn export_playlist(&self, playlist_id: i64, file: &Path, relative: bool) -> Result<()> {
+        let format = PlaylistFormat::from_path(file)
+            .ok_or_else(|| anyhow!("Unsupported playlist format: {:?}", file))?;
+        let name: String = self.conn.query_row(
+            "SELECT name FROM playlists WHERE id = ?1",
+            params![playlist_id],
+            |row| row.get(0),
+        ).optional()?
+            .ok_or_else(|| anyhow!("Playlist {} does not exist", playlist_id))?;
+        let tracks: Vec<Track> = self
+            .get_playlist_entries(playlist_id)?
+            .into_iter()
+            .map(|entry| entry.track)
+            .collect();
+
+        // Library paths are absolute, so the folder has to be as well for
+        // them to be written relative to it.
+        let base = std::path::absolute(file.parent().unwrap_or(Path::new("")))?;
+        let location = |track: &Track| -> PathBuf {
+            let path = Path::new(&track.path);
+            if relative {
+                if let Some(relative) = relative_path(&base, path) {
+                    return relative;
+                }
+            }
+            path.to_path_buf()
+        };
+
+        let text = match format {
+            PlaylistFormat::M3u | PlaylistFormat::M3u8 => write_m3u(&tracks, location),
+            PlaylistFormat::Pls => {
+                // PLS has no way to say where in a file an entry starts.
+                let (cut, whole): (Vec<Track>, Vec<Track>) =
+                    tracks.into_iter().partition(|track| track.start_ms.is_some());
+                for track in &cut {
+                    log::warn!("Left CUE track {:?} of {:?} out of PLS playlist {:?}", track.title, track.path, file);
+                }
+                write_pls(&whole, location)
+            }
+            PlaylistFormat::Xspf => write_xspf(&name, &tracks, location),
+        };
+        std::fs::write(file, text)
+            .with_context(|| format!("Failed to write playlist {:?}", file))?;
+        Ok(())
+    }
+
+    fn resolve_entry(&self, entry: &FileEntry, uri: bool, base: &Path, roots: &[PathBuf]) -> Result<Option<i64>> {
+        if let Some(path) = location_to_path(&entry.location, uri) {
+            for candidate in candidate_paths(&path, base, roots) {
+                if let Some(id) = self.track_id_by_path(&candidate, entry.start_ms)? {
+                    return Ok(Some(id));
+                }
+            }
+        }
+
+        // Playlists from other machines may point nowhere near our files;
+        // fall back to the tags they carry if they single out one track.
+        let Some(title) = &entry.title else {
+            return Ok(None);
+        };
+        let mut stmt = self.conn.prepare(
+            "SELECT t.id FROM tracks t
+             JOIN artists ar ON t.artist_id = ar.id
+             WHERE t.title = ?1 COLLATE NOCASE
+               AND (?2 IS NULL OR ar.name = ?2 COLLATE NOCASE)
+             LIMIT 2"
+        )?;
+        let ids: Vec<i64> = stmt
+            .query_map(params![title, entry.artist], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(if ids.len() == 1 { Some(ids[0]) } else { None })
+    }
+
+    /// The track at `path`, or for a CUE image the one playing at
+    /// `start_ms`. Without a start, an image stands for its first track.
+    fn track_id_by_path(&self, path: &Path, start_ms: Option<u32>) -> Result<Option<i64>> {
+        let sql = match start_ms {
+            Some(_) => "SELECT id FROM tracks WHERE path = ?1 AND coalesce(start_ms, 0) <= ?2
+                        ORDER BY start_ms DESC LIMIT 1",
+            None => "SELECT id FROM tracks WHERE path = ?1 AND ?2 IS NULL ORDER BY start_ms LIMIT 1",
+        };
+        let id = self.conn.query_row(sql, params![path.to_string_lossy(), start_ms], |row| row.get(0))
+            .optional()?;
+        Ok(id)
+    }
+}
+
+/// Playlists are UTF-8 more often than not, even with an `.m3u`
+/// extension; anything that is not valid UTF-8 is taken as Latin-1.
+pub(crate) fn decode_text(bytes: &[u8]) -> String {
+    let bytes = bytes.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(bytes);
+    match std::str::from_utf8(bytes) {
+        Ok(text) => text.to_string(),
+        Err(_) => bytes.iter().map(|&b| b as char).collect(),
+    }
+}
+
+fn parse_m3u(text: &str) -> Vec<FileEntry> {
+    let mut entries = Vec::new();
+    let mut pending = FileEntry::default();
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        if let Some(option) = line.strip_prefix("#EXTVLCOPT:") {
+            if let Some(start_ms) = parse_start_time(option) {
+                pending.start_ms = Some(start_ms);
+            }
+        } else if let Some(info) = line.strip_prefix("#EXTINF:") {
+            // #EXTINF:<seconds>[ attributes],<Artist - Title>
+            let display = info.split_once(',').map(|(_, display)| display.trim()).unwrap_or("");
+            match display.split_once(" - ") {
+                Some((artist, title)) => {
+                    pending.artist = Some(artist.trim().to_string());
+                    pending.title = Some(title.trim().to_string());
+                }
+                None if !display.is_empty() => pending.title = Some(display.to_string()),
+                None => {}
+            }
+        } else if !line.starts_with('#') {
+            pending.location = line.to_string();
+            entries.push(std::mem::take(&mut pending));
+        }
+    }
+    entries
+}
+
+fn parse_pls(text: &str) -> Vec<FileEntry> {
+    let mut entries: BTreeMap<u32, FileEntry> = BTreeMap::new();
+    for line in text.lines().map(str::trim) {
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        let key = key.trim().to_ascii_lowercase();
+        let value = value.trim().to_string();
+        let (field, index) = key.split_at(key.find(|c: char| c.is_ascii_digit()).unwrap_or(key.len()));
+        let Ok(index) = index.parse::<u32>() else {
+            continue;
+        };
+        let entry = entries.entry(index).or_default();
+        match field {
+            "file" => entry.location = value,
+            "title" if !value.is_empty() => entry.title = Some(value),
+            _ => {}
+        }
+    }
+    entries
+        .into_values()
+        .filter(|entry| !entry.location.is_empty())
+        .collect()
+}
+
+fn parse_xspf(text: &str) -> Result<Vec<FileEntry>> {
+    // Text is trimmed once whole: trimmed by the reader, the pieces either
+    // side of an entity such as `&amp;` would lose their spaces.
+    let mut reader = Reader::from_str(text);
+
+    let mut entries = Vec::new();
+    let mut current: Option<FileEntry> = None;
+    let mut element = String::new();
+    let mut value = String::new();
+    loop {
+        match reader.read_event().context("Malformed XSPF playlist")? {
+            Event::Start(start) => {
+                let name = String::from_utf8_lossy(start.local_name().as_ref()).into_owned();
+                if name == "track" {
+                    current = Some(FileEntry::default());
+                }
+                element = name;
+                value.clear();
+            }
+            Event::Text(text) => value.push_str(&text.decode()?),
+            Event::CData(data) => value.push_str(&data.decode()?),
+            Event::GeneralRef(reference) => match reference.resolve_char_ref()? {
+                Some(c) => value.push(c),
+                None => {
+                    let name = reference.decode()?;
+                    value.push_str(resolve_xml_entity(&name).unwrap_or_default());
+                }
+            },
+            Event::End(end) => {
+                let name = end.local_name();
+                if name.as_ref() == b"track" {
+                    if let Some(entry) = current.take().filter(|entry| !entry.location.is_empty()) {
+                        entries.push(entry);
+                    }
+                } else if let Some(entry) = current.as_mut() {
+                    let text = value.trim().to_string();
+                    match element.as_str() {
+                        // A track may list several locations; the first is preferred.
+                        "location" if entry.location.is_empty() => entry.location = text,
+                        "title" if !text.is_empty() => entry.title = Some(text),
+                        "creator" if !text.is_empty() => entry.artist = Some(text),
+                        "option" => entry.start_ms = parse_start_time(&text).or(entry.start_ms),
+                        _ => {}
+                    }
+                }
+                value.clear();
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(entries)
+}
+
+fn write_m3u(tracks: &[Track], location: impl Fn(&Track) -> PathBuf) -> String {
+    let mut out = String::from("#EXTM3U\n");
+    for track in tracks {
+        let _ = writeln!(out, "#EXTINF:{},{} - {}", track.duration, track.artist, track.title);
+        for option in time_options(track) {
+            let _ = writeln!(out, "#EXTVLCOPT:{}", option);
+        }
+        let _ = writeln!(out, "{}", location(track).display());
+    }
+    out
+}
+
+fn write_pls(tracks: &[Track], location: impl Fn(&Track) -> PathBuf) -> String {
+    let mut out = String::from("[playlist]\n");
+    for (i, track) in tracks.iter().enumerate() {
+        let n = i + 1;
+        let _ = writeln!(out, "File{}={}", n, location(track).display());
+        let _ = writeln!(out, "Title{}={} - {}", n, track.artist, track.title);
+        let _ = writeln!(out, "Length{}={}", n, track.duration);
+    }
+    let _ = writeln!(out, "NumberOfEntries={}", tracks.len());
+    out.push_str("Version=2\n");
+    out
+}
+
+fn write_xspf(name: &str, tracks: &[Track], location: impl Fn(&Track) -> PathBuf) -> String {
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    let _ = writeln!(
+        out,
+        "<playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\" xmlns:vlc=\"{}\">",
+        VLC_NAMESPACE
+    );
+    let _ = writeln!(out, "  <title>{}</title>", escape(name));
+    out.push_str("  <trackList>\n");
+    for track in tracks {
+        let path = location(track);
+        let uri = if path.is_absolute() {
+            format!("file://{}", percent_encode(&path_to_slashes(&path)))
+        } else {
+            percent_encode(&path_to_slashes(&path))
+        };
+        out.push_str("    <track>\n");
+        let _ = writeln!(out, "      <location>{}</location>", escape(uri));
+        let _ = writeln!(out, "      <title>{}</title>", escape(&track.title));
+        let _ = writeln!(out, "      <creator>{}</creator>", escape(&track.artist));
+        let _ = writeln!(out, "      <album>{}</album>", escape(&track.album));
+        let _ = writeln!(out, "      <duration>{}</duration>", u64::from(track.duration) * 1000);
+        let options = time_options(track);
+        if !options.is_empty() {
+            let _ = writeln!(out, "      <extension application=\"{}\">", VLC_APPLICATION);
+            for option in options {
+                let _ = writeln!(out, "        <vlc:option>{}</vlc:option>", escape(option));
+            }
+            out.push_str("      </extension>\n");
+        }
+        out.push_str("    </track>\n");
+    }
+    out.push_str("  </trackList>\n</playlist>\n");
+    out
+}
+
+/// VLC options placing a CUE track within its image.
+fn time_options(track: &Track) -> Vec<String> {
+    let seconds = |ms: u32| format!("{}.{:03}", ms / 1000, ms % 1000);
+    let Some(start_ms) = track.start_ms else {
+        return Vec::new();
+    };
+    let mut options = vec![format!("{}{}", START_TIME, seconds(start_ms))];
+    if let Some(end_ms) = track.end_ms {
+        options.push(format!("{}{}", STOP_TIME, seconds(end_ms)));
+    }
+    options
+}
+
+/// Milliseconds from a `start-time=<seconds>` option.
+fn parse_start_time(option: &str) -> Option<u32> {
+    let seconds: f64 = option.trim().strip_prefix(START_TIME)?.trim().parse().ok()?;
+    (seconds >= 0.0).then(|| (seconds * 1000.0).round() as u32)
+}
+
+/// Turns a playlist location into a path. `uri` locations (XSPF, and
+/// `file://` anywhere) are percent-decoded; other URL schemes are not files.
+fn location_to_path(location: &str, uri: bool) -> Option<PathBuf> {
+    let location = location.trim();
+    let (raw, decode) = if let Some(rest) = location.strip_prefix("file://") {
+        // file:///music/a.flac and file://localhost/music/a.flac
+        (rest.strip_prefix("localhost").unwrap_or(rest), true)
+    } else if location.contains("://") {
+        return None;
+    } else {
+        (location, uri)
+    };
+
+    let mut path = if decode { percent_decode(raw) } else { raw.to_string() };
+    if MAIN_SEPARATOR == '/' {
+        // Playlists written on Windows.
+        path = path.replace('\\', "/");
+    }
+    (!path.is_empty()).then(|| PathBuf::from(path))
+}
+
+/// Where a playlist location may live in the library, most likely first:
+/// the path itself (relative ones against the playlist's folder and then
+/// each root), then ever shorter trailing parts of it under each root.
+fn candidate_paths(path: &Path, base: &Path, roots: &[PathBuf]) -> Vec<PathBuf> {
+    let mut candidates = Vec::new();
+    if path.is_absolute() {
+        candidates.push(normalize(path));
+    } else {
+        candidates.push(normalize(&base.join(path)));
+        candidates.extend(roots.iter().map(|root| normalize(&root.join(path))));
+    }
+
+    let parts: Vec<_> = path
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(part) => Some(part),
+            _ => None,
+        })
+        .collect();
+    // A bare file name on its own is too ambiguous to match against a root,
+    // so keep at least the enclosing folder.
+    for len in (2..=parts.len()).rev() {
+        let suffix: PathBuf = parts[parts.len() - len..].iter().collect();
+        candidates.extend(roots.iter().map(|root| root.join(&suffix)));
+    }
+    let mut seen = HashSet::new();
+    candidates.retain(|candidate| seen.insert(candidate.clone()));
+    candidates
+}
+
+/// Resolves `.` and `..` without touching the filesystem.
+fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                out.pop();
+            }
+            other => out.push(other),
+        }
+    }
+    out
+}
+
+/// `to` expressed relative to the directory `from`, if they share a root.
+fn relative_path(from: &Path, to: &Path) -> Option<PathBuf> {
+    let from = normalize(from);
+    let to = normalize(to);
+    if from.as_os_str().is_empty() || from.is_absolute() != to.is_absolute() {
+        return None;
+    }
+
+    let from: Vec<_> = from.components().collect();
+    let to: Vec<_> = to.components().collect();
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+    if common == 0 || (from[0] != to[0]) {
+        return None;
+    }
+
+    let mut relative = PathBuf::new();
+    for _ in common..from.len() {
+        relative.push("..");
+    }
+    for component in &to[common..] {
+        relative.push(component);
+    }
+    Some(relative)
+}
+
+fn path_to_slashes(path: &Path) -> String {
+    path.to_string_lossy().replace(MAIN_SEPARATOR, "/")
+}
+
+fn percent_encode(path: &str) -> String {
+    let mut out = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => out.push(byte as char),
+            _ => {
+                let _ = write!(out, "%{:02X}", byte);
+            }
+        }
+    }
+    out
+}
+
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        // Only `%` and two hex digits; anything else is kept as written.
+        if let [b'%', high, low, ..] = bytes[i..] {
+            if let (Some(high), Some(low)) = ((high as char).to_digit(16), (low as char).to_digit(16)) {
+                out.push((high * 16 + low) as u8);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+impl mlua::UserData for UnmatchedEntry {
+    fn add_fields<'lua, F: mlua::UserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_method_get("position", |_lua, this| Ok(this.position + 1));
+        fields.add_field_method_get("location", |_lua, this| Ok(this.location.clone()));
+        fields.add_field_method_get("title", |_lua, this| Ok(this.title.clone()));
+    }
+}
+
+impl mlua::UserData for PlaylistImport {
+    fn add_fields<'lua, F: mlua::UserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_method_get("playlist_id", |_lua, this| Ok(this.playlist_id));
+        fields.add_field_method_get("matched", |_lua, this| Ok(this.matched));
+        fields.add_field_method_get("unmatched", |_lua, this| Ok(this.unmatched.clone()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use crate::LibraryRoot;
+
+    const SHEET: &str = "FILE image.wav WAVE\n TRACK 01 AUDIO\n  INDEX 01 00:00:00\n TRACK 02 AUDIO\n  INDEX 01 00:00:05\n";
+
+    /// A library of three files and an image cut in two by a CUE sheet.
+    fn library(name: &str) -> (TempDir, LibraryManager) {
+        let dir = TempDir::new(name);
+        for file in ["music/Artist/Album/one.wav", "music/Artist/Album/two.wav", "music/Other/three.wav", "music/Image/image.wav"] {
+            dir.write_wav(file);
+        }
+        dir.write("music/Image/image.cue", SHEET);
+        std::fs::create_dir_all(dir.path().join("lists")).unwrap();
+        let manager = LibraryManager::new(dir.path().join("aurora.db")).unwrap();
+        manager.add_library_root(&LibraryRoot::new(dir.path().join("music"))).unwrap();
+        manager.scan_library().unwrap();
+        (dir, manager)
+    }
+
+    /// The tracks of the file at `path` under the directory, in order.
+    fn ids(dir: &TempDir, manager: &LibraryManager, path: &str) -> Vec<i64> {
+        let path = dir.path().join(path);
+        let mut stmt = manager.conn.prepare("SELECT id FROM tracks WHERE path = ?1 ORDER BY start_ms").unwrap();
+        let ids = stmt.query_map(params![path.to_string_lossy()], |row| row.get(0)).unwrap();
+        ids.map(Result::unwrap).collect()
+    }
+
+    fn entries(manager: &LibraryManager, playlist_id: i64) -> Vec<i64> {
+        manager.get_playlist_entries(playlist_id).unwrap().into_iter().map(|entry| entry.track.id).collect()
+    }
+
+    #[test]
+    fn round_trips_every_format() {
+        let (dir, manager) = library("playlist-round-trip");
+        let [one, two, three] = ["one", "two", "three"].map(|name| {
+            let path = if name == "three" { "music/Other/three.wav".to_string() } else { format!("music/Artist/Album/{}.wav", name) };
+            ids(&dir, &manager, &path)[0]
+        });
+        let image = ids(&dir, &manager, "music/Image/image.wav");
+        assert_eq!(image.len(), 2);
+        let tracks = vec![two, one, image[1], three, one, image[0]];
+
+        let playlist = manager.create_playlist("Mix & Match <1>").unwrap();
+        manager.add_to_playlist(playlist, &tracks, None).unwrap();
+
+        for extension in ["m3u", "M3U8", "xspf", "pls"] {
+            for relative in [false, true] {
+                let file = dir.path().join("lists").join(format!("mix-{}.{}", relative, extension));
+                manager.export_playlist(playlist, &file, relative).unwrap();
+                let imported = manager.import_playlist(&file, None).unwrap();
+                assert!(imported.unmatched.is_empty(), "{:?}: {:?}", file, imported.unmatched);
+
+                // PLS cannot place CUE tracks and leaves them out.
+                let expected: Vec<i64> = if extension == "pls" {
+                    tracks.iter().copied().filter(|id| !image.contains(id)).collect()
+                } else {
+                    tracks.clone()
+                };
+                assert_eq!(entries(&manager, imported.playlist_id), expected, "{:?}", file);
+                assert_eq!(imported.matched as usize, expected.len());
+            }
+        }
+    }
+
+    #[test]
+    fn writes_paths_relative_to_the_playlist() {
+        let (dir, manager) = library("playlist-relative");
+        let one = ids(&dir, &manager, "music/Artist/Album/one.wav")[0];
+        let image = ids(&dir, &manager, "music/Image/image.wav");
+        let playlist = manager.create_playlist("Relative").unwrap();
+        manager.add_to_playlist(playlist, &[one, image[1]], None).unwrap();
+
+        let m3u = dir.path().join("lists").join("relative.m3u");
+        manager.export_playlist(playlist, &m3u, true).unwrap();
+        let text = std::fs::read_to_string(&m3u).unwrap();
+        let lines: Vec<&str> = text.lines().filter(|line| !line.starts_with('#')).collect();
+        assert_eq!(lines, vec!["../music/Artist/Album/one.wav", "../music/Image/image.wav"]);
+        assert!(text.contains("#EXTVLCOPT:start-time=0.066\n../music/Image/image.wav"), "{}", text);
+
+        manager.export_playlist(playlist, &m3u, false).unwrap();
+        let text = std::fs::read_to_string(&m3u).unwrap();
+        assert!(text.contains(&dir.path().join("music/Artist/Album/one.wav").display().to_string()), "{}", text);
+
+        let xspf = dir.path().join("lists").join("relative.xspf");
+        manager.export_playlist(playlist, &xspf, true).unwrap();
+        let text = std::fs::read_to_string(&xspf).unwrap();
+        assert!(text.contains("<location>../music/Artist/Album/one.wav</location>"), "{}", text);
+        assert!(text.contains("<title>Relative</title>"), "{}", text);
+    }
+
+    #[test]
+    fn reports_entries_that_match_no_track() {
+        let (dir, manager) = library("playlist-unmatched");
+        let one = ids(&dir, &manager, "music/Artist/Album/one.wav")[0];
+        let two = ids(&dir, &manager, "music/Artist/Album/two.wav")[0];
+        let three = ids(&dir, &manager, "music/Other/three.wav")[0];
+        manager.conn.execute("UPDATE tracks SET title = 'Song Two' WHERE id = ?1", params![two]).unwrap();
+        manager.conn.execute("UPDATE tracks SET title = 'Shared' WHERE id IN (?1, ?2)", params![one, three]).unwrap();
+
+        let file = dir.write(
+            "lists/moved.m3u",
+            "#EXTM3U\n\
+             #EXTINF:10,Nobody - Missing\n\
+             /nowhere/missing.wav\n\
+             http://radio.example/stream\n\
+             #EXTINF:1,Song Two\n\
+             /elsewhere/two-renamed.wav\n\
+             #EXTINF:1,Wrong Artist - Song Two\n\
+             /elsewhere/two-renamed.wav\n\
+             #EXTINF:1,Shared\n\
+             /elsewhere/shared.wav\n\
+             one.wav\n\
+             /home/someone/Music/Artist/Album/one.wav\n\
+             C:\\Users\\someone\\Music\\Other\\three.wav\n",
+        );
+        let imported = manager.import_playlist(&file, Some("Moved")).unwrap();
+        assert_eq!(entries(&manager, imported.playlist_id), vec![two, one, three]);
+        assert_eq!(imported.matched, 3);
+        let unmatched: Vec<(u32, &str, Option<&str>)> = imported
+            .unmatched
+            .iter()
+            .map(|entry| (entry.position, entry.location.as_str(), entry.title.as_deref()))
+            .collect();
+        assert_eq!(
+            unmatched,
+            vec![
+                (0, "/nowhere/missing.wav", Some("Missing")),
+                (1, "http://radio.example/stream", None),
+                (3, "/elsewhere/two-renamed.wav", Some("Song Two")),
+                (4, "/elsewhere/shared.wav", Some("Shared")),
+                (5, "one.wav", None),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_formats() {
+        let (dir, manager) = library("playlist-format");
+        let file = dir.write("lists/list.txt", "one.wav\n");
+        assert!(manager.import_playlist(&file, None).is_err());
+        let playlist = manager.create_playlist("Empty").unwrap();
+        assert!(manager.export_playlist(playlist, &file, false).is_err());
+        assert!(manager.export_playlist(playlist + 1, &dir.path().join("lists/x.m3u"), false).is_err());
+    }
+
+    #[test]
+    fn parses_m3u() {
+        let entries = parse_m3u(
+            "#EXTM3U\r\n\
+             #EXTINF:123,Artist - Title - Part 2\r\n\
+             \r\n\
+             /music/a.flac\r\n\
+             #EXTINF:-1 tvg-id=\"x\",Just a title\r\n\
+             #EXTVLCOPT:start-time=61.5\r\n\
+             image.flac\r\n\
+             # a comment\r\n\
+             plain.mp3\r\n",
+        );
+        let locations: Vec<&str> = entries.iter().map(|entry| entry.location.as_str()).collect();
+        assert_eq!(locations, vec!["/music/a.flac", "image.flac", "plain.mp3"]);
+        assert_eq!((entries[0].artist.as_deref(), entries[0].title.as_deref()), (Some("Artist"), Some("Title - Part 2")));
+        assert_eq!((entries[1].artist.as_deref(), entries[1].title.as_deref()), (None, Some("Just a title")));
+        assert_eq!(entries[1].start_ms, Some(61_500));
+        assert_eq!((entries[2].title.as_deref(), entries[2].start_ms), (None, None));
+    }
+
+    #[test]
+    fn parses_pls() {
+        let entries = parse_pls(
+            "[playlist]\nNumberOfEntries=3\nfile2 = /music/b.flac\nTitle2=Second\nFILE1=/music/a.flac\nTitle1=\n\
+             Title3=No file\nFile10=/music/c.flac\nVersion=2\n",
+        );
+        let locations: Vec<&str> = entries.iter().map(|entry| entry.location.as_str()).collect();
+        assert_eq!(locations, vec!["/music/a.flac", "/music/b.flac", "/music/c.flac"]);
+        assert_eq!(entries[0].title, None);
+        assert_eq!(entries[1].title.as_deref(), Some("Second"));
+    }
+
+    #[test]
+    fn parses_xspf() {
+        let entries = parse_xspf(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+            <playlist version="1" xmlns="http://xspf.org/ns/0/" xmlns:vlc="http://www.videolan.org/vlc/playlist/ns/0/">
+              <title>Not a track</title>
+              <trackList>
+                <track>
+                  <location>file:///music/R%26B/a%20b.flac</location>
+                  <location>file:///elsewhere/a.flac</location>
+                  <title>Rock &amp; Roll &#233;</title>
+                  <creator><![CDATA[AC/DC]]></creator>
+                  <extension application="http://www.videolan.org/vlc/playlist/0">
+                    <vlc:option>start-time=90.25</vlc:option>
+                    <vlc:option>stop-time=120</vlc:option>
+                  </extension>
+                </track>
+                <track><title>No location</title></track>
+              </trackList>
+            </playlist>"#,
+        )
+        .unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].location, "file:///music/R%26B/a%20b.flac");
+        assert_eq!(entries[0].title.as_deref(), Some("Rock & Roll é"));
+        assert_eq!(entries[0].artist.as_deref(), Some("AC/DC"));
+        assert_eq!(entries[0].start_ms, Some(90_250));
+
+        assert!(parse_xspf("<playlist><trackList><track></playlist>").is_err());
+    }
+
+    #[test]
+    fn percent_decodes_uri_locations() {
+        let path = |location: &str, uri: bool| location_to_path(location, uri).map(|path| path.to_string_lossy().into_owned());
+        assert_eq!(path("file:///music/My%20Song.flac", false).as_deref(), Some("/music/My Song.flac"));
+        assert_eq!(path("file://localhost/music/a.flac", false).as_deref(), Some("/music/a.flac"));
+        assert_eq!(path("music/%E2%82%AC%2fx.flac", true).as_deref(), Some("music/€/x.flac"));
+        // Other locations are taken as written.
+        assert_eq!(path("music/100%25.flac", false).as_deref(), Some("music/100%25.flac"));
+        assert_eq!(path("https://example.com/a.mp3", true), None);
+        assert_eq!(path("  ", false), None);
+
+        assert_eq!(percent_decode("a%20b%2Fc%2f"), "a b/c/");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%2"), "%2");
+        assert_eq!(percent_decode("%41%"), "A%");
+        assert_eq!(percent_decode("%zz%+1%-1"), "%zz%+1%-1");
+        assert_eq!(percent_decode("%%41"), "%A");
+        assert_eq!(percent_decode("é%20"), "é ");
+        assert_eq!(percent_decode("%FF"), "\u{FFFD}");
+
+        for text in ["/music/R&B/a b+c#1?.flac", "/ünï/cödé €.mp3", "100%/~tilde_-.ogg"] {
+            assert_eq!(percent_decode(&percent_encode(text)), text);
+        }
+        assert_eq!(percent_encode("/a b/é"), "/a%20b/%C3%A9");
+    }
+
+    #[test]
+    fn reads_windows_paths() {
+        let path = location_to_path("Music\\Artist\\a.flac", false).unwrap();
+        assert_eq!(path, PathBuf::from("Music/Artist/a.flac"));
+    }
+
+    #[test]
+    fn tries_trailing_parts_of_a_location_under_each_root() {
+        let roots = [PathBuf::from("/music"), PathBuf::from("/mnt/usb")];
+        let base = Path::new("/lists");
+
+        let candidates = candidate_paths(Path::new("/home/me/Music/Artist/a.flac"), base, &roots);
+        assert_eq!(candidates[0], PathBuf::from("/home/me/Music/Artist/a.flac"));
+        assert!(candidates.contains(&PathBuf::from("/music/home/me/Music/Artist/a.flac")));
+        assert!(candidates.contains(&PathBuf::from("/mnt/usb/Music/Artist/a.flac")));
+        assert_eq!(candidates.last(), Some(&PathBuf::from("/mnt/usb/Artist/a.flac")));
+        // Longer suffixes come first, and never the bare file name.
+        let music = candidates.iter().position(|path| path == Path::new("/music/Music/Artist/a.flac")).unwrap();
+        let artist = candidates.iter().position(|path| path == Path::new("/music/Artist/a.flac")).unwrap();
+        assert!(music < artist);
+        assert!(!candidates.contains(&PathBuf::from("/music/a.flac")));
+
+        // An absolute location of two parts is still looked for under the roots.
+        let candidates = candidate_paths(Path::new("/Artist/a.flac"), base, &roots);
+        assert!(candidates.contains(&PathBuf::from("/music/Artist/a.flac")), "{:?}", candidates);
+
+        // Relative locations are tried against the playlist's folder first.
+        let candidates = candidate_paths(Path::new("../music/./Artist/a.flac"), base, &roots);
+        assert_eq!(candidates[0], PathBuf::from("/music/Artist/a.flac"));
+        let unique: HashSet<_> = candidates.iter().collect();
+        assert_eq!(unique.len(), candidates.len(), "{:?}", candidates);
+
+        let candidates = candidate_paths(Path::new("a.flac"), base, &roots);
+        assert_eq!(candidates, vec![PathBuf::from("/lists/a.flac"), PathBuf::from("/music/a.flac"), PathBuf::from("/mnt/usb/a.flac")]);
+    }
+
+    #[test]
+    fn finds_relative_paths() {
+        let relative = |from: &str, to: &str| relative_path(Path::new(from), Path::new(to));
+        assert_eq!(relative("/music", "/music/a/b.flac"), Some(PathBuf::from("a/b.flac")));
+        assert_eq!(relative("/music/lists", "/music/a/b.flac"), Some(PathBuf::from("../a/b.flac")));
+        assert_eq!(relative("/x/y/z", "/a/b.flac"), Some(PathBuf::from("../../../a/b.flac")));
+        assert_eq!(relative("/music/./lists/..", "/music/a.flac"), Some(PathBuf::from("a.flac")));
+        assert_eq!(relative("/", "/a.flac"), Some(PathBuf::from("a.flac")));
+        assert_eq!(relative("", "/a.flac"), None);
+        assert_eq!(relative("lists", "/a.flac"), None);
+        assert_eq!(relative("/lists", "a.flac"), None);
+    }
+
+    #[test]
+    fn reads_latin1_and_bom_text() {
+        assert_eq!(decode_text(b"\xEF\xBB\xBF/m\xC3\xBAsica"), "/música");
+        assert_eq!(decode_text(b"/m\xFAsica"), "/música");
+    }
+}
@@ -0,0 +1,238 @@
+use crate::{track_from_row_at, LibraryManager, Track, TRACK_SELECT};
+use anyhow::{bail, Result};
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Playlist {
+    pub id: i64,
+    pub name: String,
+    pub track_count: u32,
+    pub duration: u32,
+}
+
+/// One slot in a playlist. The same track may occupy several slots, so
+/// entries are addressed by their own id rather than the track's.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PlaylistEntry {
+    pub id: i64,
+    pub position: u32,
+    pub track: Track,
+}
+
+impl LibraryManager {
+    pub fn create_playlist(&self, name: &str) -> Result<i64> {
+        let now = unix_time();
+        self.conn.execute(
+            "INSERT INTO playlists (name, created_at, updated_at) VALUES (?1, ?2, ?2)",
+            params![name, now],
+        )?;
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    pub fn rename_playlist(&self, playlist_id: i64, name: &str) -> Result<()> {
+        let changed = self.conn.execute(
+            "UPDATE playlists SET name = ?1, updated_at = ?2 WHERE id = ?3",
+            params![name, unix_time(), playlist_id],
+        )?;
+        if changed == 0 {
+            bail!("Playlist {} does not exist", playlist_id);
+        }
+        Ok(())
+    }
+
+    /// Deletes the playlist and its entries; the tracks stay in the library.
+    pub fn delete_playlist(&self, playlist_id: i64) -> Result<()> {
+        let changed = self.conn.execute("DELETE FROM playlists WHERE id = ?1", params![playlist_id])?;
+        if changed == 0 {
+            bail!("Playlist {} does not exist", playlist_id);
+        }
+        Ok(())
+    }
+
+    pub fn get_playlists(&self) -> Result<Vec<Playlist>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT p.id, p.name, COUNT(t.id), COALESCE(SUM(t.duration), 0)
+             FROM playlists p
+             LEFT JOIN playlist_entries e ON e.playlist_id = p.id
+             LEFT JOIN tracks t ON t.id = e.track_id
+             GROUP BY p.id
+             ORDER BY p.name COLLATE NOCASE"
+        )?;
+        let playlists = stmt
+            .query_map([], |row| {
+                Ok(Playlist {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                    track_count: row.get(2)?,
+                    duration: row.get(3)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(playlists)
+    }
+
+    pub fn get_playlist_entries(&self, playlist_id: i64) -> Result<Vec<PlaylistEntry>> {
+        let sql = format!(
+            "SELECT e.id, e.position, t.*
+             FROM playlist_entries e
+             JOIN ({}) t ON t.id = e.track_id
+             WHERE e.playlist_id = ?1
+             ORDER BY e.position",
+            TRACK_SELECT
+        );
+        let mut stmt = self.conn.prepare(&sql)?;
+        let entries = stmt
+            .query_map(params![playlist_id], |row| {
+                Ok(PlaylistEntry {
+                    id: row.get(0)?,
+                    position: row.get(1)?,
+                    track: track_from_row_at(row, 2)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(entries)
+    }
+
+    /// Inserts `track_ids` in order at `position`, or appends them when
+    /// `position` is `None` or past the end. Returns the new entry ids.
+    pub fn add_to_playlist(&self, playlist_id: i64, track_ids: &[i64], position: Option<usize>) -> Result<Vec<i64>> {
+        let tx = self.conn.unchecked_transaction()?;
+        let added = self.insert_playlist_entries(playlist_id, track_ids, position)?;
+        tx.commit()?;
+        Ok(added)
+    }
+
+    /// `add_to_playlist` within a transaction the caller holds.
+    pub(crate) fn insert_playlist_entries(
+        &self,
+        playlist_id: i64,
+        track_ids: &[i64],
+        position: Option<usize>,
+    ) -> Result<Vec<i64>> {
+        let mut order = self.playlist_order(playlist_id)?;
+        let at = position.unwrap_or(order.len()).min(order.len());
+
+        let mut added = Vec::with_capacity(track_ids.len());
+        for track_id in track_ids {
+            self.conn.execute(
+                "INSERT INTO playlist_entries (playlist_id, track_id, position) VALUES (?1, ?2, -1)",
+                params![playlist_id, track_id],
+            )?;
+            added.push(self.conn.last_insert_rowid());
+        }
+        order.splice(at..at, added.iter().copied());
+
+        self.write_playlist_order(playlist_id, &order)?;
+        Ok(added)
+    }
+
+    pub fn remove_from_playlist(&self, playlist_id: i64, entry_ids: &[i64]) -> Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        let mut order = self.playlist_order(playlist_id)?;
+        for entry_id in entry_ids {
+            self.conn.execute(
+                "DELETE FROM playlist_entries WHERE id = ?1 AND playlist_id = ?2",
+                params![entry_id, playlist_id],
+            )?;
+        }
+        order.retain(|id| !entry_ids.contains(id));
+
+        self.write_playlist_order(playlist_id, &order)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Moves one entry to `position`, shifting the entries in between.
+    pub fn move_playlist_entry(&self, playlist_id: i64, entry_id: i64, position: usize) -> Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        let mut order = self.playlist_order(playlist_id)?;
+        let Some(from) = order.iter().position(|id| *id == entry_id) else {
+            bail!("Entry {} is not in playlist {}", entry_id, playlist_id);
+        };
+        let entry = order.remove(from);
+        order.insert(position.min(order.len()), entry);
+
+        self.write_playlist_order(playlist_id, &order)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Replaces the order of the playlist. `entry_ids` must be a permutation
+    /// of its current entries.
+    pub fn reorder_playlist(&self, playlist_id: i64, entry_ids: &[i64]) -> Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        let mut current = self.playlist_order(playlist_id)?;
+        let mut requested = entry_ids.to_vec();
+        current.sort_unstable();
+        requested.sort_unstable();
+        if current != requested {
+            bail!("New order does not match the entries of playlist {}", playlist_id);
+        }
+
+        self.write_playlist_order(playlist_id, entry_ids)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Entry ids of the playlist by position. Fails if it does not exist.
+    fn playlist_order(&self, playlist_id: i64) -> Result<Vec<i64>> {
+        let exists = self.conn.query_row(
+            "SELECT 1 FROM playlists WHERE id = ?1",
+            params![playlist_id],
+            |_| Ok(()),
+        ).optional()?;
+        if exists.is_none() {
+            bail!("Playlist {} does not exist", playlist_id);
+        }
+
+        let mut stmt = self.conn.prepare(
+            "SELECT id FROM playlist_entries WHERE playlist_id = ?1 ORDER BY position, id"
+        )?;
+        let order = stmt
+            .query_map(params![playlist_id], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(order)
+    }
+
+    /// Renumbers entries 0..n in the given order so positions stay dense.
+    fn write_playlist_order(&self, playlist_id: i64, order: &[i64]) -> Result<()> {
+        let mut stmt = self.conn.prepare(
+            "UPDATE playlist_entries SET position = ?1 WHERE id = ?2 AND position != ?1"
+        )?;
+        for (position, entry_id) in order.iter().enumerate() {
+            stmt.execute(params![position as i64, entry_id])?;
+        }
+        self.conn.execute(
+            "UPDATE playlists SET updated_at = ?1 WHERE id = ?2",
+            params![unix_time(), playlist_id],
+        )?;
+        Ok(())
+    }
+}
+
+pub(crate) fn unix_time() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+impl mlua::UserData for Playlist {
+    fn add_fields<'lua, F: mlua::UserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_method_get("id", |_lua, this| Ok(this.id));
+        fields.add_field_method_get("name", |_lua, this| Ok(this.name.clone()));
+        fields.add_field_method_get("track_count", |_lua, this| Ok(this.track_count));
+        fields.add_field_method_get("duration", |_lua, this| Ok(this.duration));
+    }
+}
+
+impl mlua::UserData for PlaylistEntry {
+    fn add_fields<'lua, F: mlua::UserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_method_get("id", |_lua, this| Ok(this.id));
+        // 1-based, like everything else indexed from Lua.
+        fields.add_field_method_get("position", |_lua, this| Ok(this.position + 1));
+        fields.add_field_method_get("track", |_lua, this| Ok(this.track.clone()));
+    }
+}
@@ -0,0 +1,251 @@
+use crate::{file_stamp, LibraryManager};
+use anyhow::{bail, Context, Result};
+use lofty::config::WriteOptions;
+use lofty::file::TaggedFileExt;
+use lofty::id3::v2::{Frame, Id3v2Tag, PopularimeterFrame};
+use lofty::probe::Probe;
+use lofty::tag::{ItemKey, ItemValue, Tag, TagExt, TagItem, TagType};
+use rusqlite::{params, OptionalExtension};
+use std::path::Path;
+
+/// Email of the POPM frame written for new ratings. It is the one Windows
+/// Media Player writes, so most players that read POPM look for it.
+const POPM_EMAIL: &str = "Windows Media Player 9 Series";
+
+/// POPM values for ½ to 5 stars, as written by MusicBee and understood by
+/// most players that support half stars.
+const POPM_HALF_STARS: [u8; 10] = [13, 1, 54, 64, 118, 128, 186, 196, 242, 255];
+
+const FMPS_RATING: &str = "FMPS_RATING";
+const LOVED: &str = "LOVED";
+
+impl LibraryManager {
+    /// Sets a rating of 0 to 5 stars, rounded to the nearest half star, or
+    /// clears it with `None`. With `write_tags` it is saved into the file too.
+    pub fn set_rating(&self, track_id: i64, rating: Option<f32>, write_tags: bool) -> Result<()> {
+        let half_stars = rating.map(half_stars).transpose()?;
+        let changed = self.conn.execute(
+            "UPDATE tracks SET rating = ?1 WHERE id = ?2",
+            params![half_stars, track_id],
+        )?;
+        if changed == 0 {
+            bail!("Track {} does not exist", track_id);
+        }
+        if write_tags {
+            self.write_rating_tags(track_id)?;
+        }
+        Ok(())
+    }
+
+    pub fn set_loved(&self, track_id: i64, loved: bool, write_tags: bool) -> Result<()> {
+        let changed = self.conn.execute(
+            "UPDATE tracks SET loved = ?1 WHERE id = ?2",
+            params![loved, track_id],
+        )?;
+        if changed == 0 {
+            bail!("Track {} does not exist", track_id);
+        }
+        if write_tags {
+            self.write_rating_tags(track_id)?;
+        }
+        Ok(())
+    }
+
+    /// Saves the track's rating and loved flag into its file's primary tag,
+    /// where other players and a rebuilt library will find them. Tracks cut
+    /// from a file by a CUE sheet keep theirs in the library only.
+    pub fn write_rating_tags(&self, track_id: i64) -> Result<()> {
+        let (path, rating, loved, start_ms): (String, Option<u8>, bool, Option<u32>) = self.conn.query_row(
+            "SELECT path, rating, loved, start_ms FROM tracks WHERE id = ?1",
+            params![track_id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+        ).optional()?
+            .with_context(|| format!("Track {} does not exist", track_id))?;
+        if start_ms.is_some() {
+            return Ok(());
+        }
+
+        let path = Path::new(&path);
+        let mut tagged_file = Probe::open(path)?.guess_file_type()?.read()?;
+        let tag_type = tagged_file.primary_tag_type();
+        if tagged_file.tag(tag_type).is_none() {
+            tagged_file.insert_tag(Tag::new(tag_type));
+        }
+        let Some(tag) = tagged_file.tag_mut(tag_type) else {
+            bail!("{:?} cannot hold a {:?} tag", path, tag_type);
+        };
+        let saved = if tag_type == TagType::Id3v2 {
+            let mut id3v2 = Id3v2Tag::from(tag.clone());
+            write_id3v2(&mut id3v2, rating, loved)?;
+            id3v2.save_to_path(path, WriteOptions::default())
+        } else {
+            write_tag(tag, rating, loved);
+            tag.save_to_path(path, WriteOptions::default())
+        };
+        saved.with_context(|| format!("Failed to write tags to {:?}", path))?;
+
+        // Our own write is not a change the next scan needs to pick up.
+        let (mtime, size) = file_stamp(path)?;
+        self.conn.execute(
+            "UPDATE tracks SET mtime = ?1, size = ?2 WHERE id = ?3",
+            params![mtime, size, track_id],
+        )?;
+        Ok(())
+    }
+}
+
+pub(crate) fn half_stars(rating: f32) -> Result<u8> {
+    if !(0.0..=5.0).contains(&rating) {
+        bail!("Rating must be between 0 and 5 stars, got {}", rating);
+    }
+    Ok((rating * 2.0).round() as u8)
+}
+
+/// Rating in half stars (0 to 10) and loved flag, whichever the tag has,
+/// from whichever convention it uses.
+pub(crate) fn read_rating_tags(tag: &Tag) -> (Option<u8>, Option<bool>) {
+    if tag.tag_type() == TagType::Id3v2 {
+        // POPM and TXXX frames are not part of lofty's generic tag.
+        let id3v2 = Id3v2Tag::from(tag.clone());
+        let loved = id3v2.get_user_text(LOVED).and_then(parse_loved);
+        let frames: Vec<_> = id3v2
+            .into_iter()
+            .filter_map(|frame| match frame {
+                Frame::Popularimeter(frame) if frame.rating != 0 => Some(frame),
+                _ => None,
+            })
+            .collect();
+        let rating = frames
+            .iter()
+            .find(|frame| frame.email == POPM_EMAIL)
+            .or_else(|| frames.first())
+            .map(|frame| popm_to_half_stars(frame.rating));
+        return (rating, loved);
+    }
+
+    let percentage = || tag.get_string(&ItemKey::Popularimeter).and_then(parse_rating_text);
+    let rating = if tag.tag_type() == TagType::VorbisComments {
+        // FMPS_RATING is a fraction from 0.0 to 1.0.
+        tag.get_string(&ItemKey::Unknown(FMPS_RATING.to_string()))
+            .and_then(|value| value.trim().parse::<f32>().ok())
+            .filter(|value| (0.0..=1.0).contains(value))
+            .map(|value| (value * 10.0).round() as u8)
+            .or_else(percentage)
+    } else {
+        percentage()
+    };
+    let loved = tag.get_string(&loved_key(tag.tag_type())).and_then(parse_loved);
+    (rating, loved)
+}
+
+fn write_id3v2(tag: &mut Id3v2Tag, rating: Option<u8>, loved: bool) -> Result<()> {
+    // Every player keeps its own POPM frame. Update all of them so none of
+    // them disagrees with the others.
+    let mut frames = Vec::new();
+    tag.retain(|frame| match frame {
+        Frame::Popularimeter(frame) => {
+            frames.push(PopularimeterFrame::new(frame.email.clone(), frame.rating, frame.counter));
+            false
+        }
+        _ => true,
+    });
+    let byte = rating.map(half_stars_to_popm).unwrap_or(0);
+    for frame in &mut frames {
+        frame.rating = byte;
+    }
+    if rating.is_some() && !frames.iter().any(|frame| frame.email == POPM_EMAIL) {
+        frames.push(PopularimeterFrame::new(POPM_EMAIL.to_string(), byte, 0));
+    }
+    // A frame with neither a rating nor a play count says nothing.
+    for frame in frames.into_iter().filter(|frame| frame.rating != 0 || frame.counter != 0) {
+        tag.insert(Frame::Popularimeter(frame));
+    }
+
+    if loved {
+        tag.insert_user_text(LOVED.to_string(), "1".to_string());
+    } else {
+        tag.remove_user_text(LOVED);
+    }
+    Ok(())
+}
+
+fn write_tag(tag: &mut Tag, rating: Option<u8>, loved: bool) {
+    let fmps = ItemKey::Unknown(FMPS_RATING.to_string());
+    match rating {
+        Some(half_stars) => {
+            if tag.tag_type() == TagType::VorbisComments {
+                tag.insert_unchecked(TagItem::new(
+                    fmps,
+                    ItemValue::Text(format!("{:.1}", half_stars as f32 / 10.0)),
+                ));
+            }
+            // Vorbis `RATING`, MP4 `rate` and APE `Rating` hold a percentage.
+            tag.insert_text(ItemKey::Popularimeter, (half_stars as u32 * 10).to_string());
+        }
+        None => {
+            tag.remove_key(&fmps);
+            tag.remove_key(&ItemKey::Popularimeter);
+        }
+    }
+
+    let key = loved_key(tag.tag_type());
+    if loved {
+        tag.insert_unchecked(TagItem::new(key, ItemValue::Text("1".to_string())));
+    } else {
+        tag.remove_key(&key);
+    }
+}
+
+/// There is no standard field for favourites, so a plain `LOVED` field is
+/// used, stored as a freeform atom in MP4.
+fn loved_key(tag_type: TagType) -> ItemKey {
+    match tag_type {
+        TagType::Mp4Ilst => ItemKey::Unknown(format!("----:com.apple.iTunes:{}", LOVED)),
+        _ => ItemKey::Unknown(LOVED.to_string()),
+    }
+}
+
+fn parse_loved(value: &str) -> Option<bool> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" => Some(true),
+        "0" | "false" | "no" | "" => Some(false),
+        _ => None,
+    }
+}
+
+fn half_stars_to_popm(half_stars: u8) -> u8 {
+    match half_stars {
+        0 => 0,
+        n => POPM_HALF_STARS[(n.min(10) - 1) as usize],
+    }
+}
+
+/// Exact MusicBee values map back to half stars; anything else falls into
+/// Windows Media Player's whole-star ranges.
+fn popm_to_half_stars(byte: u8) -> u8 {
+    if let Some(i) = POPM_HALF_STARS.iter().position(|value| *value == byte) {
+        return i as u8 + 1;
+    }
+    match byte {
+        0 => 0,
+        1..=31 => 2,
+        32..=95 => 4,
+        96..=159 => 6,
+        160..=223 => 8,
+        _ => 10,
+    }
+}
+
+/// Text ratings are a percentage, a 0 to 5 star count, or a fraction of 1.
+fn parse_rating_text(text: &str) -> Option<u8> {
+    let text = text.trim();
+    let value: f32 = text.parse().ok()?;
+    let half_stars = if value > 5.0 {
+        value / 10.0
+    } else if value <= 1.0 && text.contains('.') {
+        value * 10.0
+    } else {
+        value * 2.0
+    };
+    Some(half_stars.round().clamp(0.0, 10.0) as u8)
+}
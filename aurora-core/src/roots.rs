@@ -0,0 +1,369 @@
+use crate::scan::is_audio_file;
+use crate::LibraryManager;
+use anyhow::{bail, Context, Result};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf, MAIN_SEPARATOR};
+
+/// A folder the library is built from, and the rules for which files below
+/// it belong to the library.
+///
+/// ```text
+/// let root = LibraryRoot::new("/mnt/music").exclude("**/Podcasts").min_size(64 * 1024);
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LibraryRoot {
+    pub path: PathBuf,
+    /// Globs matched against paths relative to the root, such as
+    /// "**/*.flac". `*` also matches across folders. Empty includes every
+    /// audio file.
+    pub include: Vec<String>,
+    /// Globs for files and folders to leave out, checked before `include`.
+    pub exclude: Vec<String>,
+    pub follow_symlinks: bool,
+    /// Whether to look at dot-files and into dot-folders.
+    pub include_hidden: bool,
+    /// Bytes. Smaller files are skipped.
+    pub min_size: u64,
+    /// Whether the folder was there at the last check. Tracks below an
+    /// offline root stay in the library, marked offline.
+    pub online: bool,
+}
+
+impl LibraryRoot {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            include: Vec::new(),
+            exclude: Vec::new(),
+            follow_symlinks: true,
+            include_hidden: false,
+            min_size: 0,
+            online: true,
+        }
+    }
+
+    pub fn include(mut self, pattern: &str) -> Self {
+        self.include.push(pattern.to_string());
+        self
+    }
+
+    pub fn exclude(mut self, pattern: &str) -> Self {
+        self.exclude.push(pattern.to_string());
+        self
+    }
+
+    pub fn follow_symlinks(mut self, follow: bool) -> Self {
+        self.follow_symlinks = follow;
+        self
+    }
+
+    pub fn include_hidden(mut self, include: bool) -> Self {
+        self.include_hidden = include;
+        self
+    }
+
+    pub fn min_size(mut self, bytes: u64) -> Self {
+        self.min_size = bytes;
+        self
+    }
+}
+
+/// A root's rules, compiled for walking the folder or checking single paths
+/// reported by the watcher.
+pub(crate) struct RootFilter {
+    root: PathBuf,
+    /// Roots further down, which their own rules and scans cover.
+    nested: Vec<PathBuf>,
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+    pub(crate) follow_symlinks: bool,
+    include_hidden: bool,
+    min_size: u64,
+}
+
+impl RootFilter {
+    fn new(root: &LibraryRoot, nested: Vec<PathBuf>) -> Result<Self> {
+        let include = if root.include.is_empty() {
+            None
+        } else {
+            Some(glob_set(&root.include)?)
+        };
+        Ok(Self {
+            root: root.path.clone(),
+            nested,
+            include,
+            exclude: glob_set(&root.exclude)?,
+            follow_symlinks: root.follow_symlinks,
+            include_hidden: root.include_hidden,
+            min_size: root.min_size,
+        })
+    }
+
+    /// Whether to walk into `dir`.
+    pub(crate) fn admits_dir(&self, dir: &Path) -> bool {
+        self.visible(dir)
+    }
+
+    /// Whether a CUE sheet or LRC file at `path` may be used. Only the
+    /// exclude and hidden rules apply: include globs and `min_size` are
+    /// written with audio files in mind and would drop every sidecar.
+    pub(crate) fn admits_sidecar(&self, path: &Path) -> bool {
+        self.visible(path)
+    }
+
+    pub(crate) fn in_nested_root(&self, path: &Path) -> bool {
+        self.nested.iter().any(|root| path.starts_with(root))
+    }
+
+    /// Whether `path` is an audio file this root takes in.
+    pub(crate) fn admits_file(&self, path: &Path) -> bool {
+        self.admits_path(path) && is_audio_file(path)
+    }
+
+    /// Whether the rules let `path` in, whatever kind of file it is. Checks
+    /// the folders above it too, since the watcher reports files without
+    /// walking to them.
+    pub(crate) fn admits_path(&self, path: &Path) -> bool {
+        if !self.visible(path) {
+            return false;
+        }
+        let relative = path.strip_prefix(&self.root).unwrap_or(path);
+        if let Some(include) = &self.include {
+            if !include.is_match(relative) {
+                return false;
+            }
+        }
+        self.min_size == 0 || std::fs::metadata(path).is_ok_and(|m| m.len() >= self.min_size)
+    }
+
+    /// Neither hidden, excluded nor below a nested root.
+    fn visible(&self, path: &Path) -> bool {
+        let relative = path.strip_prefix(&self.root).unwrap_or(path);
+        !self.hidden(relative) && !self.excluded(relative) && !self.in_nested_root(path)
+    }
+
+    fn hidden(&self, relative: &Path) -> bool {
+        !self.include_hidden
+            && relative
+                .components()
+                .any(|c| c.as_os_str().to_str().is_some_and(|name| name.starts_with('.')))
+    }
+
+    fn excluded(&self, relative: &Path) -> bool {
+        relative
+            .ancestors()
+            .filter(|p| !p.as_os_str().is_empty())
+            .any(|p| self.exclude.is_match(p))
+    }
+}
+
+fn glob_set(patterns: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern).with_context(|| format!("Invalid pattern {:?}", pattern))?);
+    }
+    Ok(builder.build()?)
+}
+
+/// `SELECT` list for `root_from_row`.
+const ROOT_COLUMNS: &str = "path, include, exclude, follow_symlinks, include_hidden, min_size, online";
+
+fn root_from_row(row: &rusqlite::Row) -> rusqlite::Result<LibraryRoot> {
+    let patterns = |i: usize| -> rusqlite::Result<Vec<String>> {
+        let json: String = row.get(i)?;
+        serde_json::from_str(&json)
+            .map_err(|e| rusqlite::Error::FromSqlConversionFailure(i, rusqlite::types::Type::Text, Box::new(e)))
+    };
+    Ok(LibraryRoot {
+        path: PathBuf::from(row.get::<_, String>(0)?),
+        include: patterns(1)?,
+        exclude: patterns(2)?,
+        follow_symlinks: row.get(3)?,
+        include_hidden: row.get(4)?,
+        min_size: row.get::<_, i64>(5)? as u64,
+        online: row.get(6)?,
+    })
+}
+
+/// SQL matching `path` and everything below it, given `?1` as the exact
+/// path and `?2` as the path with a trailing separator.
+const UNDER_PATH: &str = "(path = ?1 OR substr(path, 1, length(?2)) = ?2)";
+
+fn under_path_params(path: &Path) -> (String, String) {
+    let exact = path.to_string_lossy().into_owned();
+    let prefix = format!("{}{}", exact, MAIN_SEPARATOR);
+    (exact, prefix)
+}
+
+impl LibraryManager {
+    /// Ids and paths of the tracks at `path` or below it.
+    pub(crate) fn tracks_under(&self, path: &Path) -> Result<Vec<(i64, String)>> {
+        let (exact, prefix) = under_path_params(path);
+        let mut stmt = self.conn.prepare(&format!("SELECT id, path FROM tracks WHERE {}", UNDER_PATH))?;
+        let tracks = stmt
+            .query_map(params![exact, prefix], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(tracks)
+    }
+
+    /// Adds a root, or replaces the rules of one already there. The root is
+    /// not scanned until the next `scan_directory` or `scan_library`.
+    pub fn add_library_root(&self, root: &LibraryRoot) -> Result<()> {
+        // Reject bad patterns now rather than at the next scan.
+        RootFilter::new(root, Vec::new())?;
+        self.conn.execute(
+            "INSERT INTO library_roots (path, include, exclude, follow_symlinks, include_hidden, min_size)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+             ON CONFLICT(path) DO UPDATE SET
+                include = excluded.include,
+                exclude = excluded.exclude,
+                follow_symlinks = excluded.follow_symlinks,
+                include_hidden = excluded.include_hidden,
+                min_size = excluded.min_size",
+            params![
+                root.path.to_string_lossy(),
+                serde_json::to_string(&root.include)?,
+                serde_json::to_string(&root.exclude)?,
+                root.follow_symlinks,
+                root.include_hidden,
+                root.min_size as i64,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Forgets a root along with its tracks, apart from those that also sit
+    /// below another root. Returns how many tracks were removed.
+    pub fn remove_library_root(&self, path: &Path) -> Result<u32> {
+        let tx = self.conn.unchecked_transaction()?;
+        let removed = self.conn.execute(
+            "DELETE FROM library_roots WHERE path = ?1",
+            params![path.to_string_lossy()],
+        )?;
+        if removed == 0 {
+            bail!("Library root {:?} does not exist", path);
+        }
+
+        let mut count = 0;
+        for (id, track_path) in self.tracks_under(path)? {
+            if self.root_containing(Path::new(&track_path))?.is_none() {
+                self.delete_track(id)?;
+                count += 1;
+            }
+        }
+        self.remove_orphans()?;
+        tx.commit()?;
+        Ok(count)
+    }
+
+    /// Every root, in the order they were added.
+    pub fn get_library_roots(&self) -> Result<Vec<LibraryRoot>> {
+        let mut stmt = self.conn.prepare(&format!("SELECT {} FROM library_roots ORDER BY rowid", ROOT_COLUMNS))?;
+        let roots = stmt
+            .query_map([], root_from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(roots)
+    }
+
+    /// Paths of every root.
+    pub fn library_roots(&self) -> Result<Vec<PathBuf>> {
+        Ok(self.get_library_roots()?.into_iter().map(|root| root.path).collect())
+    }
+
+    pub fn get_library_root(&self, path: &Path) -> Result<Option<LibraryRoot>> {
+        let root = self.conn.query_row(
+            &format!("SELECT {} FROM library_roots WHERE path = ?1", ROOT_COLUMNS),
+            params![path.to_string_lossy()],
+            root_from_row,
+        ).optional()?;
+        Ok(root)
+    }
+
+    /// The innermost root that `path` is in or at.
+    pub(crate) fn root_containing(&self, path: &Path) -> Result<Option<LibraryRoot>> {
+        let root = self
+            .get_library_roots()?
+            .into_iter()
+            .filter(|root| path.starts_with(&root.path))
+            .max_by_key(|root| root.path.components().count());
+        Ok(root)
+    }
+
+    pub(crate) fn root_filter(&self, root: &LibraryRoot) -> Result<RootFilter> {
+        let nested = self
+            .library_roots()?
+            .into_iter()
+            .filter(|path| path != &root.path && path.starts_with(&root.path))
+            .collect();
+        RootFilter::new(root, nested)
+    }
+
+    /// Looks at every root on disk and marks roots, and the tracks below
+    /// them, offline or back online as needed. Returns the roots as they
+    /// are now.
+    pub fn check_library_roots(&self) -> Result<Vec<LibraryRoot>> {
+        let mut roots = self.get_library_roots()?;
+        for root in &mut roots {
+            root.online = self.check_root(root)?;
+        }
+        Ok(roots)
+    }
+
+    /// Whether `root` is there now, updating its flags if that changed.
+    pub(crate) fn check_root(&self, root: &LibraryRoot) -> Result<bool> {
+        let online = self.root_available(&root.path)?;
+        if online != root.online {
+            self.set_root_online(&root.path, online)?;
+        }
+        Ok(online)
+    }
+
+    /// Whether `root` can be scanned. The mount point of an unmounted drive
+    /// is usually left behind as an empty folder, so an empty root that
+    /// still has tracks in the library counts as missing too.
+    fn root_available(&self, root: &Path) -> Result<bool> {
+        if !root.is_dir() {
+            return Ok(false);
+        }
+        let empty = std::fs::read_dir(root).map(|mut entries| entries.next().is_none()).unwrap_or(true);
+        if !empty {
+            return Ok(true);
+        }
+        let (exact, prefix) = under_path_params(root);
+        let has_tracks: bool = self.conn.query_row(
+            &format!("SELECT EXISTS(SELECT 1 FROM tracks WHERE {})", UNDER_PATH),
+            params![exact, prefix],
+            |row| row.get(0),
+        )?;
+        Ok(!has_tracks)
+    }
+
+    fn set_root_online(&self, root: &Path, online: bool) -> Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        self.conn.execute(
+            "UPDATE library_roots SET online = ?2 WHERE path = ?1",
+            params![root.to_string_lossy(), online],
+        )?;
+        let (exact, prefix) = under_path_params(root);
+        self.conn.execute(
+            &format!("UPDATE tracks SET offline = ?3 WHERE {}", UNDER_PATH),
+            params![exact, prefix, !online],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+impl mlua::UserData for LibraryRoot {
+    fn add_fields<'lua, F: mlua::UserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_method_get("path", |_lua, this| Ok(this.path.to_string_lossy().into_owned()));
+        fields.add_field_method_get("include", |_lua, this| Ok(this.include.clone()));
+        fields.add_field_method_get("exclude", |_lua, this| Ok(this.exclude.clone()));
+        fields.add_field_method_get("follow_symlinks", |_lua, this| Ok(this.follow_symlinks));
+        fields.add_field_method_get("include_hidden", |_lua, this| Ok(this.include_hidden));
+        fields.add_field_method_get("min_size", |_lua, this| Ok(this.min_size));
+        fields.add_field_method_get("online", |_lua, this| Ok(this.online));
+    }
+}
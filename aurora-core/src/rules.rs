@@ -0,0 +1,1128 @@
+use crate::browse::NATURAL_ORDER;
+use anyhow::{bail, Result};
+use rusqlite::types::Value as SqlValue;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// A track attribute a rule can test or a smart playlist can sort by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Field {
+    Title,
+    Artist,
+    Album,
+    AlbumArtist,
+    Compilation,
+    Genre,
+    Composer,
+    Label,
+    Codec,
+    Path,
+    Year,
+    /// Seconds.
+    Duration,
+    TrackNumber,
+    DiscNumber,
+    /// kbps.
+    Bitrate,
+    /// Hz.
+    SampleRate,
+    BitDepth,
+    /// Stars, 0 to 5.
+    Rating,
+    Loved,
+    PlayCount,
+    SkipCount,
+    LastPlayed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Text,
+    Number,
+    Bool,
+    Date,
+}
+
+impl Field {
+    const ALL: [Field; 22] = [
+        Field::Title,
+        Field::Artist,
+        Field::Album,
+        Field::AlbumArtist,
+        Field::Compilation,
+        Field::Genre,
+        Field::Composer,
+        Field::Label,
+        Field::Codec,
+        Field::Path,
+        Field::Year,
+        Field::Duration,
+        Field::TrackNumber,
+        Field::DiscNumber,
+        Field::Bitrate,
+        Field::SampleRate,
+        Field::BitDepth,
+        Field::Rating,
+        Field::Loved,
+        Field::PlayCount,
+        Field::SkipCount,
+        Field::LastPlayed,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Field::Title => "title",
+            Field::Artist => "artist",
+            Field::Album => "album",
+            Field::AlbumArtist => "album_artist",
+            Field::Compilation => "compilation",
+            Field::Genre => "genre",
+            Field::Composer => "composer",
+            Field::Label => "label",
+            Field::Codec => "codec",
+            Field::Path => "path",
+            Field::Year => "year",
+            Field::Duration => "duration",
+            Field::TrackNumber => "track_number",
+            Field::DiscNumber => "disc_number",
+            Field::Bitrate => "bitrate",
+            Field::SampleRate => "sample_rate",
+            Field::BitDepth => "bit_depth",
+            Field::Rating => "rating",
+            Field::Loved => "loved",
+            Field::PlayCount => "play_count",
+            Field::SkipCount => "skip_count",
+            Field::LastPlayed => "last_played",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|field| field.name().eq_ignore_ascii_case(name))
+    }
+
+    fn kind(self) -> Kind {
+        match self {
+            Field::Title | Field::Artist | Field::Album
+            | Field::AlbumArtist
+            | Field::Genre
+            | Field::Composer
+            | Field::Label
+            | Field::Codec
+            | Field::Path => Kind::Text,
+            Field::Year
+            | Field::Duration
+            | Field::TrackNumber
+            | Field::DiscNumber
+            | Field::Bitrate
+            | Field::SampleRate
+            | Field::BitDepth
+            | Field::Rating
+            | Field::PlayCount
+            | Field::SkipCount => Kind::Number,
+            Field::Loved | Field::Compilation => Kind::Bool,
+            Field::LastPlayed => Kind::Date,
+        }
+    }
+
+    /// For fields a track can have several values of, a query for the ids of
+    /// tracks with each value, the value being `n.name`. Conditions on these
+    /// fields test every value rather than the text the tags held.
+    fn values(self) -> Option<&'static str> {
+        match self {
+            Field::Artist => Some(
+                "SELECT ta.track_id FROM track_artists ta JOIN artists n ON n.id = ta.artist_id
+                 WHERE ta.role != 'composer'",
+            ),
+            Field::Composer => Some(
+                "SELECT ta.track_id FROM track_artists ta JOIN artists n ON n.id = ta.artist_id
+                 WHERE ta.role = 'composer'",
+            ),
+            Field::Genre => Some(
+                "SELECT tg.track_id FROM track_genres tg JOIN genres n ON n.id = tg.genre_id WHERE 1",
+            ),
+            _ => None,
+        }
+    }
+
+    /// SQL for the field, given `TRACK_SELECT` joined with `track_play_stats s`.
+    fn column(self) -> &'static str {
+        match self {
+            Field::Title => "t.title",
+            Field::Artist => "ar.name",
+            Field::Album => "al.title",
+            Field::AlbumArtist => "aa.name",
+            Field::Compilation => "al.compilation",
+            Field::Genre => "t.genre",
+            Field::Composer => "t.composer",
+            Field::Label => "t.label",
+            Field::Codec => "t.codec",
+            Field::Path => "t.path",
+            Field::Year => "t.year",
+            Field::Duration => "t.duration",
+            Field::TrackNumber => "t.track_number",
+            Field::DiscNumber => "t.disc_number",
+            Field::Bitrate => "t.bitrate",
+            Field::SampleRate => "t.sample_rate",
+            Field::BitDepth => "t.bit_depth",
+            Field::Rating => "t.rating",
+            Field::Loved => "t.loved",
+            Field::PlayCount => "COALESCE(s.play_count, 0)",
+            Field::SkipCount => "COALESCE(s.skip_count, 0)",
+            Field::LastPlayed => "s.last_played",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Value {
+    Bool(bool),
+    Number(f64),
+    Text(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", content = "value", rename_all = "snake_case")]
+pub enum Op {
+    Is(Value),
+    IsNot(Value),
+    Contains(String),
+    NotContains(String),
+    StartsWith(String),
+    EndsWith(String),
+    Lt(f64),
+    Le(f64),
+    Gt(f64),
+    Ge(f64),
+    /// Inclusive at both ends.
+    Between(f64, f64),
+    /// Within the last number of days.
+    InLast(u32),
+    /// Not within the last number of days, including never.
+    NotInLast(u32),
+}
+
+/// One test on one field, e.g. `{"field": "genre", "op": "is", "value": "Jazz"}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Condition {
+    pub field: Field,
+    #[serde(flatten)]
+    pub op: Op,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Rule {
+    All(Vec<Rule>),
+    Any(Vec<Rule>),
+    Not(Box<Rule>),
+    #[serde(untagged)]
+    Condition(Condition),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SortKey {
+    pub field: Field,
+    #[serde(default)]
+    pub descending: bool,
+}
+
+/// What a smart playlist holds: the tracks matching `rule` (all of them if
+/// there is none), ordered by `sort` and cut off after `limit`.
+///
+/// Stored as JSON; the same thing can be written as text, e.g.
+///
+/// ```text
+/// genre is "Jazz" and year between 1955 and 1965 and (rating >= 4 or loved)
+///     and last_played not in last 30 days
+///     sort by play_count desc, title limit 50
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SmartQuery {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rule: Option<Rule>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sort: Vec<SortKey>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u32>,
+}
+
+impl SmartQuery {
+    /// Compiles to the SQL that follows the `FROM` clause joins: `WHERE`,
+    /// `ORDER BY` and `LIMIT`, with every value passed as a parameter.
+    /// `now` anchors relative dates such as "in last 30 days".
+    pub(crate) fn to_sql(&self, now: i64) -> Result<(String, Vec<SqlValue>)> {
+        let mut params = Vec::new();
+        let mut sql = String::new();
+
+        if let Some(rule) = &self.rule {
+            sql.push_str(" WHERE ");
+            sql.push_str(&compile_rule(rule, now, &mut params)?);
+        }
+
+        sql.push_str(" ORDER BY ");
+        sql.push_str(&order_by(&self.sort));
+
+        if let Some(limit) = self.limit {
+            params.push(SqlValue::Integer(limit as i64));
+            sql.push_str(&format!(" LIMIT ?{}", params.len()));
+        }
+        Ok((sql, params))
+    }
+}
+
+/// `ORDER BY` terms for `sort`, ending with the track id so ties keep a
+/// stable order. Text is compared naturally, see `natural_cmp`.
+pub(crate) fn order_by(sort: &[SortKey]) -> String {
+    let mut order: Vec<String> = sort
+        .iter()
+        .map(|key| {
+            let collate = if key.field.kind() == Kind::Text { format!(" COLLATE {}", NATURAL_ORDER) } else { String::new() };
+            let direction = if key.descending { " DESC" } else { "" };
+            format!("{}{}{}", key.field.column(), collate, direction)
+        })
+        .collect();
+    order.push("t.id".to_string());
+    order.join(", ")
+}
+
+fn compile_rule(rule: &Rule, now: i64, params: &mut Vec<SqlValue>) -> Result<String> {
+    let join = |rules: &[Rule], operator: &str, empty: &str, params: &mut Vec<SqlValue>| -> Result<String> {
+        if rules.is_empty() {
+            return Ok(empty.to_string());
+        }
+        let parts = rules
+            .iter()
+            .map(|rule| compile_rule(rule, now, params))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(format!("({})", parts.join(operator)))
+    };
+
+    match rule {
+        Rule::All(rules) => join(rules, " AND ", "1", params),
+        Rule::Any(rules) => join(rules, " OR ", "0", params),
+        Rule::Not(rule) => Ok(format!("NOT ({})", compile_rule(rule, now, params)?)),
+        Rule::Condition(condition) => compile_condition(condition, now, params),
+    }
+}
+
+fn compile_condition(condition: &Condition, now: i64, params: &mut Vec<SqlValue>) -> Result<String> {
+    let Condition { field, op } = condition;
+    if let Some(values) = field.values() {
+        // "is not" and "not contains" hold when no value matches, not when
+        // any value differs.
+        let (op, negated) = match op {
+            Op::IsNot(value) => (Op::Is(value.clone()), true),
+            Op::NotContains(text) => (Op::Contains(text.clone()), true),
+            op => (op.clone(), false),
+        };
+        let test = compile_test(*field, "n.name", &op, now, params)?;
+        let not = if negated { "NOT " } else { "" };
+        return Ok(format!("t.id {}IN ({} AND {})", not, values, test));
+    }
+    compile_test(*field, field.column(), op, now, params)
+}
+
+/// `op` applied to `column`, which holds `field`.
+fn compile_test(field: Field, column: &str, op: &Op, now: i64, params: &mut Vec<SqlValue>) -> Result<String> {
+    let mut param = |value: SqlValue| {
+        params.push(value);
+        format!("?{}", params.len())
+    };
+
+    let sql = match (field.kind(), op) {
+        (Kind::Text, Op::Is(value)) => format!("{} = {} COLLATE NOCASE", column, param(text(value).into())),
+        (Kind::Text, Op::IsNot(value)) => format!("{} IS NOT {} COLLATE NOCASE", column, param(text(value).into())),
+        (Kind::Text, Op::Contains(text)) => {
+            format!("{} LIKE {} ESCAPE '\\'", column, param(format!("%{}%", escape_like(text)).into()))
+        }
+        (Kind::Text, Op::NotContains(text)) => {
+            format!("COALESCE({}, '') NOT LIKE {} ESCAPE '\\'", column, param(format!("%{}%", escape_like(text)).into()))
+        }
+        (Kind::Text, Op::StartsWith(text)) => {
+            format!("{} LIKE {} ESCAPE '\\'", column, param(format!("{}%", escape_like(text)).into()))
+        }
+        (Kind::Text, Op::EndsWith(text)) => {
+            format!("{} LIKE {} ESCAPE '\\'", column, param(format!("%{}", escape_like(text)).into()))
+        }
+
+        (Kind::Number, Op::Is(value)) => format!("{} = {}", column, param(number(field, value)?.into())),
+        (Kind::Number, Op::IsNot(value)) => format!("{} IS NOT {}", column, param(number(field, value)?.into())),
+        (Kind::Number, Op::Lt(n)) => format!("{} < {}", column, param(scale(field, *n).into())),
+        (Kind::Number, Op::Le(n)) => format!("{} <= {}", column, param(scale(field, *n).into())),
+        (Kind::Number, Op::Gt(n)) => format!("{} > {}", column, param(scale(field, *n).into())),
+        (Kind::Number, Op::Ge(n)) => format!("{} >= {}", column, param(scale(field, *n).into())),
+        (Kind::Number, Op::Between(a, b)) => {
+            let low = param(scale(field, a.min(*b)).into());
+            let high = param(scale(field, a.max(*b)).into());
+            format!("{} BETWEEN {} AND {}", column, low, high)
+        }
+
+        (Kind::Bool, Op::Is(value)) => format!("{} = {}", column, param(boolean(field, value)?.into())),
+        (Kind::Bool, Op::IsNot(value)) => format!("{} != {}", column, param(boolean(field, value)?.into())),
+
+        (Kind::Date, Op::InLast(days)) => format!("{} >= {}", column, param(since(now, *days).into())),
+        (Kind::Date, Op::NotInLast(days)) => {
+            format!("({0} IS NULL OR {0} < {1})", column, param(since(now, *days).into()))
+        }
+
+        _ => bail!("`{}` cannot be used with {}", op.keyword(), field.name()),
+    };
+    Ok(sql)
+}
+
+fn text(value: &Value) -> String {
+    match value {
+        Value::Text(text) => text.clone(),
+        Value::Number(n) => n.to_string(),
+        Value::Bool(b) => b.to_string(),
+    }
+}
+
+fn number(field: Field, value: &Value) -> Result<f64> {
+    let n = match value {
+        Value::Number(n) => *n,
+        Value::Text(text) => match text.trim().parse() {
+            Ok(n) => n,
+            Err(_) => bail!("{} needs a number, got {:?}", field.name(), text),
+        },
+        Value::Bool(_) => bail!("{} needs a number, got {}", field.name(), text(value)),
+    };
+    Ok(scale(field, n))
+}
+
+/// Ratings are given in stars but stored in half stars.
+fn scale(field: Field, n: f64) -> f64 {
+    if field == Field::Rating {
+        n * 2.0
+    } else {
+        n
+    }
+}
+
+fn boolean(field: Field, value: &Value) -> Result<bool> {
+    match value {
+        Value::Bool(b) => Ok(*b),
+        Value::Number(n) => Ok(*n != 0.0),
+        Value::Text(text) => match text.to_ascii_lowercase().as_str() {
+            "true" | "yes" => Ok(true),
+            "false" | "no" => Ok(false),
+            _ => bail!("{} is either true or false, got {:?}", field.name(), text),
+        },
+    }
+}
+
+fn since(now: i64, days: u32) -> i64 {
+    now - days as i64 * 86_400
+}
+
+fn escape_like(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
+}
+
+impl Op {
+    fn keyword(&self) -> &'static str {
+        match self {
+            Op::Is(_) => "is",
+            Op::IsNot(_) => "is not",
+            Op::Contains(_) => "contains",
+            Op::NotContains(_) => "not contains",
+            Op::StartsWith(_) => "starts with",
+            Op::EndsWith(_) => "ends with",
+            Op::Lt(_) => "<",
+            Op::Le(_) => "<=",
+            Op::Gt(_) => ">",
+            Op::Ge(_) => ">=",
+            Op::Between(..) => "between",
+            Op::InLast(_) => "in last",
+            Op::NotInLast(_) => "not in last",
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Number(n) => write!(f, "{}", n),
+            Value::Text(text) => write_quoted(f, text),
+        }
+    }
+}
+
+fn write_quoted(f: &mut fmt::Formatter<'_>, text: &str) -> fmt::Result {
+    write!(f, "\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} ", self.field.name(), self.op.keyword())?;
+        match &self.op {
+            Op::Is(value) | Op::IsNot(value) => write!(f, "{}", value),
+            Op::Contains(text) | Op::NotContains(text) | Op::StartsWith(text) | Op::EndsWith(text) => {
+                write_quoted(f, text)
+            }
+            Op::Lt(n) | Op::Le(n) | Op::Gt(n) | Op::Ge(n) => write!(f, "{}", n),
+            Op::Between(a, b) => write!(f, "{} and {}", a, b),
+            Op::InLast(days) | Op::NotInLast(days) => write!(f, "{} days", days),
+        }
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Parenthesise anything but a single condition when it is nested.
+        fn nested(f: &mut fmt::Formatter<'_>, rule: &Rule) -> fmt::Result {
+            match rule {
+                Rule::Condition(_) | Rule::Not(_) => write!(f, "{}", rule),
+                _ => write!(f, "({})", rule),
+            }
+        }
+        let list = |f: &mut fmt::Formatter<'_>, rules: &[Rule], separator: &str| -> fmt::Result {
+            for (i, rule) in rules.iter().enumerate() {
+                if i > 0 {
+                    f.write_str(separator)?;
+                }
+                nested(f, rule)?;
+            }
+            Ok(())
+        };
+
+        match self {
+            Rule::All(rules) if rules.is_empty() => f.write_str("()"),
+            Rule::Any(rules) if rules.is_empty() => f.write_str("not ()"),
+            Rule::All(rules) => list(f, rules, " and "),
+            Rule::Any(rules) => list(f, rules, " or "),
+            Rule::Not(rule) => {
+                f.write_str("not ")?;
+                nested(f, rule)
+            }
+            Rule::Condition(condition) => write!(f, "{}", condition),
+        }
+    }
+}
+
+impl fmt::Display for SmartQuery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(rule) = &self.rule {
+            parts.push(rule.to_string());
+        }
+        if !self.sort.is_empty() {
+            let keys: Vec<String> = self
+                .sort
+                .iter()
+                .map(|key| {
+                    let direction = if key.descending { " desc" } else { "" };
+                    format!("{}{}", key.field.name(), direction)
+                })
+                .collect();
+            parts.push(format!("sort by {}", keys.join(", ")));
+        }
+        if let Some(limit) = self.limit {
+            parts.push(format!("limit {}", limit));
+        }
+        f.write_str(&parts.join(" "))
+    }
+}
+
+impl FromStr for SmartQuery {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        Parser { tokens: tokenize(text)?, pos: 0 }.query()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Number(f64),
+    Text(String),
+    Symbol(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Word(word) => write!(f, "`{}`", word),
+            Token::Number(n) => write!(f, "`{}`", n),
+            Token::Text(text) => write!(f, "{:?}", text),
+            Token::Symbol(symbol) => write!(f, "`{}`", symbol),
+        }
+    }
+}
+
+const SYMBOLS: [(&str, &str); 11] = [
+    ("<=", "<="),
+    (">=", ">="),
+    ("!=", "!="),
+    ("≤", "<="),
+    ("≥", ">="),
+    ("≠", "!="),
+    ("<", "<"),
+    (">", ">"),
+    ("=", "="),
+    ("(", "("),
+    (")", ")"),
+];
+
+fn tokenize(text: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut rest = text;
+    loop {
+        rest = rest.trim_start();
+        let Some(c) = rest.chars().next() else {
+            break;
+        };
+
+        if let Some((symbol, token)) = SYMBOLS.iter().find(|(symbol, _)| rest.starts_with(symbol)) {
+            tokens.push(Token::Symbol(token));
+            rest = &rest[symbol.len()..];
+        } else if c == ',' {
+            tokens.push(Token::Symbol(","));
+            rest = &rest[1..];
+        } else if c == '"' {
+            let mut value = String::new();
+            let mut chars = rest[1..].char_indices();
+            let end = loop {
+                match chars.next() {
+                    Some((i, '"')) => break i + 2,
+                    Some((_, '\\')) => {
+                        if let Some((_, escaped)) = chars.next() {
+                            value.push(escaped);
+                        }
+                    }
+                    Some((_, c)) => value.push(c),
+                    None => bail!("Unterminated string starting at {:?}", rest),
+                }
+            };
+            tokens.push(Token::Text(value));
+            rest = &rest[end..];
+        } else {
+            let end = rest
+                .find(|c: char| c.is_whitespace() || c == ',' || c == '"' || "()=<>!≤≥≠".contains(c))
+                .unwrap_or(rest.len());
+            if end == 0 {
+                bail!("Unexpected {:?}", c);
+            }
+            let word = &rest[..end];
+            tokens.push(match word.parse::<f64>() {
+                Ok(n) if n.is_finite() => Token::Number(n),
+                _ => Token::Word(word.to_string()),
+            });
+            rest = &rest[end..];
+        }
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent over the text syntax. `and` binds tighter than `or`,
+/// as in SQL.
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn query(mut self) -> Result<SmartQuery> {
+        let mut query = SmartQuery::default();
+        if !self.at_end() && !self.peek_word("sort") && !self.peek_word("limit") {
+            query.rule = Some(self.or()?);
+        }
+
+        if self.eat_word("sort") {
+            self.expect_word("by")?;
+            loop {
+                let field = self.field()?;
+                let descending = self.eat_word("desc") || self.eat_word("descending");
+                if !descending && !self.eat_word("asc") {
+                    self.eat_word("ascending");
+                }
+                query.sort.push(SortKey { field, descending });
+                if !self.eat_symbol(",") {
+                    break;
+                }
+            }
+        }
+
+        if self.eat_word("limit") {
+            let limit = self.number()?;
+            if limit < 0.0 || limit.fract() != 0.0 {
+                bail!("limit must be a whole number, got {}", limit);
+            }
+            query.limit = Some(limit as u32);
+        }
+
+        if let Some(token) = self.tokens.get(self.pos) {
+            bail!("Unexpected {}", token);
+        }
+        Ok(query)
+    }
+
+    fn or(&mut self) -> Result<Rule> {
+        let mut rules = vec![self.and()?];
+        while self.eat_word("or") {
+            rules.push(self.and()?);
+        }
+        Ok(if rules.len() == 1 { rules.remove(0) } else { Rule::Any(rules) })
+    }
+
+    fn and(&mut self) -> Result<Rule> {
+        let mut rules = vec![self.unary()?];
+        while self.eat_word("and") {
+            rules.push(self.unary()?);
+        }
+        Ok(if rules.len() == 1 { rules.remove(0) } else { Rule::All(rules) })
+    }
+
+    fn unary(&mut self) -> Result<Rule> {
+        if self.eat_word("not") {
+            return Ok(Rule::Not(Box::new(self.unary()?)));
+        }
+        if self.eat_symbol("(") {
+            if self.eat_symbol(")") {
+                return Ok(Rule::All(Vec::new()));
+            }
+            let rule = self.or()?;
+            if !self.eat_symbol(")") {
+                bail!("Expected `)` {}", self.context());
+            }
+            return Ok(rule);
+        }
+        self.condition()
+    }
+
+    fn condition(&mut self) -> Result<Rule> {
+        let field = self.field()?;
+        let op = if self.eat_symbol("=") {
+            Op::Is(self.value()?)
+        } else if self.eat_symbol("!=") {
+            Op::IsNot(self.value()?)
+        } else if self.eat_symbol("<") {
+            Op::Lt(self.number()?)
+        } else if self.eat_symbol("<=") {
+            Op::Le(self.number()?)
+        } else if self.eat_symbol(">") {
+            Op::Gt(self.number()?)
+        } else if self.eat_symbol(">=") {
+            Op::Ge(self.number()?)
+        } else if self.eat_word("is") {
+            if self.eat_word("not") {
+                Op::IsNot(self.value()?)
+            } else {
+                Op::Is(self.value()?)
+            }
+        } else if self.eat_word("contains") {
+            Op::Contains(self.text()?)
+        } else if self.eat_word("starts") {
+            self.expect_word("with")?;
+            Op::StartsWith(self.text()?)
+        } else if self.eat_word("ends") {
+            self.expect_word("with")?;
+            Op::EndsWith(self.text()?)
+        } else if self.eat_word("between") {
+            let low = self.number()?;
+            self.expect_word("and")?;
+            Op::Between(low, self.number()?)
+        } else if self.eat_word("in") {
+            self.expect_word("last")?;
+            Op::InLast(self.days()?)
+        } else if self.eat_word("not") {
+            if self.eat_word("contains") {
+                Op::NotContains(self.text()?)
+            } else {
+                self.expect_word("in")?;
+                self.expect_word("last")?;
+                Op::NotInLast(self.days()?)
+            }
+        } else if field.kind() == Kind::Bool {
+            // A bare flag, as in `loved and rating >= 4`.
+            Op::Is(Value::Bool(true))
+        } else {
+            bail!("Expected a comparison after {} {}", field.name(), self.context());
+        };
+        Ok(Rule::Condition(Condition { field, op }))
+    }
+
+    fn field(&mut self) -> Result<Field> {
+        match self.next() {
+            Some(Token::Word(word)) => match Field::from_name(&word) {
+                Some(field) => Ok(field),
+                None => bail!(
+                    "Unknown field `{}`; expected one of {}",
+                    word,
+                    Field::ALL.map(Field::name).join(", ")
+                ),
+            },
+            Some(token) => bail!("Expected a field, found {}", token),
+            None => bail!("Expected a field at the end of the query"),
+        }
+    }
+
+    fn value(&mut self) -> Result<Value> {
+        match self.next() {
+            Some(Token::Text(text)) => Ok(Value::Text(text)),
+            Some(Token::Number(n)) => Ok(Value::Number(n)),
+            Some(Token::Word(word)) if word.eq_ignore_ascii_case("true") => Ok(Value::Bool(true)),
+            Some(Token::Word(word)) if word.eq_ignore_ascii_case("false") => Ok(Value::Bool(false)),
+            Some(Token::Word(word)) => Ok(Value::Text(word)),
+            Some(token) => bail!("Expected a value, found {}", token),
+            None => bail!("Expected a value at the end of the query"),
+        }
+    }
+
+    fn text(&mut self) -> Result<String> {
+        match self.value()? {
+            Value::Text(text) => Ok(text),
+            other => Ok(text(&other)),
+        }
+    }
+
+    fn number(&mut self) -> Result<f64> {
+        match self.next() {
+            Some(Token::Number(n)) => Ok(n),
+            Some(token) => bail!("Expected a number, found {}", token),
+            None => bail!("Expected a number at the end of the query"),
+        }
+    }
+
+    /// A count with an optional unit, in days: `30`, `2 weeks`, `1 year`.
+    fn days(&mut self) -> Result<u32> {
+        let amount = self.number()?;
+        if amount < 0.0 {
+            bail!("Expected a positive duration, got {}", amount);
+        }
+        let unit = match self.tokens.get(self.pos) {
+            Some(Token::Word(word)) => match word.to_ascii_lowercase().trim_end_matches('s') {
+                "day" => Some(1.0),
+                "week" => Some(7.0),
+                "month" => Some(30.0),
+                "year" => Some(365.0),
+                _ => None,
+            },
+            _ => None,
+        };
+        if unit.is_some() {
+            self.pos += 1;
+        }
+        Ok((amount * unit.unwrap_or(1.0)).round() as u32)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos >= self.tokens.len()
+    }
+
+    fn peek_word(&self, word: &str) -> bool {
+        matches!(self.tokens.get(self.pos), Some(Token::Word(w)) if w.eq_ignore_ascii_case(word))
+    }
+
+    fn eat_word(&mut self, word: &str) -> bool {
+        let found = self.peek_word(word);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect_word(&mut self, word: &str) -> Result<()> {
+        if !self.eat_word(word) {
+            bail!("Expected `{}` {}", word, self.context());
+        }
+        Ok(())
+    }
+
+    fn eat_symbol(&mut self, symbol: &str) -> bool {
+        let found = matches!(self.tokens.get(self.pos), Some(Token::Symbol(s)) if *s == symbol);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn context(&self) -> String {
+        match self.tokens.get(self.pos) {
+            Some(token) => format!("but found {}", token),
+            None => "at the end of the query".to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: i64 = 1_700_000_000;
+
+    fn parse(text: &str) -> SmartQuery {
+        text.parse().unwrap_or_else(|e| panic!("{:?}: {}", text, e))
+    }
+
+    fn rule(text: &str) -> Rule {
+        parse(text).rule.unwrap()
+    }
+
+    fn when(field: Field, op: Op) -> Rule {
+        Rule::Condition(Condition { field, op })
+    }
+
+    #[test]
+    fn parses_every_operator() {
+        let text = |s: &str| Value::Text(s.to_string());
+        let cases = [
+            ("genre is Jazz", when(Field::Genre, Op::Is(text("Jazz")))),
+            ("genre = \"Free Jazz\"", when(Field::Genre, Op::Is(text("Free Jazz")))),
+            ("genre is not Jazz", when(Field::Genre, Op::IsNot(text("Jazz")))),
+            ("genre != Jazz", when(Field::Genre, Op::IsNot(text("Jazz")))),
+            ("genre ≠ Jazz", when(Field::Genre, Op::IsNot(text("Jazz")))),
+            ("title contains love", when(Field::Title, Op::Contains("love".into()))),
+            ("title not contains love", when(Field::Title, Op::NotContains("love".into()))),
+            ("title starts with The", when(Field::Title, Op::StartsWith("The".into()))),
+            ("path ends with .flac", when(Field::Path, Op::EndsWith(".flac".into()))),
+            ("year < 1960", when(Field::Year, Op::Lt(1960.0))),
+            ("year <= 1960", when(Field::Year, Op::Le(1960.0))),
+            ("year ≤ 1960", when(Field::Year, Op::Le(1960.0))),
+            ("year > 1960", when(Field::Year, Op::Gt(1960.0))),
+            ("year >= 1960", when(Field::Year, Op::Ge(1960.0))),
+            ("year ≥ 1960", when(Field::Year, Op::Ge(1960.0))),
+            ("year = 1960", when(Field::Year, Op::Is(Value::Number(1960.0)))),
+            ("year between 1955 and 1965", when(Field::Year, Op::Between(1955.0, 1965.0))),
+            ("last_played in last 30", when(Field::LastPlayed, Op::InLast(30))),
+            ("last_played not in last 30", when(Field::LastPlayed, Op::NotInLast(30))),
+            ("loved", when(Field::Loved, Op::Is(Value::Bool(true)))),
+            ("loved is false", when(Field::Loved, Op::Is(Value::Bool(false)))),
+            ("Album_Artist IS Various", when(Field::AlbumArtist, Op::Is(text("Various")))),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(rule(text), expected, "{:?}", text);
+            // Every operator also compiles for its field.
+            SmartQuery { rule: Some(expected), ..Default::default() }.to_sql(NOW).unwrap();
+        }
+    }
+
+    #[test]
+    fn converts_relative_dates_to_days() {
+        let cases = [
+            ("30", 30),
+            ("1 day", 1),
+            ("3 Days", 3),
+            ("2 weeks", 14),
+            ("1 month", 30),
+            ("6 months", 180),
+            ("1 year", 365),
+            ("2 YEARS", 730),
+            ("1.5 days", 2),
+            ("0.5 weeks", 4),
+        ];
+        for (amount, days) in cases {
+            let text = format!("last_played in last {}", amount);
+            assert_eq!(rule(&text), when(Field::LastPlayed, Op::InLast(days)), "{:?}", text);
+        }
+
+        // A word that is not a unit is left for the rest of the query.
+        assert_eq!(
+            rule("last_played in last 7 and loved"),
+            Rule::All(vec![when(Field::LastPlayed, Op::InLast(7)), when(Field::Loved, Op::Is(Value::Bool(true)))])
+        );
+
+        let (sql, params) = parse("last_played in last 2 weeks").to_sql(NOW).unwrap();
+        assert!(sql.contains("s.last_played >= ?1"), "{}", sql);
+        assert_eq!(params, vec![SqlValue::Integer(NOW - 14 * 86_400)]);
+
+        let (sql, params) = parse("last_played not in last 1 day").to_sql(NOW).unwrap();
+        assert!(sql.contains("(s.last_played IS NULL OR s.last_played < ?1)"), "{}", sql);
+        assert_eq!(params, vec![SqlValue::Integer(NOW - 86_400)]);
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let a = when(Field::Loved, Op::Is(Value::Bool(true)));
+        let b = when(Field::Year, Op::Gt(2000.0));
+        let c = when(Field::Rating, Op::Ge(4.0));
+
+        assert_eq!(
+            rule("loved or year > 2000 and rating >= 4"),
+            Rule::Any(vec![a.clone(), Rule::All(vec![b.clone(), c.clone()])])
+        );
+        assert_eq!(
+            rule("loved and year > 2000 or rating >= 4"),
+            Rule::Any(vec![Rule::All(vec![a.clone(), b.clone()]), c.clone()])
+        );
+        assert_eq!(
+            rule("(loved or year > 2000) and rating >= 4"),
+            Rule::All(vec![Rule::Any(vec![a.clone(), b.clone()]), c.clone()])
+        );
+        assert_eq!(
+            rule("not loved and rating >= 4"),
+            Rule::All(vec![Rule::Not(Box::new(a.clone())), c.clone()])
+        );
+        assert_eq!(
+            rule("not (loved or rating >= 4)"),
+            Rule::Not(Box::new(Rule::Any(vec![a.clone(), c.clone()])))
+        );
+        assert_eq!(rule("((loved))"), a);
+        assert_eq!(rule("()"), Rule::All(Vec::new()));
+
+        let (sql, _) = parse("loved or year > 2000 and rating >= 4").to_sql(NOW).unwrap();
+        assert!(sql.starts_with(" WHERE (t.loved = ?1 OR (t.year > ?2 AND t.rating >= ?3))"), "{}", sql);
+    }
+
+    #[test]
+    fn text_form_round_trips() {
+        let texts = [
+            "genre is \"Jazz\" and year between 1955 and 1965 and (rating >= 4 or loved is true) \
+             and last_played not in last 30 days sort by play_count desc, title limit 50",
+            "not (artist contains \"a \\\"quoted\\\" name\" or path ends with \".mp3\")",
+            "sort by year desc",
+            "limit 10",
+            "",
+        ];
+        for text in texts {
+            let query = parse(text);
+            assert_eq!(parse(&query.to_string()), query, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn parses_sort_and_limit() {
+        let query = parse("sort by year desc, title asc, track_number limit 25");
+        assert_eq!(query.rule, None);
+        assert_eq!(
+            query.sort,
+            vec![
+                SortKey { field: Field::Year, descending: true },
+                SortKey { field: Field::Title, descending: false },
+                SortKey { field: Field::TrackNumber, descending: false },
+            ]
+        );
+        assert_eq!(query.limit, Some(25));
+
+        let (sql, params) = query.to_sql(NOW).unwrap();
+        assert_eq!(
+            sql,
+            format!(" ORDER BY t.year DESC, t.title COLLATE {}, t.track_number, t.id LIMIT ?1", NATURAL_ORDER)
+        );
+        assert_eq!(params, vec![SqlValue::Integer(25)]);
+
+        // The limit comes after every parameter of the rule.
+        let (sql, params) = parse("genre is Jazz limit 5").to_sql(NOW).unwrap();
+        assert!(sql.ends_with(" LIMIT ?2"), "{}", sql);
+        assert_eq!(params.last(), Some(&SqlValue::Integer(5)));
+
+        assert_eq!(parse("limit 0").limit, Some(0));
+        assert!("limit 2.5".parse::<SmartQuery>().is_err());
+        assert!("limit -1".parse::<SmartQuery>().is_err());
+        assert!("limit ten".parse::<SmartQuery>().is_err());
+        assert!("limit".parse::<SmartQuery>().is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_queries() {
+        let malformed = [
+            "titel is Love",
+            "title",
+            "title is",
+            "title contains",
+            "title starts Love",
+            "year between 1955 1965",
+            "year between 1955 and",
+            "year > recent",
+            "title contains \"unterminated",
+            "(loved",
+            "loved)",
+            "loved or",
+            "and loved",
+            "loved loved",
+            "last_played in 30",
+            "last_played in last -3 days",
+            "last_played in last week",
+            "sort title",
+            "sort by",
+            "sort by loudness",
+            "limit 5 sort by title",
+            "title is Love !",
+            "= 3",
+        ];
+        for text in malformed {
+            assert!(text.parse::<SmartQuery>().is_err(), "{:?} parsed", text);
+        }
+    }
+
+    #[test]
+    fn rejects_operators_a_field_does_not_take() {
+        let mismatched = [
+            "title > 3",
+            "title in last 3 days",
+            "year contains 19",
+            "year in last 3 days",
+            "loved > 0",
+            "loved contains yes",
+            "last_played is 3",
+            "last_played > 3",
+            "rating is great",
+            "loved is maybe",
+        ];
+        for text in mismatched {
+            assert!(parse(text).to_sql(NOW).is_err(), "{:?} compiled", text);
+        }
+    }
+
+    #[test]
+    fn passes_every_value_as_a_parameter() {
+        let hostile = "x' OR 1=1; DROP TABLE tracks; --";
+        let query = SmartQuery {
+            rule: Some(Rule::Any(vec![
+                when(Field::Title, Op::Is(Value::Text(hostile.into()))),
+                when(Field::Album, Op::Contains(hostile.into())),
+                when(Field::Path, Op::StartsWith(hostile.into())),
+                when(Field::Label, Op::EndsWith(hostile.into())),
+                when(Field::Artist, Op::IsNot(Value::Text(hostile.into()))),
+                when(Field::Genre, Op::NotContains(hostile.into())),
+            ])),
+            ..Default::default()
+        };
+        let (sql, params) = query.to_sql(NOW).unwrap();
+        assert!(!sql.contains("DROP") && !sql.contains("1=1"), "{}", sql);
+        assert_eq!(params.len(), 6);
+        assert_eq!(params[0], SqlValue::Text(hostile.into()));
+        for (i, _) in params.iter().enumerate() {
+            assert!(sql.contains(&format!("?{}", i + 1)), "{}", sql);
+        }
+    }
+
+    #[test]
+    fn escapes_like_wildcards() {
+        let (sql, params) = parse(r#"title contains "50%_off\\" and path starts with "a_b""#).to_sql(NOW).unwrap();
+        assert!(sql.contains("t.title LIKE ?1 ESCAPE '\\'"), "{}", sql);
+        assert_eq!(
+            params,
+            vec![SqlValue::Text(r"%50\%\_off\\%".into()), SqlValue::Text(r"a\_b%".into())]
+        );
+    }
+
+    #[test]
+    fn scales_ratings_to_half_stars() {
+        let (_, params) = parse("rating >= 4 and rating between 5 and 1 and rating = 3").to_sql(NOW).unwrap();
+        assert_eq!(
+            params,
+            vec![SqlValue::Real(8.0), SqlValue::Real(2.0), SqlValue::Real(10.0), SqlValue::Real(6.0)]
+        );
+    }
+
+    #[test]
+    fn tests_every_value_of_multi_valued_fields() {
+        let (sql, params) = parse("artist is not Nico and genre contains rock").to_sql(NOW).unwrap();
+        assert!(sql.contains("t.id NOT IN (SELECT ta.track_id"), "{}", sql);
+        assert!(sql.contains("n.name = ?1 COLLATE NOCASE"), "{}", sql);
+        assert!(sql.contains("t.id IN (SELECT tg.track_id"), "{}", sql);
+        assert_eq!(params, vec![SqlValue::Text("Nico".into()), SqlValue::Text("%rock%".into())]);
+    }
+}
@@ -0,0 +1,664 @@
+use crate::roots::RootFilter;
+use crate::cue::{is_cue_sheet, match_sheets, read_tracks, sheet_for, tracks_stamp};
+use crate::decode::is_decodable;
+use crate::{LibraryManager, LibraryRoot, LibraryService};
+use anyhow::{anyhow, Result};
+use lofty::file::FileType;
+use lofty::probe::Probe;
+use rusqlite::params;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+/// Bumped whenever stored tracks lack something only the files hold, e.g.
+/// after a migration adds columns. Until a complete library scan has run
+/// under this version, scans read every file again whatever its mtime.
+const METADATA_VERSION: u32 = 1;
+/// The setting holding the `METADATA_VERSION` of the last complete scan.
+const METADATA_VERSION_KEY: &str = "metadata_version";
+
+/// Tracks written per transaction while scanning.
+const BATCH_SIZE: usize = 256;
+/// Upper bound on tag-reading threads; past this the disk is the bottleneck.
+const MAX_WORKERS: usize = 8;
+/// Minimum time between two progress events.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Outcome of a `scan_directory` run.
+#[derive(Debug, Default, Clone)]
+pub struct ScanSummary {
+    pub added: u32,
+    pub updated: u32,
+    pub unchanged: u32,
+    pub removed: u32,
+    pub failed: u32,
+    /// Roots skipped because they were offline.
+    pub offline: u32,
+    /// Audio files in formats whose tags cannot be read, such as DSD, and
+    /// CUE images that cannot be decoded, such as APE, left out of the
+    /// library.
+    pub unsupported: Vec<PathBuf>,
+    /// The scan was stopped early. Missing files are not pruned in that case.
+    pub cancelled: bool,
+}
+
+impl ScanSummary {
+    fn add(&mut self, other: &ScanSummary) {
+        self.added += other.added;
+        self.updated += other.updated;
+        self.unchanged += other.unchanged;
+        self.removed += other.removed;
+        self.failed += other.failed;
+        self.offline += other.offline;
+        self.unsupported.extend(other.unsupported.iter().cloned());
+    }
+}
+
+/// The tracks the library holds for one file, with the stamp and the CUE
+/// sheet they were read with.
+#[derive(Debug, Default)]
+struct KnownFile {
+    ids: Vec<i64>,
+    mtime: Option<i64>,
+    size: Option<i64>,
+    cue_path: Option<String>,
+}
+
+impl KnownFile {
+    fn add_row(files: &mut HashMap<String, KnownFile>, row: &rusqlite::Row) -> rusqlite::Result<()> {
+        let file = files.entry(row.get(0)?).or_default();
+        file.ids.push(row.get(1)?);
+        (file.mtime, file.size, file.cue_path) = (row.get(2)?, row.get(3)?, row.get(4)?);
+        Ok(())
+    }
+
+    /// Whether reading the file again, cut by `sheet`, would give the same tracks.
+    fn is_current(&self, path: &Path, sheet: Option<&Path>) -> bool {
+        let sheet = sheet.map(|sheet| sheet.to_string_lossy().into_owned());
+        self.cue_path == sheet
+            && tracks_stamp(path, sheet.as_deref().map(Path::new))
+                .is_ok_and(|(mtime, size)| self.mtime == Some(mtime) && self.size == Some(size))
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct ScanProgress {
+    pub seen: u32,
+    pub total: u32,
+    pub added: u32,
+    pub updated: u32,
+    pub failed: u32,
+    pub current_path: PathBuf,
+}
+
+#[derive(Debug, Clone)]
+pub enum ScanEvent {
+    Progress(ScanProgress),
+    Finished(ScanSummary),
+}
+
+impl LibraryManager {
+    /// Scans `path` recursively under the rules of the root it is in,
+    /// re-reading tags only for files whose mtime or size changed since the
+    /// last scan, and removes tracks under `path` whose files no longer exist
+    /// or no longer match the rules. A folder outside every root becomes a
+    /// root of its own.
+    pub fn scan_directory(&self, path: &Path) -> Result<ScanSummary> {
+        self.scan_directory_with(path, &AtomicBool::new(false), |_| {})
+    }
+
+    /// Like `scan_directory`, but reads tags on a pool of worker threads,
+    /// reports progress to `on_event` and stops early once `cancel` is set.
+    /// `on_event` is only ever called on the calling thread.
+    pub fn scan_directory_with<F>(&self, path: &Path, cancel: &AtomicBool, mut on_event: F) -> Result<ScanSummary>
+    where
+        F: FnMut(ScanEvent),
+    {
+        let mut summary = ScanSummary::default();
+        let root = self.root_containing(path)?;
+        let available = match &root {
+            Some(root) => self.check_root(root)?,
+            None => true,
+        };
+        if !available || !path.is_dir() {
+            // An unmounted or missing root must not wipe its tracks.
+            summary.offline = u32::from(!available);
+            on_event(ScanEvent::Finished(summary.clone()));
+            return Ok(summary);
+        }
+
+        let root = match root {
+            Some(root) => root,
+            None => {
+                let root = LibraryRoot::new(path);
+                self.add_library_root(&root)?;
+                root
+            }
+        };
+        let filter = self.root_filter(&root)?;
+        summary.add(&self.scan_tree(path, &filter, cancel, &mut on_event)?);
+        self.finish_scan(summary, cancel, on_event)
+    }
+
+    /// Scans every root in turn, as `scan_directory` would. Roots that are
+    /// offline are skipped, keeping their tracks, and counted in `offline`.
+    pub fn scan_library(&self) -> Result<ScanSummary> {
+        self.scan_library_with(&AtomicBool::new(false), |_| {})
+    }
+
+    /// `scan_library` with progress and cancellation as in
+    /// `scan_directory_with`. Progress counts restart with each root.
+    pub fn scan_library_with<F>(&self, cancel: &AtomicBool, mut on_event: F) -> Result<ScanSummary>
+    where
+        F: FnMut(ScanEvent),
+    {
+        let mut summary = ScanSummary::default();
+        for root in self.get_library_roots()? {
+            if cancel.load(Ordering::Relaxed) {
+                break;
+            }
+            if !self.check_root(&root)? {
+                summary.offline += 1;
+                continue;
+            }
+            let filter = self.root_filter(&root)?;
+            summary.add(&self.scan_tree(&root.path, &filter, cancel, &mut on_event)?);
+        }
+        // Offline roots still hold tracks read the old way.
+        if !cancel.load(Ordering::Relaxed) && summary.offline == 0 {
+            self.set_setting(METADATA_VERSION_KEY, &METADATA_VERSION)?;
+        }
+        self.finish_scan(summary, cancel, on_event)
+    }
+
+    /// Whether stored tracks may lack what this build reads from files.
+    fn metadata_stale(&self) -> Result<bool> {
+        Ok(self.setting::<u32>(METADATA_VERSION_KEY)?.unwrap_or(0) < METADATA_VERSION)
+    }
+
+    /// Makes scans read every file again until a complete library scan.
+    pub(crate) fn mark_metadata_stale(&self) -> Result<()> {
+        self.set_setting(METADATA_VERSION_KEY, &0)
+    }
+
+    fn finish_scan<F>(&self, mut summary: ScanSummary, cancel: &AtomicBool, mut on_event: F) -> Result<ScanSummary>
+    where
+        F: FnMut(ScanEvent),
+    {
+        summary.cancelled = cancel.load(Ordering::Relaxed);
+        // Rescanned tracks may have moved to another album.
+        self.remove_orphans()?;
+        if let Err(e) = self.update_covers() {
+            log::error!("Failed to update album covers: {}", e);
+        }
+
+        on_event(ScanEvent::Finished(summary.clone()));
+        Ok(summary)
+    }
+
+    /// Reads and stores the files below `path` that `filter` admits, then
+    /// prunes what was not found unless the scan was cancelled.
+    fn scan_tree<F>(&self, path: &Path, filter: &RootFilter, cancel: &AtomicBool, on_event: &mut F) -> Result<ScanSummary>
+    where
+        F: FnMut(ScanEvent),
+    {
+        let mut summary = ScanSummary::default();
+        let generation = self.current_generation()? + 1;
+
+        let AudioFiles { audio: files, unsupported, sheets } = collect_audio_files(path, filter);
+        summary.unsupported = unsupported;
+        let known = self.known_files()?;
+        let stale = self.metadata_stale()?;
+
+        let mut unchanged_files = 0;
+        let mut unchanged = Vec::new();
+        let mut to_read = Vec::new();
+        for file in files {
+            let sheet = sheets.get(&file).cloned();
+            match known.get(&*file.to_string_lossy()) {
+                Some(known) if !stale && known.is_current(&file, sheet.as_deref()) => {
+                    unchanged_files += 1;
+                    unchanged.extend_from_slice(&known.ids);
+                }
+                known => {
+                    let existing = known.map(|known| known.ids.clone()).unwrap_or_default();
+                    to_read.push((file, sheet, existing));
+                }
+            }
+        }
+
+        let tx = self.conn.unchecked_transaction()?;
+        for id in &unchanged {
+            self.touch_track(*id, generation)?;
+        }
+        tx.commit()?;
+        summary.unchanged = unchanged.len() as u32;
+
+        let mut progress = ScanProgress {
+            seen: unchanged_files,
+            total: unchanged_files + to_read.len() as u32,
+            ..Default::default()
+        };
+
+        let workers = std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(4)
+            .min(MAX_WORKERS);
+        let next = AtomicUsize::new(0);
+
+        std::thread::scope(|scope| -> Result<()> {
+            let (results_tx, results) = mpsc::channel();
+            for _ in 0..workers {
+                let results_tx = results_tx.clone();
+                let (to_read, next) = (&to_read, &next);
+                scope.spawn(move || loop {
+                    if cancel.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let Some((path, sheet, _)) = to_read.get(i) else {
+                        break;
+                    };
+                    if results_tx.send((i, read_tracks(path, sheet.as_deref()))).is_err() {
+                        break;
+                    }
+                });
+            }
+            drop(results_tx);
+
+            let mut batch = self.conn.unchecked_transaction()?;
+            let mut in_batch = 0;
+            let mut last_event = Instant::now();
+            for (i, result) in results {
+                let (path, _, existing) = &to_read[i];
+                let stored = result.and_then(|tracks| {
+                    self.store_tracks(path, &tracks, generation)?;
+                    Ok(tracks.len() as u32)
+                });
+                match stored {
+                    Ok(count) if !existing.is_empty() => summary.updated += count,
+                    Ok(count) => summary.added += count,
+                    Err(e) => {
+                        log::error!("Failed to add track {:?}: {}", path, e);
+                        summary.failed += 1;
+                        // Keep the old rows: the file is still there, it just failed to parse.
+                        for id in existing {
+                            self.touch_track(*id, generation)?;
+                        }
+                    }
+                }
+
+                in_batch += 1;
+                if in_batch >= BATCH_SIZE {
+                    batch.commit()?;
+                    batch = self.conn.unchecked_transaction()?;
+                    in_batch = 0;
+                }
+
+                progress.seen += 1;
+                if last_event.elapsed() >= PROGRESS_INTERVAL {
+                    progress.added = summary.added;
+                    progress.updated = summary.updated;
+                    progress.failed = summary.failed;
+                    progress.current_path = path.clone();
+                    on_event(ScanEvent::Progress(progress.clone()));
+                    last_event = Instant::now();
+                }
+            }
+            batch.commit()?;
+            Ok(())
+        })?;
+
+        // Files we never got to would look deleted, so only prune complete scans.
+        if !cancel.load(Ordering::Relaxed) {
+            summary.removed = self.prune_missing(path, filter, generation)?;
+        }
+        Ok(summary)
+    }
+
+    /// What the library holds for every file, keyed by path.
+    fn known_files(&self) -> Result<HashMap<String, KnownFile>> {
+        let mut files = HashMap::new();
+        let mut stmt = self.conn.prepare("SELECT path, id, mtime, size, cue_path FROM tracks")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            KnownFile::add_row(&mut files, row)?;
+        }
+        Ok(files)
+    }
+
+    fn known_file(&self, path: &Path) -> Result<Option<KnownFile>> {
+        let mut files = HashMap::new();
+        let mut stmt = self.conn.prepare("SELECT path, id, mtime, size, cue_path FROM tracks WHERE path = ?1")?;
+        let mut rows = stmt.query(params![path.to_string_lossy()])?;
+        while let Some(row) = rows.next()? {
+            KnownFile::add_row(&mut files, row)?;
+        }
+        Ok(files.into_values().next())
+    }
+
+    pub(crate) fn scan_file(&self, path: &Path, filter: &RootFilter, generation: i64, summary: &mut ScanSummary) {
+        let sheet = sheet_for(path, |sheet| filter.admits_sidecar(sheet));
+        if sheet.is_some() && !is_decodable(path) {
+            summary.unsupported.push(path.to_path_buf());
+            return;
+        }
+        let existing = self.known_file(path).unwrap_or_else(|e| {
+            log::error!("Failed to look up track {:?}: {}", path, e);
+            None
+        });
+        let stale = self.metadata_stale().unwrap_or_else(|e| {
+            log::error!("Failed to read the metadata version: {}", e);
+            true
+        });
+
+        if let Some(known) = existing.as_ref().filter(|known| !stale && known.is_current(path, sheet.as_deref())) {
+            for id in &known.ids {
+                match self.touch_track(*id, generation) {
+                    Ok(()) => summary.unchanged += 1,
+                    Err(e) => {
+                        log::error!("Failed to update track {:?}: {}", path, e);
+                        summary.failed += 1;
+                    }
+                }
+            }
+            return;
+        }
+
+        let stored = read_tracks(path, sheet.as_deref()).and_then(|tracks| {
+            self.store_tracks(path, &tracks, generation)?;
+            Ok(tracks.len() as u32)
+        });
+        match stored {
+            Ok(count) if existing.is_some() => summary.updated += count,
+            Ok(count) => summary.added += count,
+            Err(e) => {
+                log::error!("Failed to add track {:?}: {}", path, e);
+                summary.failed += 1;
+                // Keep the old rows: the file is still there, it just failed to parse.
+                for id in existing.iter().flat_map(|known| &known.ids) {
+                    let _ = self.touch_track(*id, generation);
+                }
+            }
+        }
+    }
+
+    fn touch_track(&self, id: i64, generation: i64) -> Result<()> {
+        self.conn.execute(
+            "UPDATE tracks SET scan_generation = ?1 WHERE id = ?2",
+            params![generation, id],
+        )?;
+        Ok(())
+    }
+
+    /// Deletes tracks below `path` that were not seen during scan
+    /// `generation` because their file is gone or `filter` now leaves it
+    /// out. Tracks of a root nested below belong to that root's scans.
+    fn prune_missing(&self, path: &Path, filter: &RootFilter, generation: i64) -> Result<u32> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, path FROM tracks WHERE scan_generation < ?1"
+        )?;
+        let stale: Vec<(i64, String)> = stmt
+            .query_map(params![generation], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+
+        let mut removed = 0;
+        for (id, track_path) in stale {
+            let track_path = Path::new(&track_path);
+            if !track_path.starts_with(path) || filter.in_nested_root(track_path) {
+                continue;
+            }
+            if !track_path.exists() || !filter.admits_file(track_path) {
+                self.delete_track(id)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+/// A scan queued on the library writer.
+pub struct ScanJob {
+    cancel: Arc<AtomicBool>,
+    events: Receiver<ScanEvent>,
+    result: Receiver<Result<ScanSummary>>,
+}
+
+impl ScanJob {
+    /// Progress and completion events. The channel closes when the scan ends,
+    /// including when it fails, in which case `wait` returns the error.
+    pub fn events(&self) -> &Receiver<ScanEvent> {
+        &self.events
+    }
+
+    pub fn cancel(&self) {
+        self.cancel.store(true, Ordering::Relaxed);
+    }
+
+    pub fn wait(self) -> Result<ScanSummary> {
+        self.result.recv().map_err(|_| anyhow!("Scan ended without a result"))?
+    }
+}
+
+impl LibraryService {
+    /// Scans `path` on the writer. Reads carry on meanwhile; other writes
+    /// queue up behind the scan.
+    pub fn scan(&self, path: PathBuf) -> Result<ScanJob> {
+        self.spawn_scan(move |library, cancel, on_event| library.scan_directory_with(&path, cancel, on_event))
+    }
+
+    /// Scans every root on the writer, as `scan` does a single folder.
+    pub fn scan_library(&self) -> Result<ScanJob> {
+        self.spawn_scan(|library, cancel, on_event| library.scan_library_with(cancel, on_event))
+    }
+
+    fn spawn_scan<F>(&self, scan: F) -> Result<ScanJob>
+    where
+        F: FnOnce(&LibraryManager, &AtomicBool, &mut dyn FnMut(ScanEvent)) -> Result<ScanSummary> + Send + 'static,
+    {
+        let cancel = Arc::new(AtomicBool::new(false));
+        let (events_tx, events) = mpsc::channel();
+        let (result_tx, result) = mpsc::sync_channel(1);
+
+        let job_cancel = cancel.clone();
+        self.submit(move |library| {
+            let summary = scan(library, &job_cancel, &mut |event| {
+                let _ = events_tx.send(event);
+            });
+            let _ = result_tx.send(summary);
+        })?;
+
+        Ok(ScanJob { cancel, events, result })
+    }
+}
+
+impl mlua::UserData for ScanSummary {
+    fn add_fields<'lua, F: mlua::UserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_method_get("added", |_lua, this| Ok(this.added));
+        fields.add_field_method_get("updated", |_lua, this| Ok(this.updated));
+        fields.add_field_method_get("unchanged", |_lua, this| Ok(this.unchanged));
+        fields.add_field_method_get("removed", |_lua, this| Ok(this.removed));
+        fields.add_field_method_get("failed", |_lua, this| Ok(this.failed));
+        fields.add_field_method_get("offline", |_lua, this| Ok(this.offline));
+        fields.add_field_method_get("unsupported", |_lua, this| {
+            Ok(this.unsupported.iter().map(|path| path.to_string_lossy().into_owned()).collect::<Vec<_>>())
+        });
+        fields.add_field_method_get("cancelled", |_lua, this| Ok(this.cancelled));
+    }
+}
+
+impl mlua::UserData for ScanProgress {
+    fn add_fields<'lua, F: mlua::UserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_method_get("seen", |_lua, this| Ok(this.seen));
+        fields.add_field_method_get("total", |_lua, this| Ok(this.total));
+        fields.add_field_method_get("added", |_lua, this| Ok(this.added));
+        fields.add_field_method_get("updated", |_lua, this| Ok(this.updated));
+        fields.add_field_method_get("failed", |_lua, this| Ok(this.failed));
+        fields.add_field_method_get("current_path", |_lua, this| {
+            Ok(this.current_path.to_string_lossy().into_owned())
+        });
+    }
+}
+
+/// Files found by `collect_audio_files`.
+#[derive(Default)]
+pub(crate) struct AudioFiles {
+    pub(crate) audio: Vec<PathBuf>,
+    /// Audio in formats that cannot be read.
+    pub(crate) unsupported: Vec<PathBuf>,
+    /// The CUE sheet beside each audio file that one cuts into tracks.
+    pub(crate) sheets: HashMap<PathBuf, PathBuf>,
+}
+
+/// Audio files below `dir` that `filter` admits.
+pub(crate) fn collect_audio_files(dir: &Path, filter: &RootFilter) -> AudioFiles {
+    let mut files = AudioFiles::default();
+    walk(dir, filter, &mut HashSet::new(), &mut files);
+    files
+}
+
+/// `visited` holds the real path of every folder walked so far, so a
+/// symlink back up the tree is not followed round in circles.
+fn walk(dir: &Path, filter: &RootFilter, visited: &mut HashSet<PathBuf>, files: &mut AudioFiles) {
+    if let Ok(real) = dir.canonicalize() {
+        if !visited.insert(real) {
+            return;
+        }
+    }
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            log::error!("Failed to read directory {:?}: {}", dir, e);
+            return;
+        }
+    };
+    let mut sheets = Vec::new();
+    for entry in entries.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        if !filter.follow_symlinks && entry.file_type().is_ok_and(|t| t.is_symlink()) {
+            continue;
+        }
+        if path.is_dir() {
+            if filter.admits_dir(&path) {
+                walk(&path, filter, visited, files);
+            }
+        } else if is_cue_sheet(&path) {
+            if filter.admits_sidecar(&path) {
+                sheets.push(path);
+            }
+        } else if filter.admits_path(&path) {
+            if is_audio_file(&path) {
+                files.audio.push(path);
+            } else if is_unsupported_audio(&path) {
+                files.unsupported.push(path);
+            }
+        }
+    }
+    for (audio, sheet) in match_sheets(&sheets) {
+        if is_decodable(&audio) {
+            files.sheets.insert(audio, sheet);
+        } else if let Some(i) = files.audio.iter().position(|path| *path == audio) {
+            // An image that cannot be decoded, such as APE, would only list
+            // tracks that fail to play.
+            files.unsupported.push(files.audio.remove(i));
+        }
+    }
+}
+
+/// Modification time (nanoseconds since the epoch) and size of a file.
+pub(crate) fn file_stamp(path: &Path) -> Result<(i64, i64)> {
+    let metadata = std::fs::metadata(path)?;
+    let mtime = metadata.modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as i64)
+        .unwrap_or(0);
+    Ok((mtime, metadata.len() as i64))
+}
+
+/// Extensions of audio formats whose tags cannot be read. Files like these
+/// are reported by scans rather than skipped like any other file.
+const UNSUPPORTED_AUDIO: &[&str] = &[
+    "dsf", "dff", "wma", "mka", "tak", "tta", "ac3", "dts", "amr", "au", "ra", "shn",
+];
+
+/// The format of an audio file whose tags can be read. The header decides
+/// first, so misnamed files are still recognized; files it says nothing
+/// about fall back to their extension, in any case.
+pub(crate) fn audio_file_type(path: &Path) -> Option<FileType> {
+    let probe = Probe::open(path).ok()?;
+    match probe.guess_file_type() {
+        // A bare MPEG frame sync is a few set bits that JPEG images and
+        // plenty of other files start with too, so without an ID3v2 tag
+        // in front the extension has to agree.
+        Ok(probe) if probe.file_type() == Some(FileType::Mpeg) => {
+            (has_id3v2(path) || FileType::from_path(path) == Some(FileType::Mpeg)).then_some(FileType::Mpeg)
+        }
+        Ok(probe) => probe.file_type(),
+        Err(_) => FileType::from_path(path),
+    }
+}
+
+fn has_id3v2(path: &Path) -> bool {
+    let mut magic = [0; 3];
+    File::open(path).and_then(|mut file| file.read_exact(&mut magic)).is_ok() && &magic == b"ID3"
+}
+
+pub(crate) fn is_audio_file(path: &Path) -> bool {
+    audio_file_type(path).is_some()
+}
+
+/// Audio that `audio_file_type` does not take.
+pub(crate) fn is_unsupported_audio(path: &Path) -> bool {
+    path.extension()
+        .and_then(|s| s.to_str())
+        .is_some_and(|ext| UNSUPPORTED_AUDIO.iter().any(|audio| ext.eq_ignore_ascii_case(audio)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    const JPEG: [u8; 12] = [0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x10, b'J', b'F', b'I', b'F', 0x00, 0x01];
+    /// An MPEG-1 layer III frame header, 128 kbps at 44.1 kHz.
+    const MP3_FRAME: [u8; 4] = [0xFF, 0xFB, 0x90, 0x64];
+
+    #[test]
+    fn detects_audio_from_its_header() {
+        let dir = TempDir::new("file-type");
+        let wav = dir.write_wav("track.wav");
+        assert_eq!(audio_file_type(&wav), Some(FileType::Wav));
+        // Misnamed, or with no extension at all.
+        std::fs::copy(&wav, dir.path().join("track.mp3")).unwrap();
+        std::fs::copy(&wav, dir.path().join("track")).unwrap();
+        assert_eq!(audio_file_type(&dir.path().join("track.mp3")), Some(FileType::Wav));
+        assert_eq!(audio_file_type(&dir.path().join("track")), Some(FileType::Wav));
+    }
+
+    #[test]
+    fn falls_back_to_the_extension_in_any_case() {
+        let dir = TempDir::new("file-type-extension");
+        let frames: Vec<u8> = MP3_FRAME.iter().copied().chain([0; 413]).cycle().take(417 * 4).collect();
+        assert_eq!(audio_file_type(&dir.write("a.mp3", &frames)), Some(FileType::Mpeg));
+        assert_eq!(audio_file_type(&dir.write("b.MP3", &frames)), Some(FileType::Mpeg));
+        assert_eq!(audio_file_type(&dir.write("c.txt", "just text")), None);
+    }
+
+    #[test]
+    fn does_not_take_images_for_mpeg() {
+        let dir = TempDir::new("file-type-jpeg");
+        assert_eq!(audio_file_type(&dir.write("cover.jpg", JPEG)), None);
+        assert_eq!(audio_file_type(&dir.write("cover", JPEG)), None);
+        assert!(!is_audio_file(&dir.write("folder.JPEG", JPEG)));
+
+        // An ID3v2 tag in front is enough to go by.
+        let mut tagged = b"ID3\x04\x00\x00\x00\x00\x00\x00".to_vec();
+        tagged.extend(MP3_FRAME.iter().copied().chain([0; 413]).cycle().take(417 * 4));
+        assert_eq!(audio_file_type(&dir.write("untitled.bin", &tagged)), Some(FileType::Mpeg));
+    }
+}
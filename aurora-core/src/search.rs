@@ -0,0 +1,225 @@
+use crate::{track_from_row, LibraryManager, Track, TRACK_SELECT};
+use anyhow::Result;
+use rusqlite::params;
+
+/// Columns of `tracks_fts`, in declaration order, with their bm25 weights.
+/// A hit in the title counts for more than one in the folder name, and
+/// one in the lyrics for least, as they hold the most words.
+const SEARCH_COLUMNS: [(&str, f64); 6] = [
+    ("title", 10.0),
+    ("artist", 5.0),
+    ("album", 4.0),
+    ("genre", 2.0),
+    ("path", 1.0),
+    ("lyrics", 0.5),
+];
+
+impl LibraryManager {
+    /// Full-text search over title, artist, album, genre, path and lyrics,
+    /// best matches first. Every word is matched as a prefix, quoted phrases
+    /// are kept together, and a word or phrase can be limited to one field
+    /// with `field:`, e.g. `artist:radiohead "ok comp"`.
+    pub fn search(&self, query: &str) -> Result<Vec<Track>> {
+        let Some(expression) = fts_expression(query) else {
+            return Ok(Vec::new());
+        };
+
+        let weights = SEARCH_COLUMNS
+            .iter()
+            .map(|(_, weight)| weight.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        let sql = format!(
+            "{} JOIN tracks_fts ON tracks_fts.rowid = t.id
+             WHERE tracks_fts MATCH ?1
+             ORDER BY bm25(tracks_fts, {})",
+            TRACK_SELECT, weights
+        );
+
+        let mut stmt = self.conn.prepare(&sql)?;
+        let tracks = stmt
+            .query_map(params![expression], track_from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(tracks)
+    }
+}
+
+/// Turns user input into an FTS5 MATCH expression. Every term is quoted, so
+/// FTS5 operators and punctuation typed by the user are matched literally
+/// rather than parsed.
+fn fts_expression(query: &str) -> Option<String> {
+    let mut terms = Vec::new();
+    let mut chars = query.chars().peekable();
+
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        if chars.peek().is_none() {
+            break;
+        }
+
+        // An optional `field:` qualifier, then a bare word or a quoted phrase.
+        let mut word = String::new();
+        let mut column = None;
+        loop {
+            match chars.peek() {
+                None => break,
+                Some(c) if c.is_whitespace() => break,
+                Some('"') if word.is_empty() => break,
+                Some(':') if column.is_none() && is_search_column(&word) => {
+                    chars.next();
+                    column = Some(std::mem::take(&mut word).to_lowercase());
+                    if chars.peek() == Some(&'"') {
+                        break;
+                    }
+                }
+                Some(&c) => {
+                    word.push(c);
+                    chars.next();
+                }
+            }
+        }
+        if word.is_empty() && chars.next_if_eq(&'"').is_some() {
+            while let Some(c) = chars.next_if(|c| *c != '"') {
+                word.push(c);
+            }
+            chars.next();
+        }
+
+        if word.trim().is_empty() {
+            continue;
+        }
+        let phrase = format!("\"{}\" *", word.replace('"', "\"\""));
+        terms.push(match column {
+            Some(column) => format!("{} : {}", column, phrase),
+            None => phrase,
+        });
+    }
+
+    if terms.is_empty() {
+        None
+    } else {
+        Some(terms.join(" "))
+    }
+}
+
+fn is_search_column(name: &str) -> bool {
+    SEARCH_COLUMNS
+        .iter()
+        .any(|(column, _)| column.eq_ignore_ascii_case(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::Connection;
+
+    fn expression(query: &str) -> String {
+        fts_expression(query).unwrap_or_else(|| panic!("{:?} gave no expression", query))
+    }
+
+    /// An index like `tracks_fts`, holding a few rows.
+    fn index() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE VIRTUAL TABLE tracks_fts USING fts5(
+                title, artist, album, genre, path, lyrics,
+                tokenize = 'unicode61 remove_diacritics 2'
+            );
+            INSERT INTO tracks_fts (rowid, title, artist, album, genre, path) VALUES
+                (1, 'Jóga', 'Björk', 'Homogenic', 'Electronic', '/music/bjork/joga.flac'),
+                (2, 'Back in Black', 'AC/DC', 'Back in Black', 'Rock', '/music/acdc/back.flac'),
+                (3, 'Paranoid Android', 'Radiohead', 'OK Computer', 'Rock', '/music/radiohead/android.flac'),
+                (4, '群青日和', '東京事変', '教育', 'Rock', '/music/tokyo/gunjou.flac'),
+                (5, 'Radiohead Live', 'Someone Else', 'Near Misses', 'Pop', '/music/live/radio.flac');",
+        )
+        .unwrap();
+        conn
+    }
+
+    fn hits(conn: &Connection, query: &str) -> Vec<i64> {
+        let mut stmt = conn.prepare("SELECT rowid FROM tracks_fts WHERE tracks_fts MATCH ?1 ORDER BY rowid").unwrap();
+        let rows = stmt.query_map([expression(query)], |row| row.get(0)).unwrap();
+        rows.map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn empty_input_gives_no_expression() {
+        for query in ["", "   ", "\t\n", "\"", "\"\"", "\"  \"", "title:", "artist:\"\""] {
+            assert_eq!(fts_expression(query), None, "{:?}", query);
+        }
+    }
+
+    #[test]
+    fn quotes_every_word_as_a_prefix() {
+        assert_eq!(expression("paranoid"), "\"paranoid\" *");
+        assert_eq!(expression("  paranoid   android "), "\"paranoid\" * \"android\" *");
+    }
+
+    #[test]
+    fn keeps_quoted_phrases_together() {
+        assert_eq!(expression("\"ok comp\""), "\"ok comp\" *");
+        assert_eq!(expression("back \"in black\""), "\"back\" * \"in black\" *");
+        // An unterminated phrase runs to the end.
+        assert_eq!(expression("say \"hello there"), "\"say\" * \"hello there\" *");
+        // A quote inside a word is doubled, as FTS5 strings escape it.
+        assert_eq!(expression("it\"s"), "\"it\"\"s\" *");
+    }
+
+    #[test]
+    fn matches_fts_operators_literally() {
+        assert_eq!(expression("*"), "\"*\" *");
+        assert_eq!(expression("rock*"), "\"rock*\" *");
+        assert_eq!(expression("-live"), "\"-live\" *");
+        assert_eq!(expression("a OR b"), "\"a\" * \"OR\" * \"b\" *");
+        assert_eq!(expression("NOT AND"), "\"NOT\" * \"AND\" *");
+        assert_eq!(expression("NEAR(radiohead live)"), "\"NEAR(radiohead\" * \"live)\" *");
+        assert_eq!(expression("^start"), "\"^start\" *");
+        assert_eq!(expression("AC/DC"), "\"AC/DC\" *");
+    }
+
+    #[test]
+    fn limits_terms_to_a_column() {
+        assert_eq!(expression("artist:radiohead"), "artist : \"radiohead\" *");
+        assert_eq!(expression("ARTIST:Radiohead"), "artist : \"Radiohead\" *");
+        assert_eq!(expression("album:\"ok computer\""), "album : \"ok computer\" *");
+        assert_eq!(expression("lyrics:love rock"), "lyrics : \"love\" * \"rock\" *");
+        // Anything else before a colon is part of the word.
+        assert_eq!(expression("mood:happy"), "\"mood:happy\" *");
+        assert_eq!(expression("title:artist:x"), "title : \"artist:x\" *");
+        assert_eq!(expression("10:30"), "\"10:30\" *");
+    }
+
+    #[test]
+    fn keeps_non_ascii_text() {
+        assert_eq!(expression("Björk Sigur Rós"), "\"Björk\" * \"Sigur\" * \"Rós\" *");
+        assert_eq!(expression("artist:東京"), "artist : \"東京\" *");
+        assert_eq!(expression("«quoted»"), "\"«quoted»\" *");
+    }
+
+    #[test]
+    fn expressions_run_against_the_index() {
+        let conn = index();
+        assert_eq!(hits(&conn, "bjork"), vec![1]);
+        assert_eq!(hits(&conn, "JOGA"), vec![1]);
+        assert_eq!(hits(&conn, "AC/DC"), vec![2]);
+        assert_eq!(hits(&conn, "\"in black\""), vec![2]);
+        assert_eq!(hits(&conn, "rad"), vec![3, 5]);
+        assert_eq!(hits(&conn, "artist:radiohead"), vec![3]);
+        assert_eq!(hits(&conn, "title:radiohead"), vec![5]);
+        assert_eq!(hits(&conn, "album:\"ok comp\""), vec![3]);
+        assert_eq!(hits(&conn, "東京"), vec![4]);
+        assert_eq!(hits(&conn, "rock android"), vec![3]);
+
+        // Operator syntax neither fails nor acts as an operator.
+        for query in ["-live", "NEAR(radiohead live)", "radiohead OR bjork", "NOT rock", "*", "(", "a:b:c", "^"] {
+            let expression = expression(query);
+            conn.prepare("SELECT rowid FROM tracks_fts WHERE tracks_fts MATCH ?1")
+                .unwrap()
+                .query_map([&expression], |row| row.get::<_, i64>(0))
+                .unwrap()
+                .collect::<rusqlite::Result<Vec<_>>>()
+                .unwrap_or_else(|e| panic!("{:?} -> {:?}: {}", query, expression, e));
+        }
+        assert!(hits(&conn, "radiohead OR bjork").is_empty());
+    }
+}
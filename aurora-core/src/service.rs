@@ -0,0 +1,141 @@
+use crate::LibraryManager;
+use anyhow::{anyhow, Result};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+
+/// Read-only connections kept open between reads. More are opened while
+/// every idle one is in use, and closed again afterwards.
+const IDLE_READERS: usize = 4;
+
+type Job = Box<dyn FnOnce(&LibraryManager) + Send>;
+
+/// The library as shared between threads. A single worker thread owns the
+/// connection that writes and runs write jobs one at a time, in the order
+/// they were queued. Reads run on the calling thread over read-only
+/// connections, which in WAL mode see the last commit without waiting for
+/// the writer. Clones are cheap and share the same worker.
+#[derive(Clone)]
+pub struct LibraryService {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    db_path: PathBuf,
+    jobs: Sender<Job>,
+    readers: Mutex<Vec<LibraryManager>>,
+}
+
+impl LibraryService {
+    /// Opens the database, migrating it first if needed, and starts the
+    /// writer. The writer stops once the last clone is dropped and the
+    /// jobs already queued have run.
+    pub fn open(db_path: PathBuf) -> Result<Self> {
+        let writer = LibraryManager::new(db_path.clone())?;
+        let (jobs, queue) = mpsc::channel::<Job>();
+        std::thread::Builder::new()
+            .name("library-writer".into())
+            .spawn(move || {
+                for job in queue {
+                    // A transaction left open by the job rolls back as it unwinds.
+                    if catch_unwind(AssertUnwindSafe(|| job(&writer))).is_err() {
+                        log::error!("Library write job panicked");
+                    }
+                }
+            })?;
+
+        Ok(Self {
+            inner: Arc::new(Inner { db_path, jobs, readers: Mutex::new(Vec::new()) }),
+        })
+    }
+
+    pub fn db_path(&self) -> &Path {
+        &self.inner.db_path
+    }
+
+    /// Runs `f` on a read-only connection, on this thread. Anything that
+    /// writes fails, including lookups that cache what they find, such as
+    /// covers; those go through `write`. Audio hashes and fingerprints have
+    /// methods of their own here that decode on the calling thread.
+    pub fn read<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&LibraryManager) -> Result<T>,
+    {
+        let reader = self.inner.readers.lock().unwrap().pop();
+        let reader = match reader {
+            Some(reader) => reader,
+            None => LibraryManager::open_read_only(&self.inner.db_path)?,
+        };
+        let result = f(&reader);
+        let mut readers = self.inner.readers.lock().unwrap();
+        if readers.len() < IDLE_READERS {
+            readers.push(reader);
+        }
+        result
+    }
+
+    /// Queues `f` for the writer and waits for its result. Blocks for as
+    /// long as the jobs ahead of it take, a scan included, so UI code should
+    /// prefer `enqueue` or `write_async`.
+    pub fn write<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&LibraryManager) -> Result<T> + Send + 'static,
+    {
+        let (reply, result) = mpsc::sync_channel(1);
+        self.submit(move |library| {
+            let _ = reply.send(f(library));
+        })?;
+        result.recv().map_err(|_| anyhow!("Library writer dropped the job"))?
+    }
+
+    /// Queues `f` for the writer without waiting for it. The result is
+    /// dropped and a failure only logged.
+    pub fn enqueue<T, F>(&self, f: F)
+    where
+        F: FnOnce(&LibraryManager) -> Result<T> + Send + 'static,
+    {
+        let queued = self.submit(move |library| {
+            if let Err(e) = f(library) {
+                log::error!("Library write failed: {}", e);
+            }
+        });
+        if let Err(e) = queued {
+            log::error!("{}", e);
+        }
+    }
+
+    /// `read` on tokio's blocking pool.
+    pub async fn read_async<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&LibraryManager) -> Result<T> + Send + 'static,
+    {
+        let service = self.clone();
+        tokio::task::spawn_blocking(move || service.read(f)).await?
+    }
+
+    /// `write` without blocking the task while the writer is busy.
+    pub async fn write_async<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&LibraryManager) -> Result<T> + Send + 'static,
+    {
+        let (reply, result) = tokio::sync::oneshot::channel();
+        self.submit(move |library| {
+            let _ = reply.send(f(library));
+        })?;
+        result.await.map_err(|_| anyhow!("Library writer dropped the job"))?
+    }
+
+    pub(crate) fn submit<F>(&self, job: F) -> Result<()>
+    where
+        F: FnOnce(&LibraryManager) + Send + 'static,
+    {
+        self.inner
+            .jobs
+            .send(Box::new(job))
+            .map_err(|_| anyhow!("Library writer has stopped"))
+    }
+}
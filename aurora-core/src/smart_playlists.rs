@@ -0,0 +1,115 @@
+use crate::playlists::unix_time;
+use crate::{track_from_row, LibraryManager, SmartQuery, Track, TRACK_SELECT};
+use anyhow::{bail, Context, Result};
+use rusqlite::{params, params_from_iter, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+/// A saved query. Its tracks are worked out each time they are asked for,
+/// so it follows the library as it changes.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SmartPlaylist {
+    pub id: i64,
+    pub name: String,
+    pub query: SmartQuery,
+}
+
+impl LibraryManager {
+    pub fn create_smart_playlist(&self, name: &str, query: &SmartQuery) -> Result<i64> {
+        self.query_tracks_sql(query)?;
+        let now = unix_time();
+        self.conn.execute(
+            "INSERT INTO smart_playlists (name, query, created_at, updated_at) VALUES (?1, ?2, ?3, ?3)",
+            params![name, serde_json::to_string(query)?, now],
+        )?;
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    pub fn update_smart_playlist(&self, playlist_id: i64, query: &SmartQuery) -> Result<()> {
+        self.query_tracks_sql(query)?;
+        let changed = self.conn.execute(
+            "UPDATE smart_playlists SET query = ?1, updated_at = ?2 WHERE id = ?3",
+            params![serde_json::to_string(query)?, unix_time(), playlist_id],
+        )?;
+        if changed == 0 {
+            bail!("Smart playlist {} does not exist", playlist_id);
+        }
+        Ok(())
+    }
+
+    pub fn rename_smart_playlist(&self, playlist_id: i64, name: &str) -> Result<()> {
+        let changed = self.conn.execute(
+            "UPDATE smart_playlists SET name = ?1, updated_at = ?2 WHERE id = ?3",
+            params![name, unix_time(), playlist_id],
+        )?;
+        if changed == 0 {
+            bail!("Smart playlist {} does not exist", playlist_id);
+        }
+        Ok(())
+    }
+
+    pub fn delete_smart_playlist(&self, playlist_id: i64) -> Result<()> {
+        let changed = self.conn.execute("DELETE FROM smart_playlists WHERE id = ?1", params![playlist_id])?;
+        if changed == 0 {
+            bail!("Smart playlist {} does not exist", playlist_id);
+        }
+        Ok(())
+    }
+
+    pub fn get_smart_playlists(&self) -> Result<Vec<SmartPlaylist>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, name, query FROM smart_playlists ORDER BY name COLLATE NOCASE"
+        )?;
+        let rows = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get::<_, String>(2)?)))?
+            .collect::<rusqlite::Result<Vec<(i64, String, String)>>>()?;
+        rows.into_iter()
+            .map(|(id, name, query)| {
+                let query = serde_json::from_str(&query)
+                    .with_context(|| format!("Smart playlist {} has an invalid query", id))?;
+                Ok(SmartPlaylist { id, name, query })
+            })
+            .collect()
+    }
+
+    pub fn get_smart_playlist_tracks(&self, playlist_id: i64) -> Result<Vec<Track>> {
+        let query: String = self.conn.query_row(
+            "SELECT query FROM smart_playlists WHERE id = ?1",
+            params![playlist_id],
+            |row| row.get(0),
+        ).optional()?
+            .with_context(|| format!("Smart playlist {} does not exist", playlist_id))?;
+        let query = serde_json::from_str(&query)
+            .with_context(|| format!("Smart playlist {} has an invalid query", playlist_id))?;
+        self.query_tracks(&query)
+    }
+
+    /// Tracks matching `query` right now, without saving it.
+    pub fn query_tracks(&self, query: &SmartQuery) -> Result<Vec<Track>> {
+        let (sql, params) = self.query_tracks_sql(query)?;
+        let mut stmt = self.conn.prepare(&sql)?;
+        let tracks = stmt
+            .query_map(params_from_iter(params), track_from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(tracks)
+    }
+
+    /// Compiles and prepares the query, so a bad one is refused before it is saved.
+    fn query_tracks_sql(&self, query: &SmartQuery) -> Result<(String, Vec<rusqlite::types::Value>)> {
+        let (clauses, params) = query.to_sql(unix_time())?;
+        let sql = format!(
+            "{} LEFT JOIN track_play_stats s ON s.track_id = t.id{}",
+            TRACK_SELECT, clauses
+        );
+        self.conn.prepare(&sql)?;
+        Ok((sql, params))
+    }
+}
+
+impl mlua::UserData for SmartPlaylist {
+    fn add_fields<'lua, F: mlua::UserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_method_get("id", |_lua, this| Ok(this.id));
+        fields.add_field_method_get("name", |_lua, this| Ok(this.name.clone()));
+        // In the text syntax, ready to edit and hand back.
+        fields.add_field_method_get("query", |_lua, this| Ok(this.query.to_string()));
+    }
+}
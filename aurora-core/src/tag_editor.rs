@@ -0,0 +1,451 @@
+use crate::LibraryManager;
+use anyhow::{bail, Context, Result};
+use lofty::config::WriteOptions;
+use lofty::file::{TaggedFile, TaggedFileExt};
+use lofty::id3::v2::Id3v2Tag;
+use lofty::probe::Probe;
+use lofty::tag::{Accessor, ItemKey, Tag, TagExt, TagType};
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// A tag field that can be edited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TagField {
+    Title,
+    Artist,
+    Album,
+    AlbumArtist,
+    Genre,
+    Composer,
+    Year,
+    TrackNumber,
+    TrackTotal,
+    DiscNumber,
+    DiscTotal,
+    Compilation,
+    ReleaseDate,
+    OriginalDate,
+    Label,
+    CatalogNumber,
+    Comment,
+}
+
+impl TagField {
+    const ALL: [TagField; 17] = [
+        TagField::Title,
+        TagField::Artist,
+        TagField::Album,
+        TagField::AlbumArtist,
+        TagField::Genre,
+        TagField::Composer,
+        TagField::Year,
+        TagField::TrackNumber,
+        TagField::TrackTotal,
+        TagField::DiscNumber,
+        TagField::DiscTotal,
+        TagField::Compilation,
+        TagField::ReleaseDate,
+        TagField::OriginalDate,
+        TagField::Label,
+        TagField::CatalogNumber,
+        TagField::Comment,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            TagField::Title => "title",
+            TagField::Artist => "artist",
+            TagField::Album => "album",
+            TagField::AlbumArtist => "album_artist",
+            TagField::Genre => "genre",
+            TagField::Composer => "composer",
+            TagField::Year => "year",
+            TagField::TrackNumber => "track_number",
+            TagField::TrackTotal => "track_total",
+            TagField::DiscNumber => "disc_number",
+            TagField::DiscTotal => "disc_total",
+            TagField::Compilation => "compilation",
+            TagField::ReleaseDate => "release_date",
+            TagField::OriginalDate => "original_date",
+            TagField::Label => "label",
+            TagField::CatalogNumber => "catalog_number",
+            TagField::Comment => "comment",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|field| field.name().eq_ignore_ascii_case(name))
+    }
+
+    /// The item holding a plain text field.
+    fn text_key(self) -> Option<ItemKey> {
+        match self {
+            TagField::Title => Some(ItemKey::TrackTitle),
+            TagField::Artist => Some(ItemKey::TrackArtist),
+            TagField::Album => Some(ItemKey::AlbumTitle),
+            TagField::AlbumArtist => Some(ItemKey::AlbumArtist),
+            TagField::Genre => Some(ItemKey::Genre),
+            TagField::Composer => Some(ItemKey::Composer),
+            TagField::OriginalDate => Some(ItemKey::OriginalReleaseDate),
+            TagField::Label => Some(ItemKey::Label),
+            TagField::CatalogNumber => Some(ItemKey::CatalogNumber),
+            TagField::Comment => Some(ItemKey::Comment),
+            _ => None,
+        }
+    }
+
+    fn read(self, tag: &Tag) -> Option<String> {
+        if let Some(key) = self.text_key() {
+            return tag.get_string(&key).map(str::to_string);
+        }
+        let number = match self {
+            TagField::Year => tag.year(),
+            TagField::TrackNumber => tag.track(),
+            TagField::TrackTotal => tag.track_total(),
+            TagField::DiscNumber => tag.disk(),
+            TagField::DiscTotal => tag.disk_total(),
+            TagField::Compilation => {
+                let flag = tag.get_string(&ItemKey::FlagCompilation)?;
+                return Some(if flag == "1" || flag.eq_ignore_ascii_case("true") { "1" } else { "0" }.to_string());
+            }
+            // Read the way a scan reads it.
+            TagField::ReleaseDate => {
+                return tag
+                    .get_string(&ItemKey::ReleaseDate)
+                    .or_else(|| tag.get_string(&ItemKey::RecordingDate))
+                    .map(str::to_string);
+            }
+            _ => None,
+        };
+        number.map(|number| number.to_string())
+    }
+
+    /// Sets the field, or removes it for `None`. Numbers and flags are
+    /// checked here, before anything is written.
+    fn write(self, tag: &mut Tag, value: Option<&str>) -> Result<()> {
+        if let Some(key) = self.text_key() {
+            match value {
+                Some(value) => {
+                    tag.insert_text(key, value.to_string());
+                }
+                None => tag.remove_key(&key),
+            }
+            return Ok(());
+        }
+        match self {
+            TagField::Compilation => match value.map(parse_flag).transpose()? {
+                Some(true) => {
+                    tag.insert_text(ItemKey::FlagCompilation, "1".to_string());
+                }
+                _ => tag.remove_key(&ItemKey::FlagCompilation),
+            },
+            TagField::ReleaseDate => {
+                let key = if tag.get_string(&ItemKey::ReleaseDate).is_some() {
+                    ItemKey::ReleaseDate
+                } else {
+                    ItemKey::RecordingDate
+                };
+                match value {
+                    Some(value) => {
+                        tag.insert_text(key, value.to_string());
+                    }
+                    None => tag.remove_key(&key),
+                }
+            }
+            _ => {
+                let number = value.map(|value| parse_number(self, value)).transpose()?;
+                match (self, number) {
+                    (TagField::Year, Some(n)) => tag.set_year(n),
+                    (TagField::Year, None) => tag.remove_year(),
+                    (TagField::TrackNumber, Some(n)) => tag.set_track(n),
+                    (TagField::TrackNumber, None) => tag.remove_track(),
+                    (TagField::TrackTotal, Some(n)) => tag.set_track_total(n),
+                    (TagField::TrackTotal, None) => tag.remove_track_total(),
+                    (TagField::DiscNumber, Some(n)) => tag.set_disk(n),
+                    (TagField::DiscNumber, None) => tag.remove_disk(),
+                    (TagField::DiscTotal, Some(n)) => tag.set_disk_total(n),
+                    (TagField::DiscTotal, None) => tag.remove_disk_total(),
+                    _ => unreachable!("{:?} is a text field", self),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_number(field: TagField, value: &str) -> Result<u32> {
+    value
+        .trim()
+        .parse()
+        .with_context(|| format!("{} must be a whole number, got {:?}", field.name(), value))
+}
+
+fn parse_flag(value: &str) -> Result<bool> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" => Ok(true),
+        "0" | "false" | "no" => Ok(false),
+        _ => bail!("compilation must be true or false, got {:?}", value),
+    }
+}
+
+/// Edits to apply to a track's tags. A field set to `None`, or to an empty
+/// string, is removed from the file.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct TagChanges {
+    pub fields: BTreeMap<TagField, Option<String>>,
+}
+
+impl TagChanges {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(mut self, field: TagField, value: impl Into<String>) -> Self {
+        self.fields.insert(field, Some(value.into()));
+        self
+    }
+
+    pub fn clear(mut self, field: TagField) -> Self {
+        self.fields.insert(field, None);
+        self
+    }
+}
+
+/// One field as it was and as it will be, or has become.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FieldChange {
+    pub field: TagField,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+/// What an edit changes in one file. Fields the edit sets to the value they
+/// already have are left out.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TagDiff {
+    pub track_id: i64,
+    pub path: String,
+    pub changes: Vec<FieldChange>,
+}
+
+/// An edit worked out in memory, ready to be written.
+struct PlannedEdit {
+    diff: TagDiff,
+    file: TaggedFile,
+    tag_type: TagType,
+}
+
+impl LibraryManager {
+    /// Edits one track's tags. See `update_tags_batch`.
+    pub fn update_tags(&self, track_id: i64, changes: &TagChanges, dry_run: bool) -> Result<TagDiff> {
+        let mut diffs = self.update_tags_batch(&[(track_id, changes.clone())], dry_run)?;
+        Ok(diffs.remove(0))
+    }
+
+    /// Writes tag edits into each file's native tag, re-reads the files and
+    /// updates their tracks in one transaction. With `dry_run` nothing is
+    /// written and the diffs say what would change.
+    ///
+    /// Every file is read and every value checked before the first write, so
+    /// a bad value or unreadable file leaves all of them alone. Each file is
+    /// written to a copy that then replaces it, so a failed write never
+    /// leaves a file half written.
+    pub fn update_tags_batch(&self, edits: &[(i64, TagChanges)], dry_run: bool) -> Result<Vec<TagDiff>> {
+        let mut planned = Vec::with_capacity(edits.len());
+        for (track_id, changes) in edits {
+            planned.push(self.plan_edit(*track_id, changes)?);
+        }
+        if dry_run {
+            return Ok(planned.into_iter().map(|edit| edit.diff).collect());
+        }
+
+        let mut written = Vec::new();
+        let mut failure = None;
+        for edit in &planned {
+            if edit.diff.changes.is_empty() {
+                continue;
+            }
+            let path = Path::new(&edit.diff.path);
+            match save_tag(&edit.file, edit.tag_type, path) {
+                Ok(()) => written.push(path.to_path_buf()),
+                Err(e) => {
+                    failure = Some(e.context(format!("Failed to write tags to {:?}", path)));
+                    break;
+                }
+            }
+        }
+
+        // Files already written are recorded even if a later one failed.
+        self.reload_tracks(&written)?;
+        if let Some(e) = failure {
+            return Err(e.context(format!("{} of {} files were written before the failure", written.len(), planned.len())));
+        }
+        Ok(planned.into_iter().map(|edit| edit.diff).collect())
+    }
+
+    fn plan_edit(&self, track_id: i64, changes: &TagChanges) -> Result<PlannedEdit> {
+        let (path, start_ms): (String, Option<u32>) = self.conn.query_row(
+            "SELECT path, start_ms FROM tracks WHERE id = ?1",
+            params![track_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        ).optional()?
+            .with_context(|| format!("Track {} does not exist", track_id))?;
+        if start_ms.is_some() {
+            bail!("Track {} comes from a CUE sheet, whose file it shares with other tracks; edit the sheet instead", track_id);
+        }
+
+        let mut file = Probe::open(&path)
+            .and_then(|probe| probe.guess_file_type()?.read())
+            .with_context(|| format!("Failed to read tags from {:?}", path))?;
+        let tag_type = file.primary_tag_type();
+        if file.tag(tag_type).is_none() {
+            file.insert_tag(Tag::new(tag_type));
+        }
+        let Some(tag) = file.tag_mut(tag_type) else {
+            bail!("{:?} cannot hold a {:?} tag", path, tag_type);
+        };
+
+        let mut diff = TagDiff { track_id, path, changes: Vec::new() };
+        for (field, value) in &changes.fields {
+            let new = value.as_deref().map(str::trim).filter(|value| !value.is_empty());
+            let old = field.read(tag);
+            field.write(tag, new).with_context(|| format!("Invalid edit for track {}", track_id))?;
+            let new = field.read(tag);
+            if old != new {
+                diff.changes.push(FieldChange { field: *field, old, new });
+            }
+        }
+        Ok(PlannedEdit { diff, file, tag_type })
+    }
+
+    /// Re-reads edited files into the library, all or none.
+    fn reload_tracks(&self, paths: &[PathBuf]) -> Result<()> {
+        if paths.is_empty() {
+            return Ok(());
+        }
+        let generation = self.current_generation()?;
+        let tx = self.conn.unchecked_transaction()?;
+        for path in paths {
+            self.add_track_with_generation(path, generation)?;
+        }
+        tx.commit()?;
+        // An edited album or artist may have left an empty one behind.
+        self.remove_orphans()
+    }
+}
+
+/// Saves the edited tag to a copy of the file next to it, then moves the
+/// copy over the original.
+fn save_tag(file: &TaggedFile, tag_type: TagType, path: &Path) -> Result<()> {
+    let Some(tag) = file.tag(tag_type) else {
+        bail!("{:?} has no {:?} tag", path, tag_type);
+    };
+    let name = path.file_name().context("Path has no file name")?.to_string_lossy();
+    let temp = path.with_file_name(format!(".{}.aurora-edit", name));
+    std::fs::copy(path, &temp)?;
+
+    let saved = if tag_type == TagType::Id3v2 {
+        // Through `Id3v2Tag`, so frames the generic tag leaves out survive.
+        Id3v2Tag::from(tag.clone()).save_to_path(&temp, WriteOptions::default())
+    } else {
+        tag.save_to_path(&temp, WriteOptions::default())
+    };
+    let result = saved
+        .map_err(anyhow::Error::from)
+        .and_then(|()| std::fs::rename(&temp, path).map_err(anyhow::Error::from));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    result
+}
+
+impl mlua::UserData for FieldChange {
+    fn add_fields<'lua, F: mlua::UserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_method_get("field", |_lua, this| Ok(this.field.name()));
+        fields.add_field_method_get("old", |_lua, this| Ok(this.old.clone()));
+        fields.add_field_method_get("new", |_lua, this| Ok(this.new.clone()));
+    }
+}
+
+impl mlua::UserData for TagDiff {
+    fn add_fields<'lua, F: mlua::UserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_method_get("track_id", |_lua, this| Ok(this.track_id));
+        fields.add_field_method_get("path", |_lua, this| Ok(this.path.clone()));
+        fields.add_field_method_get("changes", |_lua, this| Ok(this.changes.clone()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use crate::{read_metadata, LibraryRoot};
+
+    fn library(dir: &TempDir) -> LibraryManager {
+        let manager = LibraryManager::new(dir.path().join("aurora.db")).unwrap();
+        manager.add_library_root(&LibraryRoot::new(dir.path().join("music"))).unwrap();
+        manager.scan_library().unwrap();
+        manager
+    }
+
+    fn track_id(manager: &LibraryManager, path: &Path) -> i64 {
+        manager.get_track_by_path(path).unwrap().unwrap().id
+    }
+
+    #[test]
+    fn dry_runs_write_nothing() {
+        let dir = TempDir::new("tag-editor-dry-run");
+        let path = dir.write_wav("music/one.wav");
+        let manager = library(&dir);
+        let id = track_id(&manager, &path);
+        let before = std::fs::read(&path).unwrap();
+
+        let changes = TagChanges::new().set(TagField::Title, "Renamed").set(TagField::Year, "1999");
+        let diff = manager.update_tags(id, &changes, true).unwrap();
+        let changed: Vec<(TagField, Option<&str>)> =
+            diff.changes.iter().map(|change| (change.field, change.new.as_deref())).collect();
+        assert_eq!(changed, [(TagField::Title, Some("Renamed")), (TagField::Year, Some("1999"))]);
+
+        assert_eq!(std::fs::read(&path).unwrap(), before);
+        let track = manager.get_track_by_path(&path).unwrap().unwrap();
+        assert_eq!((track.title.as_str(), track.year), ("one", None));
+    }
+
+    #[test]
+    fn batch_edits_write_the_files_and_the_library() {
+        let dir = TempDir::new("tag-editor-batch");
+        let one = dir.write_wav("music/one.wav");
+        let two = dir.write_wav("music/two.wav");
+        let manager = library(&dir);
+        let edits = [
+            (track_id(&manager, &one), TagChanges::new().set(TagField::Title, " Renamed ").set(TagField::Artist, "Someone")),
+            (track_id(&manager, &two), TagChanges::new().set(TagField::Album, "Somewhere").set(TagField::Year, "2001")),
+        ];
+
+        let diffs = manager.update_tags_batch(&edits, false).unwrap();
+        assert_eq!(diffs.iter().map(|diff| diff.changes.len()).collect::<Vec<_>>(), [2, 2]);
+        let written = read_metadata(&one).unwrap();
+        assert_eq!((written.title.as_str(), written.artist.as_str()), ("Renamed", "Someone"));
+        let written = read_metadata(&two).unwrap();
+        assert_eq!(written.album, "Somewhere");
+
+        let track = manager.get_track_by_path(&one).unwrap().unwrap();
+        assert_eq!((track.title.as_str(), track.artist.as_str()), ("Renamed", "Someone"));
+        let track = manager.get_track_by_path(&two).unwrap().unwrap();
+        assert_eq!((track.album.as_str(), track.year), ("Somewhere", Some(2001)));
+        assert!(!dir.path().join("music/.one.wav.aurora-edit").exists());
+
+        // One bad value leaves every file in the batch alone.
+        let before = std::fs::read(&one).unwrap();
+        let edits = [
+            (edits[0].0, TagChanges::new().set(TagField::Title, "Again")),
+            (edits[1].0, TagChanges::new().set(TagField::TrackNumber, "first")),
+        ];
+        assert!(manager.update_tags_batch(&edits, false).is_err());
+        assert_eq!(std::fs::read(&one).unwrap(), before);
+        assert_eq!(manager.get_track_by_path(&one).unwrap().unwrap().title, "Renamed");
+    }
+}
@@ -0,0 +1,465 @@
+use crate::cover_art::is_cover_image;
+use crate::cue::{is_cue_sheet, resolve_file};
+use crate::lyrics::{is_lyrics_sidecar, sidecar_audio};
+use crate::roots::RootFilter;
+use crate::scan::collect_audio_files;
+use crate::{CueSheet, LibraryManager, LibraryRoot, LibraryService, ScanSummary, Track};
+use anyhow::Result;
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use rusqlite::params;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+/// How long the filesystem has to stay quiet before queued events are applied.
+/// Copies and rips emit a burst of modify events per file.
+const DEBOUNCE: Duration = Duration::from_millis(750);
+
+/// How often roots are looked at again while the filesystem is quiet, so
+/// drives that are plugged back in, and roots added or removed elsewhere,
+/// are picked up.
+const ROOT_CHECK: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone)]
+pub enum LibraryChange {
+    /// A track was added, or its file changed and its tags were re-read.
+    Upserted(Box<Track>),
+    Removed { id: i64, path: String },
+    /// A root went offline or came back, and every track below it with it.
+    RootStatus { path: PathBuf, online: bool },
+}
+
+/// Follows library roots on disk and applies changes to the database from a
+/// background thread. Watching stops when this is dropped.
+pub struct LibraryWatcher {
+    watches: Arc<Mutex<Watches>>,
+}
+
+/// The notify watcher and the roots it is following. Only online roots are
+/// watched; one that goes offline is dropped and picked up again when it is
+/// back.
+struct Watches {
+    watcher: RecommendedWatcher,
+    roots: BTreeSet<PathBuf>,
+}
+
+impl Watches {
+    fn watch(&mut self, root: &Path) -> Result<()> {
+        if !self.roots.contains(root) {
+            self.watcher.watch(root, RecursiveMode::Recursive)?;
+            self.roots.insert(root.to_path_buf());
+        }
+        Ok(())
+    }
+
+    fn unwatch(&mut self, root: &Path) -> Result<()> {
+        if self.roots.remove(root) {
+            self.watcher.unwatch(root)?;
+        }
+        Ok(())
+    }
+
+    fn set_online(&mut self, root: &Path, online: bool) {
+        if online {
+            if let Err(e) = self.watch(root) {
+                log::error!("Failed to watch {:?}: {}", root, e);
+            }
+        } else if let Err(e) = self.unwatch(root) {
+            // The watch usually went away with the drive.
+            log::debug!("Failed to unwatch {:?}: {}", root, e);
+        }
+    }
+
+    fn set_roots(&mut self, roots: &[LibraryRoot]) {
+        let stale: Vec<PathBuf> = self
+            .roots
+            .iter()
+            .filter(|path| !roots.iter().any(|root| root.online && &root.path == *path))
+            .cloned()
+            .collect();
+        for path in stale {
+            self.set_online(&path, false);
+        }
+        for root in roots.iter().filter(|root| root.online) {
+            self.set_online(&root.path, true);
+        }
+    }
+}
+
+impl LibraryWatcher {
+    /// Starts watching the online `roots`, applying changes through
+    /// `library`'s writer. `on_change` runs on the watcher thread once per
+    /// debounced batch.
+    pub fn spawn<F>(library: LibraryService, roots: &[LibraryRoot], on_change: F) -> Result<Self>
+    where
+        F: FnMut(Vec<LibraryChange>) + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel();
+        let watcher = notify::recommended_watcher(tx)?;
+        let watches = Arc::new(Mutex::new(Watches { watcher, roots: BTreeSet::new() }));
+
+        // The thread only holds on weakly, so dropping this drops the notify
+        // watcher, which closes the channel and ends the thread.
+        let thread_watches = Arc::downgrade(&watches);
+        std::thread::Builder::new()
+            .name("library-watcher".into())
+            .spawn(move || run(library, thread_watches, rx, on_change))?;
+
+        let this = Self { watches };
+        this.set_roots(roots);
+        Ok(this)
+    }
+
+    /// Follows `roots` from now on: online roots not watched yet are added,
+    /// and roots that went offline or are no longer given are dropped.
+    pub fn set_roots(&self, roots: &[LibraryRoot]) {
+        self.watches.lock().unwrap().set_roots(roots);
+    }
+
+    pub fn watch(&self, root: &Path) -> Result<()> {
+        self.watches.lock().unwrap().watch(root)
+    }
+
+    pub fn unwatch(&self, root: &Path) -> Result<()> {
+        self.watches.lock().unwrap().unwatch(root)
+    }
+}
+
+fn run<F>(
+    library: LibraryService,
+    watches: Weak<Mutex<Watches>>,
+    rx: Receiver<notify::Result<Event>>,
+    mut on_change: F,
+) where
+    F: FnMut(Vec<LibraryChange>),
+{
+    let mut pending = BTreeSet::new();
+    let mut last_check = Instant::now();
+    loop {
+        let event = if pending.is_empty() {
+            match rx.recv_timeout(ROOT_CHECK.saturating_sub(last_check.elapsed())) {
+                Ok(event) => event,
+                Err(RecvTimeoutError::Timeout) => {
+                    check_roots(&library, &watches, &mut pending, &mut on_change);
+                    last_check = Instant::now();
+                    continue;
+                }
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        } else {
+            match rx.recv_timeout(DEBOUNCE) {
+                Ok(event) => event,
+                Err(RecvTimeoutError::Timeout) => {
+                    flush(&library, &watches, &mut pending, &mut on_change);
+                    continue;
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    flush(&library, &watches, &mut pending, &mut on_change);
+                    return;
+                }
+            }
+        };
+
+        match event {
+            Ok(event) if !matches!(event.kind, EventKind::Access(_)) => pending.extend(event.paths),
+            Ok(_) => {}
+            Err(e) => log::error!("Library watcher error: {}", e),
+        }
+    }
+}
+
+fn flush<F>(library: &LibraryService, watches: &Weak<Mutex<Watches>>, pending: &mut BTreeSet<PathBuf>, on_change: &mut F)
+where
+    F: FnMut(Vec<LibraryChange>),
+{
+    let paths = std::mem::take(pending);
+    let changes = library.write(move |library| {
+        let mut changes = Vec::new();
+        for path in paths {
+            match library.sync_path(&path) {
+                Ok(c) => changes.extend(c),
+                Err(e) => log::error!("Failed to sync {:?}: {}", path, e),
+            }
+        }
+        Ok(changes)
+    });
+    update_covers(library);
+    match changes {
+        Ok(changes) if !changes.is_empty() => {
+            follow_root_status(watches, &changes);
+            on_change(changes);
+        }
+        Ok(_) => {}
+        Err(e) => log::error!("Failed to apply library changes: {}", e),
+    }
+}
+
+/// Looks for covers of the albums the batch touched. Probing their files
+/// happens on a reader, so only recording what was found holds up the
+/// writer.
+fn update_covers(library: &LibraryService) {
+    let stored = library.read(|library| library.find_covers()).and_then(|found| {
+        if found.is_empty() {
+            return Ok(0);
+        }
+        library.write(move |library| library.store_covers(found))
+    });
+    if let Err(e) = stored {
+        log::error!("Failed to update album covers: {}", e);
+    }
+}
+
+/// Looks at every root again and reports those that went offline or came
+/// back. Roots that are back are queued to be synced, as their files may
+/// have changed while they were away.
+fn check_roots<F>(library: &LibraryService, watches: &Weak<Mutex<Watches>>, pending: &mut BTreeSet<PathBuf>, on_change: &mut F)
+where
+    F: FnMut(Vec<LibraryChange>),
+{
+    let checked = library.write(|library| {
+        let before = library.get_library_roots()?;
+        let after = library.check_library_roots()?;
+        let changes: Vec<LibraryChange> = after
+            .iter()
+            .filter(|root| before.iter().any(|was| was.path == root.path && was.online != root.online))
+            .map(|root| LibraryChange::RootStatus { path: root.path.clone(), online: root.online })
+            .collect();
+        Ok((after, changes))
+    });
+    let (roots, changes) = match checked {
+        Ok(checked) => checked,
+        Err(e) => {
+            log::error!("Failed to check library roots: {}", e);
+            return;
+        }
+    };
+
+    if let Some(watches) = watches.upgrade() {
+        watches.lock().unwrap().set_roots(&roots);
+    }
+    for change in &changes {
+        if let LibraryChange::RootStatus { path, online: true } = change {
+            pending.insert(path.clone());
+        }
+    }
+    if !changes.is_empty() {
+        on_change(changes);
+    }
+}
+
+/// Watches roots that came back online and drops those that went offline.
+fn follow_root_status(watches: &Weak<Mutex<Watches>>, changes: &[LibraryChange]) {
+    let Some(watches) = watches.upgrade() else {
+        return;
+    };
+    let mut watches = watches.lock().unwrap();
+    for change in changes {
+        if let LibraryChange::RootStatus { path, online } = change {
+            watches.set_online(path, *online);
+        }
+    }
+}
+
+impl LibraryManager {
+    /// Brings the library in line with whatever is at `path` now: audio files
+    /// are added or re-read, directories are walked, and tracks at or below a
+    /// path that no longer exists are removed. Handles creates, modifies,
+    /// deletes and both ends of a rename alike. Files go through the rules of
+    /// their root, so tracks the rules now leave out are removed too, and a
+    /// root that has gone away is marked offline instead.
+    /// A CUE sheet changing re-reads the files it cuts, and those it used to,
+    /// and an LRC file changing re-reads the file it has the lyrics for.
+    pub fn sync_path(&self, path: &Path) -> Result<Vec<LibraryChange>> {
+        let mut changes = Vec::new();
+        if is_cover_image(path) {
+            if let Some(dir) = path.parent() {
+                self.forget_covers_in(dir)?;
+            }
+            return Ok(changes);
+        }
+
+        let root = match self.root_containing(path)? {
+            Some(root) => {
+                let online = self.check_root(&root)?;
+                if online != root.online {
+                    changes.push(LibraryChange::RootStatus { path: root.path.clone(), online });
+                }
+                if !online {
+                    return Ok(changes);
+                }
+                root
+            }
+            None => LibraryRoot::new(path),
+        };
+        let filter = self.root_filter(&root)?;
+
+        if is_cue_sheet(path) {
+            let mut files: BTreeSet<PathBuf> = self.files_cut_by(path)?.into_iter().collect();
+            if let Ok(sheet) = CueSheet::read(path) {
+                files.extend(sheet.files.iter().filter_map(|file| resolve_file(path, &file.name)));
+            }
+            for file in files.iter().filter(|file| filter.admits_file(file)) {
+                self.sync_file(file, &filter, &mut changes)?;
+            }
+        } else if is_lyrics_sidecar(path) {
+            if filter.admits_sidecar(path) {
+                for file in sidecar_audio(path).iter().filter(|file| filter.admits_file(file)) {
+                    self.sync_file(file, &filter, &mut changes)?;
+                }
+            }
+        } else if path.is_dir() && filter.admits_dir(path) {
+            for file in collect_audio_files(path, &filter).audio {
+                self.sync_file(&file, &filter, &mut changes)?;
+            }
+        } else if path.is_file() && filter.admits_file(path) {
+            self.sync_file(path, &filter, &mut changes)?;
+        } else {
+            // Gone, or no longer let in by the root's rules.
+            for (id, path) in self.remove_tracks_under(path)? {
+                changes.push(LibraryChange::Removed { id, path });
+            }
+        }
+        Ok(changes)
+    }
+
+    fn sync_file(&self, path: &Path, filter: &RootFilter, changes: &mut Vec<LibraryChange>) -> Result<()> {
+        let generation = self.current_generation()?;
+        let before = self.get_tracks_by_path(path)?;
+        let mut summary = ScanSummary::default();
+        self.scan_file(path, filter, generation, &mut summary);
+        if !summary.unsupported.is_empty() {
+            for (id, path) in self.remove_tracks_under(path)? {
+                changes.push(LibraryChange::Removed { id, path });
+            }
+            return Ok(());
+        }
+        if summary.added + summary.updated > 0 {
+            let after = self.get_tracks_by_path(path)?;
+            for track in before.into_iter().filter(|track| !after.iter().any(|kept| kept.id == track.id)) {
+                changes.push(LibraryChange::Removed { id: track.id, path: track.path });
+            }
+            for track in after {
+                changes.push(LibraryChange::Upserted(Box::new(track)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Files with tracks cut by the sheet at `path`.
+    fn files_cut_by(&self, path: &Path) -> Result<Vec<PathBuf>> {
+        let mut stmt = self.conn.prepare("SELECT DISTINCT path FROM tracks WHERE cue_path = ?1")?;
+        let files = stmt
+            .query_map(params![path.to_string_lossy()], |row| row.get::<_, String>(0))?
+            .map(|path| path.map(PathBuf::from))
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(files)
+    }
+
+    fn remove_tracks_under(&self, path: &Path) -> Result<Vec<(i64, String)>> {
+        let removed = self.tracks_under(path)?;
+        for (id, _) in &removed {
+            self.delete_track(*id)?;
+        }
+        if !removed.is_empty() {
+            self.remove_orphans()?;
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn spawn(dir: &TempDir, roots: &[LibraryRoot]) -> LibraryWatcher {
+        let library = LibraryService::open(dir.path().join("library.db")).unwrap();
+        LibraryWatcher::spawn(library, roots, |_| {}).unwrap()
+    }
+
+    fn watched(watcher: &LibraryWatcher) -> Vec<PathBuf> {
+        watcher.watches.lock().unwrap().roots.iter().cloned().collect()
+    }
+
+    fn root(dir: &TempDir, name: &str, online: bool) -> LibraryRoot {
+        let path = dir.path().join(name);
+        std::fs::create_dir_all(&path).unwrap();
+        LibraryRoot { online, ..LibraryRoot::new(path) }
+    }
+
+    #[test]
+    fn watches_only_online_roots() {
+        let dir = TempDir::new("watcher-online");
+        let (a, b) = (root(&dir, "a", true), root(&dir, "b", false));
+        let watcher = spawn(&dir, &[a.clone(), b.clone()]);
+        assert_eq!(watched(&watcher), vec![a.path.clone()]);
+    }
+
+    #[test]
+    fn set_roots_follows_added_removed_and_offline_roots() {
+        let dir = TempDir::new("watcher-set-roots");
+        let (a, b, c) = (root(&dir, "a", true), root(&dir, "b", true), root(&dir, "c", false));
+        let watcher = spawn(&dir, &[a.clone(), b.clone()]);
+
+        // b goes offline, a is removed and c is added and comes online.
+        let b_offline = LibraryRoot { online: false, ..b.clone() };
+        let c_online = LibraryRoot { online: true, ..c.clone() };
+        watcher.set_roots(&[b_offline, c_online]);
+        assert_eq!(watched(&watcher), vec![c.path.clone()]);
+
+        watcher.set_roots(&[b.clone(), c.clone()]);
+        assert_eq!(watched(&watcher), vec![b.path.clone()]);
+    }
+
+    #[test]
+    fn root_status_changes_watch_and_unwatch() {
+        let dir = TempDir::new("watcher-status");
+        let (a, b) = (root(&dir, "a", true), root(&dir, "b", false));
+        let watcher = spawn(&dir, &[a.clone(), b.clone()]);
+
+        let changes = [
+            LibraryChange::RootStatus { path: a.path.clone(), online: false },
+            LibraryChange::RootStatus { path: b.path.clone(), online: true },
+        ];
+        follow_root_status(&Arc::downgrade(&watcher.watches), &changes);
+        assert_eq!(watched(&watcher), vec![b.path.clone()]);
+    }
+
+    #[test]
+    fn root_check_reports_roots_going_and_coming_back() {
+        let dir = TempDir::new("watcher-check");
+        let music = dir.write("music/a.txt", "");
+        let path = music.parent().unwrap().to_path_buf();
+        let library = LibraryService::open(dir.path().join("library.db")).unwrap();
+        let root = LibraryRoot::new(path.clone());
+        let added = root.clone();
+        library.write(move |library| library.add_library_root(&added)).unwrap();
+        let watcher = LibraryWatcher::spawn(library.clone(), &[root], |_| {}).unwrap();
+        let watches = Arc::downgrade(&watcher.watches);
+
+        let mut pending = BTreeSet::new();
+        let mut reported = Vec::new();
+        let mut on_change = |changes: Vec<LibraryChange>| reported.extend(changes);
+
+        std::fs::remove_dir_all(&path).unwrap();
+        check_roots(&library, &watches, &mut pending, &mut on_change);
+        std::fs::create_dir_all(&path).unwrap();
+        std::fs::write(path.join("b.txt"), "").unwrap();
+        check_roots(&library, &watches, &mut pending, &mut on_change);
+        // Nothing changed since the last check.
+        check_roots(&library, &watches, &mut pending, &mut on_change);
+
+        let statuses: Vec<bool> = reported
+            .iter()
+            .map(|change| match change {
+                LibraryChange::RootStatus { path: p, online } if *p == path => *online,
+                other => panic!("unexpected change {:?}", other),
+            })
+            .collect();
+        assert_eq!(statuses, vec![false, true]);
+        assert_eq!(pending, BTreeSet::from([path.clone()]));
+        assert_eq!(watched(&watcher), vec![path]);
+    }
+}
@@ -0,0 +1,235 @@
+//! Duplicate finding end to end: copies of generated recordings under
+//! matching and unrelated names, in better and worse encodings.
+
+use audiopus::coder::Encoder;
+use audiopus::{Application, Bitrate, Channels, SampleRate};
+use aurora_core::{DuplicateGroup, DuplicateOptions, LibraryRoot, LibraryService, Track};
+use hound::{SampleFormat, WavSpec, WavWriter};
+use ogg::writing::{PacketWriteEndInfo, PacketWriter};
+use std::f64::consts::PI;
+use std::path::{Path, PathBuf};
+
+const TUNE: [f64; 4] = [261.63, 392.00, 329.63, 523.25];
+const OTHER_TUNE: [f64; 4] = [415.30, 233.08, 369.99, 277.18];
+
+/// A fresh directory for one test, removed again when dropped.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("aurora-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("music")).unwrap();
+        TempDir(dir)
+    }
+
+    /// Where `name` goes in the library, its folders created.
+    fn music(&self, name: &str) -> PathBuf {
+        let path = self.0.join("music").join(name);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// How a recording is encoded.
+#[derive(Clone, Copy)]
+struct Encoding {
+    rate: u32,
+    bits: u16,
+    channels: u16,
+    seconds: u32,
+    /// Scales the whole recording.
+    gain: f64,
+}
+
+impl Default for Encoding {
+    fn default() -> Self {
+        Encoding { rate: 44100, bits: 16, channels: 2, seconds: 10, gain: 0.5 }
+    }
+}
+
+/// `notes` in turn, half a second each, from -1 to 1.
+fn melody(notes: &[f64], rate: u32, seconds: u32) -> impl Iterator<Item = f64> + '_ {
+    (0..rate * seconds).map(move |i| {
+        let t = i as f64 / rate as f64;
+        (2.0 * PI * notes[(t * 2.0) as usize % notes.len()] * t).sin()
+    })
+}
+
+fn write_wav(path: &Path, notes: &[f64], encoding: Encoding) {
+    let spec = WavSpec {
+        channels: encoding.channels,
+        sample_rate: encoding.rate,
+        bits_per_sample: encoding.bits,
+        sample_format: SampleFormat::Int,
+    };
+    let full_scale = ((1i64 << (encoding.bits - 1)) - 1) as f64;
+    let mut writer = WavWriter::create(path, spec).unwrap();
+    for sample in melody(notes, encoding.rate, encoding.seconds) {
+        for _ in 0..encoding.channels {
+            writer.write_sample((sample * encoding.gain * full_scale) as i32).unwrap();
+        }
+    }
+    writer.finalize().unwrap();
+}
+
+/// Stereo Ogg Opus at 192 kbps, in 20 ms packets.
+fn write_opus(path: &Path, notes: &[f64], seconds: u32) {
+    let mut encoder = Encoder::new(SampleRate::Hz48000, Channels::Stereo, Application::Audio).unwrap();
+    encoder.set_bitrate(Bitrate::BitsPerSecond(192000)).unwrap();
+    let pre_skip = encoder.lookahead().unwrap() as u16;
+
+    let mut head = b"OpusHead".to_vec();
+    head.extend_from_slice(&[1, 2]);
+    head.extend_from_slice(&pre_skip.to_le_bytes());
+    head.extend_from_slice(&48000u32.to_le_bytes());
+    head.extend_from_slice(&[0, 0, 0]);
+    let mut tags = b"OpusTags".to_vec();
+    tags.extend_from_slice(&6u32.to_le_bytes());
+    tags.extend_from_slice(b"aurora");
+    tags.extend_from_slice(&0u32.to_le_bytes());
+
+    let mut writer = PacketWriter::new(std::fs::File::create(path).unwrap());
+    writer.write_packet(head.into(), 1, PacketWriteEndInfo::EndPage, 0).unwrap();
+    writer.write_packet(tags.into(), 1, PacketWriteEndInfo::EndPage, 0).unwrap();
+    let samples: Vec<i16> = melody(notes, 48000, seconds)
+        .flat_map(|sample| [(sample * 0.5 * i16::MAX as f64) as i16; 2])
+        .collect();
+    let frames: Vec<&[i16]> = samples.chunks(960 * 2).collect();
+    let mut granule = pre_skip as u64;
+    for (i, frame) in frames.iter().enumerate() {
+        let mut packet = [0u8; 4000];
+        let len = encoder.encode(frame, &mut packet).unwrap();
+        granule += frame.len() as u64 / 2;
+        let end = if i + 1 == frames.len() { PacketWriteEndInfo::EndStream } else { PacketWriteEndInfo::NormalPacket };
+        writer.write_packet(packet[..len].into(), 1, end, granule).unwrap();
+    }
+}
+
+fn open_library(dir: &TempDir) -> LibraryService {
+    let library = LibraryService::open(dir.0.join("aurora.db")).unwrap();
+    let music = dir.0.join("music");
+    library
+        .write(move |library| {
+            library.add_library_root(&LibraryRoot::new(&music))?;
+            library.scan_library()
+        })
+        .unwrap();
+    library
+}
+
+/// Each group as the library-relative paths of its tracks, all sorted.
+fn groups(dir: &TempDir, duplicates: &[DuplicateGroup]) -> Vec<Vec<String>> {
+    let mut groups: Vec<Vec<String>> = duplicates.iter().map(|group| paths(dir, &group.tracks)).collect();
+    groups.sort();
+    groups
+}
+
+fn paths(dir: &TempDir, tracks: &[Track]) -> Vec<String> {
+    let music = dir.0.join("music");
+    let mut paths: Vec<String> = tracks
+        .iter()
+        .map(|track| Path::new(&track.path).strip_prefix(&music).unwrap().to_string_lossy().replace('\\', "/"))
+        .collect();
+    paths.sort();
+    paths
+}
+
+fn keeper(group: &DuplicateGroup) -> &Track {
+    group.tracks.iter().find(|track| track.id == group.keeper).unwrap()
+}
+
+const BY_NAME: DuplicateOptions = DuplicateOptions { duration_tolerance: 3, compare_audio: false, compare_fingerprints: false };
+
+#[test]
+fn groups_names_within_the_duration_tolerance() {
+    let dir = TempDir::new("duplicates-names");
+    write_wav(&dir.music("a/Song.wav"), &TUNE, Encoding::default());
+    write_wav(&dir.music("b/song.wav"), &OTHER_TUNE, Encoding { seconds: 12, ..Encoding::default() });
+    write_wav(&dir.music("c/Song (2011 Remaster).wav"), &TUNE, Encoding { seconds: 14, ..Encoding::default() });
+    // Too long to be the same recording, even by way of the others.
+    write_wav(&dir.music("d/Song.wav"), &TUNE, Encoding { seconds: 18, ..Encoding::default() });
+    write_wav(&dir.music("e/Another Song.wav"), &TUNE, Encoding::default());
+    let library = open_library(&dir);
+
+    let duplicates = library.find_duplicates(&BY_NAME).unwrap();
+    assert_eq!(groups(&dir, &duplicates), [["a/Song.wav", "b/song.wav", "c/Song (2011 Remaster).wav"]]);
+    assert!(!duplicates[0].identical_audio);
+
+    let strict = DuplicateOptions { duration_tolerance: 1, ..BY_NAME };
+    assert!(library.find_duplicates(&strict).unwrap().is_empty());
+}
+
+#[test]
+fn groups_identical_audio_whatever_the_names() {
+    let dir = TempDir::new("duplicates-audio");
+    write_wav(&dir.music("x/Alpha.wav"), &TUNE, Encoding::default());
+    write_wav(&dir.music("y/Beta.wav"), &TUNE, Encoding::default());
+    write_wav(&dir.music("z/Gamma.wav"), &OTHER_TUNE, Encoding::default());
+    // The same name, but not the same audio.
+    write_wav(&dir.music("x/Delta.wav"), &TUNE, Encoding { gain: 0.25, ..Encoding::default() });
+    write_wav(&dir.music("y/Delta.wav"), &OTHER_TUNE, Encoding { gain: 0.25, ..Encoding::default() });
+    let library = open_library(&dir);
+
+    let duplicates = library.find_duplicates(&DuplicateOptions::default()).unwrap();
+    assert_eq!(groups(&dir, &duplicates), [vec!["x/Alpha.wav", "y/Beta.wav"], vec!["x/Delta.wav", "y/Delta.wav"]]);
+    for group in &duplicates {
+        assert_eq!(group.identical_audio, keeper(group).title != "Delta");
+    }
+
+    let duplicates = library.find_duplicates(&BY_NAME).unwrap();
+    assert_eq!(groups(&dir, &duplicates), [["x/Delta.wav", "y/Delta.wav"]]);
+}
+
+#[test]
+fn groups_other_encodings_by_fingerprint() {
+    let dir = TempDir::new("duplicates-fingerprints");
+    write_wav(&dir.music("Alpha.wav"), &TUNE, Encoding { seconds: 20, ..Encoding::default() });
+    write_wav(&dir.music("Beta.wav"), &TUNE, Encoding { seconds: 20, rate: 48000, gain: 0.3, ..Encoding::default() });
+    write_wav(&dir.music("Gamma.wav"), &OTHER_TUNE, Encoding { seconds: 20, ..Encoding::default() });
+    let library = open_library(&dir);
+
+    assert!(library.find_duplicates(&DuplicateOptions::default()).unwrap().is_empty());
+
+    let options = DuplicateOptions { compare_fingerprints: true, ..DuplicateOptions::default() };
+    let duplicates = library.find_duplicates(&options).unwrap();
+    assert_eq!(groups(&dir, &duplicates), [["Alpha.wav", "Beta.wav"]]);
+    assert!(!duplicates[0].identical_audio);
+}
+
+#[test]
+fn keeps_the_best_copy() {
+    let dir = TempDir::new("duplicates-keeper");
+    write_wav(&dir.music("cd/Song.wav"), &TUNE, Encoding::default());
+    write_wav(&dir.music("hires/Song.wav"), &TUNE, Encoding { rate: 48000, bits: 24, ..Encoding::default() });
+    write_opus(&dir.music("lossy/Song.opus"), &TUNE, 10);
+    // Lossless wins over lossy, even at a lower bitrate.
+    write_wav(&dir.music("a/Tape.wav"), &TUNE, Encoding { rate: 8000, channels: 1, ..Encoding::default() });
+    write_opus(&dir.music("b/Tape.opus"), &TUNE, 10);
+    // Between equals, the one the library knew first.
+    write_wav(&dir.music("a/Twin.wav"), &OTHER_TUNE, Encoding::default());
+    write_wav(&dir.music("b/Twin.wav"), &OTHER_TUNE, Encoding::default());
+    let library = open_library(&dir);
+
+    let duplicates = library.find_duplicates(&BY_NAME).unwrap();
+    assert_eq!(duplicates.len(), 3);
+    let group = |title: &str| duplicates.iter().find(|group| keeper(group).title == title).unwrap();
+
+    let songs = group("Song");
+    assert_eq!(paths(&dir, &songs.tracks), ["cd/Song.wav", "hires/Song.wav", "lossy/Song.opus"]);
+    assert_eq!(paths(&dir, std::slice::from_ref(keeper(songs))), ["hires/Song.wav"]);
+
+    let tapes = group("Tape");
+    assert_eq!(paths(&dir, &tapes.tracks), ["a/Tape.wav", "b/Tape.opus"]);
+    assert_eq!(paths(&dir, std::slice::from_ref(keeper(tapes))), ["a/Tape.wav"]);
+
+    let twins = group("Twin");
+    assert_eq!(paths(&dir, &twins.tracks), ["a/Twin.wav", "b/Twin.wav"]);
+    assert_eq!(twins.keeper, twins.tracks.iter().map(|track| track.id).min().unwrap());
+}
@@ -0,0 +1,151 @@
+//! Acoustic fingerprints end to end: melodies rendered at different rates,
+//! levels and offsets, and a library asked which of its tracks they match.
+
+use aurora_core::{Fingerprint, LibraryRoot, LibraryService, SimilarTrack, FINGERPRINT_MATCH};
+use hound::{SampleFormat, WavSpec, WavWriter};
+use std::f64::consts::PI;
+use std::path::{Path, PathBuf};
+
+/// Seconds each note of a melody lasts.
+const NOTE: f64 = 0.5;
+
+const TUNE: [f64; 8] = [261.63, 329.63, 392.00, 523.25, 440.00, 349.23, 293.66, 246.94];
+const OTHER_TUNE: [f64; 8] = [415.30, 277.18, 311.13, 466.16, 233.08, 369.99, 207.65, 554.37];
+
+/// A fresh directory for one test, removed again when dropped.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("aurora-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("music")).unwrap();
+        TempDir(dir)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// How a melody is rendered.
+#[derive(Clone, Copy)]
+struct Render {
+    rate: u32,
+    seconds: f64,
+    /// Silence before the first note.
+    delay: f64,
+    amplitude_db: f64,
+}
+
+impl Default for Render {
+    fn default() -> Self {
+        Render { rate: 44100, seconds: 20.0, delay: 0.0, amplitude_db: -6.0 }
+    }
+}
+
+/// `notes` over and over, each with its octave above, in stereo.
+fn write_melody(path: &Path, notes: &[f64], render: Render) {
+    let spec = WavSpec { channels: 2, sample_rate: render.rate, bits_per_sample: 16, sample_format: SampleFormat::Int };
+    let mut writer = WavWriter::create(path, spec).unwrap();
+    let amplitude = 10f64.powf(render.amplitude_db / 20.0) * i16::MAX as f64 / 2.0;
+    let frames = (render.seconds * render.rate as f64) as u32;
+    for i in 0..frames {
+        let t = i as f64 / render.rate as f64 - render.delay;
+        let sample = if t < 0.0 {
+            0
+        } else {
+            let freq = notes[(t / NOTE) as usize % notes.len()];
+            (amplitude * ((2.0 * PI * freq * t).sin() + 0.5 * (4.0 * PI * freq * t).sin()) / 1.5) as i16
+        };
+        writer.write_sample(sample).unwrap();
+        writer.write_sample(sample).unwrap();
+    }
+    writer.finalize().unwrap();
+}
+
+fn fingerprint(dir: &TempDir, name: &str, notes: &[f64], render: Render) -> Fingerprint {
+    let path = dir.0.join(name);
+    write_melody(&path, notes, render);
+    Fingerprint::compute(&path).unwrap()
+}
+
+#[test]
+fn matches_a_recording_whatever_its_rate_level_or_lead_in() {
+    let dir = TempDir::new("fingerprint-match");
+    let original = fingerprint(&dir, "original.wav", &TUNE, Render::default());
+    assert!(!original.words.is_empty());
+    assert_eq!(original.similarity(&original), 1.0);
+
+    let resampled = fingerprint(&dir, "resampled.wav", &TUNE, Render { rate: 48000, ..Render::default() });
+    let quieter = fingerprint(&dir, "quieter.wav", &TUNE, Render { amplitude_db: -20.0, ..Render::default() });
+    let delayed = fingerprint(&dir, "delayed.wav", &TUNE, Render { delay: 1.0, ..Render::default() });
+    for copy in [&resampled, &quieter, &delayed] {
+        let similarity = original.similarity(copy);
+        assert!(similarity >= FINGERPRINT_MATCH, "similarity {}", similarity);
+        assert_eq!(similarity, copy.similarity(&original));
+    }
+}
+
+#[test]
+fn tells_other_recordings_apart() {
+    let dir = TempDir::new("fingerprint-differ");
+    let original = fingerprint(&dir, "original.wav", &TUNE, Render::default());
+    let other = fingerprint(&dir, "other.wav", &OTHER_TUNE, Render::default());
+    let reversed: Vec<f64> = TUNE.iter().rev().copied().collect();
+    let backwards = fingerprint(&dir, "backwards.wav", &reversed, Render::default());
+    for different in [&other, &backwards] {
+        let similarity = original.similarity(different);
+        assert!(similarity < FINGERPRINT_MATCH, "similarity {}", similarity);
+    }
+}
+
+#[test]
+fn compares_empty_fingerprints() {
+    let empty = Fingerprint { words: Vec::new() };
+    let some = Fingerprint { words: vec![0; 40] };
+    assert_eq!(empty.similarity(&empty), 1.0);
+    assert_eq!(empty.similarity(&some), 0.0);
+    assert_eq!(some.similarity(&Fingerprint { words: vec![u32::MAX; 40] }), 0.0);
+}
+
+#[test]
+fn finds_similar_tracks_in_the_library() {
+    let dir = TempDir::new("fingerprint-library");
+    let music = dir.0.join("music");
+    write_melody(&music.join("original.wav"), &TUNE, Render::default());
+    write_melody(&music.join("remaster.wav"), &TUNE, Render { rate: 48000, amplitude_db: -3.0, ..Render::default() });
+    write_melody(&music.join("other.wav"), &OTHER_TUNE, Render::default());
+    // Sounds the same at the start, but far too long to be the recording.
+    write_melody(&music.join("extended.wav"), &TUNE, Render { seconds: 40.0, ..Render::default() });
+    let outside = dir.0.join("unknown.wav");
+    write_melody(&outside, &TUNE, Render { delay: 0.5, ..Render::default() });
+
+    let library = LibraryService::open(dir.0.join("aurora.db")).unwrap();
+    let scanned = library
+        .write(move |library| {
+            library.add_library_root(&LibraryRoot::new(&music))?;
+            library.scan_library()
+        })
+        .unwrap();
+    assert_eq!(scanned.added, 4);
+
+    let path = dir.0.join("music").join("original.wav");
+    let original = library.read(move |library| library.get_track_by_path(&path)).unwrap().unwrap();
+    let titles = |similar: Vec<SimilarTrack>| -> Vec<String> {
+        similar.into_iter().map(|similar| similar.track.title).collect()
+    };
+    assert_eq!(titles(library.find_similar(original.id, FINGERPRINT_MATCH).unwrap()), ["remaster"]);
+
+    let identified = library.identify_file(&outside, FINGERPRINT_MATCH).unwrap();
+    assert!(identified.windows(2).all(|pair| pair[0].similarity >= pair[1].similarity));
+    let mut found = titles(identified);
+    found.sort();
+    assert_eq!(found, ["original", "remaster"]);
+
+    // What was worked out along the way is kept.
+    assert_eq!(library.update_fingerprints().unwrap(), 1);
+    assert_eq!(library.update_fingerprints().unwrap(), 0);
+}
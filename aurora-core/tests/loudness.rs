@@ -0,0 +1,190 @@
+//! Loudness analysis end to end: a library holding tones of known loudness
+//! and a file in a format there is no decoder for.
+
+use audiopus::coder::Encoder;
+use audiopus::{Application, Bitrate, Channels, SampleRate};
+use aurora_core::{LibraryRoot, LibraryService, LoudnessOptions};
+use hound::{SampleFormat, WavSpec, WavWriter};
+use lofty::file::TaggedFileExt;
+use lofty::tag::ItemKey;
+use ogg::writing::{PacketWriteEndInfo, PacketWriter};
+use std::f64::consts::PI;
+use std::path::{Path, PathBuf};
+
+const RATE: u32 = 48000;
+const SECONDS: u32 = 10;
+
+/// A fresh directory for one test, removed again when dropped.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("aurora-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("music")).unwrap();
+        TempDir(dir)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A 1 kHz sine on both channels. At full scale this measures 0 LUFS, so
+/// `amplitude_db` is also its loudness in LUFS.
+fn sine(amplitude_db: f64) -> impl Iterator<Item = i16> {
+    let amplitude = 10f64.powf(amplitude_db / 20.0) * i16::MAX as f64;
+    (0..RATE * SECONDS).flat_map(move |i| {
+        let sample = (amplitude * (2.0 * PI * 1000.0 * i as f64 / RATE as f64).sin()) as i16;
+        [sample, sample]
+    })
+}
+
+fn write_wav(path: &Path, samples: impl Iterator<Item = i16>) {
+    let spec = WavSpec { channels: 2, sample_rate: RATE, bits_per_sample: 16, sample_format: SampleFormat::Int };
+    let mut writer = WavWriter::create(path, spec).unwrap();
+    for sample in samples {
+        writer.write_sample(sample).unwrap();
+    }
+    writer.finalize().unwrap();
+}
+
+/// An Ogg Opus stream as RFC 7845 lays it out, in 20 ms packets.
+fn write_opus(path: &Path, samples: impl Iterator<Item = i16>) {
+    let mut encoder = Encoder::new(SampleRate::Hz48000, Channels::Stereo, Application::Audio).unwrap();
+    encoder.set_bitrate(Bitrate::BitsPerSecond(192000)).unwrap();
+    let pre_skip = encoder.lookahead().unwrap() as u16;
+
+    let mut head = b"OpusHead".to_vec();
+    head.extend_from_slice(&[1, 2]);
+    head.extend_from_slice(&pre_skip.to_le_bytes());
+    head.extend_from_slice(&RATE.to_le_bytes());
+    head.extend_from_slice(&[0, 0, 0]);
+    let mut tags = b"OpusTags".to_vec();
+    tags.extend_from_slice(&6u32.to_le_bytes());
+    tags.extend_from_slice(b"aurora");
+    tags.extend_from_slice(&0u32.to_le_bytes());
+
+    let mut writer = PacketWriter::new(std::fs::File::create(path).unwrap());
+    writer.write_packet(head.into(), 1, PacketWriteEndInfo::EndPage, 0).unwrap();
+    writer.write_packet(tags.into(), 1, PacketWriteEndInfo::EndPage, 0).unwrap();
+    let samples: Vec<i16> = samples.collect();
+    let frames = samples.chunks(960 * 2).collect::<Vec<_>>();
+    let mut granule = pre_skip as u64;
+    for (i, frame) in frames.iter().enumerate() {
+        let mut packet = [0u8; 4000];
+        let len = encoder.encode(frame, &mut packet).unwrap();
+        granule += frame.len() as u64 / 2;
+        let end = if i + 1 == frames.len() { PacketWriteEndInfo::EndStream } else { PacketWriteEndInfo::NormalPacket };
+        writer.write_packet(packet[..len].into(), 1, end, granule).unwrap();
+    }
+}
+
+/// AIFF reads fine for tags but has no decoder here.
+fn write_aiff(path: &Path, samples: impl Iterator<Item = i16>) {
+    let data: Vec<u8> = samples.flat_map(i16::to_be_bytes).collect();
+    let frames = (data.len() / 4) as u32;
+    let mut comm = Vec::new();
+    comm.extend_from_slice(&2u16.to_be_bytes());
+    comm.extend_from_slice(&frames.to_be_bytes());
+    comm.extend_from_slice(&16u16.to_be_bytes());
+    // 48000 as an 80-bit extended float.
+    comm.extend_from_slice(&[0x40, 0x0E, 0xBB, 0x80, 0, 0, 0, 0, 0, 0]);
+
+    let mut file = b"AIFF".to_vec();
+    file.extend_from_slice(b"COMM");
+    file.extend_from_slice(&(comm.len() as u32).to_be_bytes());
+    file.extend_from_slice(&comm);
+    file.extend_from_slice(b"SSND");
+    file.extend_from_slice(&(data.len() as u32 + 8).to_be_bytes());
+    file.extend_from_slice(&[0; 8]);
+    file.extend_from_slice(&data);
+
+    let mut form = b"FORM".to_vec();
+    form.extend_from_slice(&(file.len() as u32).to_be_bytes());
+    form.extend_from_slice(&file);
+    std::fs::write(path, form).unwrap();
+}
+
+#[test]
+fn measures_a_known_tone_and_writes_replay_gain() {
+    let dir = TempDir::new("loudness");
+    let music = dir.0.join("music");
+    let tone = music.join("tone.wav");
+    write_wav(&tone, sine(-23.0));
+    write_aiff(&music.join("tone.aiff"), sine(-23.0));
+
+    let library = LibraryService::open(dir.0.join("aurora.db")).unwrap();
+    let scanned = library
+        .write(move |library| {
+            library.add_library_root(&LibraryRoot::new(&music))?;
+            library.scan_library()
+        })
+        .unwrap();
+    assert_eq!(scanned.added, 2);
+
+    let options = LoudnessOptions { write_tags: true, ..Default::default() };
+    let summary = library.analyze_loudness(options).unwrap().wait().unwrap();
+    assert_eq!((summary.tracks, summary.failed, summary.skipped), (1, 0, 1));
+    assert_eq!(summary.tags_written, 1);
+
+    let path = tone.clone();
+    let loudness = library
+        .read(move |library| {
+            let track = library.get_track_by_path(&path)?.unwrap();
+            library.get_loudness(track.id)
+        })
+        .unwrap()
+        .unwrap();
+    assert!((loudness.track.integrated + 23.0).abs() < 0.1, "{:?}", loudness);
+    assert!((loudness.track.true_peak + 23.0).abs() < 0.1, "{:?}", loudness);
+    assert!(loudness.track.range < 0.1, "{:?}", loudness);
+
+    let tagged = lofty::read_from_path(&tone).unwrap();
+    let tag = tagged.primary_tag().unwrap();
+    assert_eq!(tag.get_string(&ItemKey::ReplayGainTrackGain), Some("+5.00 dB"));
+
+    // Skipped tracks are not tried again.
+    let again = library.analyze_loudness(LoudnessOptions::default()).unwrap().wait().unwrap();
+    assert_eq!((again.tracks, again.skipped), (0, 0));
+}
+
+#[test]
+fn measures_opus_and_writes_r128_gains() {
+    let dir = TempDir::new("loudness-opus");
+    let music = dir.0.join("music");
+    let tone = music.join("tone.opus");
+    write_opus(&tone, sine(-23.0));
+
+    let library = LibraryService::open(dir.0.join("aurora.db")).unwrap();
+    library
+        .write(move |library| {
+            library.add_library_root(&LibraryRoot::new(&music))?;
+            library.scan_library()
+        })
+        .unwrap();
+
+    let options = LoudnessOptions { write_tags: true, ..Default::default() };
+    let summary = library.analyze_loudness(options).unwrap().wait().unwrap();
+    assert_eq!((summary.tracks, summary.failed, summary.skipped), (1, 0, 0));
+    assert_eq!(summary.tags_written, 1);
+
+    let path = tone.clone();
+    let loudness = library
+        .read(move |library| {
+            let track = library.get_track_by_path(&path)?.unwrap();
+            library.get_loudness(track.id)
+        })
+        .unwrap()
+        .unwrap();
+    assert!((loudness.track.integrated + 23.0).abs() < 0.2, "{:?}", loudness);
+
+    // The gain to -23 LUFS, in 1/256 dB, and no ReplayGain fields.
+    let tagged = lofty::read_from_path(&tone).unwrap();
+    let tag = tagged.primary_tag().unwrap();
+    let gain: i32 = tag.get_string(&ItemKey::Unknown("R128_TRACK_GAIN".to_string())).unwrap().parse().unwrap();
+    assert!(gain.abs() < 52, "{}", gain);
+    assert_eq!(tag.get_string(&ItemKey::ReplayGainTrackGain), None);
+}
@@ -0,0 +1,159 @@
+//! Library scans end to end: which files a rescan reads again, and what it
+//! leaves in the library.
+
+use aurora_core::{LibraryManager, LibraryRoot};
+use hound::{SampleFormat, WavSpec, WavWriter};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+
+/// A fresh directory for one test, removed again when dropped.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("aurora-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("music")).unwrap();
+        TempDir(dir)
+    }
+
+    fn music(&self) -> PathBuf {
+        self.0.join("music")
+    }
+
+    /// Writes `seconds` of silence to `name` in the library, creating
+    /// folders on the way, and returns its path.
+    fn write_wav(&self, name: &str, seconds: u32) -> PathBuf {
+        let path = self.music().join(name);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let spec = WavSpec { channels: 1, sample_rate: 8000, bits_per_sample: 16, sample_format: SampleFormat::Int };
+        let mut writer = WavWriter::create(&path, spec).unwrap();
+        for _ in 0..8000 * seconds {
+            writer.write_sample(0i16).unwrap();
+        }
+        writer.finalize().unwrap();
+        path
+    }
+
+    fn library(&self, root: LibraryRoot) -> LibraryManager {
+        let library = LibraryManager::new(self.0.join("aurora.db")).unwrap();
+        library.add_library_root(&root).unwrap();
+        library
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+fn set_modified(path: &Path, modified: std::time::SystemTime) {
+    File::options().write(true).open(path).unwrap().set_modified(modified).unwrap();
+}
+
+#[test]
+fn rescans_only_read_files_whose_stamp_changed() {
+    let dir = TempDir::new("scan-unchanged");
+    let one = dir.write_wav("one.wav", 1);
+    dir.write_wav("two.wav", 1);
+    let library = dir.library(LibraryRoot::new(dir.music()));
+    let scanned = library.scan_library().unwrap();
+    assert_eq!((scanned.added, scanned.updated, scanned.unchanged), (2, 0, 0));
+
+    // Same size and mtime: the file is not opened, or this would fail to parse.
+    let modified = std::fs::metadata(&one).unwrap().modified().unwrap();
+    let size = std::fs::metadata(&one).unwrap().len();
+    std::fs::write(&one, vec![0u8; size as usize]).unwrap();
+    set_modified(&one, modified);
+    let scanned = library.scan_library().unwrap();
+    assert_eq!((scanned.added, scanned.updated, scanned.unchanged, scanned.failed), (0, 0, 2, 0));
+
+    // A new mtime alone is enough to read it again. It fails, but keeps its track.
+    set_modified(&one, modified + Duration::from_secs(1));
+    let scanned = library.scan_library().unwrap();
+    assert_eq!((scanned.updated, scanned.unchanged, scanned.failed, scanned.removed), (0, 1, 1, 0));
+    assert!(library.get_track_by_path(&one).unwrap().is_some());
+
+    // As is a new size.
+    dir.write_wav("one.wav", 3);
+    let scanned = library.scan_library().unwrap();
+    assert_eq!((scanned.updated, scanned.unchanged, scanned.failed), (1, 1, 0));
+    assert_eq!(library.get_track_by_path(&one).unwrap().unwrap().duration, 3);
+}
+
+#[test]
+fn only_complete_scans_prune_missing_files() {
+    let dir = TempDir::new("scan-prune");
+    let one = dir.write_wav("one.wav", 1);
+    let two = dir.write_wav("two.wav", 1);
+    let library = dir.library(LibraryRoot::new(dir.music()));
+    library.scan_library().unwrap();
+    std::fs::remove_file(&two).unwrap();
+    let three = dir.write_wav("three.wav", 1);
+
+    let cancelled = AtomicBool::new(true);
+    let scanned = library.scan_directory_with(&dir.music(), &cancelled, |_| {}).unwrap();
+    assert!(scanned.cancelled);
+    assert_eq!((scanned.added, scanned.removed), (0, 0));
+    let scanned = library.scan_library_with(&cancelled, |_| {}).unwrap();
+    assert!(scanned.cancelled);
+    assert_eq!(scanned.removed, 0);
+    assert!(library.get_track_by_path(&two).unwrap().is_some());
+
+    // Nor does a root that has gone missing lose its tracks.
+    let away = dir.0.join("away");
+    std::fs::rename(dir.music(), &away).unwrap();
+    let scanned = library.scan_library().unwrap();
+    assert_eq!((scanned.offline, scanned.removed), (1, 0));
+    assert!(library.get_track_by_path(&one).unwrap().is_some());
+    std::fs::rename(&away, dir.music()).unwrap();
+
+    let scanned = library.scan_library().unwrap();
+    assert!(!scanned.cancelled);
+    assert_eq!((scanned.added, scanned.unchanged, scanned.removed), (1, 1, 1));
+    assert!(library.get_track_by_path(&two).unwrap().is_none());
+    assert!(library.get_track_by_path(&three).unwrap().is_some());
+}
+
+#[test]
+fn each_root_applies_its_own_filters() {
+    let dir = TempDir::new("scan-filters");
+    dir.write_wav("albums/one.wav", 2);
+    dir.write_wav("albums/short.wav", 1);
+    dir.write_wav("albums/.hidden.wav", 2);
+    dir.write_wav("albums/.sync/copy.wav", 2);
+    dir.write_wav("albums/Podcasts/episode.wav", 2);
+    dir.write_wav("albums/notes.wav", 2);
+    dir.write_wav("albums/one.aiff", 2);
+    dir.write_wav("loose/two.wav", 2);
+    dir.write_wav("loose/.three.wav", 1);
+    let min_size = std::fs::metadata(dir.music().join("albums/one.wav")).unwrap().len();
+    let library = dir.library(
+        LibraryRoot::new(dir.music().join("albums"))
+            .include("**/*.wav")
+            .exclude("**/Podcasts")
+            .exclude("notes.*")
+            .min_size(min_size),
+    );
+    library.add_library_root(&LibraryRoot::new(dir.music().join("loose")).include_hidden(true)).unwrap();
+
+    let scanned = library.scan_library().unwrap();
+    assert_eq!(scanned.added, 3);
+    let mut names: Vec<String> = library
+        .get_all_tracks()
+        .unwrap()
+        .into_iter()
+        .map(|track| Path::new(&track.path).strip_prefix(dir.music()).unwrap().to_string_lossy().replace('\\', "/"))
+        .collect();
+    names.sort();
+    assert_eq!(names, ["albums/one.wav", "loose/.three.wav", "loose/two.wav"]);
+
+    // Tightening a root's rules prunes what they now leave out.
+    library.add_library_root(&LibraryRoot::new(dir.music().join("loose")).exclude("two.wav")).unwrap();
+    let scanned = library.scan_library().unwrap();
+    assert_eq!((scanned.added, scanned.unchanged, scanned.removed), (0, 1, 2));
+    assert_eq!(library.get_all_tracks().unwrap().len(), 1);
+}
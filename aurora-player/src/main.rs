@@ -1,11 +1,20 @@
 use anyhow::Result;
 use aurora_audio::{AudioEngine, ScriptableAudioEngine};
-use aurora_core::{LibraryManager, Track, ScriptableLibraryManager};
+use aurora_core::{LibraryChange, LibraryRoot, LibraryService, LibraryWatcher, LoudnessEvent, LoudnessOptions, Lyrics, ScanEvent, StoredCover, Track, TrackQuery, ScriptableLibraryManager};
 use aurora_script::{ScriptHost, ScriptableUI};
 use aurora_ui::{MainWindow, extract_palette, AppColors};
-use slint::ComponentHandle;
+use slint::{ComponentHandle, Model};
+use std::collections::HashSet;
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The library is read a page at a time, as the list scrolls to it or
+/// playback steps onto it.
+const LIBRARY_PAGE: u32 = 500;
+
+/// How often the lyrics panel catches up with the playback position.
+const LYRICS_TICK: Duration = Duration::from_millis(200);
 
 struct ThreadSafePalette {
    bg: String,
@@ -14,6 +23,172 @@ struct ThreadSafePalette {
    accent: String,
 }
 
+// Shared state for playback control
+struct PlayerState {
+    tracks: LibraryList,
+    current_index: usize,
+    playback: Option<Playback>,
+    /// The track queued to follow on gaplessly from the current one, when
+    /// both are cut from the same CUE sheet image.
+    queued: Option<usize>,
+}
+
+/// The track playing now, timed so the play history only counts time
+/// actually spent listening.
+struct Playback {
+    track: Track,
+    started_at: i64,
+    listened: Duration,
+    resumed_at: Option<Instant>,
+}
+
+impl Playback {
+    fn start(track: &Track) -> Self {
+        let started_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or(0);
+        Self { track: track.clone(), started_at, listened: Duration::ZERO, resumed_at: Some(Instant::now()) }
+    }
+
+    fn pause(&mut self) {
+        if let Some(resumed_at) = self.resumed_at.take() {
+            self.listened += resumed_at.elapsed();
+        }
+    }
+
+    fn resume(&mut self) {
+        self.resumed_at.get_or_insert_with(Instant::now);
+    }
+
+    fn listened(&self) -> Duration {
+        self.listened + self.resumed_at.map(|t| t.elapsed()).unwrap_or_default()
+    }
+}
+
+/// The library in list order. Only how many tracks there are is known up
+/// front; each page is read the first time the list shows one of its rows
+/// or playback needs one of its tracks.
+struct LibraryList {
+    rows: Vec<Option<Box<Track>>>,
+    /// Pages the list has asked for that are being read.
+    loading: HashSet<usize>,
+    /// Bumped whenever the list starts over, so pages read before then are
+    /// dropped.
+    generation: u64,
+}
+
+impl LibraryList {
+    fn new(len: usize) -> Self {
+        Self { rows: vec![None; len], loading: HashSet::new(), generation: 0 }
+    }
+
+    fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// The track at `index`, if its page has been read.
+    fn get(&self, index: usize) -> Option<&Track> {
+        self.rows.get(index)?.as_deref()
+    }
+
+    /// The track at `index`, reading its page first if need be.
+    fn load(&mut self, library: &LibraryService, index: usize) -> Option<&Track> {
+        if index < self.len() && self.rows[index].is_none() {
+            let page = index / LIBRARY_PAGE as usize;
+            let query = TrackQuery::new().page(page as u32 * LIBRARY_PAGE, LIBRARY_PAGE);
+            match library.read(move |library| library.find_tracks(&query)) {
+                Ok(tracks) => {
+                    self.fill(self.generation, page, tracks);
+                }
+                Err(e) => log::error!("Failed to load library: {}", e),
+            }
+        }
+        self.get(index)
+    }
+
+    /// Where the track with `id` is, among the pages read so far.
+    fn position(&self, id: i64) -> Option<usize> {
+        self.rows.iter().position(|row| row.as_ref().is_some_and(|track| track.id == id))
+    }
+
+    /// Stores a page read for the list as it was at `generation`, and
+    /// returns the rows it filled, if the list has not started over since.
+    fn fill(&mut self, generation: u64, page: usize, tracks: Vec<Track>) -> Option<std::ops::Range<usize>> {
+        if generation != self.generation {
+            return None;
+        }
+        self.loading.remove(&page);
+        let start = page * LIBRARY_PAGE as usize;
+        let end = (start + tracks.len()).min(self.len());
+        for (row, track) in self.rows[start.min(end)..end].iter_mut().zip(tracks) {
+            *row = Some(Box::new(track));
+        }
+        Some(start.min(end)..end)
+    }
+
+    /// Forgets every page read so far, for a library now `len` tracks long.
+    fn reset(&mut self, len: usize) {
+        *self = Self { generation: self.generation + 1, ..Self::new(len) };
+    }
+}
+
+/// Rows of the track list, read from `PlayerState.tracks`. A row whose
+/// page has not been read yet shows empty while it is, and fills in once
+/// it has.
+struct LibraryModel {
+    state: Arc<Mutex<PlayerState>>,
+    library: LibraryService,
+    ui: slint::Weak<MainWindow>,
+    notify: slint::ModelNotify,
+}
+
+impl Model for LibraryModel {
+    type Data = aurora_ui::LibraryTrack;
+
+    fn row_count(&self) -> usize {
+        self.state.lock().unwrap().tracks.len()
+    }
+
+    fn row_data(&self, row: usize) -> Option<Self::Data> {
+        let mut state = self.state.lock().unwrap();
+        if row >= state.tracks.len() {
+            return None;
+        }
+        if let Some(track) = state.tracks.get(row) {
+            return Some(to_library_track(track));
+        }
+
+        let page = row / LIBRARY_PAGE as usize;
+        if state.tracks.loading.insert(page) {
+            let generation = state.tracks.generation;
+            let (library, ui, state) = (self.library.clone(), self.ui.clone(), self.state.clone());
+            std::thread::spawn(move || load_page(&library, ui, state, generation, page));
+        }
+        Some(aurora_ui::LibraryTrack::default())
+    }
+
+    fn model_tracker(&self) -> &dyn slint::ModelTracker {
+        &self.notify
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+}
+
+/// What the lyrics panel shows: the track it is for and its lyrics, once
+/// the script thread has found them.
+#[derive(Default)]
+struct LyricsPanel {
+    track_id: Option<i64>,
+    lyrics: Option<Lyrics>,
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     env_logger::init();
@@ -23,103 +198,154 @@ async fn main() -> Result<()> {
     println!("Audio Engine initialized.");
 
     // Initialize Library Manager
-    let library = Arc::new(LibraryManager::new(PathBuf::from("aurora.db"))?);
+    let db_path = PathBuf::from("aurora.db");
+    let library = LibraryService::open(db_path)?;
     println!("Library Manager initialized.");
 
     // Initialize UI
     let ui = aurora_ui::create_window();
     let ui_handle = ui.as_weak();
 
-    // Initialize Scripting Host
-    let script_host = ScriptHost::new()?;
-    script_host.register_global("player", ScriptableAudioEngine(engine.clone()))?;
-    script_host.register_global("library", ScriptableLibraryManager(library.clone()))?;
-    script_host.register_global("ui", ScriptableUI(ui_handle.clone()))?;
+    // Initialize Scripting Host, on a thread of its own so a slow lyrics
+    // provider never holds up the UI
+    let lyrics_panel = Arc::new(Mutex::new(LyricsPanel::default()));
+    let lyrics_requests = start_scripts(engine.clone(), library.clone(), ui_handle.clone(), lyrics_panel.clone())?;
     println!("Scripting Host initialized.");
 
-    // Simple test if a file is provided as argument
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() > 1 {
-        let path_str = &args[1];
-        let path = Path::new(path_str);
-        
-        if path.exists() {
-            println!("Scanning directory: {:?}", path);
-            library.scan_directory(path)?;
+    // Folders given as arguments join the library roots; those already
+    // known keep their rules
+    let new_roots: Vec<PathBuf> = std::env::args().skip(1).map(PathBuf::from).filter(|path| path.is_dir()).collect();
+    let roots = library.write(move |library| {
+        for path in &new_roots {
+            if library.get_library_root(path)?.is_none() {
+                library.add_library_root(&LibraryRoot::new(path))?;
+            }
         }
+        library.check_library_roots()
+    })?;
+    for root in roots.iter().filter(|root| !root.online) {
+        println!("Library folder offline: {:?}", root.path);
     }
 
-    // Load tracks from library
-    let tracks = library.get_all_tracks()?;
-    println!("Loaded {} tracks from library.", tracks.len());
-    
-    // Shared state for playback control
-    struct PlayerState {
-        tracks: Vec<Track>,
-        current_index: usize,
-    }
+    // Only count the library; the list reads its pages as it shows them
+    let total = library.read(|library| library.count_tracks(&TrackQuery::new()))?;
+    println!("Library holds {} tracks.", total);
     
     let state = Arc::new(Mutex::new(PlayerState {
-        tracks: tracks.clone(),
+        tracks: LibraryList::new(total as usize),
         current_index: 0,
+        playback: None,
+        queued: None,
     }));
 
     // Populate UI Library
-    let slint_tracks: Vec<aurora_ui::LibraryTrack> = tracks.iter().map(|t| aurora_ui::LibraryTrack {
-        id: t.id as i32,
-        title: t.title.clone().into(),
-        artist: t.artist.clone().into(),
-        album: t.album.clone().into(),
-    }).collect();
-    
-    let model = std::rc::Rc::new(slint::VecModel::from(slint_tracks));
-    ui.set_library_tracks(slint::ModelRc::from(model.clone()));
+    ui.set_library_tracks(slint::ModelRc::new(LibraryModel {
+        state: state.clone(),
+        library: library.clone(),
+        ui: ui_handle.clone(),
+        notify: Default::default(),
+    }));
+
+    // Keep the library in sync with its folders while the player is running
+    let ui_watch = ui_handle.clone();
+    let state_watch = state.clone();
+    let library_watch = library.clone();
+    let _watcher = LibraryWatcher::spawn(library.clone(), &roots, move |changes| {
+        let ui_weak = ui_watch.clone();
+        let state = state_watch.clone();
+        let library = library_watch.clone();
+        let _ = slint::invoke_from_event_loop(move || {
+            if let Some(ui) = ui_weak.upgrade() {
+                apply_library_changes(&library, &ui, &state, changes);
+            }
+        });
+    })?;
+
+    // Scan in the background so the window opens straight away
+    if !roots.is_empty() {
+        println!("Scanning {} library folders", roots.len());
+        let job = library.scan_library()?;
+        let ui_scan = ui_handle.clone();
+        let state_scan = state.clone();
+        let library_scan = library.clone();
+        std::thread::spawn(move || {
+            for event in job.events() {
+                let status = match event {
+                    ScanEvent::Progress(progress) => format!("Scanning {}/{}", progress.seen, progress.total),
+                    ScanEvent::Finished(summary) => {
+                        println!(
+                            "Scan complete: {} added, {} updated, {} unchanged, {} removed, {} failed.",
+                            summary.added, summary.updated, summary.unchanged, summary.removed, summary.failed
+                        );
+                        for path in &summary.unsupported {
+                            println!("Unsupported audio format: {}", path.display());
+                        }
+                        String::new()
+                    }
+                };
+                let ui_weak = ui_scan.clone();
+                let _ = slint::invoke_from_event_loop(move || {
+                    if let Some(ui) = ui_weak.upgrade() {
+                        ui.set_scan_status(status.into());
+                    }
+                });
+            }
+
+            if let Err(e) = job.wait() {
+                log::error!("Library scan failed: {}", e);
+                return;
+            }
+            reload_library(&library_scan, &ui_scan, &state_scan);
+            analyze_loudness(&library_scan, &ui_scan);
+        });
+    }
 
     // Handle track selection from UI
     let ui_handle_select = ui.as_weak();
     let engine_select = engine.clone();
     let state_select = state.clone();
+    let library_select = library.clone();
     ui.on_track_selected(move |index| {
         let index = index as usize;
         println!("UI: Track selected at index {}", index);
         let mut state = state_select.lock().unwrap();
-        if index < state.tracks.len() {
-            state.current_index = index;
-            let track = &state.tracks[index];
-            let uri = format!("file://{}", track.path);
-            
-            if let Err(e) = engine_select.play_file(&uri) {
-                log::error!("Failed to play selected track: {}", e);
-                return;
-            }
+        let Some(track) = state.tracks.load(&library_select, index).cloned() else {
+            return;
+        };
+        if track.offline {
+            println!("Track is offline: {}", track.path);
+            return;
+        }
+        finish_playback(&library_select, &mut state, false);
+        state.current_index = index;
+        state.queued = None;
+        
+        if let Err(e) = play_track(&engine_select, &track) {
+            log::error!("Failed to play selected track: {}", e);
+            return;
+        }
+        state.playback = Some(Playback::start(&track));
+        
+        if let Some(ui) = ui_handle_select.upgrade() {
+            ui.set_track_title(track.title.clone().into());
+            ui.set_track_artist(track.artist.clone().into());
             
-            if let Some(ui) = ui_handle_select.upgrade() {
-                ui.set_track_title(track.title.clone().into());
-                ui.set_track_artist(track.artist.clone().into());
-                
-                // Update theme
-                let cover_art = find_cover_art(Path::new(&track.path).parent().unwrap_or(Path::new(&track.path)));
-                if let Some(cp) = cover_art {
-                    update_ui_theme(ui_handle_select.clone(), &cp);
-                }
-            }
+            // Update theme
+            show_cover_art(&library_select, ui_handle_select.clone(), &track);
         }
     });
 
     // Play first track if available
     {
-        let state = state.lock().unwrap();
-        if !state.tracks.is_empty() {
-            let track = &state.tracks[0];
-            let uri = format!("file://{}", track.path);
-            engine.play_file(&uri)?;
+        let mut state = state.lock().unwrap();
+        if let Some((index, track)) = playable_from(&mut state, &library, 0, true) {
+            state.current_index = index;
+            play_track(&engine, &track)?;
+            state.playback = Some(Playback::start(&track));
             ui.set_track_title(track.title.clone().into());
             ui.set_track_artist(track.artist.clone().into());
             
-            let cover_art = find_cover_art(Path::new(&track.path).parent().unwrap_or(Path::new(&track.path)));
-            if let Some(cp) = cover_art {
-                update_ui_theme(ui_handle.clone(), &cp);
-            }
+            show_cover_art(&library, ui_handle.clone(), &track);
         }
     }
 
@@ -131,14 +357,24 @@ async fn main() -> Result<()> {
     let engine_prev = engine.clone();
     let state_prev = state.clone();
     let ui_prev = ui_handle.clone();
+    let state_pause = state.clone();
+    let library_next = library.clone();
+    let library_prev = library.clone();
 
     let mut is_paused = false;
     ui.on_play_pause(move || {
+        let mut state = state_pause.lock().unwrap();
         if is_paused {
             let _ = engine_c.resume();
+            if let Some(playback) = state.playback.as_mut() {
+                playback.resume();
+            }
             is_paused = false;
         } else {
             let _ = engine_c.pause();
+            if let Some(playback) = state.playback.as_mut() {
+                playback.pause();
+            }
             is_paused = true;
         }
     });
@@ -146,13 +382,17 @@ async fn main() -> Result<()> {
     ui.on_next(move || {
         let mut state = state_next.lock().unwrap();
         if state.tracks.is_empty() { return; }
+        let start = (state.current_index + 1) % state.tracks.len();
+        let Some((index, next_track)) = playable_from(&mut state, &library_next, start, true) else { return; };
 
-        state.current_index = (state.current_index + 1) % state.tracks.len();
+        finish_playback(&library_next, &mut state, false);
+        state.current_index = index;
+        state.queued = None;
         
-        let next_track = &state.tracks[state.current_index];
-        let uri = format!("file://{}", next_track.path);
-        println!("Playing Next: {}", uri);
-        let _ = engine_next.play_file(&uri);
+        println!("Playing Next: {}", next_track.path);
+        if play_track(&engine_next, &next_track).is_ok() {
+            state.playback = Some(Playback::start(&next_track));
+        }
         
         // Update UI
         if let Some(ui) = ui_next.upgrade() {
@@ -160,10 +400,7 @@ async fn main() -> Result<()> {
             ui.set_track_artist(next_track.artist.clone().into());
             
             // Update cover & theme
-            let track_path = Path::new(&next_track.path);
-            if let Some(cp) = find_cover_art(track_path.parent().unwrap_or(track_path)) {
-                update_ui_theme(ui_next.clone(), &cp);
-            }
+            show_cover_art(&library_next, ui_next.clone(), &next_track);
         }
     });
 
@@ -171,27 +408,24 @@ async fn main() -> Result<()> {
     ui.on_prev(move || {
         let mut state = state_prev.lock().unwrap();
         if state.tracks.is_empty() { return; }
+        let start = (state.current_index + state.tracks.len() - 1) % state.tracks.len();
+        let Some((index, prev_track)) = playable_from(&mut state, &library_prev, start, false) else { return; };
 
-        if state.current_index == 0 {
-            state.current_index = state.tracks.len() - 1;
-        } else {
-            state.current_index -= 1;
-        }
+        finish_playback(&library_prev, &mut state, false);
+        state.current_index = index;
+        state.queued = None;
         
-        let prev_track = &state.tracks[state.current_index];
-        let uri = format!("file://{}", prev_track.path);
-        println!("Playing Prev: {}", uri);
-        let _ = engine_prev.play_file(&uri);
+        println!("Playing Prev: {}", prev_track.path);
+        if play_track(&engine_prev, &prev_track).is_ok() {
+            state.playback = Some(Playback::start(&prev_track));
+        }
         
         // Update UI
         if let Some(ui) = ui_prev.upgrade() {
             ui.set_track_title(prev_track.title.clone().into());
             ui.set_track_artist(prev_track.artist.clone().into());
 
-            let track_path = Path::new(&prev_track.path);
-            if let Some(cp) = find_cover_art(track_path.parent().unwrap_or(track_path)) {
-                update_ui_theme(ui_prev.clone(), &cp);
-            }
+            show_cover_art(&library_prev, ui_prev.clone(), &prev_track);
         }
     });
 
@@ -199,46 +433,63 @@ async fn main() -> Result<()> {
     let engine_poll = engine.clone();
     let state_poll = state.clone();
     let ui_poll = ui_handle.clone();
+    let library_poll = library.clone();
     
     tokio::spawn(async move {
         let mut was_playing = false;
         loop {
             tokio::time::sleep(std::time::Duration::from_secs(1)).await;
             
+            // A queued track has taken over once the one before it drains
+            // from the queue
+            let followed_on = {
+                let mut state = state_poll.lock().unwrap();
+                match state.queued {
+                    Some(index) if engine_poll.queue_len() <= 1 && state.tracks.get(index).is_some() => {
+                        let track = state.tracks.get(index).cloned().unwrap();
+                        finish_playback(&library_poll, &mut state, true);
+                        state.current_index = index;
+                        state.queued = None;
+                        println!("Following on to: {}", track.title);
+                        state.playback = Some(Playback::start(&track));
+                        Some(track)
+                    }
+                    _ => None,
+                }
+            };
+            if let Some(track) = followed_on {
+                show_now_playing(&library_poll, ui_poll.clone(), track);
+            }
+            queue_follow_on(&engine_poll, &library_poll, &mut state_poll.lock().unwrap());
+
             let is_busy = engine_poll.is_busy();
             
             if was_playing && !is_busy {
-                 let mut state = state_poll.lock().unwrap();
-                 if !state.tracks.is_empty() {
-                    state.current_index = (state.current_index + 1) % state.tracks.len();
-                    let next_track = &state.tracks[state.current_index];
-                    let uri = format!("file://{}", next_track.path);
-                    println!("Auto-advancing to: {}", uri);
-                    let _ = engine_poll.play_file(&uri);
-                    
-                    let title = next_track.title.clone();
-                    let artist = next_track.artist.clone();
-                    let track_path = PathBuf::from(&next_track.path);
-                    let cover_path = find_cover_art(track_path.parent().unwrap_or(&track_path));
-
-                    let ui_weak = ui_poll.clone();
-                    let cp_for_theme = cover_path.clone();
-                    let _ = slint::invoke_from_event_loop(move || {
-                         if let Some(ui) = ui_weak.upgrade() {
-                             ui.set_track_title(title.into());
-                             ui.set_track_artist(artist.into());
-                             if let Some(ref cp) = cover_path {
-                                 if let Ok(slint_img) = slint::Image::load_from_path(cp) {
-                                     ui.set_album_art(slint_img);
-                                 }
-                             }
-                         }
-                    });
-
-                    // Trigger palette update separately
-                    if let Some(ref cp) = cp_for_theme {
-                        update_ui_theme(ui_poll.clone(), cp);
+                 // The state lock is released before waiting on the library
+                 let next_track = {
+                    let mut state = state_poll.lock().unwrap();
+                    finish_playback(&library_poll, &mut state, true);
+                    state.queued = None;
+                    let next = match state.tracks.len() {
+                        0 => None,
+                        len => {
+                            let start = (state.current_index + 1) % len;
+                            playable_from(&mut state, &library_poll, start, true)
+                        }
+                    };
+                    if let Some((index, next_track)) = next {
+                        state.current_index = index;
+                        println!("Auto-advancing to: {}", next_track.path);
+                        if play_track(&engine_poll, &next_track).is_ok() {
+                            state.playback = Some(Playback::start(&next_track));
+                        }
+                        Some(next_track)
+                    } else {
+                        None
                     }
+                 };
+                 if let Some(next_track) = next_track {
+                    show_now_playing(&library_poll, ui_poll.clone(), next_track);
                  }
             }
             
@@ -246,19 +497,399 @@ async fn main() -> Result<()> {
         }
     });
 
+    // Lyrics of the track playing. A new track clears the panel and asks
+    // the script thread for its lyrics, which it shows once found
+    let lyrics_timer = slint::Timer::default();
+    let ui_lyrics = ui_handle.clone();
+    let state_lyrics = state.clone();
+    let engine_lyrics = engine.clone();
+    lyrics_timer.start(slint::TimerMode::Repeated, LYRICS_TICK, move || {
+        let Some(ui) = ui_lyrics.upgrade() else { return; };
+        let mut panel = lyrics_panel.lock().unwrap();
+        {
+            let state = state_lyrics.lock().unwrap();
+            let playing = state.playback.as_ref().map(|playback| &playback.track);
+            if panel.track_id != playing.map(|track| track.id) {
+                *panel = LyricsPanel { track_id: playing.map(|track| track.id), lyrics: None };
+                show_lyrics(&ui, None);
+                if let Some(track) = playing {
+                    let _ = lyrics_requests.send(track.clone());
+                }
+            }
+        }
+
+        let position_ms = engine_lyrics.position().as_millis() as u32;
+        let current = panel
+            .lyrics
+            .as_ref()
+            .and_then(|lyrics| lyrics.line_at(position_ms))
+            .map_or(-1, |i| i as i32);
+        if ui.get_current_lyric() != current {
+            ui.set_current_lyric(current);
+        }
+    });
+
     ui.run()?;
 
     Ok(())
 }
 
-fn find_cover_art(dir: &Path) -> Option<PathBuf> {
-    if !dir.is_dir() { return None; }
-    std::fs::read_dir(dir).ok()?.filter_map(|e| e.ok()).find(|e| {
-        let name = e.file_name().to_string_lossy().to_lowercase();
-        let is_image = ["jpg", "jpeg", "png"].iter().any(|ext| name.ends_with(ext));
-        let is_common_name = name.starts_with("cover") || name.starts_with("folder") || name.starts_with("front") || name.contains("album");
-        is_image && (is_common_name || true)
-    }).map(|e| e.path())
+/// Records the playback in progress, if any. `completed` is set when the
+/// track ran to its end rather than being skipped.
+fn finish_playback(library: &LibraryService, state: &mut PlayerState, completed: bool) {
+    let Some(playback) = state.playback.take() else {
+        return;
+    };
+    let listened_ms = playback.listened().as_millis() as u64;
+    library.enqueue(move |library| {
+        library.record_play(playback.track.id, playback.started_at, listened_ms, completed)
+    });
+}
+
+fn play_track(engine: &AudioEngine, track: &Track) -> Result<()> {
+    let uri = format!("file://{}", track.path);
+    match track.start_ms {
+        Some(start_ms) => engine.play_range(&uri, millis(start_ms), track.end_ms.map(millis)),
+        None => engine.play_file(&uri),
+    }
+}
+
+fn millis(ms: u32) -> Duration {
+    Duration::from_millis(ms as u64)
+}
+
+/// Queues the next track behind the current one when it carries straight on
+/// in the same file, as consecutive tracks of a CUE sheet image do, so the
+/// join between them plays without a gap.
+fn queue_follow_on(engine: &AudioEngine, library: &LibraryService, state: &mut PlayerState) {
+    if state.queued.is_some() || state.playback.is_none() || state.tracks.is_empty() {
+        return;
+    }
+    let index = (state.current_index + 1) % state.tracks.len();
+    let Some(next) = state.tracks.load(library, index).cloned() else {
+        return;
+    };
+    let Some(current) = state.tracks.get(state.current_index) else {
+        return;
+    };
+    if next.offline || next.path != current.path || current.end_ms.is_none() || next.start_ms != current.end_ms {
+        return;
+    }
+
+    let uri = format!("file://{}", next.path);
+    let start = millis(next.start_ms.unwrap_or(0));
+    match engine.queue_range(&uri, start, next.end_ms.map(millis)) {
+        Ok(()) => state.queued = Some(index),
+        Err(e) => log::error!("Failed to queue {}: {}", next.title, e),
+    }
+}
+
+/// Starts the script host on its own thread, as Lua state cannot move
+/// between threads, and returns where to send the tracks whose lyrics the
+/// panel wants. Found lyrics go to `panel` and the UI, unless another track
+/// has started meanwhile.
+fn start_scripts(
+    engine: Arc<AudioEngine>,
+    library: LibraryService,
+    ui_handle: slint::Weak<MainWindow>,
+    panel: Arc<Mutex<LyricsPanel>>,
+) -> Result<mpsc::Sender<Track>> {
+    let (requests_tx, requests) = mpsc::channel::<Track>();
+    let (ready_tx, ready) = mpsc::sync_channel(1);
+    std::thread::Builder::new().name("scripts".into()).spawn(move || {
+        let started = ScriptHost::new().and_then(|script_host| {
+            script_host.register_global("player", ScriptableAudioEngine(engine))?;
+            script_host.register_global("library", ScriptableLibraryManager(library.clone()))?;
+            script_host.register_global("ui", ScriptableUI(ui_handle.clone()))?;
+            Ok(script_host)
+        });
+        let script_host = match started {
+            Ok(script_host) => {
+                let _ = ready_tx.send(Ok(()));
+                script_host
+            }
+            Err(e) => {
+                let _ = ready_tx.send(Err(e));
+                return;
+            }
+        };
+
+        while let Ok(mut track) = requests.recv() {
+            // Only the latest track still matters after a run of skips.
+            while let Ok(newer) = requests.try_recv() {
+                track = newer;
+            }
+            let lyrics = find_lyrics(&library, &script_host, &track);
+            let (panel, ui_handle) = (panel.clone(), ui_handle.clone());
+            let _ = slint::invoke_from_event_loop(move || {
+                let mut panel = panel.lock().unwrap();
+                if panel.track_id != Some(track.id) {
+                    return;
+                }
+                if let Some(ui) = ui_handle.upgrade() {
+                    show_lyrics(&ui, lyrics.as_ref());
+                }
+                panel.lyrics = lyrics;
+            });
+        }
+    })?;
+    ready.recv()??;
+    Ok(requests_tx)
+}
+
+fn show_lyrics(ui: &MainWindow, lyrics: Option<&Lyrics>) {
+    let lines: Vec<aurora_ui::LyricLine> = lyrics
+        .iter()
+        .flat_map(|lyrics| &lyrics.lines)
+        .map(|line| aurora_ui::LyricLine { text: line.text.clone().into() })
+        .collect();
+    ui.set_lyrics(slint::ModelRc::new(slint::VecModel::from(lines)));
+}
+
+/// The lyrics stored for `track`, or failing that the first a script
+/// provider finds, which are stored for next time.
+fn find_lyrics(library: &LibraryService, script_host: &ScriptHost, track: &Track) -> Option<Lyrics> {
+    let track_id = track.id;
+    match library.read(|library| library.get_lyrics(track_id)) {
+        Ok(Some(lyrics)) => return Some(lyrics),
+        Ok(None) => {}
+        Err(e) => {
+            log::error!("Failed to read lyrics for {}: {}", track.path, e);
+            return None;
+        }
+    }
+
+    let (source, text) = match script_host.find_lyrics(track.clone()) {
+        Ok(found) => found?,
+        Err(e) => {
+            log::error!("Failed to ask lyrics providers for {}: {}", track.path, e);
+            return None;
+        }
+    };
+    let lyrics = Lyrics::parse(&source, &text)?;
+    let stored = lyrics.clone();
+    library.enqueue(move |library| library.set_lyrics(track_id, &stored));
+    Some(lyrics)
+}
+
+fn to_library_track(track: &Track) -> aurora_ui::LibraryTrack {
+    aurora_ui::LibraryTrack {
+        id: track.id as i32,
+        title: track.title.clone().into(),
+        artist: track.artist.clone().into(),
+        album: track.album.clone().into(),
+        offline: track.offline,
+    }
+}
+
+/// The first track that can play, and where it is, starting at `start` and
+/// stepping forwards or backwards with wrap-around. Skips tracks whose
+/// drive is offline, reading pages of the library on the way as needed.
+fn playable_from(state: &mut PlayerState, library: &LibraryService, start: usize, forward: bool) -> Option<(usize, Track)> {
+    let len = state.tracks.len();
+    (0..len)
+        .map(|step| if forward { (start + step) % len } else { (start + len - step) % len })
+        .find_map(|i| Some((i, state.tracks.load(library, i).filter(|track| !track.offline)?.clone())))
+}
+
+/// Reads one page of the list for the model and shows its rows, unless the
+/// list has started over since the page was asked for.
+fn load_page(library: &LibraryService, ui: slint::Weak<MainWindow>, state: Arc<Mutex<PlayerState>>, generation: u64, page: usize) {
+    let query = TrackQuery::new().page(page as u32 * LIBRARY_PAGE, LIBRARY_PAGE);
+    let tracks = match library.read(move |library| library.find_tracks(&query)) {
+        Ok(tracks) => tracks,
+        Err(e) => {
+            log::error!("Failed to load library: {}", e);
+            return;
+        }
+    };
+    let _ = slint::invoke_from_event_loop(move || {
+        // The lock is let go before the list reads the rows back.
+        let filled = state.lock().unwrap().tracks.fill(generation, page, tracks);
+        if let (Some(rows), Some(ui)) = (filled, ui.upgrade()) {
+            with_library_model(&ui, |model| rows.for_each(|row| model.notify.row_changed(row)));
+        }
+    });
+}
+
+/// Starts the list over from the library as it is now, with the current
+/// and queued tracks wherever they have moved to. Reads the library on the
+/// calling thread.
+fn reload_library(library: &LibraryService, ui: &slint::Weak<MainWindow>, state: &Arc<Mutex<PlayerState>>) {
+    let (current, queued) = {
+        let state = state.lock().unwrap();
+        (kept_track(&state, Some(state.current_index)), kept_track(&state, state.queued))
+    };
+    let ids = (current.as_ref().map(|t| t.id), queued.as_ref().map(|t| t.id));
+    let read = library.read(move |library| {
+        let query = TrackQuery::new();
+        let position = |id: Option<i64>| -> Result<Option<usize>> {
+            let Some(id) = id else { return Ok(None) };
+            Ok(library.track_position(&query, id)?.map(|position| position as usize))
+        };
+        Ok((library.count_tracks(&query)? as usize, position(ids.0)?, position(ids.1)?))
+    });
+    let (total, current_index, queued_index) = match read {
+        Ok(read) => read,
+        Err(e) => {
+            log::error!("Failed to load library: {}", e);
+            return;
+        }
+    };
+
+    let (library, ui, state) = (library.clone(), ui.clone(), state.clone());
+    let _ = slint::invoke_from_event_loop(move || {
+        {
+            let mut player = state.lock().unwrap();
+            let now = (
+                kept_track(&player, Some(player.current_index)).map(|t| t.id),
+                kept_track(&player, player.queued).map(|t| t.id),
+            );
+            if now != ids {
+                // Playback moved on meanwhile, so look again.
+                drop(player);
+                std::thread::spawn(move || reload_library(&library, &ui, &state));
+                return;
+            }
+            player.tracks.reset(total);
+            player.current_index = current_index.unwrap_or(0);
+            player.queued = queued_index;
+            // Keep them read, so the next start over can find them too.
+            for (index, track) in [(current_index, current), (queued_index, queued)] {
+                if let (Some(index), Some(track)) = (index, track) {
+                    player.tracks.rows[index] = Some(Box::new(track));
+                }
+            }
+        }
+        if let Some(ui) = ui.upgrade() {
+            with_library_model(&ui, |model| model.notify.reset());
+        }
+    });
+}
+
+fn kept_track(state: &PlayerState, index: Option<usize>) -> Option<Track> {
+    state.tracks.get(index?).cloned()
+}
+
+fn with_library_model(ui: &MainWindow, f: impl FnOnce(&LibraryModel)) {
+    let model = ui.get_library_tracks();
+    if let Some(model) = model.as_any().downcast_ref::<LibraryModel>() {
+        f(model);
+    }
+}
+
+/// Mirrors watcher updates into `PlayerState.tracks` and the list. Tracks
+/// already read are updated in place; a track added or removed moves those
+/// after it, so the list starts over instead.
+fn apply_library_changes(library: &LibraryService, ui: &MainWindow, state: &Arc<Mutex<PlayerState>>, changes: Vec<LibraryChange>) {
+    let mut changed = Vec::new();
+    let mut reload = false;
+    {
+        let mut state = state.lock().unwrap();
+        for change in changes {
+            match change {
+                LibraryChange::Upserted(track) => match state.tracks.position(track.id) {
+                    Some(i) => {
+                        state.tracks.rows[i] = Some(track);
+                        changed.push(i);
+                    }
+                    // New, or on a page not read yet.
+                    None => reload = true,
+                },
+                LibraryChange::RootStatus { path, online } => {
+                    for (i, row) in state.tracks.rows.iter_mut().enumerate() {
+                        if let Some(track) = row.as_mut().filter(|track| Path::new(&track.path).starts_with(&path)) {
+                            if track.offline == online {
+                                track.offline = !online;
+                                changed.push(i);
+                            }
+                        }
+                    }
+                }
+                LibraryChange::Removed { .. } => reload = true,
+            }
+        }
+    }
+
+    with_library_model(ui, |model| changed.into_iter().for_each(|row| model.notify.row_changed(row)));
+    if reload {
+        let (library, ui, state) = (library.clone(), ui.as_weak(), state.clone());
+        std::thread::spawn(move || reload_library(&library, &ui, &state));
+    }
+}
+
+/// Measures the loudness of tracks the library has no measurement for yet,
+/// showing progress where the scan shows its own.
+fn analyze_loudness(library: &LibraryService, ui: &slint::Weak<MainWindow>) {
+    let job = match library.analyze_loudness(LoudnessOptions::default()) {
+        Ok(job) => job,
+        Err(e) => {
+            log::error!("Failed to start loudness analysis: {}", e);
+            return;
+        }
+    };
+    for event in job.events() {
+        let status = match event {
+            LoudnessEvent::Progress(progress) if progress.total > 0 => {
+                format!("Analysing loudness {}/{}", progress.done, progress.total)
+            }
+            LoudnessEvent::Progress(_) => continue,
+            LoudnessEvent::Finished(summary) => {
+                if summary.tracks + summary.failed + summary.skipped > 0 {
+                    println!(
+                        "Loudness analysis complete: {} tracks, {} albums, {} failed, {} skipped.",
+                        summary.tracks, summary.albums, summary.failed, summary.skipped
+                    );
+                }
+                String::new()
+            }
+        };
+        let ui_weak = ui.clone();
+        let _ = slint::invoke_from_event_loop(move || {
+            if let Some(ui) = ui_weak.upgrade() {
+                ui.set_scan_status(status.into());
+            }
+        });
+    }
+    if let Err(e) = job.wait() {
+        log::error!("Loudness analysis failed: {}", e);
+    }
+}
+
+/// Shows `track` as the one playing, from outside the UI thread.
+fn show_now_playing(library: &LibraryService, ui_handle: slint::Weak<MainWindow>, track: Track) {
+    let ui_weak = ui_handle.clone();
+    let (title, artist) = (track.title.clone(), track.artist.clone());
+    let _ = slint::invoke_from_event_loop(move || {
+        if let Some(ui) = ui_weak.upgrade() {
+            ui.set_track_title(title.into());
+            ui.set_track_artist(artist.into());
+        }
+    });
+    show_cover_art(library, ui_handle, &track);
+}
+
+/// Shows the track's cover and retints the UI from a background thread.
+/// The stored cover is read directly; an album nobody has looked at yet
+/// gets its lookup queued for the writer, which may be busy with a scan,
+/// and the cover shows once that has run.
+fn show_cover_art(library: &LibraryService, ui_handle: slint::Weak<MainWindow>, track: &Track) {
+    let library = library.clone();
+    let track = track.clone();
+    std::thread::spawn(move || {
+        let track_id = track.id;
+        match library.read(|library| library.stored_track_cover(track_id)) {
+            Ok(StoredCover::Found(cover_path)) => update_ui_theme(ui_handle, &cover_path),
+            Ok(StoredCover::Missing) => {}
+            Ok(StoredCover::Unknown) => library.enqueue(move |library| {
+                if let Some(cover_path) = library.get_track_cover(track_id)? {
+                    std::thread::spawn(move || update_ui_theme(ui_handle, &cover_path));
+                }
+                Ok(())
+            }),
+            Err(e) => log::error!("Failed to find cover art for {}: {}", track.path, e),
+        }
+    });
 }
 
 fn update_ui_theme(ui_handle: slint::Weak<MainWindow>, cover_path: &Path) {
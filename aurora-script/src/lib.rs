@@ -3,6 +3,9 @@ use mlua::prelude::*;
 use aurora_ui::{MainWindow, AppColors};
 use slint::ComponentHandle;
 
+/// Registry table of lyrics providers, in the order they were registered.
+const LYRICS_PROVIDERS: &str = "lyrics_providers";
+
 pub struct ScriptHost {
     lua: Lua,
 }
@@ -10,6 +13,7 @@ pub struct ScriptHost {
 impl ScriptHost {
     pub fn new() -> Result<Self> {
         let lua = Lua::new();
+        register_lyrics(&lua)?;
         Ok(Self { lua })
     }
 
@@ -23,6 +27,53 @@ impl ScriptHost {
         self.lua.load(script).exec()?;
         Ok(())
     }
+
+    /// Asks each lyrics provider in turn for the lyrics of `track`, which it
+    /// gets as is. Returns the name of the first to find any and what it
+    /// found, LRC or plain text. A provider that fails is logged and skipped.
+    pub fn find_lyrics<T: LuaUserData + 'static>(&self, track: T) -> Result<Option<(String, String)>> {
+        let providers: LuaTable = self.lua.named_registry_value(LYRICS_PROVIDERS)?;
+        let track = self.lua.create_userdata(track)?;
+        for provider in providers.sequence_values::<LuaTable>() {
+            let provider = provider?;
+            let name: String = provider.get("name")?;
+            let find: LuaFunction = provider.get("find")?;
+            match find.call::<_, Option<String>>(track.clone()) {
+                Ok(Some(text)) if !text.trim().is_empty() => return Ok(Some((name, text))),
+                Ok(_) => {}
+                Err(e) => log::error!("Lyrics provider {} failed: {}", name, e),
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// The `lyrics` global. `lyrics.register_provider(name, function(track) ... end)`
+/// adds a provider, or replaces the one of that name; it is asked for the
+/// lyrics of tracks that have none and returns LRC or plain text, or nil.
+fn register_lyrics(lua: &Lua) -> Result<()> {
+    lua.set_named_registry_value(LYRICS_PROVIDERS, lua.create_table()?)?;
+
+    let register = lua.create_function(|lua, (name, find): (String, LuaFunction)| {
+        let providers: LuaTable = lua.named_registry_value(LYRICS_PROVIDERS)?;
+        let provider = lua.create_table()?;
+        provider.set("name", name.clone())?;
+        provider.set("find", find)?;
+
+        let existing = providers
+            .clone()
+            .sequence_values::<LuaTable>()
+            .position(|p| p.and_then(|p| p.get::<_, String>("name")).is_ok_and(|n| n == name));
+        match existing {
+            Some(i) => providers.raw_set(i + 1, provider),
+            None => providers.raw_push(provider),
+        }
+    })?;
+
+    let lyrics = lua.create_table()?;
+    lyrics.set("register_provider", register)?;
+    lua.globals().set("lyrics", lyrics)?;
+    Ok(())
 }
 
 pub struct ScriptableUI(pub slint::Weak<MainWindow>);